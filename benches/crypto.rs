@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use jsonwebtoken as jwt;
+
+use authn::crypto;
+
+const PRIV_KEY : &[u8] = include_bytes!("../src/test-priv-key.pem");
+const PUB_KEY : &[u8] = include_bytes!("../src/test-pub-key.pem");
+
+fn token() -> crypto::Token {
+    crypto::Token{
+        iss : "authn.bench".to_string(),
+        aud : "example.com".to_string(),
+        sub : "alice".to_string(),
+        version : 0,
+        aud_version : 0,
+        acr : Default::default(),
+        act : None,
+        auth_time : None,
+        org : None,
+        extra : Default::default(),
+    }
+}
+
+fn hashing(c : &mut Criterion) {
+    let mut group = c.benchmark_group("argon2");
+    group.sample_size(10);
+
+    group.bench_function("encode_password", |b| {
+        b.iter(|| crypto::encode_password(b"hunter2").unwrap())
+    });
+
+    let hash = crypto::encode_password(b"hunter2").unwrap();
+    group.bench_function("verify_password", |b| {
+        b.iter(|| crypto::verify_password(&hash, b"hunter2").unwrap())
+    });
+
+    group.finish();
+}
+
+fn signing(c : &mut Criterion) {
+    let key = jwt::EncodingKey::from_ec_pem(PRIV_KEY).unwrap();
+    let token = token();
+
+    c.bench_function("issue", |b| {
+        b.iter(|| token.issue(&key, jwt::Algorithm::ES256, Duration::from_secs(60)).unwrap())
+    });
+}
+
+fn validation(c : &mut Criterion) {
+    let enc_key = jwt::EncodingKey::from_ec_pem(PRIV_KEY).unwrap();
+    let dec_key = crypto::decoding_key(jwt::Algorithm::ES256, PUB_KEY).unwrap();
+    let validation = crypto::validation(jwt::Algorithm::ES256, "example.com", "authn.bench");
+
+    let s = token().issue(&enc_key, jwt::Algorithm::ES256, Duration::from_secs(60)).unwrap();
+
+    c.bench_function("validate", |b| {
+        b.iter(|| crypto::Token::validate(&s, &validation, &dec_key).unwrap())
+    });
+}
+
+criterion_group!(benches, hashing, signing, validation);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+//! Records the commit and time of the build, as served by `GET /version`.
+//! `AUTHN_GIT_COMMIT` and `SOURCE_DATE_EPOCH` take their place when set,
+//! e.g. when building from a tarball or reproducibly.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let commit = std::env::var("AUTHN_GIT_COMMIT").ok()
+        .or_else(|| {
+            let out = Command::new("git")
+                .args(["rev-parse", "--short=12", "HEAD"])
+                .output()
+                .ok()
+                .filter(|out| out.status.success())?;
+
+            String::from_utf8(out.stdout).ok()
+        })
+        .unwrap_or_default();
+
+    let time = std::env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+
+    println!("cargo:rustc-env=AUTHN_GIT_COMMIT={}", commit.trim());
+    println!("cargo:rustc-env=AUTHN_BUILD_TIME={}", time);
+}
@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use authn::api;
+
+// every body the server deserializes from a request
+fuzz_target!(|data : &[u8]| {
+    let _ = serde_json::from_slice::<api::PostLoginRequest>(data);
+    let _ = serde_json::from_slice::<api::PostDeviceLoginRequest>(data);
+    let _ = serde_json::from_slice::<api::PostRenewRequest>(data);
+    let _ = serde_json::from_slice::<api::PostLogoutRequest>(data);
+    let _ = serde_json::from_slice::<api::PostAdminImpersonateRequest>(data);
+});
@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use jsonwebtoken as jwt;
+
+use authn::crypto;
+
+const PUB_KEY : &[u8] = include_bytes!("../../src/test-pub-key.pem");
+
+fuzz_target!(|data : &[u8]| {
+    if let Ok(token) = std::str::from_utf8(data) {
+        let key = crypto::decoding_key(jwt::Algorithm::ES256, PUB_KEY).unwrap();
+        let validation = crypto::validation(jwt::Algorithm::ES256, "example.com", "authn.test");
+
+        let _ = crypto::Token::validate(token, &validation, &key);
+    }
+});
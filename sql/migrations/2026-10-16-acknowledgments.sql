@@ -0,0 +1,16 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-acknowledgments.sql');
+
+-- every version of a policy document, e.g. terms of service, a user accepted
+CREATE TABLE acknowledgments (
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	document text NOT NULL,
+	version integer NOT NULL,
+	time integer NOT NULL,
+	PRIMARY KEY (name, document, version)
+);
+
+END;
@@ -0,0 +1,14 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-audience-versions.sql');
+
+CREATE TABLE audience_versions (
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	aud text NOT NULL,
+	token_version integer NOT NULL DEFAULT 0,
+	PRIMARY KEY (name, aud)
+);
+
+END;
@@ -0,0 +1,21 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-authorization-codes.sql');
+
+-- oauth2 authorization codes, each is exchanged for a token at most once
+CREATE TABLE authorization_codes (
+	-- sha256 of the code, the code itself is never stored
+	code_hash text PRIMARY KEY,
+	client_id text NOT NULL,
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	-- the redirect_uri of the authorization request, NULL if it was left
+	-- out for the client's only uri
+	redirect_uri text,
+	-- the S256 pkce challenge
+	code_challenge text NOT NULL,
+	expires integer NOT NULL
+);
+
+END;
@@ -0,0 +1,15 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-break-glass.sql');
+
+-- emergency accounts whose password works once, see `break_glass`
+CREATE TABLE break_glass (
+	name text PRIMARY KEY REFERENCES users(name) ON DELETE CASCADE,
+	armed integer NOT NULL,
+	-- NULL until the password is used
+	used integer
+);
+
+END;
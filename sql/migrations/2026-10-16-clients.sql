@@ -0,0 +1,17 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-clients.sql');
+
+-- every distinct (audience, user agent) a user has logged in through
+CREATE TABLE clients (
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	aud text NOT NULL,
+	user_agent text NOT NULL,
+	first_seen integer NOT NULL,
+	last_seen integer NOT NULL,
+	PRIMARY KEY (name, aud, user_agent)
+);
+
+END;
@@ -0,0 +1,20 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-device-authorizations.sql');
+
+-- pending oauth2 device authorizations (RFC 8628)
+CREATE TABLE device_authorizations (
+	-- sha256 of the device code, the code itself is never stored
+	device_code_hash text PRIMARY KEY,
+	-- the code the user enters, without the dash
+	user_code text NOT NULL UNIQUE,
+	client_id text NOT NULL,
+	-- the user who approved the device, NULL while pending
+	name text REFERENCES users(name) ON DELETE CASCADE,
+	expires integer NOT NULL,
+	last_poll integer
+);
+
+END;
@@ -0,0 +1,19 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-devices.sql');
+
+CREATE TABLE devices (
+	id integer PRIMARY KEY,
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	aud text NOT NULL,
+	-- sha256 of the device token, the token itself is never stored
+	token_hash text NOT NULL UNIQUE,
+	-- the user's global token_version when the device was registered
+	token_version integer NOT NULL,
+	created integer NOT NULL,
+	last_used integer
+);
+
+END;
@@ -0,0 +1,19 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-impersonation.sql');
+
+-- space separated list of roles
+ALTER TABLE users ADD COLUMN roles text NOT NULL DEFAULT '';
+
+CREATE TABLE audit (
+	id integer PRIMARY KEY,
+	time integer NOT NULL DEFAULT (strftime('%s', 'now')),
+	actor text,
+	action text NOT NULL,
+	subject text,
+	detail text
+);
+
+END;
@@ -0,0 +1,24 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-invites.sql');
+
+-- invites to register, each is accepted at most once
+CREATE TABLE invites (
+	id integer PRIMARY KEY,
+	-- sha256 of the invite, the invite itself is never stored
+	token_hash text NOT NULL UNIQUE,
+	-- the only name the invitee may register, any if null
+	name text,
+	-- given to the new user, space separated
+	roles text NOT NULL DEFAULT '',
+	-- the new user joins it with groups
+	org text REFERENCES orgs(name) ON DELETE CASCADE,
+	groups text NOT NULL DEFAULT '',
+	-- null for invites made with authn-utils
+	created_by text,
+	expires integer NOT NULL
+);
+
+END;
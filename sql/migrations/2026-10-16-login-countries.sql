@@ -0,0 +1,16 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-login-countries.sql');
+
+-- every country a user has logged in from, see `geoip`
+CREATE TABLE login_countries (
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	country text NOT NULL,
+	first_seen integer NOT NULL,
+	last_seen integer NOT NULL,
+	PRIMARY KEY (name, country)
+);
+
+END;
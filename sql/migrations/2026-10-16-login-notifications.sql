@@ -0,0 +1,16 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-login-notifications.sql');
+
+ALTER TABLE users ADD COLUMN notify_logins integer NOT NULL DEFAULT 1;
+
+CREATE TABLE logins (
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	aud text NOT NULL,
+	last_login integer NOT NULL,
+	PRIMARY KEY (name, aud)
+);
+
+END;
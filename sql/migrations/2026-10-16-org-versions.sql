@@ -0,0 +1,16 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-org-versions.sql');
+
+-- versions of tokens scoped to an org, kept when the member is removed so
+-- their old tokens stay invalid if they're added again
+CREATE TABLE org_versions (
+	org text NOT NULL REFERENCES orgs(name) ON DELETE CASCADE,
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	token_version integer NOT NULL DEFAULT 0,
+	PRIMARY KEY (org, name)
+);
+
+END;
@@ -0,0 +1,24 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-organizations.sql');
+
+CREATE TABLE orgs (
+	name text PRIMARY KEY,
+	created integer NOT NULL
+);
+
+CREATE TABLE org_members (
+	org text NOT NULL REFERENCES orgs(name) ON DELETE CASCADE,
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	-- may manage the org's members
+	admin integer NOT NULL DEFAULT 0,
+	-- space separated, like users.roles
+	groups text NOT NULL DEFAULT '',
+	PRIMARY KEY (org, name)
+);
+
+CREATE INDEX org_members_name ON org_members (name);
+
+END;
@@ -0,0 +1,13 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-password-history.sql');
+
+CREATE TABLE password_history (
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	pass_hash text NOT NULL,
+	created integer NOT NULL DEFAULT (strftime('%s', 'now'))
+);
+
+END;
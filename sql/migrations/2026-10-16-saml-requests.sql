@@ -0,0 +1,18 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-saml-requests.sql');
+
+-- saml AuthnRequests answered with an assertion, each is answered at most
+-- once while it's fresh
+CREATE TABLE saml_requests (
+	-- entity id of the service provider
+	issuer text NOT NULL,
+	id text NOT NULL,
+	-- when the request stops being fresh and can be forgotten
+	expires integer NOT NULL,
+	PRIMARY KEY (issuer, id)
+);
+
+END;
@@ -0,0 +1,20 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-schema-indexes.sql');
+
+-- users.name is the primary key, so it's unique and indexed already. These
+-- cover the other lookups, the expiry sweeps, and deleting a user, which
+-- cascades to every table referencing users(name).
+CREATE INDEX devices_name ON devices (name);
+CREATE INDEX password_history_name ON password_history (name, created);
+CREATE INDEX audit_time ON audit (time);
+CREATE INDEX authorization_codes_name ON authorization_codes (name);
+CREATE INDEX authorization_codes_expires ON authorization_codes (expires);
+CREATE INDEX device_authorizations_name ON device_authorizations (name);
+CREATE INDEX device_authorizations_expires ON device_authorizations (expires);
+CREATE INDEX invites_expires ON invites (expires);
+CREATE INDEX invites_org ON invites (org);
+
+END;
@@ -0,0 +1,32 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-ssh-keys.sql');
+
+-- public keys users log in with by signing a challenge, see `ssh`
+CREATE TABLE ssh_keys (
+	id integer PRIMARY KEY,
+	name text NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+	-- base64 of the key in the ssh wire format, as in authorized_keys
+	public_key text NOT NULL,
+	comment text NOT NULL,
+	created integer NOT NULL,
+	last_used integer,
+	UNIQUE (name, public_key)
+);
+
+-- challenges handed out for a key, until they're answered or expire
+CREATE TABLE ssh_challenges (
+	-- sha256 of the challenge, the challenge itself is never stored
+	challenge_hash text PRIMARY KEY,
+	key_id integer NOT NULL REFERENCES ssh_keys(id) ON DELETE CASCADE,
+	aud text NOT NULL,
+	expires integer NOT NULL
+);
+
+-- ssh_keys is covered by its unique index on (name, public_key)
+CREATE INDEX ssh_challenges_key_id ON ssh_challenges (key_id);
+CREATE INDEX ssh_challenges_expires ON ssh_challenges (expires);
+
+END;
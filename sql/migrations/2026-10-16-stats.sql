@@ -0,0 +1,16 @@
+PRAGMA foreign_keys = ON;
+
+BEGIN EXCLUSIVE;
+
+INSERT INTO migrations (name) VALUES ('2026-10-16-stats.sql');
+
+-- hourly counters, flushed periodically by the server
+CREATE TABLE stats (
+	hour integer NOT NULL,
+	counter text NOT NULL,
+	label text NOT NULL DEFAULT '',
+	value integer NOT NULL DEFAULT 0,
+	PRIMARY KEY (hour, counter, label)
+);
+
+END;
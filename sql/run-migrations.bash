@@ -5,13 +5,14 @@ if [ $# -ne 1 ]; then
 	exit 1
 fi
 
-latest=$(sqlite3 $1 "SELECT name FROM migrations ORDER BY name DESC LIMIT 1" || true)
-
 dir=$(dirname $0)/migrations
 echo $dir
 
 for file in $(ls $dir | sort); do
-	if [ $file ">" "$latest" ]; then
+	# migrations from the same day don't necessarily sort after the latest
+	# applied one, so check each file individually
+	applied=$(sqlite3 $1 "SELECT count(*) FROM migrations WHERE name = '$file'" 2> /dev/null || echo 0)
+	if [ "$applied" = "0" ]; then
 		echo "running $file"
 		cat $dir/$file | sqlite3 -bail $1
 	fi
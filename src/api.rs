@@ -0,0 +1,699 @@
+//! Request and response bodies of the server's http api, all of them are
+//! json. Types the server may grow new fields on are `#[non_exhaustive]`
+//! and have constructors instead.
+
+use std::collections::BTreeMap;
+
+use serde::{Serialize,Deserialize};
+
+use crate::crypto::Secret;
+use crate::strength::Estimate;
+
+/// `POST /login`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostLoginRequest {
+    /// audience the token is issued for
+    pub aud : String,
+    /// requested lifetime of the token in seconds, the server may shorten
+    /// it
+    pub duration : u64,
+    pub name : String,
+    pub pass : Secret,
+    /// also issue a long lived device token
+    #[serde(default)]
+    pub remember : bool,
+    /// scope the token to an organization the user is a member of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org : Option<String>,
+}
+
+impl PostLoginRequest {
+    pub fn new(name : &str, pass : &str, aud : &str, duration : u64) -> Self {
+        Self{
+            aud : aud.to_string(),
+            duration,
+            name : name.to_string(),
+            pass : pass.into(),
+            remember : false,
+            org : None,
+        }
+    }
+
+    pub fn remember(mut self, remember : bool) -> Self {
+        self.remember = remember;
+        self
+    }
+
+    pub fn org(mut self, org : &str) -> Self {
+        self.org = Some(org.to_string());
+        self
+    }
+}
+
+/// Response of `POST /login`, `POST /device/login`, `POST /login/ssh`,
+/// `POST /renew` and `POST /admin/impersonate`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostLoginResponse {
+    pub token : String,
+    /// only set when the login asked to be remembered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_token : Option<String>,
+    /// the id of the device of `device_token`, to revoke it with
+    /// `DELETE /devices/:id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id : Option<i64>,
+}
+
+impl PostLoginResponse {
+    pub fn new(token : String) -> Self {
+        Self{
+            token,
+            device_token : None,
+            device_id : None,
+        }
+    }
+}
+
+/// `POST /device/login`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostDeviceLoginRequest {
+    pub device_token : String,
+    /// requested lifetime of the token in seconds
+    pub duration : u64,
+}
+
+impl PostDeviceLoginRequest {
+    pub fn new(device_token : &str, duration : u64) -> Self {
+        Self{
+            device_token : device_token.to_string(),
+            duration,
+        }
+    }
+}
+
+/// `POST /login` with a kerberos ticket in the `authorization` header
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostNegotiateLoginRequest {
+    pub aud : String,
+    /// requested lifetime of the token in seconds
+    pub duration : u64,
+}
+
+impl PostNegotiateLoginRequest {
+    pub fn new(aud : &str, duration : u64) -> Self {
+        Self{
+            aud : aud.to_string(),
+            duration,
+        }
+    }
+}
+
+/// `POST /login/ssh/challenge`, asks for a challenge to sign with one of
+/// the user's ssh keys, see `ssh`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostSshChallengeRequest {
+    pub name : String,
+    /// audience the token is issued for
+    pub aud : String,
+    /// base64 of the key in the ssh wire format, as in authorized_keys
+    pub public_key : String,
+}
+
+impl PostSshChallengeRequest {
+    pub fn new(name : &str, aud : &str, public_key : &str) -> Self {
+        Self{
+            name : name.to_string(),
+            aud : aud.to_string(),
+            public_key : public_key.to_string(),
+        }
+    }
+}
+
+/// Response of `POST /login/ssh/challenge`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostSshChallengeResponse {
+    pub challenge : String,
+    /// unix time
+    pub expires : i64,
+}
+
+/// `POST /login/ssh`, answers a challenge with the signature of
+/// `ssh::signed_data`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostSshLoginRequest {
+    pub challenge : String,
+    /// base64 of the signature in the ssh wire format, as an ssh agent
+    /// returns it
+    pub signature : String,
+    /// requested lifetime of the token in seconds
+    pub duration : u64,
+}
+
+impl PostSshLoginRequest {
+    pub fn new(challenge : &str, signature : &str, duration : u64) -> Self {
+        Self{
+            challenge : challenge.to_string(),
+            signature : signature.to_string(),
+            duration,
+        }
+    }
+}
+
+/// `POST /ssh-keys`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostSshKeyRequest {
+    /// a line of authorized_keys without options, e.g.
+    /// `ssh-ed25519 AAAA... alice@laptop`
+    pub public_key : String,
+}
+
+impl PostSshKeyRequest {
+    pub fn new(public_key : &str) -> Self {
+        Self{
+            public_key : public_key.to_string(),
+        }
+    }
+}
+
+/// Response of `POST /ssh-keys`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostSshKeyResponse {
+    /// id used to remove the key with `DELETE /ssh-keys/:id`
+    pub id : i64,
+}
+
+/// An ssh key a user logs in with
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct SshKeyInfo {
+    /// id used to remove the key with `DELETE /ssh-keys/:id`
+    pub id : i64,
+    /// the key type and base64 key, as in authorized_keys
+    pub public_key : String,
+    pub comment : String,
+    /// `SHA256:...`, as `ssh-keygen -l` prints it
+    pub fingerprint : String,
+    /// unix time
+    pub created : i64,
+    /// unix time, `None` if the key was never logged in with
+    pub last_used : Option<i64>,
+}
+
+/// Response of `GET /ssh-keys`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetSshKeysResponse {
+    pub keys : Vec<SshKeyInfo>,
+}
+
+/// A remembered device
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct DeviceInfo {
+    /// id used to revoke the device with `DELETE /devices/:id`
+    pub id : i64,
+    pub aud : String,
+    /// unix time
+    pub created : i64,
+    /// unix time, `None` if the device token was never exchanged
+    pub last_used : Option<i64>,
+}
+
+/// Response of `GET /devices`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetDevicesResponse {
+    pub devices : Vec<DeviceInfo>,
+}
+
+/// An audience and user agent a user has logged in through
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct ClientInfo {
+    pub aud : String,
+    pub user_agent : String,
+    /// unix time
+    pub first_seen : i64,
+    /// unix time
+    pub last_seen : i64,
+}
+
+/// Response of `GET /me/devices`, a list endpoint, see `listing`, sorted by
+/// `last_seen` and filtered by `aud`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetMeDevicesResponse {
+    pub devices : Vec<ClientInfo>,
+    /// the cursor of the next page, if there may be one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next : Option<String>,
+}
+
+/// A policy document, e.g. terms of service, as listed by
+/// `GET /me/consent`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct ConsentDocument {
+    pub name : String,
+    /// the current version
+    pub version : u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url : Option<String>,
+    /// tokens list the document in their `pending_consent` claim until the
+    /// current version is accepted
+    pub required : bool,
+    /// the newest version the user accepted
+    #[serde(default)]
+    pub accepted : Option<u32>,
+}
+
+/// Response of `GET /me/consent`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetMeConsentResponse {
+    pub documents : Vec<ConsentDocument>,
+}
+
+/// `POST /me/consent`, accepts the current version of a document. Tokens
+/// renewed afterwards no longer list it as pending.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostMeConsentRequest {
+    pub document : String,
+    /// must be the current version, so users accept what they were shown
+    pub version : u32,
+}
+
+impl PostMeConsentRequest {
+    pub fn new(document : &str, version : u32) -> Self {
+        Self{
+            document : document.to_string(),
+            version,
+        }
+    }
+}
+
+/// `POST /renew`, refused for impersonated tokens
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostRenewRequest {
+    /// requested lifetime of the new token in seconds
+    pub duration : u64,
+}
+
+impl PostRenewRequest {
+    pub fn new(duration : u64) -> Self {
+        Self{ duration }
+    }
+}
+
+/// `POST /logout`, the body may also be empty
+#[derive(Serialize,Deserialize,Debug,Clone,Default)]
+#[non_exhaustive]
+pub struct PostLogoutRequest {
+    /// invalidate tokens for every audience, not just the token's
+    #[serde(default)]
+    pub everywhere : bool,
+}
+
+impl PostLogoutRequest {
+    pub fn everywhere(mut self, everywhere : bool) -> Self {
+        self.everywhere = everywhere;
+        self
+    }
+}
+
+/// Response of `GET /version`, what the server was built from, e.g. to tell
+/// the instances of a fleet apart while it's upgraded
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetVersionResponse {
+    /// of the crate
+    pub version : String,
+    /// the git commit, if it was known to the build
+    #[serde(default)]
+    pub commit : Option<String>,
+    /// unix time of the build
+    #[serde(default)]
+    pub built : Option<u64>,
+    /// the cargo features of the build
+    pub features : Vec<String>,
+    /// the algorithm tokens are signed with
+    pub alg : String,
+}
+
+/// Response of `GET /user/:name?aud=`, a token is only valid while both of
+/// its versions match these
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetUserResponse {
+    pub name : String,
+    /// the user's global token version
+    pub token_version : u32,
+    /// the token version for the `aud` query parameter, 0 without one
+    #[serde(default)]
+    pub aud_version : u32,
+}
+
+impl GetUserResponse {
+    pub fn new(name : String, token_version : u32, aud_version : u32) -> Self {
+        Self{
+            name,
+            token_version,
+            aud_version,
+        }
+    }
+}
+
+/// `POST /admin/impersonate`, requires the impersonate role. The token has
+/// the caller in its `act` claim and the `password` assurance, whatever
+/// the caller's. Issuing it and requests to the server with it are in the
+/// audit log, but not its uses at apps which validate tokens themselves,
+/// which should log `act` if they need a record.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostAdminImpersonateRequest {
+    /// the user to issue a token for
+    pub sub : String,
+    pub aud : String,
+    /// requested lifetime of the token in seconds
+    pub duration : u64,
+}
+
+impl PostAdminImpersonateRequest {
+    pub fn new(sub : &str, aud : &str, duration : u64) -> Self {
+        Self{
+            sub : sub.to_string(),
+            aud : aud.to_string(),
+            duration,
+        }
+    }
+}
+
+/// Response of `GET /admin/stats`, requires the admin role. Counters cover
+/// the last 24 hours.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetAdminStatsResponse {
+    /// total number of users
+    pub users : i64,
+    pub logins : i64,
+    pub failed_logins : i64,
+    /// failed logins over all login attempts, 0 without any attempts
+    pub failure_rate : f64,
+    /// by audience
+    pub tokens_issued : BTreeMap<String, i64>,
+    /// requests to the discovery routes, e.g. `GET /pub-key`, by
+    /// `anonymous` and `authenticated`, and the anonymous ones refused as
+    /// `limited`
+    #[serde(default)]
+    pub discovery : BTreeMap<String, i64>,
+    /// in bytes
+    pub database_size : i64,
+    /// of `POST /login`, over the server's latency window rather than 24
+    /// hours
+    #[serde(default)]
+    pub login_latency : LatencyQuantiles,
+    /// of bearer token validations, over the latency window
+    #[serde(default)]
+    pub validate_latency : LatencyQuantiles,
+}
+
+/// Recent latencies in seconds, zero without any requests
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,Default)]
+pub struct LatencyQuantiles {
+    pub count : usize,
+    pub p50 : f64,
+    pub p95 : f64,
+    pub p99 : f64,
+}
+
+/// A user as listed by `GET /admin/users`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct AdminUserInfo {
+    pub name : String,
+    pub token_version : u32,
+    pub roles : Vec<String>,
+}
+
+/// Response of `GET /admin/users`, requires the admin role. A list endpoint,
+/// see `listing`, sorted by name and filtered by `role` and a name
+/// `prefix`. Pages may be short of the limit, they skip users the admin
+/// may not see.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetAdminUsersResponse {
+    pub users : Vec<AdminUserInfo>,
+    /// the cursor of the next page, if there may be one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next : Option<String>,
+}
+
+/// An entry of the audit log
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct AuditEntry {
+    pub id : i64,
+    /// unix time
+    pub time : i64,
+    /// `None` for changes made with `authn-utils`
+    pub actor : Option<String>,
+    pub action : String,
+    pub subject : Option<String>,
+    pub detail : Option<String>,
+}
+
+/// Response of `GET /admin/audit`, requires the admin role. A list
+/// endpoint, see `listing`, sorted by id, newest first by default, and
+/// filtered by `actor`, `action` and `subject`. Without a limit it holds
+/// 100 entries. `before` is still accepted in place of the cursor.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetAdminAuditResponse {
+    pub entries : Vec<AuditEntry>,
+    /// the cursor of the next page, if there may be one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next : Option<String>,
+}
+
+/// `PUT /admin/maintenance`, requires the admin role. Until a `DELETE` of
+/// the same path, logins and other changes fail with a 503 and `message`,
+/// reads such as token validation keep working.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PutAdminMaintenanceRequest {
+    pub message : String,
+}
+
+impl PutAdminMaintenanceRequest {
+    pub fn new(message : &str) -> Self {
+        Self{ message : message.to_string() }
+    }
+}
+
+/// `POST /admin/users/:name/password`, requires the admin role. Also
+/// invalidates the user's tokens.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostAdminPasswordRequest {
+    pub pass : Secret,
+}
+
+impl PostAdminPasswordRequest {
+    pub fn new(pass : &str) -> Self {
+        Self{ pass : pass.into() }
+    }
+}
+
+/// `POST /admin/invites`, the invitee registers with
+/// `PostRegisterAcceptRequest`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostAdminInviteRequest {
+    /// the only name the invitee may register, any if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name : Option<String>,
+    /// only roles the creator holds
+    #[serde(default)]
+    pub roles : Vec<String>,
+    /// an organization the invitee joins, with `groups`, one the creator
+    /// may manage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org : Option<String>,
+    #[serde(default)]
+    pub groups : Vec<String>,
+    /// requested lifetime of the invite in seconds, the server may shorten
+    /// it
+    pub duration : u64,
+}
+
+impl PostAdminInviteRequest {
+    pub fn new(duration : u64) -> Self {
+        Self{
+            name : None,
+            roles : Vec::new(),
+            org : None,
+            groups : Vec::new(),
+            duration,
+        }
+    }
+
+    pub fn name(mut self, name : &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    pub fn roles(mut self, roles : &[&str]) -> Self {
+        self.roles = roles.iter().map(|role| role.to_string()).collect();
+        self
+    }
+
+    pub fn org(mut self, org : &str, groups : &[&str]) -> Self {
+        self.org = Some(org.to_string());
+        self.groups = groups.iter().map(|group| group.to_string()).collect();
+        self
+    }
+}
+
+/// Response of `POST /admin/invites`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostAdminInviteResponse {
+    pub invite : String,
+    /// the registration page with the invite, if the server has one
+    /// configured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url : Option<String>,
+    /// unix time
+    pub expires : i64,
+}
+
+/// `POST /register/accept`, creates the user an invite is for
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostRegisterAcceptRequest {
+    pub invite : String,
+    pub name : String,
+    pub pass : Secret,
+}
+
+impl PostRegisterAcceptRequest {
+    pub fn new(invite : &str, name : &str, pass : &str) -> Self {
+        Self{
+            invite : invite.to_string(),
+            name : name.to_string(),
+            pass : pass.into(),
+        }
+    }
+}
+
+/// `POST /password/estimate`, how strong `pass` would be, e.g. while a
+/// user picks one. `user_inputs` are guessed first, e.g. the user's name.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostPasswordEstimateRequest {
+    pub pass : Secret,
+    #[serde(default)]
+    pub user_inputs : Vec<String>,
+}
+
+impl PostPasswordEstimateRequest {
+    pub fn new(pass : &str, user_inputs : &[&str]) -> Self {
+        Self{
+            pass : pass.into(),
+            user_inputs : user_inputs.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+/// Response of `POST /password/estimate`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostPasswordEstimateResponse {
+    #[serde(flatten)]
+    pub estimate : Estimate,
+    /// the lowest score the server accepts, see `strength::Config`
+    pub min_score : u8,
+}
+
+/// A member as listed by `GET /orgs/:org/members`
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct OrgMemberInfo {
+    pub name : String,
+    pub admin : bool,
+    pub groups : Vec<String>,
+}
+
+/// Response of `GET /orgs/:org/members`, requires being an admin of the
+/// organization or the admin role
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct GetOrgMembersResponse {
+    pub members : Vec<OrgMemberInfo>,
+}
+
+/// `PUT /orgs/:org/members/:name`, adds the user to the organization or
+/// replaces their membership. Requires being an admin of the organization
+/// or the admin role. Changing a membership invalidates the member's
+/// tokens, so none carry stale org claims.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PutOrgMemberRequest {
+    #[serde(default)]
+    pub admin : bool,
+    #[serde(default)]
+    pub groups : Vec<String>,
+}
+
+impl PutOrgMemberRequest {
+    pub fn new(admin : bool, groups : &[&str]) -> Self {
+        Self{
+            admin,
+            groups : groups.iter().map(|group| group.to_string()).collect(),
+        }
+    }
+}
+
+/// Response of `POST /token`, as RFC 6749 defines it. The request is form
+/// encoded.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostTokenResponse {
+    pub access_token : String,
+    /// always `Bearer`
+    pub token_type : String,
+    /// lifetime of the token in seconds
+    pub expires_in : u64,
+    /// set by token exchanges, as RFC 8693 requires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub issued_token_type : Option<String>,
+}
+
+/// Response of `POST /device_authorization`, as RFC 8628 defines it. The
+/// request is form encoded.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[non_exhaustive]
+pub struct PostDeviceAuthorizationResponse {
+    /// exchanged at `POST /token` once the user approved the device
+    pub device_code : String,
+    /// shown to the user, to be entered at `verification_uri`
+    pub user_code : String,
+    pub verification_uri : String,
+    /// `verification_uri` with the user code filled in
+    pub verification_uri_complete : String,
+    /// seconds until the codes expire
+    pub expires_in : u64,
+    /// seconds to wait between polls of `POST /token`
+    pub interval : u64,
+}
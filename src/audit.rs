@@ -0,0 +1,401 @@
+//! Shipping the audit log elsewhere, e.g. into a SIEM. Every entry is
+//! written to the `audit` table first, then handed to each sink of
+//! `Config::audit_sinks` and `Server::with_audit_sink` in the background, as
+//! the json of `api::AuditEntry`. A failing sink is logged but never fails
+//! the audited request, the table stays the complete record. Changes made
+//! with `authn-utils` only go to the table.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+#[cfg(any(feature = "nats", feature = "kafka"))]
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::api::AuditEntry;
+use crate::logging;
+use crate::models;
+use crate::server::{
+    self,
+    Error,
+    HookFuture,
+    TransportError,
+    WebhookClient,
+};
+
+/// how long a publish to nats or kafka may take before it fails
+#[cfg(any(feature = "nats", feature = "kafka"))]
+const PUBLISH_TIMEOUT : Duration = Duration::from_secs(10);
+
+fn default_syslog_path() -> PathBuf {
+    "/dev/log".into()
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum SinkConfig {
+    /// one json object per line, the file is only ever appended to
+    File {
+        path : PathBuf,
+    },
+    /// a syslog socket, with the authpriv facility
+    Syslog {
+        #[serde(default = "default_syslog_path")]
+        path : PathBuf,
+    },
+    /// each entry is `POST`ed as json, `url` must be https
+    Webhook {
+        url : String,
+    },
+    /// each entry is published to `subject` of a nats server
+    #[cfg(feature = "nats")]
+    Nats {
+        /// `host:port` of the server
+        addr : String,
+        subject : String,
+        #[serde(default)]
+        token : Option<String>,
+    },
+    /// each entry is produced to `topic`, keyed by its id
+    #[cfg(feature = "kafka")]
+    Kafka {
+        /// comma separated `host:port` of the brokers
+        brokers : String,
+        topic : String,
+        /// further librdkafka properties, e.g. `security.protocol`
+        #[serde(default)]
+        options : std::collections::BTreeMap<String, String>,
+    },
+}
+
+/// Receives every audit entry after it's in the table. The entry is only
+/// borrowed for the call, sinks serialize it before returning the future.
+pub trait AuditSink : Send + Sync {
+    fn write(&self, entry : &AuditEntry) -> HookFuture<Result<(), Error>>;
+}
+
+impl From<models::AuditEntry> for AuditEntry {
+    fn from(entry : models::AuditEntry) -> Self {
+        Self{
+            id : entry.id,
+            time : entry.time,
+            actor : entry.actor,
+            action : entry.action,
+            subject : entry.subject,
+            detail : entry.detail,
+        }
+    }
+}
+
+/// opens the sinks of the config
+pub fn open(configs : &[SinkConfig]) -> Result<Vec<Box<dyn AuditSink>>, Error> {
+    let mut sinks = Vec::<Box<dyn AuditSink>>::new();
+    for config in configs {
+        sinks.push(match config {
+            SinkConfig::File{ path } => Box::new(FileSink::new(path.clone())?),
+            #[cfg(unix)]
+            SinkConfig::Syslog{ path } => Box::new(SyslogSink{
+                path : path.clone(),
+                socket : UnixDatagram::unbound()?,
+            }),
+            #[cfg(not(unix))]
+            SinkConfig::Syslog{ .. } => return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "syslog is only available on unix",
+            ).into()),
+            SinkConfig::Webhook{ url } => {
+                let (url, client) = server::webhook(url)?;
+                Box::new(WebhookSink{ url, client })
+            },
+            #[cfg(feature = "nats")]
+            SinkConfig::Nats{ addr, subject, token } => Box::new(NatsSink::new(addr, subject, token.as_deref())),
+            #[cfg(feature = "kafka")]
+            SinkConfig::Kafka{ brokers, topic, options } => Box::new(KafkaSink::new(brokers, topic, options)?),
+        });
+    }
+
+    Ok(sinks)
+}
+
+/// hands `entry` to every sink in the background, failures are logged
+pub(crate) fn deliver(sinks : &[Box<dyn AuditSink>], entry : &AuditEntry) {
+    if sinks.is_empty() {
+        return
+    }
+
+    let id = entry.id;
+    let writes = sinks.iter()
+        .map(|sink| sink.write(entry))
+        .collect::<Vec<_>>();
+
+    tokio::spawn(async move {
+        for write in writes {
+            if let Err(err) = write.await {
+                logging::error!([("audit_id", serde_json::json!(id))], "audit sink failed: {:?}", err);
+            }
+        }
+    });
+}
+
+struct FileSink {
+    file : Mutex<File>,
+}
+
+impl FileSink {
+    fn new(path : PathBuf) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self{
+            file : Mutex::new(file),
+        })
+    }
+}
+
+impl AuditSink for FileSink {
+    fn write(&self, entry : &AuditEntry) -> HookFuture<Result<(), Error>> {
+        let res = serde_json::to_string(entry)
+            .map_err(Error::from)
+            .and_then(|mut line| {
+                line.push('\n');
+                // a single write, so lines of concurrent entries don't mix
+                Ok(self.file.lock().unwrap().write_all(line.as_bytes())?)
+            });
+
+        Box::pin(async move { res })
+    }
+}
+
+#[cfg(unix)]
+struct SyslogSink {
+    path : PathBuf,
+    socket : UnixDatagram,
+}
+
+#[cfg(unix)]
+impl AuditSink for SyslogSink {
+    fn write(&self, entry : &AuditEntry) -> HookFuture<Result<(), Error>> {
+        // facility authpriv (10), severity info (6), the timestamp is added
+        // by the receiver
+        let res = serde_json::to_string(entry)
+            .map_err(Error::from)
+            .and_then(|json| {
+                let line = format!("<{}>authn[{}]: {}", 10 * 8 + 6, std::process::id(), json);
+                self.socket.send_to(line.as_bytes(), &self.path)?;
+                Ok(())
+            });
+
+        Box::pin(async move { res })
+    }
+}
+
+struct WebhookSink {
+    url : hyper::Uri,
+    client : WebhookClient,
+}
+
+impl AuditSink for WebhookSink {
+    fn write(&self, entry : &AuditEntry) -> HookFuture<Result<(), Error>> {
+        let req = http::Request::builder()
+            .uri(self.url.clone())
+            .method("POST")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(entry).unwrap().into())
+            .unwrap();
+
+        let client = self.client.clone();
+
+        Box::pin(async move {
+            let res = client.request(req).await?;
+            if !res.status().is_success() {
+                return Err(TransportError::AuditSink(format!("webhook responded {}", res.status())).into())
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Publishes with the text protocol of nats over a connection kept between
+/// entries. Each publish is followed by a `PING`, the `PONG` confirms the
+/// server took the entry.
+#[cfg(feature = "nats")]
+struct NatsSink {
+    addr : String,
+    subject : String,
+    /// the `CONNECT` line sent on every new connection
+    connect : String,
+    conn : std::sync::Arc<tokio::sync::Mutex<Option<NatsConn>>>,
+}
+
+#[cfg(feature = "nats")]
+type NatsConn = tokio::io::BufStream<tokio::net::TcpStream>;
+
+#[cfg(feature = "nats")]
+impl NatsSink {
+    fn new(addr : &str, subject : &str, token : Option<&str>) -> Self {
+        let mut options = serde_json::json!({
+            "verbose" : false,
+            "pedantic" : false,
+            "name" : "authn",
+        });
+        if let Some(token) = token {
+            options["auth_token"] = token.into();
+        }
+
+        Self{
+            addr : addr.to_string(),
+            subject : subject.to_string(),
+            connect : format!("CONNECT {}\r\n", options),
+            conn : Default::default(),
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+async fn nats_connect(addr : &str, connect : &str) -> Result<NatsConn, Error> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let mut conn = tokio::io::BufStream::new(tokio::net::TcpStream::connect(addr).await?);
+
+    let mut line = String::new();
+    conn.read_line(&mut line).await?;
+    if !line.starts_with("INFO ") {
+        return Err(TransportError::AuditSink(format!("unexpected nats greeting: {}", line.trim_end())).into())
+    }
+
+    conn.write_all(connect.as_bytes()).await?;
+    conn.write_all(b"PING\r\n").await?;
+    conn.flush().await?;
+    nats_pong(&mut conn).await?;
+
+    Ok(conn)
+}
+
+/// reads up to the `PONG`, answering the server's own pings
+#[cfg(feature = "nats")]
+async fn nats_pong(conn : &mut NatsConn) -> Result<(), Error> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(TransportError::AuditSink("nats closed the connection".to_string()).into())
+        }
+
+        match line.trim_end() {
+            "PONG" => return Ok(()),
+            "PING" => {
+                conn.write_all(b"PONG\r\n").await?;
+                conn.flush().await?;
+            },
+            err if err.starts_with("-ERR") => {
+                return Err(TransportError::AuditSink(format!("nats: {}", err)).into())
+            },
+            // +OK and INFO updates
+            _ => {},
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+async fn nats_publish(conn : &mut NatsConn, subject : &str, payload : &[u8]) -> Result<(), Error> {
+    use tokio::io::AsyncWriteExt;
+
+    conn.write_all(format!("PUB {} {}\r\n", subject, payload.len()).as_bytes()).await?;
+    conn.write_all(payload).await?;
+    conn.write_all(b"\r\nPING\r\n").await?;
+    conn.flush().await?;
+    nats_pong(conn).await
+}
+
+#[cfg(feature = "nats")]
+impl AuditSink for NatsSink {
+    fn write(&self, entry : &AuditEntry) -> HookFuture<Result<(), Error>> {
+        let payload = serde_json::to_vec(entry).unwrap();
+        let addr = self.addr.clone();
+        let subject = self.subject.clone();
+        let connect = self.connect.clone();
+        let conn = self.conn.clone();
+
+        Box::pin(async move {
+            let mut conn = conn.lock().await;
+
+            // a connection the server dropped since is replaced once
+            let mut retried = false;
+            loop {
+                if conn.is_none() {
+                    *conn = Some(nats_connect(&addr, &connect).await?);
+                }
+
+                let publish = nats_publish(conn.as_mut().unwrap(), &subject, &payload);
+                let res = match tokio::time::timeout(PUBLISH_TIMEOUT, publish).await {
+                    Ok(res) => res,
+                    Err(_) => Err(TransportError::AuditSink("nats timed out".to_string()).into()),
+                };
+
+                match res {
+                    Ok(()) => return Ok(()),
+                    Err(err) if retried => {
+                        *conn = None;
+                        return Err(err)
+                    },
+                    Err(_) => {
+                        *conn = None;
+                        retried = true;
+                    },
+                }
+            }
+        })
+    }
+}
+
+#[cfg(feature = "kafka")]
+struct KafkaSink {
+    producer : rdkafka::producer::FutureProducer,
+    topic : String,
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    fn new(
+        brokers : &str,
+        topic : &str,
+        options : &std::collections::BTreeMap<String, String>,
+    ) -> Result<Self, Error> {
+        let mut config = rdkafka::ClientConfig::new();
+        config.set("bootstrap.servers", brokers);
+        for (key, value) in options {
+            config.set(key, value);
+        }
+
+        Ok(Self{
+            producer : config.create()
+                .map_err(|err| crate::server::ConfigError::InvalidAuditSink(err.to_string()))?,
+            topic : topic.to_string(),
+        })
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl AuditSink for KafkaSink {
+    fn write(&self, entry : &AuditEntry) -> HookFuture<Result<(), Error>> {
+        let payload = serde_json::to_vec(entry).unwrap();
+        let key = entry.id.to_string();
+        let producer = self.producer.clone();
+        let topic = self.topic.clone();
+
+        Box::pin(async move {
+            let record = rdkafka::producer::FutureRecord::to(&topic)
+                .key(&key)
+                .payload(&payload);
+
+            producer.send(record, PUBLISH_TIMEOUT).await
+                .map(|_| ())
+                .map_err(|(err, _)| TransportError::AuditSink(format!("kafka: {}", err)).into())
+        })
+    }
+}
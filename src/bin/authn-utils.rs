@@ -1,109 +1,1904 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::str::FromStr;
 use std::convert::TryInto;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 
+use rand::rngs::OsRng;
+use serde::{Serialize, Deserialize};
+use rustyline::{Editor, Helper};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
 
-use authn::database::Database;
+
+use authn::database::{Database, DEFAULT_PASSWORD_HISTORY, MIGRATIONS};
+use authn::server;
+use authn::config;
 use authn::crypto;
+use authn::strength;
+use authn::models;
+use authn::invites;
+use authn::ssh;
 use authn::client::{Config, Client};
+use authn::events::{Event, EventBus};
+#[cfg(feature = "redis")]
+use authn::events::RedisBus;
 
 
 #[tokio::main]
 async fn main() {
 
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
     let config_file = std::env::var("AUTHN_CONFIG").unwrap_or("config.json".to_string());
+    let sources = match config::Sources::file(&config_file).with_env().with_flags(&mut args) {
+        Ok(sources) => sources,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
+    };
+    let args_ref = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
 
-    let config_string = if let Ok(s) = std::fs::read_to_string(&config_file) {
-        s
-    } else {
-        eprintln!(concat!(
-            "could not find config file, set AUTHN_CONFIG ",
-            "or write a file to config.json"
-        ));
-        std::process::exit(1);
+    // these don't need a config, so they work at packaging time
+    match &args_ref[..] {
+        ["completions", shell] => {
+            match completions(shell) {
+                Some(script) => print!("{}", script),
+                None => {
+                    eprintln!("unknown shell: {}", shell);
+                    std::process::exit(1);
+                },
+            }
+            return
+        },
+        ["man"] => {
+            print!("{}", man_page());
+            return
+        },
+        ["migrate-config", config_file] => {
+            if let Err(err) = migrate_config(config_file) {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return
+        },
+        ["demo"] => {
+            if let Err(err) = demo().await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+            return
+        },
+        _ => {},
+    }
+
+    let config : Config = match sources.load() {
+        Ok(config) => config,
+        Err(config::LoadError::Io(_, _)) => {
+            eprintln!(concat!(
+                "could not find config file, set AUTHN_CONFIG ",
+                "or write a file to config.json"
+            ));
+            std::process::exit(1);
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
+    };
+    let passwords : Passwords = match sources.load() {
+        Ok(passwords) => passwords,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
+    };
+    let events : Events = match sources.load() {
+        Ok(events) => events,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
+    };
+    let client : Client = match config.clone().try_into() {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("invalid client config: {:?}", err);
+            std::process::exit(1);
+        },
     };
 
-    let config : Config = serde_json::from_str(&config_string).unwrap();
-    let client : Client = config.try_into().unwrap();
+    let ctx = Arc::new(Context{
+        client : Arc::new(client),
+        config,
+        passwords,
+        event_bus : events.bus(),
+        databases : Mutex::new(HashMap::new()),
+    });
+
+    if let ["shell", db_file] = &args_ref[..] {
+        shell(ctx, db_file).await;
+        return
+    }
+
+    if run(ctx, args).await.is_err() {
+        std::process::exit(1);
+    }
+}
+
+/// a subcommand, as listed by `help`, completions and the man page
+struct Command {
+    name : &'static str,
+    /// the arguments after the name, as shown in the usage
+    args : &'static str,
+    about : &'static str,
+}
+
+impl Command {
+    /// whether the first argument is db_file, which the shell fills in
+    fn takes_db(&self) -> bool {
+        self.args.starts_with("db_file")
+    }
+}
+
+const COMMANDS : &[Command] = &[
+    Command{
+        name : "add-user",
+        args : "db_file user",
+        about : "add a user, prompting for the password and rating its strength",
+    },
+    Command{
+        name : "update-user-pass",
+        args : "db_file user",
+        about : "change a user's password, refusing recently used ones",
+    },
+    Command{
+        name : "list-users",
+        args : "db_file",
+        about : "list users and their roles",
+    },
+    Command{
+        name : "prune-password-history",
+        args : "db_file",
+        about : "forget passwords beyond the history length",
+    },
+    Command{
+        name : "tune-argon2",
+        args : "[--target-ms ms]",
+        about : "print argon2 costs hashing in about the target time",
+    },
+    Command{
+        name : "rotate-keys",
+        args : "server_config_file [alg]",
+        about : "replace the signing key pair and set its kid in the config, the old key validates until its tokens expire",
+    },
+    Command{
+        name : "invalidate-user-tokens",
+        args : "db_file user [aud]",
+        about : "invalidate a user's tokens, for one audience or all of them",
+    },
+    Command{
+        name : "set-roles",
+        args : "db_file user \"role1 role2 ...\"",
+        about : "replace a user's roles",
+    },
+    Command{
+        name : "add-org",
+        args : "db_file org",
+        about : "add an organization",
+    },
+    Command{
+        name : "list-orgs",
+        args : "db_file",
+        about : "list organizations",
+    },
+    Command{
+        name : "set-org-member",
+        args : "db_file org user member|admin [\"group1 group2 ...\"]",
+        about : "add a user to an organization or replace their membership",
+    },
+    Command{
+        name : "create-invite",
+        args : "db_file \"role1 role2 ...\" [org \"group1 group2 ...\"]",
+        about : "print an invite to register, with roles and an organization",
+    },
+    Command{
+        name : "list-invites",
+        args : "db_file",
+        about : "list pending invites",
+    },
+    Command{
+        name : "delete-invite",
+        args : "db_file id",
+        about : "withdraw an invite",
+    },
+    Command{
+        name : "accept-invite",
+        args : "invite user",
+        about : "register through the server with an invite, prompting for the password",
+    },
+    Command{
+        name : "arm-break-glass",
+        args : "db_file user \"role1 role2 ...\" [--force]",
+        about : "create or rearm an emergency account and print its one time password, --force turns an existing user into one",
+    },
+    Command{
+        name : "list-break-glass",
+        args : "db_file",
+        about : "list emergency accounts and when they were used",
+    },
+    Command{
+        name : "set-login-notifications",
+        args : "db_file user on|off",
+        about : "turn a user's new device login notifications on or off",
+    },
+    Command{
+        name : "add-ssh-key",
+        args : "db_file user key_file",
+        about : "let a user log in with the ssh public key in key_file, e.g. ~/.ssh/id_ed25519.pub",
+    },
+    Command{
+        name : "validate-token",
+        args : "token",
+        about : "print the user a token belongs to",
+    },
+    Command{
+        name : "inspect-token",
+        args : "token",
+        about : "print a token's header and claims, without verifying it",
+    },
+    Command{
+        name : "login",
+        args : "user duration [--save | --ssh] | --device",
+        about : "log in through the server and print the token, --save keeps it for whoami and token, --ssh signs in with a key of the ssh agent instead of a password, --device logs in from another device",
+    },
+    Command{
+        name : "whoami",
+        args : "",
+        about : "print the user of the saved login",
+    },
+    Command{
+        name : "token",
+        args : "",
+        about : "print the saved token, renewing it once it expired",
+    },
+    Command{
+        name : "logout",
+        args : "[token [everywhere]]",
+        about : "invalidate a token, or every token of its user, or the saved login",
+    },
+    Command{
+        name : "bench-login",
+        args : "user --concurrency n [--requests n]",
+        about : "measure login latency and throughput",
+    },
+    Command{
+        name : "doctor",
+        args : "server_config_file",
+        about : "check the keys, configs, database schema, socket and clock of a deployment",
+    },
+    Command{
+        name : "migrate-config",
+        args : "config_file",
+        about : "rename the deprecated fields of a config file, keeping the original as config_file.bak",
+    },
+    Command{
+        name : "shell",
+        args : "db_file",
+        about : "run commands at a prompt, with db_file filled in",
+    },
+    Command{
+        name : "demo",
+        args : "",
+        about : "run a throwaway server with a new key pair, database and user, and print how to use it",
+    },
+    Command{
+        name : "completions",
+        args : "bash|zsh|fish",
+        about : "print a completion script for the shell",
+    },
+    Command{
+        name : "man",
+        args : "",
+        about : "print the man page",
+    },
+];
+
+fn command(name : &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|cmd| cmd.name == name)
+}
+
+/// state shared by the commands of one invocation or shell session
+struct Context {
+    client : Arc<Client>,
+    config : Config,
+    passwords : Passwords,
+    event_bus : Option<Box<dyn EventBus>>,
+    /// databases opened so far, by file
+    databases : Mutex<HashMap<String, Arc<Database>>>,
+}
+
+impl Context {
+    /// publishes on the event bus of the server config, if any. The change
+    /// was already made, so failures are only reported.
+    async fn publish(&self, event : Event) {
+        if let Some(bus) = &self.event_bus {
+            if let Err(err) = bus.publish(event).await {
+                eprintln!("could not publish the event: {:?}", err);
+            }
+        }
+    }
+
+    /// opens `db_file` the first time it's used
+    fn database(&self, db_file : &str) -> Result<Arc<Database>, String> {
+        let mut databases = self.databases.lock().unwrap();
+
+        if let Some(db) = databases.get(db_file) {
+            return Ok(Arc::clone(db))
+        }
+
+        let db = Database::new(db_file)
+            .map_err(|err| format!("could not open {}: {:?}", db_file, err))?;
+        let db = Arc::new(db);
+        databases.insert(db_file.to_string(), Arc::clone(&db));
+
+        Ok(db)
+    }
+}
+
+/// how commands print their results
+#[derive(Clone,Copy,PartialEq,Eq)]
+enum Format {
+    Text,
+    /// one json value per command, selected by `--json`. Field names are
+    /// kept stable for scripts.
+    Json,
+}
+
+impl Format {
+    fn print(self, value : serde_json::Value, text : impl FnOnce() -> String) {
+        match self {
+            Format::Text => println!("{}", text()),
+            Format::Json => println!("{}", value),
+        }
+    }
+
+    fn error(self, err : &str) {
+        match self {
+            Format::Text => eprintln!("{}", err),
+            Format::Json => eprintln!("{}", serde_json::json!({ "error" : err })),
+        }
+    }
+}
+
+/// splits off the global flags in front of the command
+fn global_flags<'a>(args : &'a [&'a str]) -> (Format, &'a [&'a str]) {
+    match args {
+        ["--json", rest @ ..] => (Format::Json, rest),
+        _ => (Format::Text, args),
+    }
+}
 
-    let args = std::env::args().collect::<Vec<_>>();
+/// runs one command, `args` excludes the program name
+async fn run(ctx : Arc<Context>, args : Vec<String>) -> Result<(), String> {
     let args_ref = args.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    let (format, args) = global_flags(&args_ref);
 
-    match &args_ref[1..] {
-        ["help", "add-user"] => {
-            usage("add-user db_file user");
-        },
+    let res = run_command(ctx, format, args).await;
+    if let Err(err) = &res {
+        format.error(err);
+    }
+
+    res
+}
+
+async fn run_command(ctx : Arc<Context>, format : Format, args : &[&str]) -> Result<(), String> {
+    let client = &ctx.client;
+
+    match args {
         ["add-user", db_file, user] => {
-            let db = Database::new(db_file).unwrap();
-            let pass = rpassword::prompt_password_stdout("password: ").unwrap();
-            dbg!(&pass);
-            let pass_hash = crypto::encode_password(pass.as_bytes()).unwrap();
+            let db = ctx.database(db_file)?;
+            let pass = prompt_password();
+            ctx.passwords.check_strength(user, &pass)?;
 
-            db.insert_user(&user, &pass_hash).await.unwrap();
-        },
-        ["help", "update-user-pass"] => {
-            usage("update-user-pass db_file user");
+            let pass_hash = ctx.passwords.hash(pass.expose().as_bytes())?;
+
+            db.insert_user(user, &pass_hash).await
+                .map_err(|err| format!("could not add {}: {:?}", user, err))?;
         },
         ["update-user-pass", db_file, user] => {
-            let db = Database::new(db_file).unwrap();
-            let pass = rpassword::prompt_password_stdout("password: ").unwrap();
-            let pass_hash = crypto::encode_password(pass.as_bytes()).unwrap();
+            let history = ctx.passwords.password_history;
+            let db = ctx.database(db_file)?;
+            let pass = prompt_password();
+            ctx.passwords.check_strength(user, &pass)?;
+
+            let old_hashes = db.get_password_history(user, history).await
+                .map_err(|err| format!("could not read the password history: {:?}", err))?;
+            for old_hash in old_hashes {
+                if crypto::verify_password(&old_hash, pass.expose().as_bytes()).unwrap_or(false) {
+                    return Err(format!(
+                        "password matches one of the last {} passwords",
+                        history,
+                    ))
+                }
+            }
+
+            let pass_hash = ctx.passwords.hash(pass.expose().as_bytes())?;
+
+            db.update_user_pass(user, &pass_hash, history).await
+                .map_err(|err| format!("could not update the password: {:?}", err))?;
+        },
+        ["tune-argon2"] => {
+            tune_argon2(format, DEFAULT_TUNE_TARGET_MS)?;
+        },
+        ["tune-argon2", "--target-ms", ms] => {
+            let ms = u64::from_str(ms).map_err(|_| format!("invalid target: {}", ms))?;
+            tune_argon2(format, ms)?;
+        },
+        ["rotate-keys", server_config_file] => {
+            rotate_keys(&ctx, format, server_config_file, None).await?;
+        },
+        ["rotate-keys", server_config_file, alg] => {
+            rotate_keys(&ctx, format, server_config_file, Some(alg)).await?;
+        },
+        ["list-users", db_file] => {
+            let db = ctx.database(db_file)?;
+            let users = db.list_users().await
+                .map_err(|err| format!("could not list users: {:?}", err))?;
 
-            db.insert_user(&user, &pass_hash).await.unwrap();
+            let value = serde_json::json!({
+                "users" : users.iter().map(|user| serde_json::json!({
+                    "name" : user.name,
+                    "roles" : user.roles.split_whitespace().collect::<Vec<_>>(),
+                    "token_version" : user.token_version,
+                })).collect::<Vec<_>>(),
+            });
+
+            format.print(value, || {
+                users.iter()
+                    .map(|user| format!("{}\t{}", user.name, user.roles))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
         },
-        ["help", "invalidate-user-tokens"] => {
-            usage("invalidate-user-tokens db_file user");
+        ["prune-password-history", db_file] => {
+            let db = ctx.database(db_file)?;
+
+            db.prune_password_history(ctx.passwords.password_history).await
+                .map_err(|err| format!("could not prune the password history: {:?}", err))?;
         },
         ["invalidate-user-tokens", db_file, user] => {
-            let db = Database::new(db_file).unwrap();
+            let db = ctx.database(db_file)?;
+
+            db.increment_token(user).await
+                .map_err(|err| format!("could not invalidate the tokens of {}: {:?}", user, err))?;
+            ctx.publish(Event::TokensInvalidated{ name : user.to_string(), aud : None }).await;
+        },
+        ["invalidate-user-tokens", db_file, user, aud] => {
+            let db = ctx.database(db_file)?;
+
+            db.increment_audience_token(user, aud).await
+                .map_err(|err| format!("could not invalidate the tokens of {}: {:?}", user, err))?;
+            ctx.publish(Event::TokensInvalidated{ name : user.to_string(), aud : Some(aud.to_string()) }).await;
+        },
+        ["set-roles", db_file, user, roles] => {
+            let db = ctx.database(db_file)?;
 
-            db.increment_token(&user).await.unwrap();
+            db.set_roles(user, roles).await
+                .map_err(|err| format!("could not set the roles of {}: {:?}", user, err))?;
+            audit(&db, "set-roles", Some(user), Some(roles)).await?;
         },
-        ["help", "validate-token"] => {
-            usage("validate-token token");
+        ["add-org", db_file, org] => {
+            let db = ctx.database(db_file)?;
+
+            db.insert_org(org, unix_now()).await
+                .map_err(|err| format!("could not add {}: {:?}", org, err))?;
+            audit(&db, "add-org", None, Some(org)).await?;
+        },
+        ["list-orgs", db_file] => {
+            let db = ctx.database(db_file)?;
+            let orgs = db.list_orgs().await
+                .map_err(|err| format!("could not list orgs: {:?}", err))?;
+
+            let value = serde_json::json!({
+                "orgs" : orgs.iter().map(|org| serde_json::json!({
+                    "name" : org.name,
+                    "created" : org.created,
+                })).collect::<Vec<_>>(),
+            });
+
+            format.print(value, || {
+                orgs.iter()
+                    .map(|org| org.name.clone())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        },
+        ["set-org-member", db_file, org, user, role, groups @ ..] if groups.len() <= 1 => {
+            let admin = match *role {
+                "member" => false,
+                "admin" => true,
+                _ => return usage("set-org-member"),
+            };
+            let groups = groups.first().copied().unwrap_or("");
+
+            let db = ctx.database(db_file)?;
+
+            let changed = db.set_org_member(org, user, admin, groups).await
+                .map_err(|err| format!("could not add {} to {}: {:?}", user, org, err))?;
+            audit(&db, "set-org-member", Some(user), Some(org)).await?;
+
+            // tokens scoped to the org hold the old membership, the
+            // database already invalidated them
+            if changed {
+                ctx.publish(Event::OrgTokensInvalidated{ name : user.to_string(), org : org.to_string() }).await;
+            }
+        },
+        ["create-invite", db_file, roles, org_groups @ ..] if org_groups.len() != 1 && org_groups.len() <= 2 => {
+            let db = ctx.database(db_file)?;
+            let token = crypto::new_device_token();
+            let invite = models::Invite{
+                // assigned by the database
+                id : 0,
+                name : None,
+                roles : roles.to_string(),
+                org : org_groups.first().map(|org| org.to_string()),
+                groups : org_groups.get(1).copied().unwrap_or("").to_string(),
+                created_by : None,
+                expires : unix_now() + invites::DEFAULT_MAX_DURATION as i64,
+            };
+
+            let id = db.insert_invite(&crypto::hash_device_token(&token), &invite).await
+                .map_err(|err| format!("could not create the invite: {:?}", err))?;
+            audit(&db, "create-invite", None, invite.org.as_deref()).await?;
+
+            let value = serde_json::json!({
+                "id" : id,
+                "invite" : token,
+                "expires" : invite.expires,
+            });
+            format.print(value, || token.clone());
+        },
+        ["list-invites", db_file] => {
+            let db = ctx.database(db_file)?;
+            let invites = db.list_invites(unix_now()).await
+                .map_err(|err| format!("could not list invites: {:?}", err))?;
+
+            let value = serde_json::json!({
+                "invites" : invites.iter().map(|invite| serde_json::json!({
+                    "id" : invite.id,
+                    "name" : invite.name,
+                    "roles" : invite.roles.split_whitespace().collect::<Vec<_>>(),
+                    "org" : invite.org,
+                    "groups" : invite.groups.split_whitespace().collect::<Vec<_>>(),
+                    "created_by" : invite.created_by,
+                    "expires" : invite.expires,
+                })).collect::<Vec<_>>(),
+            });
+
+            format.print(value, || {
+                invites.iter()
+                    .map(|invite| format!(
+                        "{}\t{}\t{}\t{}",
+                        invite.id,
+                        invite.name.as_deref().unwrap_or("*"),
+                        invite.roles,
+                        invite.org.as_deref().unwrap_or("-"),
+                    ))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        },
+        ["delete-invite", db_file, id] => {
+            let id = i64::from_str(id).map_err(|_| format!("invalid invite id: {}", id))?;
+            let db = ctx.database(db_file)?;
+
+            let deleted = db.delete_invite(id).await
+                .map_err(|err| format!("could not delete invite {}: {:?}", id, err))?;
+            if !deleted {
+                return Err(format!("no invite {}", id))
+            }
+            audit(&db, "delete-invite", None, Some(&id.to_string())).await?;
+        },
+        ["accept-invite", invite, user] => {
+            let pass = prompt_password();
+
+            client.accept_invite(invite, user, pass.expose()).await
+                .map_err(|err| format!("could not accept the invite: {:?}", err))?;
+        },
+        ["arm-break-glass", db_file, user, roles, flags @ ..] => {
+            let force = match flags {
+                [] => false,
+                ["--force"] => true,
+                _ => return Err(format!("unknown flags: {}", flags.join(" "))),
+            };
+
+            let db = ctx.database(db_file)?;
+            let pass = crypto::new_device_token();
+            let pass_hash = ctx.passwords.hash(pass.as_bytes())?;
+
+            match db.arm_break_glass(user, &pass_hash, roles, unix_now(), force).await {
+                Ok(()) => {},
+                Err(server::Error::Storage(server::StorageError::DuplicateName(_))) => {
+                    return Err(format!("{} is an existing user, --force turns them into a break glass account", user))
+                },
+                Err(err) => return Err(format!("could not arm {}: {:?}", user, err)),
+            }
+            audit(&db, "arm-break-glass", Some(user), Some(roles)).await?;
+
+            format.print(serde_json::json!({ "name" : user, "pass" : pass }), || pass.clone());
+        },
+        ["list-break-glass", db_file] => {
+            let db = ctx.database(db_file)?;
+            let accounts = db.list_break_glass().await
+                .map_err(|err| format!("could not list break glass accounts: {:?}", err))?;
+
+            let value = serde_json::json!({
+                "accounts" : accounts.iter().map(|account| serde_json::json!({
+                    "name" : account.name,
+                    "armed" : account.armed,
+                    "used" : account.used,
+                })).collect::<Vec<_>>(),
+            });
+
+            format.print(value, || {
+                accounts.iter()
+                    .map(|account| format!(
+                        "{}\t{}\t{}",
+                        account.name,
+                        account.armed,
+                        account.used.map_or("-".to_string(), |used| used.to_string()),
+                    ))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        },
+        ["set-login-notifications", db_file, user, setting] => {
+            let notify = match *setting {
+                "on" => true,
+                "off" => false,
+                _ => return usage("set-login-notifications"),
+            };
+
+            let db = ctx.database(db_file)?;
+
+            db.set_notify_logins(user, notify).await
+                .map_err(|err| format!("could not set the login notifications of {}: {:?}", user, err))?;
+        },
+        ["add-ssh-key", db_file, user, key_file] => {
+            let line = std::fs::read_to_string(key_file)
+                .map_err(|err| format!("could not read {}: {}", key_file, err))?;
+            let (public_key, comment) = ssh::parse_authorized_key(&line)
+                .ok_or_else(|| format!("{} isn't a supported ssh public key", key_file))?;
+
+            let db = ctx.database(db_file)?;
+
+            let id = db.add_ssh_key(user, &base64::encode(&public_key), &comment, unix_now()).await
+                .map_err(|err| format!("could not add the key of {}: {:?}", user, err))?;
+            let fingerprint = ssh::fingerprint(&public_key);
+            audit(&db, "add-ssh-key", Some(user), Some(&fingerprint)).await?;
+
+            format.print(serde_json::json!({ "id" : id, "fingerprint" : fingerprint }), || fingerprint.clone());
         },
         ["validate-token", token] => {
-            let user_name = client.validate_token(token).await.unwrap();
-            println!("{}", user_name);
+            let user_name = client.validate_token(token).await
+                .map_err(|err| format!("invalid token: {:?}", err))?;
+
+            format.print(serde_json::json!({ "name" : user_name }), || user_name.clone());
         },
-        ["help", "login"] => {
-            usage("login user duration");
+        ["inspect-token", token] => {
+            let data = jsonwebtoken::dangerous_insecure_decode::<serde_json::Value>(token)
+                .map_err(|err| format!("invalid token: {}", err))?;
+
+            let value = serde_json::json!({
+                "header" : data.header,
+                "claims" : data.claims,
+            });
+
+            format.print(value.clone(), || serde_json::to_string_pretty(&value).unwrap_or_default());
         },
         ["login", user, duration] => {
-            let secs = u64::from_str(&duration).unwrap();
+            let secs = u64::from_str(duration).map_err(|_| format!("invalid duration: {}", duration))?;
 
-            let pass = rpassword::prompt_password_stdout("password: ").unwrap();
+            let pass = prompt_password();
 
             let token = client.login(
                 user,
-                &pass,
+                pass.expose(),
+                Duration::from_secs(secs)
+            ).await
+                .map_err(|err| format!("could not log in: {:?}", err))?;
+
+            format.print(serde_json::json!({ "token" : token }), || token.clone());
+        },
+        ["bench-login", user, "--concurrency", concurrency] => {
+            let concurrency = usize::from_str(concurrency).map_err(|_| format!("invalid concurrency: {}", concurrency))?;
+            bench_login(format, Arc::clone(client), user, concurrency, DEFAULT_BENCH_REQUESTS).await;
+        },
+        ["bench-login", user, "--concurrency", concurrency, "--requests", requests] => {
+            let concurrency = usize::from_str(concurrency).map_err(|_| format!("invalid concurrency: {}", concurrency))?;
+            let requests = usize::from_str(requests).map_err(|_| format!("invalid requests: {}", requests))?;
+            bench_login(format, Arc::clone(client), user, concurrency, requests).await;
+        },
+        ["login", user, duration, "--save"] => {
+            let secs = u64::from_str(duration).map_err(|_| format!("invalid duration: {}", duration))?;
+
+            let pass = prompt_password();
+
+            let (token, device) = client.login_remember(
+                user,
+                pass.expose(),
                 Duration::from_secs(secs)
-            ).await.unwrap();
+            ).await
+                .map_err(|err| format!("could not log in: {:?}", err))?;
+
+            Credentials{
+                name : user.to_string(),
+                token : token.clone(),
+                device_token : device.token,
+                device_id : Some(device.id),
+                duration : secs,
+            }.save(&ctx.config.client_name)?;
+
+            format.print(serde_json::json!({ "token" : token }), || token.clone());
+        },
+        #[cfg(unix)]
+        ["login", user, duration, "--ssh"] => {
+            let secs = u64::from_str(duration).map_err(|_| format!("invalid duration: {}", duration))?;
+
+            let mut agent = ssh::Agent::from_env().await
+                .map_err(|err| format!("could not reach the ssh agent: {}", err))?;
+
+            let token = ssh::login(client, &mut agent, user, Duration::from_secs(secs)).await
+                .map_err(|err| format!("could not log in: {:?}", err))?;
 
-            println!("{}", token);
+            format.print(serde_json::json!({ "token" : token }), || token.clone());
+        },
+        ["login", "--device"] => {
+            let auth = client.device_authorization().await
+                .map_err(|err| format!("could not start the device login: {:?}", err))?;
+
+            // the prompt goes to stderr, so stdout only has the token
+            eprintln!("to log in, go to {} and enter {}", auth.verification_uri, auth.user_code);
+
+            let token = client.poll_device_token(&auth).await
+                .map_err(|err| format!("device login failed: {:?}", err))?;
+
+            format.print(serde_json::json!({ "token" : token }), || token.clone());
+        },
+        ["whoami"] => {
+            let creds = saved_credentials(&ctx).await?;
+
+            format.print(serde_json::json!({ "name" : creds.name }), || creds.name.clone());
+        },
+        ["token"] => {
+            let creds = saved_credentials(&ctx).await?;
+
+            format.print(serde_json::json!({ "token" : creds.token }), || creds.token.clone());
+        },
+        ["logout"] => {
+            // the login is forgotten even if the server can't be reached,
+            // so failing to log out is only reported. The device goes
+            // first, revoking it takes a token logging out invalidates.
+            match saved_credentials(&ctx).await {
+                Ok(creds) => {
+                    if let Some(id) = creds.device_id {
+                        if let Err(err) = client.revoke_device(&creds.token, id).await {
+                            eprintln!("could not revoke the device: {:?}", err);
+                        }
+                    }
+                    if let Err(err) = client.logout(&creds.token).await {
+                        eprintln!("could not log out: {:?}", err);
+                    }
+                },
+                Err(err) => eprintln!("{}", err),
+            }
+
+            Credentials::forget(&ctx.config.client_name)?;
+        },
+        ["logout", token] => {
+            client.logout(token).await
+                .map_err(|err| format!("could not log out: {:?}", err))?;
+        },
+        ["logout", token, "everywhere"] => {
+            client.logout_everywhere(token).await
+                .map_err(|err| format!("could not log out: {:?}", err))?;
+        },
+        ["doctor", server_config_file] => {
+            return doctor(&ctx, format, server_config_file).await
+        },
+        ["help", cmd] => {
+            return usage(cmd)
         },
         args => {
-            eprintln!("invalid args: {:?}", args);
-            eprintln!("try `./authn-utils help cmd` where cmd is:");
-
-            let cmds = &[
-                "add-user",
-                "update-user-pass",
-                "invalidate-user-tokens",
-                "validate-token",
-                "login",
-            ];
-
-            for cmd in cmds.iter() {
-                eprintln!("{}", cmd);
+            let mut err = format!("invalid args: {:?}\n", args);
+            err.push_str("try `./authn-utils help cmd` where cmd is:");
+
+            for cmd in COMMANDS {
+                err.push('\n');
+                err.push_str(cmd.name);
             }
 
-            std::process::exit(1);
+            return Err(err)
+        }
+    }
+
+    Ok(())
+}
+
+/// file the shell history is kept in, under $HOME
+const HISTORY_FILE : &str = ".authn-utils-history";
+
+/// reads commands until eof or `exit`, running each like a separate
+/// invocation but with `db_file` opened once and filled in
+async fn shell(ctx : Arc<Context>, db_file : &str) {
+    if let Err(err) = ctx.database(db_file) {
+        eprintln!("{}", err);
+        return
+    }
+
+    let history = std::env::var("HOME")
+        .map(|home| Path::new(&home).join(HISTORY_FILE))
+        .ok();
+
+    let mut editor = Editor::<ShellHelper>::new();
+    editor.set_helper(Some(ShellHelper));
+    if let Some(history) = &history {
+        // there's no history on the first run
+        let _ = editor.load_history(history);
+    }
+
+    println!("using {}, commands taking db_file don't need it here", db_file);
+
+    loop {
+        let line = match editor.readline("authn> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("{}", err);
+                break
+            },
+        };
+
+        let mut args = match split_line(&line) {
+            Ok(args) => args,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue
+            },
+        };
+
+        editor.add_history_entry(line.as_str());
+
+        // the command follows the global flags
+        let at = args.iter().take_while(|arg| *arg == "--json").count();
+
+        match args.get(at).map(String::as_str) {
+            None => continue,
+            Some("exit") | Some("quit") => break,
+            Some("shell") => {
+                eprintln!("already in a shell");
+                continue
+            },
+            Some(cmd) if command(cmd).is_some_and(Command::takes_db) => {
+                args.insert(at + 1, db_file.to_string());
+            },
+            Some(_) => {},
+        }
+
+        // commands panic on errors, running them in a task keeps the
+        // shell alive, the panic message is printed by the hook
+        match tokio::spawn(run(Arc::clone(&ctx), args)).await {
+            Ok(_) => {},
+            Err(_) => eprintln!("command failed"),
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            eprintln!("could not save history: {}", err);
+        }
+    }
+}
+
+/// splits a shell line on whitespace, double quotes group words like
+/// they do in sh, e.g. `set-roles alice "admin ops"`
+fn split_line(line : &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg : Option<String> = None;
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                arg.get_or_insert_with(String::new);
+            },
+            c if c.is_whitespace() && !quoted => {
+                args.extend(arg.take());
+            },
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quoted {
+        return Err("unterminated quote".to_string())
+    }
+
+    args.extend(arg);
+    Ok(args)
+}
+
+/// completes command names, as the first word or after `help`
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(&self, line : &str, pos : usize, _ : &rustyline::Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let prefix = line[..start].trim_start().trim_start_matches("--json").trim();
+
+        let candidates : Vec<&str> = match prefix {
+            "" => COMMANDS.iter()
+                .map(|cmd| cmd.name)
+                .filter(|name| *name != "shell")
+                .chain(["help", "exit"].iter().copied())
+                .collect(),
+            "help" => COMMANDS.iter().map(|cmd| cmd.name).collect(),
+            _ => Vec::new(),
+        };
+
+        let word = &line[start..];
+        let matches = candidates.into_iter()
+            .filter(|cmd| cmd.starts_with(word))
+            .map(|cmd| format!("{} ", cmd))
+            .collect();
+
+        Ok((start, matches))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// the password settings of the server config, read from the same
+/// sources as the client config, so e.g. `AUTHN__PASSWORD_HISTORY` applies
+/// to both the server and these commands
+#[derive(Deserialize)]
+#[serde(default)]
+struct Passwords {
+    password_strength : strength::Config,
+    password_hash : crypto::Argon2Params,
+    password_salt_len : usize,
+    password_history : usize,
+}
+
+impl Default for Passwords {
+    fn default() -> Self {
+        Self{
+            password_strength : strength::Config::default(),
+            password_hash : crypto::Argon2Params::default(),
+            password_salt_len : crypto::DEFAULT_SALT_LEN,
+            password_history : DEFAULT_PASSWORD_HISTORY,
+        }
+    }
+}
+
+/// the event bus of the server config, read like `Passwords`, so the
+/// invalidations of these commands reach the servers and token caches
+/// subscribed to it
+#[derive(Deserialize,Default)]
+#[serde(default)]
+struct Events {
+    #[cfg(feature = "redis")]
+    redis : Option<authn::redis::Config>,
+}
+
+impl Events {
+    fn bus(&self) -> Option<Box<dyn EventBus>> {
+        #[cfg(feature = "redis")]
+        if let Some(bus) = self.redis.as_ref().and_then(RedisBus::from_config) {
+            return Some(Box::new(bus))
+        }
+
+        None
+    }
+}
+
+impl Passwords {
+    /// prints how to make a weak password stronger, refusing it below
+    /// `password_strength.min_score`
+    fn check_strength(&self, user : &str, pass : &crypto::Secret) -> Result<(), String> {
+        let estimate = strength::estimate(pass.expose(), &[user]);
+
+        if let Some(warning) = &estimate.warning {
+            eprintln!("{}", warning);
+        }
+        for suggestion in &estimate.suggestions {
+            eprintln!("  - {}", suggestion);
+        }
+
+        let min_score = self.password_strength.min_score;
+        if estimate.score < min_score {
+            return Err(format!(
+                "password scored {} of {}, at least {} is required",
+                estimate.score,
+                strength::MAX_SCORE,
+                min_score,
+            ))
+        }
+
+        Ok(())
+    }
+
+    /// hashes `pass` the way the server does
+    fn hash(&self, pass : &[u8]) -> Result<String, String> {
+        crypto::encode_password_with(&mut OsRng, self.password_salt_len, self.password_hash, pass)
+            .map_err(|err| format!("could not hash the password: {:?}", err))
+    }
+}
+
+const DEFAULT_TUNE_TARGET_MS : u64 = 250;
+
+/// prints the costs hitting the target latency, as overrides of
+/// `password_hash`
+fn tune_argon2(format : Format, target_ms : u64) -> Result<(), String> {
+    let params = crypto::tune_argon2(Duration::from_millis(target_ms))
+        .map_err(|err| format!("could not tune argon2: {:?}", err))?;
+
+    let value = serde_json::json!({
+        "mem_cost" : params.mem_cost,
+        "time_cost" : params.time_cost,
+    });
+
+    format.print(value, || format!(
+        "AUTHN__PASSWORD_HASH__MEM_COST={}\nAUTHN__PASSWORD_HASH__TIME_COST={}",
+        params.mem_cost,
+        params.time_cost,
+    ));
+
+    Ok(())
+}
+
+/// replaces the signing key of the server config with a new one of `alg`,
+/// or the config's, keeping the previous public key next to the new one as
+/// `pub_key_file.old`. The new `alg` and `token_header.kid` are written to
+/// the config file, and the old key as its `retired_key`. The server picks
+/// the keys up on restart, and validates tokens signed with the old key by
+/// their `kid` until they could have expired. Apps validating tokens
+/// themselves need the new public key before the restart.
+async fn rotate_keys(ctx : &Context, format : Format, server_config_file : &str, alg : Option<&str>) -> Result<(), String> {
+    let config : server::Config = config::Sources::file(server_config_file)
+        .with_env()
+        .load()
+        .map_err(|err| format!("server config: {}", err))?;
+    let db = ctx.database(&config.database)?;
+
+    let alg = match alg {
+        Some(alg) => jsonwebtoken::Algorithm::from_str(alg)
+            .map_err(|_| format!("unknown algorithm: {}", alg))?,
+        None => config.alg,
+    };
+    let pair = crypto::generate_key_pair(alg)
+        .map_err(|err| format!("could not generate a key pair: {:?}", err))?;
+
+    // read before anything changes, so a config which isn't json fails
+    // the rotation rather than leaving the old kid in place
+    let s = std::fs::read_to_string(server_config_file)
+        .map_err(|err| format!("could not read {}: {}", server_config_file, err))?;
+    let mut value : serde_json::Value = serde_json::from_str(&s)
+        .map_err(|err| format!("{} is not json: {}", server_config_file, err))?;
+    let fields = value.as_object_mut()
+        .ok_or_else(|| format!("{} is not a json object", server_config_file))?;
+    fields.insert("alg".to_string(), serde_json::json!(alg));
+    let header = fields.entry("token_header").or_insert_with(|| serde_json::json!({}));
+    header["kid"] = serde_json::json!(pair.kid);
+
+    if Path::new(&config.pub_key_file).exists() {
+        let old_pub_key_file = format!("{}.old", config.pub_key_file);
+        std::fs::copy(&config.pub_key_file, &old_pub_key_file)
+            .map_err(|err| format!("could not keep the old public key: {}", err))?;
+
+        fields.insert("retired_key".to_string(), serde_json::json!({
+            "pub_key_file" : old_pub_key_file,
+            "alg" : config.alg,
+            "kid" : config.token_header.kid,
+            "until" : unix_now() as u64 + server::MAX_DURATION,
+        }));
+    }
+
+    write_atomic(&config.priv_key_file, pair.private_pem.as_bytes(), 0o600)
+        .and_then(|_| write_atomic(&config.pub_key_file, pair.public_pem.as_bytes(), 0o644))
+        .map_err(|err| format!("could not write the key pair: {}", err))?;
+
+    let mode = file_mode(server_config_file)
+        .map_err(|err| format!("could not read {}: {}", server_config_file, err))?;
+    write_atomic(server_config_file, (serde_json::to_string_pretty(&value).unwrap() + "\n").as_bytes(), mode)
+        .map_err(|err| format!("could not write {}: {}", server_config_file, err))?;
+
+    audit(&db, "rotate-keys", None, Some(&pair.kid)).await?;
+
+    if let Some(cert_file) = &config.cert_file {
+        eprintln!("{} is for the old key, the server won't start until it's replaced", cert_file);
+    }
+
+    format.print(serde_json::json!({ "kid" : pair.kid }), || format!("kid: {}", pair.kid));
+    Ok(())
+}
+
+/// records a change made by a command in the audit log
+async fn audit(db : &Database, action : &str, subject : Option<&str>, detail : Option<&str>) -> Result<(), String> {
+    db.insert_audit(None, action, subject, detail).await
+        .map(|_| ())
+        .map_err(|err| format!("could not audit {}: {:?}", action, err))
+}
+
+/// writes to a temporary file next to `path` and renames it over `path`,
+/// so readers never see a partial file. `mode` is ignored on windows. The
+/// temporary file has a random name, so one left over by a crash doesn't
+/// get in the way.
+fn write_atomic(path : &str, contents : &[u8], mode : u32) -> std::io::Result<()> {
+    let tmp = format!("{}.{:016x}.tmp", path, rand::random::<u64>());
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let res = options.open(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp, path));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+
+    res
+}
+
+/// the permission bits of `path`, kept when it's rewritten
+#[cfg(unix)]
+fn file_mode(path : &str) -> std::io::Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_ : &str) -> std::io::Result<u32> {
+    Ok(0o644)
+}
+
+/// the user `demo` adds
+const DEMO_USER : &str = "demo";
+
+/// the audience of the clients in the `demo` examples
+const DEMO_AUD : &str = "demo.example.com";
+
+/// runs a server in a temporary directory, with a new key pair and a
+/// database holding one user, until interrupted. The directory is removed
+/// afterwards.
+#[cfg(unix)]
+async fn demo() -> Result<(), String> {
+    let dir = std::env::temp_dir()
+        .join(format!("authn-demo-{:016x}", rand::random::<u64>()));
+    std::fs::create_dir(&dir)
+        .map_err(|err| format!("could not create {}: {}", dir.display(), err))?;
+
+    let res = run_demo(&dir).await;
+    let _ = std::fs::remove_dir_all(&dir);
+
+    res
+}
+
+/// the demo needs a unix socket for the server
+#[cfg(not(unix))]
+async fn demo() -> Result<(), String> {
+    Err("demo is only supported on unix".to_string())
+}
+
+#[cfg(unix)]
+async fn run_demo(dir : &Path) -> Result<(), String> {
+    use std::convert::Infallible;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyperlocal::UnixServerExt;
+    use plumb::{Pipe, PipeExt};
+    use authn::peer::PeerCredentials;
+
+    let file = |name : &str| dir.join(name).to_string_lossy().into_owned();
+
+    let pair = crypto::generate_key_pair(jsonwebtoken::Algorithm::ES256)
+        .map_err(|err| format!("could not generate a key pair: {}", err))?;
+    write_atomic(&file("priv-key.pem"), pair.private_pem.as_bytes(), 0o600)
+        .and_then(|_| write_atomic(&file("pub-key.pem"), pair.public_pem.as_bytes(), 0o644))
+        .map_err(|err| format!("could not write the key pair: {}", err))?;
+
+    let db = Database::new(&file("authn.sqlite3"))
+        .map_err(|err| format!("could not create the database: {:?}", err))?;
+    db.migrate().await
+        .map_err(|err| format!("could not create the database: {:?}", err))?;
+
+    let pass = crypto::new_device_token()[..16].to_string();
+    let pass_hash = Passwords::default().hash(pass.as_bytes())?;
+    db.insert_user(DEMO_USER, &pass_hash).await
+        .map_err(|err| format!("could not add {}: {:?}", DEMO_USER, err))?;
+
+    let server_config = serde_json::json!({
+        "server_name" : "authn.demo",
+        "server_path" : file("authn.sock"),
+        "alg" : "ES256",
+        "priv_key_file" : file("priv-key.pem"),
+        "pub_key_file" : file("pub-key.pem"),
+        "database" : file("authn.sqlite3"),
+    });
+    let client_config = serde_json::json!({
+        "server_name" : "authn.demo",
+        "server_path" : file("authn.sock"),
+        "client_name" : DEMO_AUD,
+        "alg" : "ES256",
+        "pub_key_file" : file("pub-key.pem"),
+    });
+    for (name, config) in [("config.json", &server_config), ("client.json", &client_config)].iter() {
+        std::fs::write(dir.join(name), serde_json::to_string_pretty(config).unwrap())
+            .map_err(|err| format!("could not write {}: {}", name, err))?;
+    }
+
+    let config = serde_json::from_value(server_config).unwrap();
+    let (server, path) = server::new_server(config)
+        .map_err(|err| format!("could not start the server: {:?}", err))?;
+    let pipe : &'static _ = Box::leak(Box::new(
+        server::routes(server).tuple().seq(Ok::<_, Infallible>)
+    ));
+
+    let make_service = make_service_fn(move |conn : &tokio::net::UnixStream| {
+        let peer = PeerCredentials::of_stream(conn);
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req : hyper::Request<hyper::Body>| {
+                if let Some(peer) = peer {
+                    req.extensions_mut().insert(peer);
+                }
+                pipe.run((req,))
+            }))
         }
+    });
+    let serve = hyper::Server::bind_unix(&path)
+        .map_err(|err| format!("could not listen on {}: {}", path.display(), err))?
+        .serve(make_service);
+
+    let sock = path.display();
+    println!("an authn server is listening on {}", sock);
+    println!("ctrl-c stops it and removes {}", dir.display());
+    println!();
+    println!("user:     {}", DEMO_USER);
+    println!("password: {}", pass);
+    println!();
+    println!("log in for an hour:");
+    println!(
+        "  curl --unix-socket {} http://authn/login -d '{{\"name\":\"{}\",\"pass\":\"{}\",\"aud\":\"{}\",\"duration\":3600}}'",
+        sock, DEMO_USER, pass, DEMO_AUD,
+    );
+    println!("list the user's devices with the token:");
+    println!("  curl --unix-socket {} http://authn/me/devices -H \"Authorization: Bearer $TOKEN\"", sock);
+    println!();
+    println!("or with the client:");
+    println!("  AUTHN_CONFIG={} authn-utils login {} 3600", file("client.json"), DEMO_USER);
+    println!("  AUTHN_CONFIG={} authn-utils validate-token $TOKEN", file("client.json"));
+    println!();
+    println!("the server config is {}", file("config.json"));
+
+    tokio::select! {
+        res = serve => res.map_err(|err| format!("the server failed: {}", err)),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+/// one line of the `doctor` report, `Ok` and `Err` hold the details
+type Check = (&'static str, Result<String, String>);
+
+/// rewrites the fields of `config::DEPRECATED` in the file
+fn migrate_config(config_file : &str) -> Result<(), String> {
+    let s = std::fs::read_to_string(config_file)
+        .map_err(|err| format!("could not read {}: {}", config_file, err))?;
+    let mut value : serde_json::Value = serde_json::from_str(&s)
+        .map_err(|err| format!("{} is not json: {}", config_file, err))?;
+
+    let changes = config::migrate(&mut value);
+    if changes.is_empty() {
+        println!("{} is up to date", config_file);
+        return Ok(())
+    }
+
+    let backup = format!("{}.bak", config_file);
+    std::fs::copy(config_file, &backup)
+        .map_err(|err| format!("could not write {}: {}", backup, err))?;
+    std::fs::write(config_file, serde_json::to_string_pretty(&value).unwrap() + "\n")
+        .map_err(|err| format!("could not write {}: {}", config_file, err))?;
+
+    for change in changes {
+        println!("{}", change);
+    }
+    println!("the original is at {}", backup);
+
+    Ok(())
+}
+
+/// runs every check against the server config and the client config in
+/// use, failing if any check fails
+async fn doctor(ctx : &Context, format : Format, server_config_file : &str) -> Result<(), String> {
+    let config : server::Config = config::Sources::file(server_config_file)
+        .with_env()
+        .load()
+        .map_err(|err| format!("server config: {}", err))?;
+
+    let db = Database::new(&config.database)
+        .map_err(|err| format!("could not open {}: {:?}", config.database, err));
+
+    let checks : Vec<Check> = vec![
+        ("key pair", check_key_pair(&config)),
+        ("client config", check_client_config(&ctx.config, &config)),
+        ("schema", match &db {
+            Ok(db) => check_schema(db).await,
+            Err(err) => Err(err.clone()),
+        }),
+        ("socket", check_socket(&config.server_path)),
+        ("clock", match &db {
+            Ok(db) => check_clock(db).await,
+            Err(err) => Err(err.clone()),
+        }),
+    ];
+
+    let value = serde_json::json!({
+        "checks" : checks.iter().map(|(name, res)| serde_json::json!({
+            "name" : name,
+            "ok" : res.is_ok(),
+            "detail" : match res {
+                Ok(s) | Err(s) => s,
+            },
+        })).collect::<Vec<_>>(),
+    });
+
+    format.print(value, || {
+        checks.iter()
+            .map(|(name, res)| match res {
+                Ok(detail) => format!("ok    {}: {}", name, detail),
+                Err(detail) => format!("FAIL  {}: {}", name, detail),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    });
+
+    if checks.iter().any(|(_, res)| res.is_err()) {
+        return Err("some checks failed".to_string())
     }
+
+    Ok(())
+}
+
+/// signs a token with the private key and verifies it with the public key
+fn check_key_pair(config : &server::Config) -> Result<String, String> {
+    use jsonwebtoken::Algorithm::*;
+
+    let priv_key = std::fs::read_to_string(&config.priv_key_file)
+        .map_err(|err| format!("could not read {}: {}", config.priv_key_file, err))?;
+    let pub_key = std::fs::read_to_string(&config.pub_key_file)
+        .map_err(|err| format!("could not read {}: {}", config.pub_key_file, err))?;
+
+    let enc_key = match config.alg {
+        ES256 | ES384 => jsonwebtoken::EncodingKey::from_ec_pem(priv_key.as_bytes()),
+        RS256 | RS384 | RS512 |
+        PS256 | PS384 | PS512 => jsonwebtoken::EncodingKey::from_rsa_pem(priv_key.as_bytes()),
+        alg => return Err(format!("{:?} is not allowed", alg)),
+    }.map_err(|err| format!("invalid private key for {:?}: {}", config.alg, err))?;
+
+    let dec_key = crypto::decoding_key(config.alg, pub_key.as_bytes())
+        .map_err(|err| format!("invalid public key for {:?}: {}", config.alg, err))?;
+
+    let iss = config.issuer()
+        .map_err(|err| format!("invalid external_url: {:?}", err))?;
+
+    let aud = "authn-utils-doctor";
+    let token = crypto::Token{
+        iss : iss.clone(),
+        aud : aud.to_string(),
+        sub : aud.to_string(),
+        version : 0,
+        aud_version : 0,
+        acr : Default::default(),
+        act : None,
+        auth_time : None,
+        org : None,
+        extra : Default::default(),
+    };
+
+    let s = token.issue(&enc_key, config.alg, Duration::from_secs(60))
+        .map_err(|err| format!("could not sign: {:?}", err))?;
+    crypto::Token::validate(&s, &crypto::validation(config.alg, aud, &iss), &dec_key)
+        .map_err(|_| "the public key does not verify tokens of the private key".to_string())?;
+
+    Ok(format!("signed and verified a {:?} token", config.alg))
 }
 
-fn usage(s : &str) -> ! {
-    println!("usage: ./authn-utils {}", s);
-    std::process::exit(0)
+/// the client config validates the server's tokens
+fn check_client_config(client : &Config, server : &server::Config) -> Result<String, String> {
+    let mut mismatches = Vec::new();
+
+    if client.alg != server.alg {
+        mismatches.push(format!("alg is {:?}, the server's is {:?}", client.alg, server.alg));
+    }
+
+    match (client.issuer(), server.issuer()) {
+        (Ok(client), Ok(server)) if client != server => {
+            mismatches.push(format!("tokens are expected from {}, the server issues them as {}", client, server));
+        },
+        (Err(err), _) => mismatches.push(format!("external_url is invalid: {:?}", err)),
+        (_, Err(err)) => mismatches.push(format!("the server's external_url is invalid: {:?}", err)),
+        _ => {},
+    }
+
+    let client_key = std::fs::read_to_string(&client.pub_key_file)
+        .map_err(|err| format!("could not read {}: {}", client.pub_key_file, err))?;
+    let server_key = std::fs::read_to_string(&server.pub_key_file)
+        .map_err(|err| format!("could not read {}: {}", server.pub_key_file, err))?;
+    if client_key.trim() != server_key.trim() {
+        mismatches.push(format!("{} differs from the server's {}", client.pub_key_file, server.pub_key_file));
+    }
+
+    if !mismatches.is_empty() {
+        return Err(mismatches.join(", "))
+    }
+
+    Ok(format!("matches the server for {}", client.client_name))
+}
+
+/// every migration this build knows of is applied, and no others
+async fn check_schema(db : &Database) -> Result<String, String> {
+    let applied = db.applied_migrations().await
+        .map_err(|err| format!("could not read migrations: {:?}", err))?;
+
+    let missing = MIGRATIONS.iter()
+        .map(|(name, _)| *name)
+        .filter(|name| !applied.iter().any(|a| a == name))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!("missing migrations {}, run sql/run-migrations.bash", missing.join(", ")))
+    }
+
+    let unknown = applied.iter()
+        .filter(|a| !MIGRATIONS.iter().any(|(name, _)| name == a))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Err(format!("migrations {} are newer than this build", unknown.join(", ")))
+    }
+
+    Ok(format!("{} migrations applied", applied.len()))
+}
+
+/// the server's socket exists and accepts connections from this user
+#[cfg(unix)]
+fn check_socket(server_path : &str) -> Result<String, String> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    let path = authn::config::expand_path(server_path)
+        .ok_or_else(|| format!("{} has an unknown % specifier", server_path))?;
+
+    let meta = std::fs::metadata(&path)
+        .map_err(|err| format!("{}: {}, is the server running?", path.display(), err))?;
+    if !meta.file_type().is_socket() {
+        return Err(format!("{} is not a socket", path.display()))
+    }
+
+    let mode = meta.permissions().mode() & 0o777;
+
+    std::os::unix::net::UnixStream::connect(&path)
+        .map_err(|err| format!("could not connect to {} (mode {:o}): {}", path.display(), mode, err))?;
+
+    Ok(format!("{} (mode {:o}) accepts connections", path.display(), mode))
+}
+
+/// the server's loopback address accepts connections
+#[cfg(windows)]
+fn check_socket(addr : &str) -> Result<String, String> {
+    std::net::TcpStream::connect(addr)
+        .map_err(|err| format!("could not connect to {}: {}, is the server running?", addr, err))?;
+
+    Ok(format!("{} accepts connections", addr))
+}
+
+/// the clock isn't behind the newest audit entry, which would put issued
+/// tokens in the past
+async fn check_clock(db : &Database) -> Result<String, String> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|_| "the clock is before 1970".to_string())?
+        .as_secs() as i64;
+
+    let newest = db.list_audit(None, 1).await
+        .map_err(|err| format!("could not read the audit log: {:?}", err))?
+        .into_iter()
+        .next();
+
+    if let Some(entry) = newest {
+        if entry.time > now {
+            return Err(format!("the clock is {}s behind the newest audit entry", entry.time - now))
+        }
+    }
+
+    Ok(format!("unix time {}", now))
+}
+
+const DEFAULT_BENCH_REQUESTS : usize = 1000;
+
+/// logs in `requests` times from `concurrency` tasks and reports latency
+/// percentiles of the successful logins
+async fn bench_login(format : Format, client : Arc<Client>, user : &str, concurrency : usize, requests : usize) {
+    let pass = Arc::new(prompt_password());
+    let remaining = Arc::new(AtomicUsize::new(requests));
+
+    let start = Instant::now();
+
+    let tasks = (0..concurrency).map(|_| {
+        let client = Arc::clone(&client);
+        let pass = Arc::clone(&pass);
+        let remaining = Arc::clone(&remaining);
+        let user = user.to_string();
+
+        tokio::spawn(async move {
+            let mut latencies = Vec::new();
+            let mut errors = 0;
+
+            while remaining.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok() {
+                let start = Instant::now();
+                match client.login(&user, pass.expose(), Duration::from_secs(60)).await {
+                    Ok(_) => latencies.push(start.elapsed()),
+                    Err(_) => errors += 1,
+                }
+            }
+
+            (latencies, errors)
+        })
+    }).collect::<Vec<_>>();
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for task in tasks {
+        let (l, e) = task.await.unwrap();
+        latencies.extend(l);
+        errors += e;
+    }
+
+    let total = start.elapsed();
+    latencies.sort();
+
+    let percentile = |p : usize| {
+        latencies.get((latencies.len() * p / 100).min(latencies.len().saturating_sub(1)))
+    };
+
+    let p = |p : usize| percentile(p).copied().unwrap_or_default();
+    let max = latencies.last().copied().unwrap_or_default();
+    let throughput = latencies.len() as f64 / total.as_secs_f64();
+    let ms = |d : Duration| d.as_secs_f64() * 1000.0;
+
+    let value = serde_json::json!({
+        "ok" : latencies.len(),
+        "failed" : errors,
+        "total_ms" : ms(total),
+        "throughput" : throughput,
+        "p50_ms" : ms(p(50)),
+        "p90_ms" : ms(p(90)),
+        "p99_ms" : ms(p(99)),
+        "max_ms" : ms(max),
+    });
+
+    format.print(value, || {
+        let mut text = format!("requests: {} ok, {} failed in {:?}\n", latencies.len(), errors, total);
+        text.push_str(&format!("throughput: {:.1}/s\n", throughput));
+        for n in &[50, 90, 99] {
+            text.push_str(&format!("p{}: {:?}\n", n, p(*n)));
+        }
+        text.push_str(&format!("max: {:?}", max));
+        text
+    });
+}
+
+const NOT_LOGGED_IN : &str = "not logged in, run `login user duration --save`";
+
+/// a login kept by `login --save`. The device token gets a new token once
+/// the saved one expires.
+#[derive(Serialize,Deserialize)]
+struct Credentials {
+    name : String,
+    token : String,
+    device_token : String,
+    /// revoked on `logout`, logins saved before it was kept have none
+    #[serde(default)]
+    device_id : Option<i64>,
+    /// seconds, the duration renewed tokens are requested for
+    duration : u64,
+}
+
+impl Credentials {
+    fn load(aud : &str) -> Result<Option<Self>, String> {
+        credential_store::load(aud)?
+            .map(|s| serde_json::from_str(&s).map_err(|err| format!("invalid saved login: {}", err)))
+            .transpose()
+    }
+
+    fn save(&self, aud : &str) -> Result<(), String> {
+        credential_store::save(aud, &serde_json::to_string(self).unwrap())
+    }
+
+    fn forget(aud : &str) -> Result<(), String> {
+        credential_store::forget(aud)
+    }
+}
+
+/// the saved login, renewing its token if it no longer validates
+async fn saved_credentials(ctx : &Context) -> Result<Credentials, String> {
+    let mut creds = Credentials::load(&ctx.config.client_name)?
+        .ok_or_else(|| NOT_LOGGED_IN.to_string())?;
+
+    if ctx.client.validate_token(&creds.token).await.is_ok() {
+        return Ok(creds)
+    }
+
+    creds.token = ctx.client.login_device(&creds.device_token, Duration::from_secs(creds.duration))
+        .await
+        .map_err(|err| format!("could not renew the saved login, log in again: {:?}", err))?;
+    creds.save(&ctx.config.client_name)?;
+
+    Ok(creds)
+}
+
+/// keeps saved logins in the OS keyring, one entry per audience
+#[cfg(feature = "keyring")]
+mod credential_store {
+    const SERVICE : &str = "authn-utils";
+
+    fn entry(aud : &str) -> Result<keyring::Entry, String> {
+        keyring::Entry::new(SERVICE, aud).map_err(|err| err.to_string())
+    }
+
+    pub fn load(aud : &str) -> Result<Option<String>, String> {
+        match entry(aud)?.get_password() {
+            Ok(s) => Ok(Some(s)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    pub fn save(aud : &str, s : &str) -> Result<(), String> {
+        entry(aud)?.set_password(s).map_err(|err| err.to_string())
+    }
+
+    pub fn forget(aud : &str) -> Result<(), String> {
+        match entry(aud)?.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+/// keeps saved logins in files only the user can read, under
+/// $XDG_CONFIG_HOME/authn, for builds without the keyring feature
+#[cfg(not(feature = "keyring"))]
+mod credential_store {
+    #[cfg(unix)]
+    use std::os::unix::fs::DirBuilderExt;
+    use std::path::PathBuf;
+
+    fn path(aud : &str) -> Result<PathBuf, String> {
+        let config = std::env::var("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .map_err(|_| "neither XDG_CONFIG_HOME nor HOME is set".to_string())?;
+
+        Ok(config.join("authn").join(format!("credentials-{}.json", aud)))
+    }
+
+    pub fn load(aud : &str) -> Result<Option<String>, String> {
+        match std::fs::read_to_string(path(aud)?) {
+            Ok(s) => Ok(Some(s)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    pub fn save(aud : &str, s : &str) -> Result<(), String> {
+        let path = path(aud)?;
+
+        let mut dir = std::fs::DirBuilder::new();
+        dir.recursive(true);
+        #[cfg(unix)]
+        dir.mode(0o700);
+        dir.create(path.parent().unwrap())
+            .map_err(|err| err.to_string())?;
+
+        super::write_atomic(path.to_str().unwrap(), s.as_bytes(), 0o600)
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn forget(aud : &str) -> Result<(), String> {
+        match std::fs::remove_file(path(aud)?) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+fn prompt_password() -> crypto::Secret {
+    crypto::Secret::new(rpassword::prompt_password_stderr("password: ").unwrap())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+fn usage(name : &str) -> Result<(), String> {
+    let cmd = command(name).ok_or_else(|| format!("unknown command: {}", name))?;
+
+    println!("usage: ./authn-utils {}", format!("{} {}", cmd.name, cmd.args).trim_end());
+    Ok(())
+}
+
+/// a completion script for `shell`, completing command names and files
+/// for their arguments
+fn completions(shell : &str) -> Option<String> {
+    let names = COMMANDS.iter()
+        .map(|cmd| cmd.name)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let script = match shell {
+        "bash" => format!(
+            concat!(
+                "_authn_utils() {{\n",
+                "    local cur=${{COMP_WORDS[COMP_CWORD]}}\n",
+                "    if [ \"$COMP_CWORD\" -eq 1 ]; then\n",
+                "        COMPREPLY=($(compgen -W \"help {names}\" -- \"$cur\"))\n",
+                "    elif [ \"$COMP_CWORD\" -eq 2 ] && [ \"${{COMP_WORDS[1]}}\" = help ]; then\n",
+                "        COMPREPLY=($(compgen -W \"{names}\" -- \"$cur\"))\n",
+                "    else\n",
+                "        COMPREPLY=($(compgen -f -- \"$cur\"))\n",
+                "    fi\n",
+                "}}\n",
+                "complete -F _authn_utils authn-utils\n",
+            ),
+            names = names,
+        ),
+        "zsh" => {
+            let described = COMMANDS.iter()
+                .map(|cmd| format!("        '{}:{}'\n", cmd.name, cmd.about.replace('\'', "'\\''")))
+                .collect::<String>();
+
+            format!(
+                concat!(
+                    "#compdef authn-utils\n",
+                    "\n",
+                    "_authn_utils() {{\n",
+                    "    local -a commands\n",
+                    "    commands=(\n",
+                    "{described}",
+                    "        'help:print the usage of a command'\n",
+                    "    )\n",
+                    "    if (( CURRENT == 2 )); then\n",
+                    "        _describe command commands\n",
+                    "    elif (( CURRENT == 3 )) && [[ $words[2] == help ]]; then\n",
+                    "        _describe command commands\n",
+                    "    else\n",
+                    "        _files\n",
+                    "    fi\n",
+                    "}}\n",
+                    "\n",
+                    "_authn_utils \"$@\"\n",
+                ),
+                described = described,
+            )
+        },
+        "fish" => {
+            let mut script = String::from("complete -c authn-utils -n __fish_use_subcommand -a help -d 'print the usage of a command'\n");
+            for cmd in COMMANDS {
+                script.push_str(&format!(
+                    "complete -c authn-utils -n __fish_use_subcommand -a {} -d '{}'\n",
+                    cmd.name,
+                    cmd.about.replace('\'', "\\'"),
+                ));
+            }
+            script.push_str(&format!(
+                "complete -c authn-utils -n '__fish_seen_subcommand_from help' -f -a '{}'\n",
+                names,
+            ));
+            script
+        },
+        _ => return None,
+    };
+
+    Some(script)
+}
+
+/// the man page in roff
+fn man_page() -> String {
+    // backslashes start escapes in roff, and a plain - may be rendered
+    // as a hyphen
+    fn roff(s : &str) -> String {
+        s.replace('\\', "\\e").replace('-', "\\-")
+    }
+
+    let mut page = String::from(concat!(
+        ".TH AUTHN-UTILS 1\n",
+        ".SH NAME\n",
+        "authn\\-utils \\- manage an authn server and its database\n",
+        ".SH SYNOPSIS\n",
+        ".B authn\\-utils\n",
+        "[\\fB\\-\\-json\\fR]\n",
+        "[\\fB\\-\\-set\\fR \\fIkey\\fR=\\fIvalue\\fR]...\n",
+        ".I command\n",
+        "[\\fIargs\\fR...]\n",
+        ".br\n",
+        ".B authn\\-utils help\n",
+        ".I command\n",
+        ".SH OPTIONS\n",
+        ".TP\n",
+        ".B \\-\\-json\n",
+        "print results and errors as json, with stable field names\n",
+        ".TP\n",
+        ".BI \\-\\-set \" key\" = value\n",
+        "override a field of the client config, nested fields are joined by \\fB.\\fR\n",
+        ".SH COMMANDS\n",
+    ));
+
+    for cmd in COMMANDS {
+        page.push_str(&format!(
+            ".TP\n\\fB{}\\fR {}\n{}\n",
+            roff(cmd.name),
+            roff(cmd.args),
+            roff(cmd.about),
+        ));
+    }
+
+    page.push_str(concat!(
+        ".SH ENVIRONMENT\n",
+        ".TP\n",
+        ".B AUTHN_CONFIG\n",
+        "the client config, config.json by default\n",
+        ".TP\n",
+        ".B AUTHN__\\fIKEY\\fR\n",
+        "override a field of the client config, nested fields are joined by \\fB__\\fR. ",
+        "The server's \\fBpassword_strength\\fR, \\fBpassword_hash\\fR, ",
+        "\\fBpassword_salt_len\\fR and \\fBpassword_history\\fR apply to ",
+        "the commands setting passwords too, and \\fBredis\\fR to those invalidating tokens\n",
+        ".SH FILES\n",
+        ".TP\n",
+        ".I ~/.authn\\-utils\\-history\n",
+        "the history of \\fBshell\\fR\n",
+    ));
+
+    page
 }
@@ -0,0 +1,112 @@
+//! Emergency access. Break glass accounts are provisioned ahead of time
+//! with `authn-utils arm-break-glass`, whose password is printed once, e.g.
+//! to be sealed away, and works for a single login. They recover admin
+//! access when the usual way in, e.g. an identity provider behind `saml`
+//! or `pam`, is down, so they only log in with that password, never e.g.
+//! with a kerberos ticket for the same name. An existing user only becomes
+//! one with `--force`.
+//!
+//! Every use is audited as `break-glass-login`, logged as an error and
+//! notified through `Config::login_notifications`, whatever the account's
+//! own setting. Its tokens, renewed or not, last at most
+//! `Config::max_session` seconds past the login, and the account can't
+//! log in again until it's armed again.
+
+use serde::Deserialize;
+
+use crate::database::USED_PASS_HASH;
+use crate::logging;
+use crate::models;
+use crate::notify::{LoginNotification, Reason};
+use crate::server::{
+    AuthError,
+    LoginAttempt,
+    Server,
+    Result,
+};
+
+const DEFAULT_MAX_SESSION : u64 = 60 * 60;
+
+fn default_max_session() -> u64 {
+    DEFAULT_MAX_SESSION
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// seconds past a break glass login after which its tokens can no
+    /// longer be used or renewed
+    #[serde(default = "default_max_session")]
+    pub max_session : u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self{ max_session : DEFAULT_MAX_SESSION }
+    }
+}
+
+impl Config {
+    /// fails if `account` was used already, before its password is checked
+    pub(crate) fn check(&self, account : &models::BreakGlass) -> Result<()> {
+        match account.used {
+            Some(_) => Err(AuthError::LoginFailed.into()),
+            None => Ok(()),
+        }
+    }
+
+    /// uses up the password of `user`, whose password just verified, and
+    /// raises the alarm. Fails if a concurrent login used it first.
+    pub(crate) async fn login(
+        &self,
+        server : &Server,
+        user : &models::User,
+        attempt : &LoginAttempt,
+        now : i64,
+    ) -> Result<()> {
+        if !server.database.use_break_glass(&user.name, now).await? {
+            return Err(AuthError::LoginFailed.into())
+        }
+
+        let detail = match &attempt.addr {
+            Some(addr) => format!("{} from {}", attempt.aud, addr),
+            None => attempt.aud.clone(),
+        };
+        server.audit(None, "break-glass-login", Some(&user.name), Some(&detail)).await?;
+        logging::error!(
+            [
+                ("user", serde_json::json!(user.name)),
+                ("aud", serde_json::json!(attempt.aud)),
+            ],
+            "break glass login as {}",
+            user.name,
+        );
+
+        if let Some(notifier) = &server.notifier {
+            notifier.send(LoginNotification{
+                name : user.name.clone(),
+                aud : attempt.aud.clone(),
+                reason : Reason::BreakGlass,
+                time : now,
+                country : attempt.country.clone(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// the unix time tokens of `user` stop being issued at, if it's a
+    /// break glass account that was used. Only those have the
+    /// `USED_PASS_HASH`, so no other user is looked up.
+    pub(crate) async fn session_end(&self, server : &Server, user : &models::User) -> Result<Option<i64>> {
+        if user.pass_hash != USED_PASS_HASH {
+            return Ok(None)
+        }
+
+        let account = server.database.get_break_glass(&user.name).await?;
+
+        Ok(account
+            .and_then(|account| account.used)
+            .map(|used| used.saturating_add(self.max_session as i64)))
+    }
+}
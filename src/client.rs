@@ -1,15 +1,52 @@
-use std::collections::HashSet;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::convert::TryFrom;
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
 use std::path::PathBuf;
+use std::pin::Pin;
 
 use jsonwebtoken as jwt;
 use quick_from::QuickFrom;
 use serde::Deserialize;
+#[cfg(unix)]
 use hyperlocal::{UnixClientExt, Uri};
+use tokio::sync::OnceCell;
 
 use crate::crypto;
-use crate::{PostLoginRequest, PostLoginResponse, GetUserResponse};
+use crate::api::{
+    PostLoginRequest,
+    PostLoginResponse,
+    PostLogoutRequest,
+    PostRenewRequest,
+    PostDeviceLoginRequest,
+    PostSshChallengeRequest,
+    PostSshChallengeResponse,
+    PostSshLoginRequest,
+    PostSshKeyRequest,
+    PostSshKeyResponse,
+    SshKeyInfo,
+    GetSshKeysResponse,
+    DeviceInfo,
+    GetDevicesResponse,
+    ClientInfo,
+    GetMeDevicesResponse,
+    PostTokenResponse,
+    PostDeviceAuthorizationResponse,
+    PostRegisterAcceptRequest,
+    PostPasswordEstimateRequest,
+    PostPasswordEstimateResponse,
+    PutAdminMaintenanceRequest,
+    ConsentDocument,
+    GetMeConsentResponse,
+    PostMeConsentRequest,
+    GetAdminUsersResponse,
+    GetAdminAuditResponse,
+};
+use crate::listing::ListQuery;
 
 
 type Result<T> = std::result::Result<T, Error>;
@@ -19,9 +56,24 @@ pub enum Error {
     AlgorithmNotAllowed(jwt::Algorithm),
     VersionMismatch,
 
+    /// The token was issued with a weaker authentication method than the
+    /// one required
+    InsufficientAssurance(crypto::Assurance),
+
+    /// `Config::external_url` isn't a url tokens can be issued by
+    InvalidExternalUrl(crypto::IssuerUrlError),
+
+    /// `Config::server_path` has a `%` specifier other than `%t` or `%%`,
+    /// or isn't a socket address, as it must be on windows
+    InvalidServerPath(String),
+
     /// Error from the api response
     Api(String),
 
+    /// The server didn't respond within the timeout, see
+    /// `Client::with_timeout`
+    Timeout,
+
     #[quick_from]
     Jwt(jwt::errors::Error),
 
@@ -39,7 +91,8 @@ pub enum Error {
 }
 
 
-fn parse_error(body : &[u8]) -> Error {
+/// turns the body of a failed api response into an error
+pub fn parse_error(body : &[u8]) -> Error {
 
     #[derive(Deserialize)]
     struct E {
@@ -52,13 +105,98 @@ fn parse_error(body : &[u8]) -> Error {
     }
 }
 
-#[derive(Deserialize)]
+/// A device remembered by `Client::login_remember`
+#[derive(Debug,Clone)]
+pub struct RememberedDevice {
+    /// revokes the device with `Client::revoke_device`
+    pub id : i64,
+    /// exchanged for new tokens with `Client::login_device`
+    pub token : String,
+}
+
+/// everything but the unreserved characters of RFC 3986
+const PATH_SEGMENT : &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// `s` escaped for a segment of a request path, user names may have
+/// slashes, `?` or anything else
+fn path_segment(s : &str) -> percent_encoding::PercentEncode<'_> {
+    percent_encoding::utf8_percent_encode(s, PATH_SEGMENT)
+}
+
+#[derive(Deserialize,Clone)]
 pub struct Config {
+    /// the server's unix socket, expanded like the server's, or its
+    /// loopback address on windows
     pub server_path : String,
     pub server_name : String,
+    /// the server's `external_url`, tokens are expected to be issued by it
+    /// rather than `server_name` if it's set
+    #[serde(default)]
+    pub external_url : Option<String>,
     pub client_name : String,
     pub alg : jwt::Algorithm,
     pub pub_key_file : String,
+    /// connection reuse of the default transport
+    #[serde(default)]
+    pub pool : PoolConfig,
+    /// seconds to wait for a response, see `Client::with_timeout`
+    #[serde(default = "default_timeout")]
+    pub timeout : u64,
+    /// failures of these classes are only reported rather than failing
+    /// validations, see `Client::with_shadow`
+    #[serde(default)]
+    pub shadow : Vec<FailureClass>,
+}
+
+impl Config {
+    /// the `iss` the server's tokens must have, see `crypto::issuer_url`
+    pub fn issuer(&self) -> Result<String> {
+        match &self.external_url {
+            Some(url) => crypto::issuer_url(url).map_err(Error::InvalidExternalUrl),
+            None => Ok(self.server_name.clone()),
+        }
+    }
+}
+
+const DEFAULT_TIMEOUT : u64 = 30;
+
+fn default_timeout() -> u64 {
+    DEFAULT_TIMEOUT
+}
+
+tokio::task_local! {
+    static CALL_TIMEOUT : Option<Duration>;
+}
+
+/// runs a client call with `limit` rather than the client's timeout, e.g.
+/// `timeout(Some(Duration::from_secs(1)), client.validate_token(&token))`
+pub async fn timeout<F : Future>(limit : Option<Duration>, call : F) -> F::Output {
+    CALL_TIMEOUT.scope(limit, call).await
+}
+
+/// Connection pool settings of `UnixTransport`, unset fields keep hyper's
+/// defaults
+#[derive(Deserialize,Clone,Debug,Default)]
+#[serde(deny_unknown_fields)]
+pub struct PoolConfig {
+    /// idle connections kept open, unbounded by default
+    #[serde(default)]
+    pub max_idle : Option<usize>,
+    /// seconds an idle connection is kept open, 90 by default
+    #[serde(default)]
+    pub idle_timeout : Option<u64>,
+    /// use http/2, multiplexing concurrent requests over one connection
+    /// instead of opening one per request
+    #[serde(default)]
+    pub http2 : bool,
+    /// seconds between http/2 pings, which keep the connection open and
+    /// detect a dead server, off by default
+    #[serde(default)]
+    pub http2_keep_alive : Option<u64>,
 }
 
 impl TryFrom<Config> for Client {
@@ -67,59 +205,451 @@ impl TryFrom<Config> for Client {
     fn try_from(
         config : Config,
     ) -> Result<Self> {
-        use jwt::Algorithm::*;
-
+        let issuer = config.issuer()?;
         let pub_key_str = std::fs::read_to_string(config.pub_key_file)?;
 
-        let pub_key = match config.alg {
-            ES256 | ES384 => jwt::DecodingKey::from_ec_pem(pub_key_str.as_bytes())?,
-            RS256 | RS384 | RS512 |
-            PS256 | PS384 | PS512 => jwt::DecodingKey::from_rsa_pem(pub_key_str.as_bytes())?,
-            alg => return Err(Error::AlgorithmNotAllowed(alg))
-        }.into_static();
+        let alg = config.alg;
+        let pub_key = crypto::decoding_key(alg, pub_key_str.as_bytes())
+            .map_err(|err| match err.kind() {
+                jwt::errors::ErrorKind::InvalidAlgorithm => Error::AlgorithmNotAllowed(alg),
+                _ => err.into(),
+            })?;
 
-        let validation = make_validation(
+        let validation = crypto::validation(
             config.alg,
-            config.client_name.clone(),
-            config.server_name,
+            &config.client_name,
+            &issuer,
         );
 
         Ok(Client{
             pub_key,
             validation,
-            path : config.server_path.into(),
             client_name : config.client_name,
+            transport : default_transport(&config.server_path, &config.pool)?,
+            clock : Box::new(crypto::SystemClock),
+            timeout : Some(Duration::from_secs(config.timeout)),
+            lookups : Default::default(),
+            versions : Default::default(),
+            shadow : config.shadow,
+            shadow_reporter : Box::new(report_to_stderr),
+            instrumentation : None,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn default_transport(server_path : &str, pool : &PoolConfig) -> Result<Box<dyn Transport>> {
+    let path = crate::config::expand_path(server_path)
+        .ok_or_else(|| Error::InvalidServerPath(server_path.to_string()))?;
+
+    Ok(Box::new(UnixTransport::with_pool(path, pool)))
+}
+
+/// there are no unix sockets, `server_path` is the server's loopback
+/// address
+#[cfg(windows)]
+fn default_transport(server_path : &str, pool : &PoolConfig) -> Result<Box<dyn Transport>> {
+    let addr = server_path.parse()
+        .map_err(|_| Error::InvalidServerPath(server_path.to_string()))?;
+
+    Ok(Box::new(LoopbackTransport::with_pool(addr, pool)))
+}
+
+pub type TransportFuture = Pin<Box<dyn Future<Output = Result<http::Response<hyper::Body>>> + Send>>;
+
+/// How the client reaches the server. Requests carry only a path and
+/// query in their uri, the transport decides where they go.
+pub trait Transport : Send + Sync {
+    fn request(&self, req : http::Request<hyper::Body>) -> TransportFuture;
+}
+
+/// a client builder with the connection pool tuned by `pool`
+fn pool_builder(pool : &PoolConfig) -> hyper::client::Builder {
+    let mut builder = hyper::Client::builder();
+
+    if let Some(max_idle) = pool.max_idle {
+        builder.pool_max_idle_per_host(max_idle);
+    }
+
+    if let Some(secs) = pool.idle_timeout {
+        builder.pool_idle_timeout(Duration::from_secs(secs));
+    }
+
+    if pool.http2 {
+        builder.http2_only(true);
+
+        if let Some(secs) = pool.http2_keep_alive {
+            builder
+                .http2_keep_alive_interval(Duration::from_secs(secs))
+                .http2_keep_alive_while_idle(true);
+        }
+    }
+
+    builder
+}
+
+/// The default transport, http over the server's unix socket
+#[cfg(unix)]
+pub struct UnixTransport {
+    path : PathBuf,
+    client : hyper::Client<hyperlocal::UnixConnector>,
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    pub fn new<P : Into<PathBuf>>(path : P) -> Self {
+        Self{
+            path : path.into(),
             client : hyper::Client::unix(),
+        }
+    }
+
+    /// like `new`, with the connection pool tuned by `pool`
+    pub fn with_pool<P : Into<PathBuf>>(path : P, pool : &PoolConfig) -> Self {
+        Self{
+            path : path.into(),
+            client : pool_builder(pool).build(hyperlocal::UnixConnector),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    fn request(&self, req : http::Request<hyper::Body>) -> TransportFuture {
+        let (mut parts, body) = req.into_parts();
+        let path = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        parts.uri = Uri::new(&self.path, path).into();
+
+        let fut = self.client.request(http::Request::from_parts(parts, body));
+        Box::pin(async move { Ok(fut.await?) })
+    }
+}
+
+/// Http over tcp, the default on windows where the server listens on a
+/// loopback address rather than a unix socket
+pub struct LoopbackTransport {
+    addr : SocketAddr,
+    client : hyper::Client<hyper::client::HttpConnector>,
+}
+
+impl LoopbackTransport {
+    pub fn new(addr : SocketAddr) -> Self {
+        Self::with_pool(addr, &PoolConfig::default())
+    }
+
+    /// like `new`, with the connection pool tuned by `pool`
+    pub fn with_pool(addr : SocketAddr, pool : &PoolConfig) -> Self {
+        Self{
+            addr,
+            client : pool_builder(pool).build_http(),
+        }
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn request(&self, req : http::Request<hyper::Body>) -> TransportFuture {
+        let (mut parts, body) = req.into_parts();
+        let path = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let uri = format!("http://{}{}", self.addr, path).parse::<http::Uri>();
+
+        let client = self.client.clone();
+        Box::pin(async move {
+            parts.uri = uri.map_err(http::Error::from)?;
+            Ok(client.request(http::Request::from_parts(parts, body)).await?)
         })
     }
 }
 
-fn make_validation(
-    alg : jwt::Algorithm,
-    aud : String,
-    iss : String,
-) -> jwt::Validation {
-    let mut aud_set = HashSet::new();
-    aud_set.insert(aud.to_string());
+/// Hands requests straight to the server's routes, without a socket, for
+/// hermetic tests or running the server and its users in one binary
+#[cfg(feature = "server")]
+pub struct LocalTransport<P> {
+    routes : std::sync::Arc<P>,
+}
+
+#[cfg(feature = "server")]
+impl<P> LocalTransport<P>
+where
+    P : plumb::Pipe<Input = (http::Request<hyper::Body>,), Output = http::Response<hyper::Body>>,
+{
+    /// `routes` is the output of `server::routes`
+    pub fn new(routes : P) -> Self {
+        Self{
+            routes : std::sync::Arc::new(routes),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl<P> Transport for LocalTransport<P>
+where
+    P : plumb::Pipe<Input = (http::Request<hyper::Body>,), Output = http::Response<hyper::Body>>
+        + Send + Sync,
+{
+    fn request(&self, req : http::Request<hyper::Body>) -> TransportFuture {
+        let fut = self.routes.run((req,));
+        Box::pin(async move { Ok(fut.await) })
+    }
+}
+
+/// The checks of a validation which can run in shadow mode, where a
+/// failing check is reported but the token is still accepted, e.g. while
+/// rolling out a stricter audience or version check. The signature,
+/// algorithm and format of tokens are always checked.
+#[derive(Deserialize,Debug,Clone,Copy,PartialEq,Eq,Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureClass {
+    /// `exp` and `nbf`
+    Expiry,
+    Audience,
+    Issuer,
+    /// the user's token version and audience version, i.e. revocations
+    Version,
+    /// the minimum of `validate_token_assurance`
+    Assurance,
+}
 
-    jwt::Validation{
-        validate_exp : true,
-        iss : Some(iss.to_string()),
-        aud : Some(aud_set),
-        algorithms : vec![alg],
-        ..Default::default()
+impl FailureClass {
+    /// the class of a failed token check, `None` if it can't be shadowed
+    fn of(err : &jwt::errors::Error) -> Option<Self> {
+        use jwt::errors::ErrorKind;
+
+        match err.kind() {
+            ErrorKind::ExpiredSignature | ErrorKind::ImmatureSignature => Some(FailureClass::Expiry),
+            ErrorKind::InvalidAudience => Some(FailureClass::Audience),
+            ErrorKind::InvalidIssuer => Some(FailureClass::Issuer),
+            _ => None,
+        }
+    }
+}
+
+/// A check which failed in shadow mode, the token was accepted anyway
+#[derive(Debug)]
+pub struct ShadowFailure<'a> {
+    pub class : FailureClass,
+    /// the user of the token
+    pub sub : &'a str,
+    /// what the validation would have failed with
+    pub error : &'a Error,
+}
+
+type ShadowReporter = Box<dyn Fn(&ShadowFailure<'_>) + Send + Sync>;
+
+fn report_to_stderr(failure : &ShadowFailure<'_>) {
+    eprintln!(
+        "authn: accepted a token of {} failing the {:?} check in shadow mode: {:?}",
+        failure.sub,
+        failure.class,
+        failure.error,
+    );
+}
+
+/// the versions a user's tokens for the client's audience must have, from
+/// `GET /user/:name/version`
+#[derive(Clone,Copy)]
+struct Versions {
+    token_version : u32,
+    aud_version : u32,
+    /// of tokens scoped to the org they were looked up for, 0 otherwise
+    org_version : u32,
+}
+
+/// whose versions are looked up, a user and the org their token is scoped
+/// to, if any
+type VersionKey = (String, Option<String>);
+
+/// Where `Instrumentation::version_lookup` got a user's versions from
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum VersionSource {
+    /// the cache of `ValidationOptions::max_staleness`
+    Cached,
+    /// the request of a concurrent validation of the same user
+    Shared,
+    /// a request of its own
+    Fetched,
+}
+
+/// Observes the client, e.g. to export metrics of the auth path. The
+/// methods do nothing by default and run on the path of the call, so they
+/// should be quick.
+pub trait Instrumentation : Send + Sync {
+    /// a request is about to be sent to the server
+    fn request_started(&self, _method : &http::Method, _path : &str) {}
+
+    /// a request finished after `elapsed`, `status` is `None` if it
+    /// failed or timed out without a response
+    fn request_completed(
+        &self,
+        _method : &http::Method,
+        _path : &str,
+        _status : Option<http::StatusCode>,
+        _elapsed : Duration,
+    ) {}
+
+    /// a validation finished after `elapsed`, failures pass on the error
+    /// the call returns
+    fn validation(&self, _outcome : std::result::Result<(), &Error>, _elapsed : Duration) {}
+
+    /// a validation got the user's versions from `source`
+    fn version_lookup(&self, _source : VersionSource) {}
+}
+
+/// users whose last looked up versions are kept for `max_staleness`, past
+/// it the cache starts over
+const MAX_CACHED_VERSIONS : usize = 10_000;
+
+/// Per call trade offs of `Client::validate_token_with` between how soon
+/// revoked tokens are refused and requests to the server, e.g.
+/// `ValidationOptions::new().with_max_staleness(Duration::from_secs(5))`.
+/// The default looks up the user's versions on every call.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct ValidationOptions {
+    /// don't look up the user's versions at all, revoked tokens are
+    /// accepted until they expire
+    pub skip_version_check : bool,
+    /// accept versions looked up at most this long ago, by any call
+    pub max_staleness : Option<Duration>,
+}
+
+impl ValidationOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn skip_version_check(mut self) -> Self {
+        self.skip_version_check = true;
+        self
+    }
+
+    pub fn with_max_staleness(mut self, max : Duration) -> Self {
+        self.max_staleness = Some(max);
+        self
     }
 }
 
 pub struct Client {
-    path : PathBuf,
     client_name : String,
-    client : hyper::Client<hyperlocal::UnixConnector>,
+    transport : Box<dyn Transport>,
+    clock : Box<dyn crypto::Clock>,
     pub_key : jwt::DecodingKey<'static>,
     validation : jwt::Validation,
+    timeout : Option<Duration>,
+    /// user lookups in flight, shared by concurrent validations of the
+    /// same user
+    lookups : Mutex<HashMap<VersionKey, Arc<OnceCell<Option<Versions>>>>>,
+    /// the last versions looked up for each user, and when
+    versions : Mutex<HashMap<VersionKey, (Versions, Instant)>>,
+    /// checks which are only reported when they fail
+    shadow : Vec<FailureClass>,
+    shadow_reporter : ShadowReporter,
+    instrumentation : Option<Box<dyn Instrumentation>>,
 }
 
 impl Client {
+    /// replaces the transport, by default requests go over the unix socket
+    /// at `Config::server_path`
+    pub fn with_transport<T>(mut self, transport : T) -> Self
+    where
+        T : Transport + 'static,
+    {
+        self.transport = Box::new(transport);
+        self
+    }
+
+    /// how long to wait for each response, including its body, before
+    /// failing with `Error::Timeout`. `None` waits indefinitely.
+    pub fn with_timeout(mut self, timeout : Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// observes requests, validations and version lookups with
+    /// `instrumentation`
+    pub fn with_instrumentation<I>(mut self, instrumentation : I) -> Self
+    where
+        I : Instrumentation + 'static,
+    {
+        self.instrumentation = Some(Box::new(instrumentation));
+        self
+    }
+
+    /// sends `req`, giving up after the call's or the client's timeout
+    async fn send(&self, req : http::Request<hyper::Body>) -> Result<(http::response::Parts, hyper::body::Bytes)> {
+        let instrumentation = match &self.instrumentation {
+            Some(instrumentation) => instrumentation,
+            None => return self.send_within_timeout(req).await,
+        };
+
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        instrumentation.request_started(&method, &path);
+
+        let start = Instant::now();
+        let res = self.send_within_timeout(req).await;
+        let status = res.as_ref().ok().map(|(parts, _)| parts.status);
+        instrumentation.request_completed(&method, &path, status, start.elapsed());
+
+        res
+    }
+
+    async fn send_within_timeout(&self, req : http::Request<hyper::Body>) -> Result<(http::response::Parts, hyper::body::Bytes)> {
+        let fut = async {
+            let (parts, body) = self.transport.request(req).await?.into_parts();
+            Ok((parts, hyper::body::to_bytes(body).await?))
+        };
+
+        let limit = CALL_TIMEOUT.try_with(|limit| *limit).unwrap_or(self.timeout);
+        match limit {
+            Some(limit) => tokio::time::timeout(limit, fut).await
+                .map_err(|_| Error::Timeout)?,
+            None => fut.await,
+        }
+    }
+
+    /// runs the checks of `classes` in shadow mode, replacing
+    /// `Config::shadow`. Failures are written to stderr, unless there's a
+    /// `with_shadow_reporter`.
+    pub fn with_shadow(mut self, classes : &[FailureClass]) -> Self {
+        self.shadow = classes.to_vec();
+        self
+    }
+
+    /// reports checks failing in shadow mode to `reporter` instead of
+    /// stderr
+    pub fn with_shadow_reporter<F>(mut self, reporter : F) -> Self
+    where
+        F : Fn(&ShadowFailure<'_>) + Send + Sync + 'static,
+    {
+        self.shadow_reporter = Box::new(reporter);
+        self
+    }
+
+    /// fails with `error`, unless `class` is shadowed, then only reports
+    /// it
+    fn check(&self, class : FailureClass, sub : &str, error : Error) -> Result<()> {
+        if !self.shadow.contains(&class) {
+            return Err(error)
+        }
+
+        (self.shadow_reporter)(&ShadowFailure{ class, sub, error : &error });
+        Ok(())
+    }
+
+    /// replaces the clock tokens are checked against, by default the
+    /// system time
+    pub fn with_clock<C>(mut self, clock : C) -> Self
+    where
+        C : crypto::Clock + 'static,
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// the `iss` of the server's tokens, see `Config::issuer`
+    pub fn issuer(&self) -> &str {
+        self.validation.iss.as_deref().unwrap_or_default()
+    }
+
     /// gets a token form the credentials
     pub async fn login(
         &self,
@@ -127,19 +657,122 @@ impl Client {
         pass : &str,
         duration : Duration
     ) -> Result<String> {
+        let req = PostLoginRequest::new(name, pass, &self.client_name, duration.as_secs());
+        Ok(self.post_login(req).await?.token)
+    }
 
+    /// like `login`, scoping the token to an organization the user is a
+    /// member of
+    pub async fn login_org(
+        &self,
+        name : &str,
+        pass : &str,
+        org : &str,
+        duration : Duration
+    ) -> Result<String> {
+        let req = PostLoginRequest::new(name, pass, &self.client_name, duration.as_secs())
+            .org(org);
+        Ok(self.post_login(req).await?.token)
+    }
+
+    /// like `login`, but also returns a long lived device token which can
+    /// later be exchanged for new tokens with `login_device`
+    pub async fn login_remember(
+        &self,
+        name : &str,
+        pass : &str,
+        duration : Duration
+    ) -> Result<(String, RememberedDevice)> {
+        let req = PostLoginRequest::new(name, pass, &self.client_name, duration.as_secs())
+            .remember(true);
+        let res = self.post_login(req).await?;
+        let device = match (res.device_token, res.device_id) {
+            (Some(token), Some(id)) => RememberedDevice{ id, token },
+            _ => return Err(Error::Api("missing device token".to_string())),
+        };
+
+        Ok((res.token, device))
+    }
+
+    async fn post_login(&self, req : PostLoginRequest) -> Result<PostLoginResponse> {
         let req = http::Request::builder()
-            .uri(Uri::new(&self.path, "/login"))
+            .uri("/login")
             .method("POST")
-            .body(serde_json::to_string(&PostLoginRequest{
-                name : name.to_string(),
-                pass : pass.to_string(),
-                aud : self.client_name.clone(),
-                duration : duration.as_secs()
+            .body(serde_json::to_string(&req).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<PostLoginResponse>(&body)?)
+    }
+
+    /// gets a token from a device token returned by `login_remember`
+    pub async fn login_device(
+        &self,
+        device_token : &str,
+        duration : Duration,
+    ) -> Result<String> {
+        let req = http::Request::builder()
+            .uri("/device/login")
+            .method("POST")
+            .body(serde_json::to_string(&PostDeviceLoginRequest{
+                device_token : device_token.to_string(),
+                duration : duration.as_secs(),
             }).unwrap().into())?;
 
-        let (parts, body) = self.client.request(req).await?.into_parts();
-        let body = hyper::body::to_bytes(body).await?;
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<PostLoginResponse>(&body)?.token)
+    }
+
+    /// asks for a challenge to log in as `name` with one of their ssh
+    /// keys, the base64 `public_key` as in authorized_keys. Fails with
+    /// `Error::Api("login failed")` if the key isn't one of theirs. See
+    /// `ssh::login`, which answers it with an ssh agent.
+    pub async fn ssh_challenge(&self, name : &str, public_key : &str) -> Result<PostSshChallengeResponse> {
+        let req = http::Request::builder()
+            .uri("/login/ssh/challenge")
+            .method("POST")
+            .body(serde_json::to_string(&PostSshChallengeRequest::new(
+                name,
+                &self.client_name,
+                public_key,
+            )).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<PostSshChallengeResponse>(&body)?)
+    }
+
+    /// gets a token for the base64 `signature` of `ssh::signed_data` over
+    /// a challenge from `ssh_challenge`
+    pub async fn login_ssh(
+        &self,
+        challenge : &str,
+        signature : &str,
+        duration : Duration,
+    ) -> Result<String> {
+        let req = http::Request::builder()
+            .uri("/login/ssh")
+            .method("POST")
+            .body(serde_json::to_string(&PostSshLoginRequest::new(
+                challenge,
+                signature,
+                duration.as_secs(),
+            )).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
 
         if parts.status != http::status::StatusCode::OK {
             return Err(parse_error(&body))
@@ -148,32 +781,669 @@ impl Client {
         Ok(serde_json::from_slice::<PostLoginResponse>(&body)?.token)
     }
 
+    /// starts a device login, the user then logs in at the returned
+    /// verification uri with the user code, while `poll_device_token`
+    /// waits for them. The client name must be a client of the server's
+    /// oauth config.
+    pub async fn device_authorization(&self) -> Result<PostDeviceAuthorizationResponse> {
+        let req = http::Request::builder()
+            .uri("/device_authorization")
+            .method("POST")
+            .header(http::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(serde_urlencoded::to_string([
+                ("client_id", self.client_name.as_str()),
+            ]).unwrap().into())?;
 
-    /// verifies the validity of the token and returns the user name
-    pub async fn validate_token(&self, token : &str) -> Result<String> {
-        let token = crypto::Token::validate(
-            token,
-            &self.validation,
-            &self.pub_key
-        )?;
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<PostDeviceAuthorizationResponse>(&body)?)
+    }
+
+    /// polls for the token of a device login started with
+    /// `device_authorization` until the user logs in, failing with
+    /// `Error::Api("expired_token")` if they don't in time
+    pub async fn poll_device_token(&self, auth : &PostDeviceAuthorizationResponse) -> Result<String> {
+        let mut interval = Duration::from_secs(auth.interval);
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let req = http::Request::builder()
+                .uri("/token")
+                .method("POST")
+                .header(http::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(serde_urlencoded::to_string([
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                    ("device_code", auth.device_code.as_str()),
+                    ("client_id", self.client_name.as_str()),
+                ]).unwrap().into())?;
+
+            let (parts, body) = self.send(req).await?;
+
+            if parts.status == http::status::StatusCode::OK {
+                return Ok(serde_json::from_slice::<PostTokenResponse>(&body)?.access_token)
+            }
+
+            match parse_error(&body) {
+                Error::Api(code) if code == "authorization_pending" => {},
+                // RFC 8628 section 3.5, back off by 5 seconds
+                Error::Api(code) if code == "slow_down" => interval += Duration::from_secs(5),
+                err => return Err(err),
+            }
+        }
+    }
+
+    /// exchanges a user's token for one for `audience`, if the server's
+    /// oauth config allows exchanging tokens of the token's audience for it
+    pub async fn exchange_token(&self, token : &str, audience : &str) -> Result<String> {
+        let token_type = "urn:ietf:params:oauth:token-type:jwt";
 
         let req = http::Request::builder()
-            .uri(Uri::new(&self.path, &format!("/user/{}", token.sub)))
+            .uri("/token")
+            .method("POST")
+            .header(http::header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(serde_urlencoded::to_string([
+                ("grant_type", "urn:ietf:params:oauth:grant-type:token-exchange"),
+                ("subject_token", token),
+                ("subject_token_type", token_type),
+                ("requested_token_type", token_type),
+                ("audience", audience),
+            ]).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<PostTokenResponse>(&body)?.access_token)
+    }
+
+    /// lists the remembered devices of the token's user
+    pub async fn devices(&self, token : &str) -> Result<Vec<DeviceInfo>> {
+        let req = http::Request::builder()
+            .uri("/devices")
             .method("GET")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
             .body("".into())?;
 
-        let (parts, body) = self.client.request(req).await?.into_parts();
-        let body = hyper::body::to_bytes(body).await?;
+        let (parts, body) = self.send(req).await?;
+
         if parts.status != http::status::StatusCode::OK {
             return Err(parse_error(&body))
         }
 
+        Ok(serde_json::from_slice::<GetDevicesResponse>(&body)?.devices)
+    }
 
-        let token_version = serde_json::from_slice::<GetUserResponse>(&body)?.token_version;
-        if token_version != token.version {
-            return Err(Error::VersionMismatch)
+    /// lists the ssh keys the token's user logs in with
+    pub async fn ssh_keys(&self, token : &str) -> Result<Vec<SshKeyInfo>> {
+        let req = http::Request::builder()
+            .uri("/ssh-keys")
+            .method("GET")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body("".into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<GetSshKeysResponse>(&body)?.keys)
+    }
+
+    /// lets the token's user log in with an ssh key, `public_key` is a line
+    /// of authorized_keys. The token must be from a login in the last few
+    /// minutes.
+    pub async fn add_ssh_key(&self, token : &str, public_key : &str) -> Result<i64> {
+        let req = http::Request::builder()
+            .uri("/ssh-keys")
+            .method("POST")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(serde_json::to_string(&PostSshKeyRequest::new(public_key)).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<PostSshKeyResponse>(&body)?.id)
+    }
+
+    /// removes one of the token user's ssh keys
+    pub async fn remove_ssh_key(&self, token : &str, id : i64) -> Result<()> {
+        let req = http::Request::builder()
+            .uri(format!("/ssh-keys/{}", id))
+            .method("DELETE")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body("".into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if !parts.status.is_success() {
+            return Err(parse_error(&body))
+        }
+
+        Ok(())
+    }
+
+    /// lists every audience and user agent the token's user has logged in
+    /// through
+    pub async fn seen_devices(&self, token : &str) -> Result<Vec<ClientInfo>> {
+        Ok(self.seen_devices_page(token, &ListQuery::new()).await?.devices)
+    }
+
+    /// a page of `seen_devices`, filtered by `aud`
+    pub async fn seen_devices_page(&self, token : &str, query : &ListQuery) -> Result<GetMeDevicesResponse> {
+        self.get_list("/me/devices", token, query).await
+    }
+
+    /// a page of the users the token's user may see, requires the admin
+    /// role, filtered by `role` and a name `prefix`
+    pub async fn admin_users(&self, token : &str, query : &ListQuery) -> Result<GetAdminUsersResponse> {
+        self.get_list("/admin/users", token, query).await
+    }
+
+    /// a page of the audit log, requires the admin role, filtered by
+    /// `actor`, `action` and `subject`
+    pub async fn admin_audit(&self, token : &str, query : &ListQuery) -> Result<GetAdminAuditResponse> {
+        self.get_list("/admin/audit", token, query).await
+    }
+
+    async fn get_list<T : serde::de::DeserializeOwned>(&self, path : &str, token : &str, query : &ListQuery) -> Result<T> {
+        let req = http::Request::builder()
+            .uri(format!("{}{}", path, query.to_query()))
+            .method("GET")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body("".into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// lists the policy documents of the server, and the versions the
+    /// token's user accepted
+    pub async fn consent(&self, token : &str) -> Result<Vec<ConsentDocument>> {
+        let req = http::Request::builder()
+            .uri("/me/consent")
+            .method("GET")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body("".into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<GetMeConsentResponse>(&body)?.documents)
+    }
+
+    /// accepts the current `version` of a policy document for the token's
+    /// user, renew the token to drop it from `pending_consent`
+    pub async fn accept_document(&self, token : &str, document : &str, version : u32) -> Result<()> {
+        let req = http::Request::builder()
+            .uri("/me/consent")
+            .method("POST")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(serde_json::to_string(&PostMeConsentRequest::new(document, version)).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if !parts.status.is_success() {
+            return Err(parse_error(&body))
+        }
+
+        Ok(())
+    }
+
+    /// turns maintenance mode on with `message`, or off with `None`,
+    /// requires the admin role
+    pub async fn set_maintenance(&self, token : &str, message : Option<&str>) -> Result<()> {
+        let req = http::Request::builder()
+            .uri("/admin/maintenance")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token));
+
+        let req = match message {
+            Some(message) => req.method("PUT")
+                .body(serde_json::to_string(&PutAdminMaintenanceRequest::new(message)).unwrap().into())?,
+            None => req.method("DELETE").body("".into())?,
+        };
+
+        let (parts, body) = self.send(req).await?;
+
+        if !parts.status.is_success() {
+            return Err(parse_error(&body))
+        }
+
+        Ok(())
+    }
+
+    /// forgets one of the token user's remembered devices, a token for
+    /// another audience than the server's only forgets that audience's
+    pub async fn revoke_device(&self, token : &str, id : i64) -> Result<()> {
+        let req = http::Request::builder()
+            .uri(format!("/devices/{}", id))
+            .method("DELETE")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body("".into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if !parts.status.is_success() {
+            return Err(parse_error(&body))
+        }
+
+        Ok(())
+    }
+
+
+    /// exchanges a still valid token for a new one expiring after
+    /// `duration`, the server may shorten the duration to respect its
+    /// maximum session length. Impersonated tokens can't be renewed.
+    pub async fn renew(&self, token : &str, duration : Duration) -> Result<String> {
+        let req = http::Request::builder()
+            .uri("/renew")
+            .method("POST")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(serde_json::to_string(&PostRenewRequest{
+                duration : duration.as_secs(),
+            }).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice::<PostLoginResponse>(&body)?.token)
+    }
+
+    /// registers the user an invite is for, see `invites`
+    pub async fn accept_invite(&self, invite : &str, name : &str, pass : &str) -> Result<()> {
+        let req = http::Request::builder()
+            .uri("/register/accept")
+            .method("POST")
+            .body(serde_json::to_string(&PostRegisterAcceptRequest::new(invite, name, pass)).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if !parts.status.is_success() {
+            return Err(parse_error(&body))
+        }
+
+        Ok(())
+    }
+
+    /// how strong `pass` would be and whether the server accepts it,
+    /// without setting it. `user_inputs` are guessed first, e.g. the
+    /// user's name.
+    pub async fn estimate_password(&self, pass : &str, user_inputs : &[&str]) -> Result<PostPasswordEstimateResponse> {
+        let req = http::Request::builder()
+            .uri("/password/estimate")
+            .method("POST")
+            .body(serde_json::to_string(&PostPasswordEstimateRequest::new(pass, user_inputs)).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if !parts.status.is_success() {
+            return Err(parse_error(&body))
+        }
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// invalidates the token along with every other token issued to the
+    /// same user for the same audience, and the devices remembered for it
+    pub async fn logout(&self, token : &str) -> Result<()> {
+        self.post_logout(token, false).await
+    }
+
+    /// invalidates every token issued to the token's user
+    pub async fn logout_everywhere(&self, token : &str) -> Result<()> {
+        self.post_logout(token, true).await
+    }
+
+    async fn post_logout(&self, token : &str, everywhere : bool) -> Result<()> {
+        let req = http::Request::builder()
+            .uri("/logout")
+            .method("POST")
+            .header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(serde_json::to_string(&PostLogoutRequest{
+                everywhere,
+            }).unwrap().into())?;
+
+        let (parts, body) = self.send(req).await?;
+
+        if !parts.status.is_success() {
+            return Err(parse_error(&body))
+        }
+
+        Ok(())
+    }
+
+    /// looks up the user's token versions. Concurrent lookups of the same
+    /// user share one request. If it fails, each caller retries on its
+    /// own, so every caller gets the error of its own request.
+    async fn get_versions(&self, key : &VersionKey) -> Result<Versions> {
+        let cell = Arc::clone(self.lookups.lock().unwrap()
+            .entry(key.clone())
+            .or_default());
+
+        let mut err = None;
+        let mut fetched = false;
+        let shared = *cell.get_or_init(|| async {
+            fetched = true;
+            self.fetch_versions(key).await
+                .map_err(|e| err = Some(e))
+                .ok()
+        }).await;
+
+        // only in flight lookups are shared, later calls see new versions
+        {
+            let mut lookups = self.lookups.lock().unwrap();
+            if lookups.get(key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+                lookups.remove(key);
+            }
+        }
+
+        let source = if fetched || shared.is_none() { VersionSource::Fetched } else { VersionSource::Shared };
+        self.observe_lookup(source);
+
+        match (shared, err) {
+            (Some(versions), _) => Ok(versions),
+            (None, Some(err)) => Err(err),
+            (None, None) => self.fetch_versions(key).await,
+        }
+    }
+
+    fn observe_lookup(&self, source : VersionSource) {
+        if let Some(instrumentation) = &self.instrumentation {
+            instrumentation.version_lookup(source);
+        }
+    }
+
+    async fn fetch_versions(&self, key : &VersionKey) -> Result<Versions> {
+        let (name, org) = key;
+        let mut query = vec![("aud", self.client_name.as_str())];
+        if let Some(org) = org {
+            query.push(("org", org));
+        }
+        let uri = format!("/user/{}/version?{}",
+            path_segment(name),
+            serde_urlencoded::to_string(query).unwrap());
+        let req = http::Request::builder()
+            .uri(uri)
+            .method("GET")
+            .body("".into())?;
+
+        let (parts, body) = self.send(req).await?;
+        if parts.status != http::status::StatusCode::OK {
+            return Err(parse_error(&body))
+        }
+
+        let versions = std::str::from_utf8(&body)
+            .ok()
+            .and_then(|body| {
+                let mut parts = body.split('.');
+                let versions = Versions{
+                    token_version : parts.next()?.parse().ok()?,
+                    aud_version : parts.next()?.parse().ok()?,
+                    org_version : match org {
+                        Some(_) => parts.next()?.parse().ok()?,
+                        None => 0,
+                    },
+                };
+                parts.next().is_none().then_some(versions)
+            })
+            .ok_or_else(|| Error::Api("invalid version".to_string()))?;
+
+        let mut cached = self.versions.lock().unwrap();
+        if cached.len() >= MAX_CACHED_VERSIONS {
+            cached.clear();
+        }
+        cached.insert(key.clone(), (versions, Instant::now()));
+
+        Ok(versions)
+    }
+
+    /// the cached versions of the user if they're at most `max_staleness`
+    /// old, otherwise looked up
+    async fn get_versions_within(&self, key : &VersionKey, max_staleness : Option<Duration>) -> Result<Versions> {
+        if let Some(max) = max_staleness {
+            let cached = self.versions.lock().unwrap().get(key).copied();
+            if let Some((versions, _)) = cached.filter(|(_, fetched)| fetched.elapsed() <= max) {
+                self.observe_lookup(VersionSource::Cached);
+                return Ok(versions)
+            }
+        }
+
+        self.get_versions(key).await
+    }
+
+    /// verifies the validity of the token and returns the user name
+    pub async fn validate_token(&self, token : &str) -> Result<String> {
+        self.validate_token_assurance(token, crypto::Assurance::Password).await
+    }
+
+    /// like `validate_token`, but also requires the user to have
+    /// authenticated with at least the `min` assurance level, e.g. to
+    /// force re-authentication before a sensitive action
+    pub async fn validate_token_assurance(
+        &self,
+        token : &str,
+        min : crypto::Assurance,
+    ) -> Result<String> {
+        Ok(self.validate(token, &ValidationOptions::default(), min).await?.sub)
+    }
+
+    /// like `validate_token`, returning all of the token's claims, e.g.
+    /// its `org`
+    pub async fn validate_token_claims(&self, token : &str) -> Result<crypto::Token> {
+        self.validate_token_claims_with(token, &ValidationOptions::default()).await
+    }
+
+    /// like `validate_token`, checking the user's versions as `options`
+    /// allow
+    pub async fn validate_token_with(&self, token : &str, options : &ValidationOptions) -> Result<String> {
+        Ok(self.validate_token_claims_with(token, options).await?.sub)
+    }
+
+    /// like `validate_token_claims`, checking the user's versions as
+    /// `options` allow
+    pub async fn validate_token_claims_with(
+        &self,
+        token : &str,
+        options : &ValidationOptions,
+    ) -> Result<crypto::Token> {
+        self.validate(token, options, crypto::Assurance::Password).await
+    }
+
+    /// every validation goes through here, observed by the
+    /// instrumentation
+    async fn validate(
+        &self,
+        token : &str,
+        options : &ValidationOptions,
+        min : crypto::Assurance,
+    ) -> Result<crypto::Token> {
+        let start = Instant::now();
+        let res = self.check_token(token, options, min).await;
+
+        if let Some(instrumentation) = &self.instrumentation {
+            instrumentation.validation(res.as_ref().map(|_| ()), start.elapsed());
+        }
+
+        res
+    }
+
+    async fn check_token(
+        &self,
+        token : &str,
+        options : &ValidationOptions,
+        min : crypto::Assurance,
+    ) -> Result<crypto::Token> {
+        let token = self.decode(token)?;
+
+        if !options.skip_version_check {
+            let key = (token.sub.clone(), token.org.as_ref().map(|org| org.name.clone()));
+            let versions = self.get_versions_within(&key, options.max_staleness).await?;
+            let org_version = token.org.as_ref().map_or(0, |org| org.version);
+            if versions.token_version != token.version
+                || versions.aud_version != token.aud_version
+                || versions.org_version != org_version
+            {
+                self.check(FailureClass::Version, &token.sub, Error::VersionMismatch)?;
+            }
+        }
+
+        if token.acr < min {
+            self.check(FailureClass::Assurance, &token.sub, Error::InsufficientAssurance(token.acr))?;
+        }
+
+        Ok(token)
+    }
+
+    /// checks the token's signature and claims, skipping the shadowed
+    /// checks if one of them fails
+    fn decode(&self, token : &str) -> Result<crypto::Token> {
+        let err = match crypto::Token::validate_with(self.clock.as_ref(), token, &self.validation, &self.pub_key) {
+            Ok(token) => return Ok(token),
+            Err(err) => err,
+        };
+
+        let class = match FailureClass::of(&err) {
+            Some(class) if self.shadow.contains(&class) => class,
+            _ => return Err(err.into()),
+        };
+
+        // the checks which aren't shadowed still apply
+        let mut relaxed = self.validation.clone();
+        for class in &self.shadow {
+            match class {
+                FailureClass::Expiry => {
+                    relaxed.validate_exp = false;
+                    relaxed.validate_nbf = false;
+                },
+                FailureClass::Audience => relaxed.aud = None,
+                FailureClass::Issuer => relaxed.iss = None,
+                FailureClass::Version | FailureClass::Assurance => {},
+            }
         }
 
-        Ok(token.sub)
+        let token = crypto::Token::validate_with(self.clock.as_ref(), token, &relaxed, &self.pub_key)?;
+        self.check(class, &token.sub, err.into())?;
+
+        Ok(token)
+    }
+}
+
+pub type AuthFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// The calls most users of the client need, so code can be written
+/// against either a `Client` or a `MockClient`
+pub trait Authenticator : Send + Sync {
+    /// gets a token from the credentials
+    fn login<'a>(&'a self, name : &'a str, pass : &'a str, duration : Duration) -> AuthFuture<'a, String>;
+
+    /// verifies the token and returns the user name
+    fn validate<'a>(&'a self, token : &'a str) -> AuthFuture<'a, String>;
+
+    /// invalidates the token
+    fn logout<'a>(&'a self, token : &'a str) -> AuthFuture<'a, ()>;
+}
+
+impl Authenticator for Client {
+    fn login<'a>(&'a self, name : &'a str, pass : &'a str, duration : Duration) -> AuthFuture<'a, String> {
+        Box::pin(Client::login(self, name, pass, duration))
+    }
+
+    fn validate<'a>(&'a self, token : &'a str) -> AuthFuture<'a, String> {
+        Box::pin(self.validate_token(token))
+    }
+
+    fn logout<'a>(&'a self, token : &'a str) -> AuthFuture<'a, ()> {
+        Box::pin(Client::logout(self, token))
+    }
+}
+
+/// An `Authenticator` without a server, for testing code which uses one.
+/// Its tokens are opaque and only valid on the mock which issued them,
+/// failures return the same errors as `Client`.
+#[derive(Default)]
+pub struct MockClient {
+    /// user name to password and token version
+    users : Mutex<HashMap<String, (String, u32)>>,
+    /// token to user name, token version and expiry
+    tokens : Mutex<HashMap<String, (String, u32, Instant)>>,
+    issued : AtomicU64,
+}
+
+impl MockClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_user(self, name : &str, pass : &str) -> Self {
+        self.users.lock().unwrap().insert(name.to_string(), (pass.to_string(), 0));
+        self
+    }
+}
+
+impl Authenticator for MockClient {
+    fn login<'a>(&'a self, name : &'a str, pass : &'a str, duration : Duration) -> AuthFuture<'a, String> {
+        let res = match self.users.lock().unwrap().get(name) {
+            Some((p, version)) if p == pass => {
+                let token = format!("mock-{}", self.issued.fetch_add(1, Ordering::Relaxed));
+                self.tokens.lock().unwrap()
+                    .insert(token.clone(), (name.to_string(), *version, Instant::now() + duration));
+                Ok(token)
+            },
+            _ => Err(Error::Api("login failed".to_string())),
+        };
+
+        Box::pin(async { res })
+    }
+
+    fn validate<'a>(&'a self, token : &'a str) -> AuthFuture<'a, String> {
+        let res = match self.tokens.lock().unwrap().get(token) {
+            None => Err(jwt::errors::Error::from(jwt::errors::ErrorKind::InvalidToken).into()),
+            Some((_, _, expires)) if *expires <= Instant::now() => {
+                Err(jwt::errors::Error::from(jwt::errors::ErrorKind::ExpiredSignature).into())
+            },
+            Some((name, version, _)) => match self.users.lock().unwrap().get(name) {
+                Some((_, v)) if v == version => Ok(name.clone()),
+                _ => Err(Error::VersionMismatch),
+            },
+        };
+
+        Box::pin(async { res })
+    }
+
+    fn logout<'a>(&'a self, token : &'a str) -> AuthFuture<'a, ()> {
+        let res = match self.tokens.lock().unwrap().get(token) {
+            Some((name, _, _)) => {
+                // the server invalidates every token of the user for the
+                // audience, a mock only has one
+                if let Some((_, version)) = self.users.lock().unwrap().get_mut(name) {
+                    *version += 1;
+                }
+                Ok(())
+            },
+            None => Err(Error::Api("unauthorized".to_string())),
+        };
+
+        Box::pin(async { res })
     }
 }
@@ -0,0 +1,430 @@
+//! Loads the configs of the server, client and `authn-utils` in layers:
+//! defaults, a json file, then `AUTHN__` environment variables, then `--set`
+//! flags, each overriding fields of the ones before. Nested fields are reached by `.` in
+//! flags and `__` in variables, e.g. `--set timeouts.header_read=5` or
+//! `AUTHN__TIMEOUTS__HEADER_READ=5`. Values are json, or strings if they
+//! don't parse as json.
+//!
+//! The three read the same file, so the top level fields of every config
+//! are allowed in it, while unknown fields anywhere else are refused.
+//!
+//! Configs with a `secrets_file` field, like the server's, can keep
+//! sensitive fields in a separate file only they may read. It holds a json
+//! object merged into the file, below the variables and flags.
+//!
+//! `server_path` may start with `%t`, the runtime directory, e.g.
+//! `%t/authn/authn.sock`, see `expand_path`.
+//!
+//! Fields which were renamed are still read from their old place, with a
+//! warning, see `DEPRECATED`. `authn-utils migrate-config` rewrites a file
+//! to the new names.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::de::{self, Deserialize, DeserializeOwned, Visitor};
+
+/// prefix of the environment variables overriding config fields
+pub const ENV_PREFIX : &str = "AUTHN__";
+
+/// the field naming the secrets file
+pub const SECRETS_FIELD : &str = "secrets_file";
+
+/// the runtime directory when `XDG_RUNTIME_DIR` isn't set, as for system
+/// services
+pub const DEFAULT_RUNTIME_DIR : &str = "/run";
+
+/// A field which moved, it's read from `old` until it's removed
+pub struct Deprecation {
+    /// dotted paths, as for `--set`
+    pub old : &'static str,
+    pub new : &'static str,
+}
+
+/// fields which moved, oldest first
+pub const DEPRECATED : &[Deprecation] = &[
+    // next to `audit_sinks`
+    Deprecation{ old : "log", new : "log_sinks" },
+];
+
+/// How a config could not be loaded, `Display` is meant for people
+#[derive(Debug)]
+pub enum LoadError {
+    Io(PathBuf, std::io::Error),
+    /// the file isn't a json object
+    Syntax(PathBuf, serde_json::Error),
+    /// a `--set` flag without a value, or setting a field of a non-object
+    InvalidOverride(String),
+    /// unknown, missing or mistyped fields
+    Invalid(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            LoadError::Syntax(path, err) => write!(f, "{} is not a json object: {}", path.display(), err),
+            LoadError::InvalidOverride(s) => write!(f, "invalid override `{}`, expected key=value", s),
+            LoadError::Invalid(s) => write!(f, "invalid config: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The layers a config is loaded from
+#[derive(Debug,Clone,Default)]
+pub struct Sources {
+    /// fields the file is applied on top of, e.g. those of
+    /// `container::defaults`
+    pub defaults : serde_json::Map<String, serde_json::Value>,
+    pub file : Option<PathBuf>,
+    /// dotted paths and their values, applied in order on top of the file
+    pub overrides : Vec<(String, String)>,
+}
+
+impl Sources {
+    pub fn file<P : Into<PathBuf>>(path : P) -> Self {
+        Self{
+            defaults : Default::default(),
+            file : Some(path.into()),
+            overrides : Vec::new(),
+        }
+    }
+
+    pub fn with_defaults(mut self, defaults : serde_json::Map<String, serde_json::Value>) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// adds the `AUTHN__` variables of the process
+    pub fn with_env(self) -> Self {
+        self.with_vars(std::env::vars())
+    }
+
+    /// adds the variables starting with `ENV_PREFIX`, ignoring the rest
+    pub fn with_vars<I>(mut self, vars : I) -> Self
+    where
+        I : IntoIterator<Item = (String, String)>,
+    {
+        let mut vars = vars.into_iter()
+            .filter_map(|(k, v)| {
+                let path = k.strip_prefix(ENV_PREFIX)?.to_lowercase().replace("__", ".");
+                Some((path, v))
+            })
+            .collect::<Vec<_>>();
+        // the environment has no order, so nested fields go last
+        vars.sort();
+
+        self.overrides.extend(vars);
+        self
+    }
+
+    /// takes the `--set key=value` flags out of `args` and adds them
+    pub fn with_flags(mut self, args : &mut Vec<String>) -> Result<Self, LoadError> {
+        let mut rest = Vec::with_capacity(args.len());
+        let mut iter = std::mem::take(args).into_iter();
+
+        while let Some(arg) = iter.next() {
+            let set = match arg.strip_prefix("--set=") {
+                Some(set) => set.to_string(),
+                None if arg == "--set" => iter.next()
+                    .ok_or_else(|| LoadError::InvalidOverride(arg.clone()))?,
+                None => {
+                    rest.push(arg);
+                    continue
+                },
+            };
+
+            let (path, value) = set.split_once('=')
+                .ok_or_else(|| LoadError::InvalidOverride(set.clone()))?;
+            self.overrides.push((path.to_string(), value.to_string()));
+        }
+
+        *args = rest;
+        Ok(self)
+    }
+
+    /// the defaults and file with the overrides applied
+    pub fn value(&self) -> Result<serde_json::Value, LoadError> {
+        let mut value = self.base()?;
+        self.apply_overrides(&mut value)?;
+
+        Ok(value)
+    }
+
+    /// like `value`, with the secrets file merged in below the overrides
+    pub fn value_with_secrets(&self) -> Result<serde_json::Value, LoadError> {
+        let value = self.value()?;
+        let secrets_file = match value.get(SECRETS_FIELD).and_then(|v| v.as_str()) {
+            Some(path) => PathBuf::from(path),
+            None => return Ok(value),
+        };
+
+        let mut merged = self.base()?;
+        let secrets = read_object(&secrets_file)?;
+        if secrets.get(SECRETS_FIELD).is_some() {
+            return Err(LoadError::Invalid(format!("{} sets {}", secrets_file.display(), SECRETS_FIELD)))
+        }
+        merge(&mut merged, secrets);
+        self.apply_overrides(&mut merged)?;
+
+        Ok(merged)
+    }
+
+    /// the file merged into the defaults
+    fn base(&self) -> Result<serde_json::Value, LoadError> {
+        let mut value = serde_json::Value::Object(self.defaults.clone());
+        if let Some(path) = &self.file {
+            merge(&mut value, read_object(path)?);
+        }
+
+        Ok(value)
+    }
+
+    fn apply_overrides(&self, value : &mut serde_json::Value) -> Result<(), LoadError> {
+        for (path, s) in &self.overrides {
+            let v = serde_json::from_str(s)
+                .unwrap_or_else(|_| serde_json::Value::String(s.clone()));
+            set(value, path, v)
+                .ok_or_else(|| LoadError::InvalidOverride(format!("{}={}", path, s)))?;
+        }
+
+        Ok(())
+    }
+
+    /// loads `T`, refusing top level fields which none of the configs
+    /// sharing the file have. The secrets file is only read if `T` has a
+    /// `secrets_file` field.
+    pub fn load<T : DeserializeOwned>(&self) -> Result<T, LoadError> {
+        let mut value = if fields_of::<T>().contains(&SECRETS_FIELD) {
+            self.value_with_secrets()?
+        } else {
+            self.value()?
+        };
+
+        for warning in migrate(&mut value) {
+            eprintln!("warning: {}, `authn-utils migrate-config` updates the file", warning);
+        }
+
+        let known = shared_fields();
+        if let Some(obj) = value.as_object() {
+            if let Some(unknown) = obj.keys().find(|k| !known.contains(&k.as_str())) {
+                return Err(LoadError::Invalid(unknown_field(unknown, &known)))
+            }
+        }
+
+        serde_json::from_value(value)
+            .map_err(|err| LoadError::Invalid(explain(&err.to_string())))
+    }
+}
+
+/// moves the fields of `DEPRECATED` to their new place, returning a warning
+/// for each one found. Where both are set the new one is kept.
+pub fn migrate(value : &mut serde_json::Value) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    for deprecation in DEPRECATED {
+        let old = match take(value, deprecation.old) {
+            Some(old) => old,
+            None => continue,
+        };
+
+        if get(value, deprecation.new).is_some() {
+            warnings.push(format!("`{}` is deprecated and ignored, `{}` is set", deprecation.old, deprecation.new));
+            continue
+        }
+
+        warnings.push(format!("`{}` is deprecated, use `{}`", deprecation.old, deprecation.new));
+        // the new path's parent may be a non-object, which loading refuses
+        let _ = set(value, deprecation.new, old);
+    }
+
+    warnings
+}
+
+/// `$XDG_RUNTIME_DIR`, or `DEFAULT_RUNTIME_DIR`
+pub fn runtime_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_RUNTIME_DIR))
+}
+
+/// replaces `%t` in `template` with `runtime_dir` and `%%` with `%`, `None`
+/// for other specifiers
+pub fn expand_path(template : &str) -> Option<PathBuf> {
+    if !template.contains('%') {
+        return Some(template.into())
+    }
+
+    let mut path = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue
+        }
+
+        match chars.next()? {
+            't' => path.push_str(runtime_dir().to_str()?),
+            '%' => path.push('%'),
+            _ => return None,
+        }
+    }
+
+    Some(path.into())
+}
+
+fn read_object(path : &PathBuf) -> Result<serde_json::Value, LoadError> {
+    let s = std::fs::read_to_string(path)
+        .map_err(|err| LoadError::Io(path.clone(), err))?;
+    let value : serde_json::Map<_, _> = serde_json::from_str(&s)
+        .map_err(|err| LoadError::Syntax(path.clone(), err))?;
+
+    Ok(serde_json::Value::Object(value))
+}
+
+/// sets the fields of `top` in `base`, merging objects field by field
+fn merge(base : &mut serde_json::Value, top : serde_json::Value) {
+    match (base, top) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(top)) => {
+            for (k, v) in top {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => { base.insert(k, v); },
+                }
+            }
+        },
+        (base, top) => *base = top,
+    }
+}
+
+/// sets the field at the dotted `path`, adding objects on the way. `None`
+/// if a non-object is in the way.
+fn set(value : &mut serde_json::Value, path : &str, v : serde_json::Value) -> Option<()> {
+    let mut parts = path.split('.').peekable();
+    let mut value = value;
+
+    while let Some(part) = parts.next() {
+        if part.is_empty() {
+            return None
+        }
+
+        let obj = value.as_object_mut()?;
+        if parts.peek().is_none() {
+            obj.insert(part.to_string(), v);
+            return Some(())
+        }
+
+        value = obj.entry(part)
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+    }
+
+    None
+}
+
+fn get<'a>(value : &'a serde_json::Value, path : &str) -> Option<&'a serde_json::Value> {
+    path.split('.').try_fold(value, |value, part| value.get(part))
+}
+
+/// removes the field at the dotted `path`
+fn take(value : &mut serde_json::Value, path : &str) -> Option<serde_json::Value> {
+    let (parent, field) = match path.rsplit_once('.') {
+        Some((parent, field)) => (parent.split('.').try_fold(value, |value, part| value.get_mut(part))?, field),
+        None => (value, path),
+    };
+
+    parent.as_object_mut()?.remove(field)
+}
+
+/// the top level fields of the configs compiled in
+fn shared_fields() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut fields = Vec::new();
+    #[cfg(feature = "client")]
+    fields.extend_from_slice(fields_of::<crate::client::Config>());
+    #[cfg(feature = "server")]
+    fields.extend_from_slice(fields_of::<crate::server::Config>());
+
+    fields
+}
+
+/// the fields of a struct deriving `Deserialize`, empty for other types
+pub fn fields_of<'de, T : Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields = &[][..];
+    let _ = T::deserialize(FieldsOf(&mut fields));
+    fields
+}
+
+/// a deserializer which only records the fields it's asked for
+struct FieldsOf<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> de::Deserializer<'de> for FieldsOf<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V : Visitor<'de>>(self, _ : V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V : Visitor<'de>>(
+        self,
+        _ : &'static str,
+        fields : &'static [&'static str],
+        _ : V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("fields recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+fn unknown_field(field : &str, expected : &[&str]) -> String {
+    match closest(field, expected) {
+        Some(near) => format!("unknown field `{}`, did you mean `{}`?", field, near),
+        None => format!("unknown field `{}`", field),
+    }
+}
+
+/// adds a suggestion to serde's unknown field errors, which list the
+/// expected fields
+fn explain(msg : &str) -> String {
+    let parse = || {
+        let rest = msg.strip_prefix("unknown field `")?;
+        let (field, rest) = rest.split_once('`')?;
+        let expected = rest.split('`').skip(1).step_by(2).collect::<Vec<_>>();
+        Some(unknown_field(field, &expected))
+    };
+
+    parse().unwrap_or_else(|| msg.to_string())
+}
+
+/// the candidate within a few edits of `s`, e.g. a typo
+fn closest<'a>(s : &str, candidates : &[&'a str]) -> Option<&'a str> {
+    candidates.iter()
+        .map(|c| (edit_distance(s, c), *c))
+        .filter(|(d, _)| *d <= 2.max(s.len() / 4))
+        .min()
+        .map(|(_, c)| c)
+}
+
+fn edit_distance(a : &str, b : &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + if ca == *cb { 0 } else { 1 };
+            cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
@@ -0,0 +1,144 @@
+//! Tracks which versions of policy documents, e.g. terms of service or a
+//! privacy policy, each user accepted. Users list the documents with
+//! `GET /me/consent` and accept their current versions with
+//! `POST /me/consent`, every accepted version is kept.
+//!
+//! Documents can be required: tokens of users who haven't accepted the
+//! current version carry it in the `pending_consent` claim, so
+//! applications can ask them to accept it before going on. Publishing a new
+//! version makes it pending again on the next login or renewal.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+use hyper::Body;
+use hyper::body::Buf;
+use plumb::PipeExt;
+use http_mux::{route,mux};
+
+use crate::api::{
+    ConsentDocument,
+    GetMeConsentResponse,
+    PostMeConsentRequest,
+};
+use crate::server::{
+    TransportError,
+    AuthError,
+    Server,
+    Router,
+    Request,
+    Response,
+    Result,
+    unix_now,
+};
+
+#[derive(Deserialize,Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub documents : Vec<Document>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Document {
+    pub name : String,
+    /// the current version, bumping it asks users to accept it again
+    pub version : u32,
+    /// where users can read it
+    #[serde(default)]
+    pub url : Option<String>,
+    /// list it in `pending_consent` until the current version is accepted
+    #[serde(default)]
+    pub required : bool,
+}
+
+impl Config {
+    fn document(&self, name : &str) -> Option<&Document> {
+        self.documents.iter().find(|doc| doc.name == name)
+    }
+
+    /// the required documents whose current version `name` hasn't
+    /// accepted
+    pub(crate) async fn pending(&self, server : &Server, name : &str) -> Result<Vec<String>> {
+        if !self.documents.iter().any(|doc| doc.required) {
+            return Ok(Vec::new())
+        }
+
+        let accepted = server.database.get_acknowledged_versions(name).await?;
+
+        Ok(self.documents.iter()
+            .filter(|doc| doc.required && match accepted.get(&doc.name) {
+                Some(version) => *version < doc.version,
+                None => true,
+            })
+            .map(|doc| doc.name.clone())
+            .collect())
+    }
+}
+
+pub(crate) fn routes(server : &Arc<Server>, m : Router) -> Router {
+    let m = get_me_consent(Arc::clone(server), m.named("get_me_consent"));
+    post_me_consent(Arc::clone(server), m.named("post_me_consent"))
+}
+
+fn get_me_consent(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "me" / "consent"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let (token, _) = server.authenticate(&req).await?;
+
+            let accepted = server.database.get_acknowledged_versions(&token.sub).await?;
+
+            let documents = server.consent.documents.iter()
+                .map(|doc| ConsentDocument{
+                    name : doc.name.clone(),
+                    version : doc.version,
+                    url : doc.url.clone(),
+                    required : doc.required,
+                    accepted : accepted.get(&doc.name).copied(),
+                })
+                .collect();
+
+            let s = serde_json::to_string(&GetMeConsentResponse{ documents })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn post_me_consent(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "me" / "consent"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let (token, _) = server.authenticate(&req).await?;
+
+            // only the user can consent
+            if token.act.is_some() {
+                return Err(AuthError::Forbidden.into())
+            }
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostMeConsentRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            match server.consent.document(&req.document) {
+                Some(doc) if doc.version == req.version => {},
+                _ => return Err(TransportError::BadRequest.into()),
+            }
+
+            server.database.insert_acknowledgment(&token.sub, &req.document, req.version, unix_now()).await?;
+
+            let detail = format!("{} {}", req.document, req.version);
+            server.audit(Some(&token.sub), "accept-document", Some(&token.sub), Some(&detail)).await?;
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
@@ -0,0 +1,82 @@
+//! Running the server in a container without a config file. `authn
+//! --container` starts from `defaults`, which keep the socket under
+//! `/run/authn`, everything else under `DATA_DIR` and log json lines, and
+//! reads the rest from `AUTHN__` variables. Only `server_name` has to be
+//! set:
+//!
+//! ```sh
+//! docker run -e AUTHN__SERVER_NAME=auth.example.com -v authn:/var/lib/authn authn --container
+//! ```
+//!
+//! Before the server starts, `provision` generates a key pair if neither
+//! key file exists and runs the migrations the database is missing, so the
+//! first start sets up an empty volume and later ones upgrade it.
+
+use std::path::Path;
+
+use crate::crypto;
+use crate::database::Database;
+use crate::logging;
+use crate::server::{Config, ConfigError, Error};
+
+/// where the keys and database are kept, worth mounting a volume at
+pub const DATA_DIR : &str = "/var/lib/authn";
+
+/// the config fields `authn --container` starts from
+pub fn defaults() -> serde_json::Map<String, serde_json::Value> {
+    serde_json::from_value(serde_json::json!({
+        "server_path" : "/run/authn/authn.sock",
+        "alg" : "ES256",
+        "priv_key_file" : format!("{}/priv-key.pem", DATA_DIR),
+        "pub_key_file" : format!("{}/pub-key.pem", DATA_DIR),
+        "database" : format!("{}/authn.sqlite3", DATA_DIR),
+        "log_sinks" : [{ "type" : "json" }],
+    })).unwrap()
+}
+
+/// generates a key pair if neither key file exists, and creates or
+/// migrates the database. Logs go to stdout unless `logging::init` was
+/// called first.
+pub async fn provision(config : &Config) -> Result<(), Error> {
+    for file in [&config.priv_key_file, &config.pub_key_file, &config.database].iter() {
+        if let Some(dir) = Path::new(file).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+
+    if !Path::new(&config.priv_key_file).exists() && !Path::new(&config.pub_key_file).exists() {
+        let pair = crypto::generate_key_pair(config.alg)
+            .map_err(|_| ConfigError::KeyGeneration(config.alg))?;
+        write_new(&config.priv_key_file, pair.private_pem.as_bytes(), 0o600)?;
+        write_new(&config.pub_key_file, pair.public_pem.as_bytes(), 0o644)?;
+
+        logging::info!(
+            [("kid", serde_json::json!(pair.kid))],
+            "generated a key pair at {} and {}", config.priv_key_file, config.pub_key_file,
+        );
+    }
+
+    let migrations = Database::new(&config.database)?.migrate().await?;
+    if !migrations.is_empty() {
+        logging::info!("ran migrations {}", migrations.join(", "));
+    }
+
+    Ok(())
+}
+
+/// writes a file which mustn't exist yet, `mode` is ignored on windows
+fn write_new(path : &str, contents : &[u8], mode : u32) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    options.open(path)?.write_all(contents)
+}
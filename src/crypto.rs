@@ -1,54 +1,852 @@
-use std::convert::TryInto;
+use std::collections::{BTreeMap,HashMap,HashSet};
+use std::fmt;
 use std::time::{self,SystemTimeError};
 
 use jsonwebtoken as jwt;
-use rand::{thread_rng, Rng};
+#[cfg(feature = "server")]
+use rand::{rngs::OsRng, CryptoRng, RngCore};
 use serde::{Serialize,Deserialize};
 use quick_from::QuickFrom;
+use zeroize::Zeroizing;
 
+#[cfg(any(test, feature = "testing"))]
+pub mod testvectors;
+
+/// A password or other plaintext secret. The memory is zeroed on drop and
+/// `Debug` never shows the value.
+#[derive(Clone,Default,PartialEq,Eq)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(s : String) -> Self {
+        Self(Zeroizing::new(s))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s : String) -> Self {
+        Self::new(s)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s : &str) -> Self {
+        Self::new(s.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S : serde::Serializer>(&self, s : S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(self.expose())
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D : serde::Deserializer<'de>>(d : D) -> Result<Self, D::Error> {
+        String::deserialize(d).map(Self::new)
+    }
+}
+
+/// salt length used by `encode_password`, in bytes
+#[cfg(feature = "server")]
+pub const DEFAULT_SALT_LEN : usize = 32;
+
+/// the argon2 version new hashes are made with, 1.3
+#[cfg(feature = "server")]
+const ARGON2_VERSION : u32 = 0x13;
+
+/// The argon2 variant of a password hash
+#[cfg(feature = "server")]
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Argon2Variant {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+#[cfg(feature = "server")]
+impl Argon2Variant {
+    /// the name in password hashes
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Argon2d => "argon2d",
+            Self::Argon2i => "argon2i",
+            Self::Argon2id => "argon2id",
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl From<Argon2Variant> for argon2::Variant {
+    fn from(variant : Argon2Variant) -> Self {
+        match variant {
+            Argon2Variant::Argon2d => argon2::Variant::Argon2d,
+            Argon2Variant::Argon2i => argon2::Variant::Argon2i,
+            Argon2Variant::Argon2id => argon2::Variant::Argon2id,
+        }
+    }
+}
+
+/// How new password hashes are made, the costs are tunable, see
+/// `tune_argon2`
+#[cfg(feature = "server")]
+#[derive(Deserialize,Debug,Clone,Copy,PartialEq,Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2Params {
+    pub variant : Argon2Variant,
+    /// memory in KiB
+    pub mem_cost : u32,
+    /// number of passes
+    pub time_cost : u32,
+}
+
+#[cfg(feature = "server")]
+impl Default for Argon2Params {
+    fn default() -> Self {
+        let config = argon2::Config::default();
+
+        Self{
+            variant : Argon2Variant::Argon2id,
+            mem_cost : config.mem_cost,
+            time_cost : config.time_cost,
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl Argon2Params {
+    fn config(&self) -> argon2::Config<'static> {
+        argon2::Config{
+            variant : self.variant.into(),
+            version : argon2::Version::Version13,
+            mem_cost : self.mem_cost,
+            time_cost : self.time_cost,
+            ..Default::default()
+        }
+    }
+}
+
+/// What a password hash was made with, as its PHC string says, e.g.
+/// `$argon2id$v=19$m=4096,t=3,p=1$salt$hash`
+#[cfg(feature = "server")]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct HashInfo {
+    pub params : Argon2Params,
+    /// 0x10 for hashes without a version, or 0x13
+    pub version : u32,
+    /// degree of parallelism
+    pub lanes : u32,
+    /// in bytes
+    pub salt_len : usize,
+}
+
+#[cfg(feature = "server")]
+impl HashInfo {
+    /// the metadata of `encoded`, if it's an argon2 hash
+    pub fn parse(encoded : &str) -> Option<Self> {
+        let mut parts = encoded.strip_prefix('$')?.split('$');
+
+        let variant = match parts.next()? {
+            "argon2d" => Argon2Variant::Argon2d,
+            "argon2i" => Argon2Variant::Argon2i,
+            "argon2id" => Argon2Variant::Argon2id,
+            _ => return None,
+        };
+
+        let mut part = parts.next()?;
+        let version = match part.strip_prefix("v=") {
+            Some(version) => {
+                part = parts.next()?;
+                version.parse().ok()?
+            },
+            None => 0x10,
+        };
+
+        let (mut mem_cost, mut time_cost, mut lanes) = (None, None, None);
+        for param in part.split(',') {
+            let (key, value) = param.split_once('=')?;
+            let value = value.parse::<u32>().ok()?;
+            match key {
+                "m" => mem_cost = Some(value),
+                "t" => time_cost = Some(value),
+                "p" => lanes = Some(value),
+                _ => {},
+            }
+        }
+
+        // unpadded base64
+        let salt = parts.next()?;
+        parts.next()?;
+
+        Some(Self{
+            params : Argon2Params{
+                variant,
+                mem_cost : mem_cost?,
+                time_cost : time_cost?,
+            },
+            version,
+            lanes : lanes?,
+            salt_len : salt.len() * 3 / 4,
+        })
+    }
+}
+
+/// whether `encoded` was made other than with `params` or with a shorter
+/// salt than `salt_len`, after it verified. It should be made again from
+/// the password then.
+#[cfg(feature = "server")]
+pub fn needs_rehash(encoded : &str, params : Argon2Params, salt_len : usize) -> bool {
+    match HashInfo::parse(encoded) {
+        Some(info) => {
+            info.params != params
+                || info.version != ARGON2_VERSION
+                || info.lanes != 1
+                || info.salt_len < salt_len
+        },
+        None => true,
+    }
+}
+
+#[cfg(feature = "server")]
 pub fn encode_password(pass : &[u8]) -> std::result::Result<String, argon2::Error> {
-    let mut salt = [0u8;32];
+    encode_password_with(&mut OsRng, DEFAULT_SALT_LEN, Argon2Params::default(), pass)
+}
+
+/// like `encode_password`, drawing a `salt_len` byte salt from `rng` and
+/// hashing with `params`
+#[cfg(feature = "server")]
+pub fn encode_password_with<R>(
+    rng : &mut R,
+    salt_len : usize,
+    params : Argon2Params,
+    pass : &[u8],
+) -> std::result::Result<String, argon2::Error>
+where
+    R : RngCore + CryptoRng,
+{
+    let mut salt = vec![0u8; salt_len];
+    rng.fill_bytes(&mut salt);
 
-    tokio::task::block_in_place(|| {
-        thread_rng().fill(&mut salt);
-    });
-    Ok(argon2::hash_encoded(pass, &salt, &Default::default())?)
+    argon2::hash_encoded(pass, &salt, &params.config())
 }
 
+/// benchmarks hashing on this host and returns the largest costs taking
+/// about `target` per hash. Memory is raised first, up to 1 GiB, then the
+/// number of passes.
+#[cfg(feature = "server")]
+pub fn tune_argon2(target : time::Duration) -> std::result::Result<Argon2Params, argon2::Error> {
+    const MAX_MEM_COST : u32 = 1024 * 1024;
+
+    let measure = |params : Argon2Params| {
+        let start = time::Instant::now();
+        argon2::hash_raw(b"password", &[0u8; DEFAULT_SALT_LEN], &params.config())
+            .map(|_| start.elapsed())
+    };
+
+    let mut params = Argon2Params{
+        mem_cost : 8 * 1024,
+        time_cost : 1,
+        ..Default::default()
+    };
+    let mut elapsed = measure(params)?;
+
+    // the cost grows about linearly with both parameters
+    while params.mem_cost < MAX_MEM_COST && elapsed * 2 <= target {
+        params.mem_cost *= 2;
+        elapsed = measure(params)?;
+    }
+
+    loop {
+        let next = Argon2Params{
+            time_cost : params.time_cost + 1,
+            ..params
+        };
+
+        let next_elapsed = measure(next)?;
+        if next_elapsed > target {
+            break
+        }
+
+        params = next;
+    }
+
+    Ok(params)
+}
+
+/// generates a random device token, these have enough entropy to be
+/// stored with a plain hash rather than a password hash
+#[cfg(feature = "server")]
+pub fn new_device_token() -> String {
+    let mut token = [0u8;32];
+    OsRng.fill_bytes(&mut token);
+
+    hex(&token)
+}
+
+#[cfg(feature = "server")]
+pub fn hash_device_token(token : &str) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, token.as_bytes()).as_ref())
+}
+
+#[cfg(feature = "server")]
+fn hex(bytes : &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(feature = "server")]
 pub fn verify_password(encoded : &str, pass : &[u8]) -> Result<bool, argon2::Error> {
-    Ok(argon2::verify_encoded(encoded, pass)?)
+    argon2::verify_encoded(encoded, pass)
+}
+
+/// A signing key and its public key, pem encoded
+#[cfg(feature = "server")]
+pub struct KeyPair {
+    /// pkcs8
+    pub private_pem : Zeroizing<String>,
+    /// spki
+    pub public_pem : String,
+    /// RFC 7638 thumbprint of the public key, usable as the `kid`
+    pub kid : String,
+}
+
+/// generates a key pair for `alg`, only ES256 and ES384 are supported
+/// since ring can't generate rsa keys
+#[cfg(feature = "server")]
+pub fn generate_key_pair(alg : jwt::Algorithm) -> Result<KeyPair, jwt::errors::Error> {
+    use ring::signature::{self, KeyPair as _};
+    use jwt::errors::ErrorKind;
+
+    // the der of an spki is this prefix followed by the uncompressed point
+    let (signing_alg, spki_prefix, crv) : (_, &[u8], _) = match alg {
+        jwt::Algorithm::ES256 => (
+            &signature::ECDSA_P256_SHA256_FIXED_SIGNING,
+            b"\x30\x59\x30\x13\x06\x07\x2a\x86\x48\xce\x3d\x02\x01\x06\x08\x2a\x86\x48\xce\x3d\x03\x01\x07\x03\x42\x00",
+            "P-256",
+        ),
+        jwt::Algorithm::ES384 => (
+            &signature::ECDSA_P384_SHA384_FIXED_SIGNING,
+            b"\x30\x76\x30\x10\x06\x07\x2a\x86\x48\xce\x3d\x02\x01\x06\x05\x2b\x81\x04\x00\x22\x03\x62\x00",
+            "P-384",
+        ),
+        _ => return Err(ErrorKind::InvalidAlgorithm.into()),
+    };
+
+    let rng = ring::rand::SystemRandom::new();
+    let pkcs8 = signature::EcdsaKeyPair::generate_pkcs8(signing_alg, &rng)
+        .map_err(|_| ErrorKind::InvalidEcdsaKey)?;
+    let pair = signature::EcdsaKeyPair::from_pkcs8(signing_alg, pkcs8.as_ref())
+        .map_err(|_| ErrorKind::InvalidEcdsaKey)?;
+
+    let point = pair.public_key().as_ref();
+    let spki = [spki_prefix, point].concat();
+
+    // the point is 0x04 followed by x and y of equal length
+    let (x, y) = point[1..].split_at((point.len() - 1) / 2);
+    let jwk = format!(
+        r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#,
+        crv,
+        base64::encode_config(x, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(y, base64::URL_SAFE_NO_PAD),
+    );
+    let thumbprint = ring::digest::digest(&ring::digest::SHA256, jwk.as_bytes());
+
+    Ok(KeyPair{
+        private_pem : Zeroizing::new(pem("PRIVATE KEY", pkcs8.as_ref())),
+        public_pem : pem("PUBLIC KEY", &spki),
+        kid : base64::encode_config(thumbprint, base64::URL_SAFE_NO_PAD),
+    })
+}
+
+#[cfg(feature = "server")]
+fn pem(label : &str, der : &[u8]) -> String {
+    let b64 = Zeroizing::new(base64::encode(der));
+
+    // sized up front so no partial copies of a private key are left behind
+    // by reallocations
+    let mut out = String::with_capacity(b64.len() * 65 / 64 + 2 * label.len() + 40);
+    out.push_str(&format!("-----BEGIN {}-----\n", label));
+    for line in b64.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {}-----\n", label));
+
+    out
+}
+
+/// the body of the first pem block labeled `label`
+#[cfg(feature = "server")]
+pub(crate) fn pem_block(pem : &str, label : &str) -> Option<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+
+    let start = pem.find(&begin)? + begin.len();
+    let len = pem[start..].find(&end)?;
+    let b64 = pem[start..start + len].split_whitespace().collect::<String>();
+
+    base64::decode(b64).ok()
+}
+
+/// a der element, and the elements after it
+#[cfg(feature = "server")]
+struct DerElement<'a> {
+    tag : u8,
+    contents : &'a [u8],
+    /// the tag, length and contents
+    whole : &'a [u8],
+    rest : &'a [u8],
+}
+
+/// splits off the der element at the start of `der`
+#[cfg(feature = "server")]
+fn der_element(der : &[u8]) -> Option<DerElement<'_>> {
+    let (&tag, rest) = der.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+
+    let (len, rest) = match len {
+        0..=0x7f => (len as usize, rest),
+        // long form, in up to 4 bytes
+        0x81..=0x84 => {
+            let n = (len & 0x7f) as usize;
+            if rest.len() < n {
+                return None
+            }
+            let len = rest[..n].iter().fold(0usize, |len, &b| len << 8 | b as usize);
+            (len, &rest[n..])
+        },
+        _ => return None,
+    };
+
+    if rest.len() < len {
+        return None
+    }
+
+    let header = der.len() - rest.len();
+    Some(DerElement{
+        tag,
+        contents : &rest[..len],
+        whole : &der[..header + len],
+        rest : &rest[len..],
+    })
+}
+
+/// the der subjectPublicKeyInfo of an x.509 certificate
+#[cfg(feature = "server")]
+fn cert_public_key(cert : &[u8]) -> Option<&[u8]> {
+    const SEQUENCE : u8 = 0x30;
+    // the explicit version tag, absent in v1 certificates
+    const VERSION : u8 = 0xa0;
+
+    let sequence = |der| der_element(der).filter(|element| element.tag == SEQUENCE);
+
+    let cert = sequence(cert)?.contents;
+    let mut rest = sequence(cert)?.contents;
+    if rest.first() == Some(&VERSION) {
+        rest = der_element(rest)?.rest;
+    }
+    // serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.rest;
+    }
+
+    sequence(rest).map(|spki| spki.whole)
+}
+
+/// whether the leaf certificate of the pem chain `cert` is for the pem
+/// public key `pub_key`, either an spki or a pkcs1 rsa key
+#[cfg(feature = "server")]
+pub fn cert_matches_key(cert : &str, pub_key : &str) -> bool {
+    let cert = match pem_block(cert, "CERTIFICATE") {
+        Some(cert) => cert,
+        None => return false,
+    };
+    let spki = match cert_public_key(&cert) {
+        Some(spki) => spki,
+        None => return false,
+    };
+
+    if let Some(key) = pem_block(pub_key, "PUBLIC KEY") {
+        return spki == &key[..]
+    }
+
+    // the pkcs1 key is the bit string after the algorithm, with no
+    // unused bits
+    let key = match pem_block(pub_key, "RSA PUBLIC KEY") {
+        Some(key) => key,
+        None => return false,
+    };
+    der_element(spki)
+        .and_then(|spki| der_element(der_element(spki.contents)?.rest))
+        .is_some_and(|bits| bits.contents.split_first() == Some((&0, &key[..])))
+}
+
+/// validation for tokens issued by the server named `iss` to the
+/// audience `aud`
+pub fn validation(
+    alg : jwt::Algorithm,
+    aud : &str,
+    iss : &str,
+) -> jwt::Validation {
+    let mut aud_set = HashSet::new();
+    aud_set.insert(aud.to_string());
+
+    jwt::Validation{
+        validate_exp : true,
+        iss : Some(iss.to_string()),
+        aud : Some(aud_set),
+        algorithms : vec![alg],
+        ..Default::default()
+    }
+}
+
+/// Why a url can't be the issuer of tokens, see `issuer_url`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum IssuerUrlError {
+    /// only https urls can be issuers, besides http ones on localhost
+    NotHttps,
+    /// no host, or a query, fragment or credentials
+    Invalid,
+}
+
+/// the `iss` of tokens issued by the server at the external url `url`: the
+/// url with its scheme and host lowercased and without a trailing slash,
+/// which is what OIDC discovery appends `/.well-known/openid-configuration`
+/// to
+pub fn issuer_url(url : &str) -> Result<String, IssuerUrlError> {
+    let (scheme, rest) = url.split_once("://")
+        .ok_or(IssuerUrlError::Invalid)?;
+    let scheme = scheme.to_ascii_lowercase();
+
+    let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let host = host.to_ascii_lowercase();
+
+    if host.is_empty() || host.contains('@') ||
+        url.contains(|c : char| c == '?' || c == '#' || c.is_whitespace()) {
+        return Err(IssuerUrlError::Invalid)
+    }
+
+    let local = ["localhost", "127.0.0.1", "[::1]"].iter()
+        .any(|local| host == *local || host.starts_with(&format!("{}:", local)));
+
+    match scheme.as_str() {
+        "https" => {},
+        "http" if local => {},
+        _ => return Err(IssuerUrlError::NotHttps),
+    }
+
+    Ok(format!("{}://{}{}", scheme, host, path.trim_end_matches('/')))
+}
+
+/// parses the server's pem encoded public key, only the asymmetric
+/// algorithms are supported
+pub fn decoding_key(
+    alg : jwt::Algorithm,
+    pem : &[u8],
+) -> Result<jwt::DecodingKey<'static>, jwt::errors::Error> {
+    use jwt::Algorithm::*;
+
+    Ok(match alg {
+        ES256 | ES384 => jwt::DecodingKey::from_ec_pem(pem)?,
+        RS256 | RS384 | RS512 |
+        PS256 | PS384 | PS512 => jwt::DecodingKey::from_rsa_pem(pem)?,
+        _ => return Err(jwt::errors::ErrorKind::InvalidAlgorithm.into()),
+    }.into_static())
+}
+
+/// Source of the current time for issuing and validating tokens
+pub trait Clock : Send + Sync {
+    fn now(&self) -> time::SystemTime;
+}
+
+/// The default clock, `SystemTime::now`
+#[derive(Debug,Clone,Copy,Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
 }
 
 #[derive(Debug, QuickFrom)]
 pub enum TokenError {
     InvalidDuration(Option<SystemTimeError>),
+    /// the token couldn't be encrypted for its audience, see `jwe`
+    Encryption,
     #[quick_from]
     Jwt(jwt::errors::Error),
 }
 
+/// How the subject authenticated, ordered from weakest to strongest so
+/// callers can require a minimum level for sensitive actions.
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Assurance {
+    #[default]
+    Password,
+    /// a kerberos ticket, see `negotiate`. A single factor like a
+    /// password, but one a phishing page can't capture, so it ranks above
+    /// passwords and below any second factor. Serialized by name, so the
+    /// position doesn't change issued tokens.
+    Kerberos,
+    /// a challenge signed with an ssh key, see `ssh`, a single factor
+    /// which can't be phished either
+    SshKey,
+    PasswordTotp,
+    Webauthn,
+}
+
+/// The party acting on behalf of the subject, as in RFC 8693
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq,Eq)]
+pub struct Actor {
+    pub sub : String,
+}
+
+/// The organization a token is scoped to, and the subject's place in it
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq,Eq)]
+pub struct Org {
+    pub name : String,
+    /// the subject may manage the organization's members
+    #[serde(default)]
+    pub admin : bool,
+    #[serde(default)]
+    pub groups : Vec<String>,
+    /// the version of the subject's tokens scoped to the organization,
+    /// bumped when their membership changes or ends. Left out while 0, as
+    /// tokens were before it existed.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub version : u32,
+}
+
+fn is_zero(n : &u32) -> bool {
+    *n == 0
+}
+
+/// the claim listing the policy documents, e.g. terms of service, the
+/// subject has yet to accept, when the server requires them. Absent if
+/// there are none.
+pub const PENDING_CONSENT_CLAIM : &str = "pending_consent";
+
+/// claims set by `Token` itself, these are never taken from `Token::extra`
+pub const RESERVED_CLAIMS : &[&str] = &[
+    "iss", "aud", "sub", "version", "aud_version",
+    "acr", "act", "auth_time", "iat", "exp", "org",
+];
+
+/// Optional fields of the jwt header of issued tokens, e.g. the
+/// `at+jwt` type of RFC 9068 access tokens, or a key id and certificate
+/// thumbprint for validators which pick the key by them
+#[derive(Deserialize,Debug,Clone,Default)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderFields {
+    /// `JWT` if unset
+    #[serde(default)]
+    pub typ : Option<String>,
+    #[serde(default)]
+    pub cty : Option<String>,
+    #[serde(default)]
+    pub kid : Option<String>,
+    /// base64url encoded SHA-1 thumbprint of the signing certificate
+    #[serde(default)]
+    pub x5t : Option<String>,
+}
+
+impl HeaderFields {
+    pub fn header(&self, alg : jwt::Algorithm) -> jwt::Header {
+        let mut header = jwt::Header::new(alg);
+        if let Some(typ) = &self.typ {
+            header.typ = Some(typ.clone());
+        }
+        header.cty = self.cty.clone();
+        header.kid = self.kid.clone();
+        header.x5t = self.x5t.clone();
+
+        header
+    }
+}
+
+/// claims which are always issued under their own name, validators rely on
+/// them and the server looks up the mapping by `aud`
+pub const REGISTERED_CLAIMS : &[&str] = &["iss", "aud", "sub", "exp", "iat"];
+
+/// claims which can be renamed but not omitted, their absence would read
+/// as a default, e.g. no actor for an impersonated token
+pub const REQUIRED_CLAIMS : &[&str] = &["version", "aud_version", "acr", "act", "auth_time", "org"];
+
+/// Renames and omits claims of issued tokens, e.g. `version` as `ver`, so
+/// they match what the validators of an identity provider being migrated
+/// from expect. Claims in `REGISTERED_CLAIMS` can't be mapped, those in
+/// `REQUIRED_CLAIMS` can only be renamed.
+#[derive(Deserialize,Debug,Clone,Default)]
+pub struct ClaimsMap {
+    /// claims to the names they're issued under
+    #[serde(default)]
+    pub rename : BTreeMap<String, String>,
+    #[serde(default)]
+    pub omit : Vec<String>,
+}
+
+impl ClaimsMap {
+    pub fn is_empty(&self) -> bool {
+        self.rename.is_empty() && self.omit.is_empty()
+    }
+
+    pub fn omits(&self, claim : &str) -> bool {
+        self.omit.iter().any(|c| c == claim)
+    }
+
+    /// the first claim which can't be mapped as configured
+    fn invalid_claim(&self) -> Option<&str> {
+        let mut targets = HashSet::new();
+
+        for claim in self.omit.iter().chain(self.rename.keys()) {
+            if REGISTERED_CLAIMS.contains(&claim.as_str()) {
+                return Some(claim)
+            }
+        }
+
+        if let Some(claim) = self.omit.iter().find(|claim| REQUIRED_CLAIMS.contains(&claim.as_str())) {
+            return Some(claim)
+        }
+
+        // renamed claims must be told apart from the others when validating
+        self.rename.values()
+            .find(|to| RESERVED_CLAIMS.contains(&to.as_str()) || !targets.insert(*to))
+            .map(String::as_str)
+    }
+
+    fn apply(&self, claims : &mut serde_json::Map<String, serde_json::Value>) {
+        for claim in &self.omit {
+            claims.remove(claim);
+        }
+
+        // taken out first so renames can swap names
+        let renamed = self.rename.iter()
+            .filter_map(|(from, to)| Some((to, claims.remove(from)?)))
+            .collect::<Vec<_>>();
+        for (to, v) in renamed {
+            claims.insert(to.clone(), v);
+        }
+    }
+
+    fn reverse(&self, claims : &mut serde_json::Map<String, serde_json::Value>) {
+        let renamed = self.rename.iter()
+            .filter_map(|(from, to)| Some((from, claims.remove(to)?)))
+            .collect::<Vec<_>>();
+        for (from, v) in renamed {
+            claims.insert(from.clone(), v);
+        }
+    }
+}
+
+/// The `ClaimsMap` of each audience. Tokens with mapped claims can only be
+/// validated with their mapping, e.g. by the server, not by `client`.
+#[derive(Deserialize,Debug,Clone,Default)]
+pub struct ClaimsMapping {
+    /// for audiences missing from `audiences`
+    #[serde(flatten)]
+    pub default : ClaimsMap,
+    /// replace `default` for their audience
+    #[serde(default)]
+    pub audiences : HashMap<String, ClaimsMap>,
+}
+
+impl ClaimsMapping {
+    pub fn for_audience(&self, aud : &str) -> &ClaimsMap {
+        self.audiences.get(aud).unwrap_or(&self.default)
+    }
+
+    /// the first claim which can't be mapped as configured, see
+    /// `ClaimsMap`
+    pub fn invalid_claim(&self) -> Option<&str> {
+        std::iter::once(&self.default)
+            .chain(self.audiences.values())
+            .find_map(ClaimsMap::invalid_claim)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.default.is_empty() && self.audiences.values().all(ClaimsMap::is_empty)
+    }
+}
+
+#[derive(Debug,Clone)]
 pub struct Token {
     pub iss : String,
     pub aud : String,
     pub sub : String,
+    /// global version, bumping it invalidates tokens for every audience
     pub version : u32,
+    /// version scoped to the (sub, aud) pair
+    pub aud_version : u32,
+    pub acr : Assurance,
+    /// set when the token was minted by someone other than the subject
+    pub act : Option<Actor>,
+    /// when the user last authenticated, `None` means at issuance
+    pub auth_time : Option<u64>,
+    /// the server fills in `admin` and `groups` from the membership when
+    /// issuing
+    pub org : Option<Org>,
+    /// application specific claims
+    pub extra : serde_json::Map<String, serde_json::Value>,
 }
 
 impl Token {
+    /// the documents listed in `PENDING_CONSENT_CLAIM`
+    pub fn pending_consent(&self) -> Vec<&str> {
+        self.extra.get(PENDING_CONSENT_CLAIM)
+            .and_then(|v| v.as_array())
+            .map(|docs| docs.iter().filter_map(|doc| doc.as_str()).collect())
+            .unwrap_or_default()
+    }
+
     pub fn issue(
         &self,
         enc_key : &jwt::EncodingKey,
         alg : jwt::Algorithm,
         exp_duration : time::Duration,
     ) -> Result<String, TokenError> {
-        let now = time::SystemTime::now();
+        self.issue_with(&SystemClock, enc_key, &jwt::Header::new(alg), exp_duration)
+    }
+
+    /// like `issue`, taking iat from `clock` and signing with `header.alg`
+    /// under `header`, see `HeaderFields`
+    pub fn issue_with(
+        &self,
+        clock : &dyn Clock,
+        enc_key : &jwt::EncodingKey,
+        header : &jwt::Header,
+        exp_duration : time::Duration,
+    ) -> Result<String, TokenError> {
+        self.issue_mapped(clock, enc_key, header, exp_duration, &ClaimsMap::default())
+    }
+
+    /// like `issue_with`, renaming and omitting claims as `map` says
+    pub fn issue_mapped(
+        &self,
+        clock : &dyn Clock,
+        enc_key : &jwt::EncodingKey,
+        header : &jwt::Header,
+        exp_duration : time::Duration,
+        map : &ClaimsMap,
+    ) -> Result<String, TokenError> {
+        let now = clock.now();
         let iat = now
             .duration_since(time::UNIX_EPOCH)
             .map_err(|err| {
                 TokenError::InvalidDuration(Some(err))
             })?
-            .as_secs()
-            .try_into()
-            .unwrap();
+            .as_secs();
 
         let exp = now
             .checked_add(exp_duration)
@@ -57,9 +855,7 @@ impl Token {
             .map_err(|err| {
                 TokenError::InvalidDuration(Some(err))
             })?
-            .as_secs()
-            .try_into()
-            .unwrap();
+            .as_secs();
 
                 #[derive(Serialize)]
         pub struct TokenFull<'a> {
@@ -67,27 +863,52 @@ impl Token {
             aud :     &'a str,
             sub :     &'a str,
             version : u32,
+            aud_version : u32,
+            acr :     Assurance,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            act :     Option<&'a Actor>,
+            auth_time : u64,
             iat :     u64,
             exp :     u64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            org :     Option<&'a Org>,
+            #[serde(flatten)]
+            extra :   serde_json::Map<String, serde_json::Value>,
         }
 
+        let extra = self.extra
+            .iter()
+            .filter(|(k, _)| !RESERVED_CLAIMS.contains(&k.as_str()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
         let tok = TokenFull {
             iss : &self.iss,
             aud : &self.aud,
             sub : &self.sub,
             version : self.version,
+            aud_version : self.aud_version,
+            acr : self.acr,
+            act : self.act.as_ref(),
+            auth_time : self.auth_time.unwrap_or(iat),
             iat,
             exp,
+            org : self.org.as_ref(),
+            extra,
         };
 
-        Ok(jwt::encode(
-            &jwt::Header{
-                alg : alg,
-                ..Default::default()
-            },
-            &tok,
-            &enc_key,
-        )?)
+        if map.is_empty() {
+            return Ok(jwt::encode(header, &tok, enc_key)?)
+        }
+
+        let mut claims = match serde_json::to_value(&tok).map_err(jwt::errors::Error::from)? {
+            serde_json::Value::Object(claims) => claims,
+            // TokenFull is a struct
+            _ => unreachable!(),
+        };
+        map.apply(&mut claims);
+
+        Ok(jwt::encode(header, &claims, enc_key)?)
     }
 
     pub fn validate(
@@ -95,6 +916,30 @@ impl Token {
         validation : &jwt::Validation,
         pub_key : &jwt::DecodingKey<'_>,
     ) -> Result<Self, jwt::errors::Error> {
+        Self::validate_with(&SystemClock, token, validation, pub_key)
+    }
+
+    /// like `validate`, checking exp and nbf against `clock`
+    pub fn validate_with(
+        clock : &dyn Clock,
+        token : &str,
+        validation : &jwt::Validation,
+        pub_key : &jwt::DecodingKey<'_>,
+    ) -> Result<Self, jwt::errors::Error> {
+        Self::validate_mapped(clock, token, validation, pub_key, &ClaimsMapping::default())
+    }
+
+    /// like `validate_with`, for tokens issued with the map of their
+    /// audience in `mapping`. Omitted claims, which can only be application
+    /// specific ones, are absent from `extra`.
+    pub fn validate_mapped(
+        clock : &dyn Clock,
+        token : &str,
+        validation : &jwt::Validation,
+        pub_key : &jwt::DecodingKey<'_>,
+        mapping : &ClaimsMapping,
+    ) -> Result<Self, jwt::errors::Error> {
+        use jwt::errors::ErrorKind;
 
         #[derive(Deserialize)]
         #[allow(dead_code)]
@@ -103,23 +948,76 @@ impl Token {
             aud :     String,
             sub :     String,
             version : u32,
+            // tokens issued before versions per audience existed had none
+            #[serde(default)]
+            aud_version : u32,
+            // tokens issued before this claim existed were password only
+            #[serde(default)]
+            acr :     Assurance,
+            #[serde(default)]
+            act :     Option<Actor>,
+            #[serde(default)]
+            auth_time : Option<u64>,
             iat :     u64,
             exp :     u64,
+            #[serde(default)]
+            org :     Option<Org>,
+            #[serde(flatten)]
+            extra :   serde_json::Map<String, serde_json::Value>,
         }
 
-        let tok : TokenFull = jwt::decode(
-            token,
-            pub_key,
-            validation,
-        )
-        .map_err(|err| err.into_kind())?
-        .claims;
+        // jsonwebtoken always uses the system time, so the time based
+        // claims are checked here instead
+        let time_free = jwt::Validation{
+            validate_exp : false,
+            validate_nbf : false,
+            ..validation.clone()
+        };
+
+        let tok : TokenFull = if mapping.is_empty() {
+            jwt::decode(token, pub_key, &time_free)
+                .map_err(|err| err.into_kind())?
+                .claims
+        } else {
+            let mut claims : serde_json::Map<String, serde_json::Value> = jwt::decode(token, pub_key, &time_free)
+                .map_err(|err| err.into_kind())?
+                .claims;
+
+            let map = mapping.for_audience(claims.get("aud")
+                .and_then(|aud| aud.as_str())
+                .unwrap_or_default());
+            map.reverse(&mut claims);
+
+            serde_json::from_value(claims.into())?
+        };
+
+        let now = clock.now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if validation.validate_exp && tok.exp < now.saturating_sub(validation.leeway) {
+            return Err(ErrorKind::ExpiredSignature.into())
+        }
+
+        let nbf = tok.extra.get("nbf").and_then(|v| v.as_u64());
+        if let (true, Some(nbf)) = (validation.validate_nbf, nbf) {
+            if nbf > now + validation.leeway {
+                return Err(ErrorKind::ImmatureSignature.into())
+            }
+        }
 
         Ok(Self {
             iss :     tok.iss,
             aud :     tok.aud,
             sub :     tok.sub,
             version : tok.version,
+            aud_version : tok.aud_version,
+            acr :     tok.acr,
+            act :     tok.act,
+            auth_time : Some(tok.auth_time.unwrap_or(tok.iat)),
+            org :     tok.org,
+            extra :   tok.extra,
         })
     }
 }
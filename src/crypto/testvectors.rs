@@ -0,0 +1,241 @@
+//! Known good tokens, for checking other implementations of token
+//! validation, e.g. in other languages, against this one. Each `Vector`
+//! holds a token with the `claims` issued at `ISSUED_AT` for `DURATION`
+//! seconds, and the keys it was signed with. The keys are for tests only.
+//!
+//! RSA PKCS#1 v1.5 signatures are deterministic, so `RS*` tokens are
+//! issued byte for byte again. ECDSA and PSS signatures are randomized,
+//! their tokens only have the same header and payload when reissued.
+
+use std::time;
+
+use jsonwebtoken as jwt;
+
+use super::{Assurance, Clock, Org, Token};
+
+pub const ISS : &str = "https://authn.example.com";
+pub const AUD : &str = "app.example.com";
+pub const SUB : &str = "alice";
+/// seconds since the unix epoch, 2027-01-15T08:00:00Z
+pub const ISSUED_AT : u64 = 1_800_000_000;
+pub const DURATION : u64 = 3600;
+
+const EC_P256_PRIV_KEY : &str = include_str!("../test-priv-key.pem");
+const EC_P256_PUB_KEY : &str = include_str!("../test-pub-key.pem");
+const EC_P384_PRIV_KEY : &str = include_str!("../test-es384-priv-key.pem");
+const EC_P384_PUB_KEY : &str = include_str!("../test-es384-pub-key.pem");
+const RSA_PRIV_KEY : &str = include_str!("../test-rsa-priv-key.pem");
+const RSA_PUB_KEY : &str = include_str!("../test-rsa-pub-key.pem");
+
+/// A clock stopped at some seconds since the unix epoch
+#[derive(Debug,Clone,Copy)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> time::SystemTime {
+        time::UNIX_EPOCH + time::Duration::from_secs(self.0)
+    }
+}
+
+#[derive(Debug,Clone,Copy)]
+pub struct Vector {
+    pub alg : jwt::Algorithm,
+    /// pkcs8 pem
+    pub priv_key : &'static str,
+    /// spki pem
+    pub pub_key : &'static str,
+    pub token : &'static str,
+}
+
+impl Vector {
+    /// the validation the token passes between `ISSUED_AT` and its expiry
+    pub fn validation(&self) -> jwt::Validation {
+        super::validation(self.alg, AUD, ISS)
+    }
+
+    /// the encoded header and payload, which reissuing reproduces for
+    /// every algorithm
+    pub fn signing_input(&self) -> &'static str {
+        self.token.rsplit_once('.').map_or(self.token, |(input, _)| input)
+    }
+
+    /// whether reissuing reproduces the whole token
+    pub fn deterministic(&self) -> bool {
+        use jwt::Algorithm::*;
+
+        matches!(self.alg, RS256 | RS384 | RS512)
+    }
+}
+
+/// the claims of every vector's token
+pub fn claims() -> Token {
+    let mut extra = serde_json::Map::new();
+    extra.insert("email".to_string(), "alice@example.com".into());
+
+    Token{
+        iss : ISS.to_string(),
+        aud : AUD.to_string(),
+        sub : SUB.to_string(),
+        version : 2,
+        aud_version : 1,
+        acr : Assurance::PasswordTotp,
+        act : None,
+        auth_time : Some(ISSUED_AT - 60),
+        org : Some(Org{
+            name : "example".to_string(),
+            admin : false,
+            groups : vec!["eng".to_string()],
+            version : 0,
+        }),
+        extra,
+    }
+}
+
+/// a token for each algorithm the server can sign with
+pub const VECTORS : &[Vector] = &[
+    Vector{
+        alg : jwt::Algorithm::ES256,
+        priv_key : EC_P256_PRIV_KEY,
+        pub_key : EC_P256_PUB_KEY,
+        token : concat!(
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJFUzI1NiJ9.eyJpc3MiOiJodHRwczovL2F1dGh",
+            "uLmV4YW1wbGUuY29tIiwiYXVkIjoiYXBwLmV4YW1wbGUuY29tIiwic3ViIjoiYWx",
+            "pY2UiLCJ2ZXJzaW9uIjoyLCJhdWRfdmVyc2lvbiI6MSwiYWNyIjoicGFzc3dvcmQ",
+            "tdG90cCIsImF1dGhfdGltZSI6MTc5OTk5OTk0MCwiaWF0IjoxODAwMDAwMDAwLCJ",
+            "leHAiOjE4MDAwMDM2MDAsIm9yZyI6eyJuYW1lIjoiZXhhbXBsZSIsImFkbWluIjp",
+            "mYWxzZSwiZ3JvdXBzIjpbImVuZyJdfSwiZW1haWwiOiJhbGljZUBleGFtcGxlLmN",
+            "vbSJ9.wLGHYApLbJnVKf4_UNxwEsIp1FtuSmVFhOYvHrVn2lKZ0hmTYrhuZjM-44",
+            "WvPWcWEYuWBm25X76n7asC2-4pqA",
+        ),
+    },
+    Vector{
+        alg : jwt::Algorithm::ES384,
+        priv_key : EC_P384_PRIV_KEY,
+        pub_key : EC_P384_PUB_KEY,
+        token : concat!(
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJFUzM4NCJ9.eyJpc3MiOiJodHRwczovL2F1dGh",
+            "uLmV4YW1wbGUuY29tIiwiYXVkIjoiYXBwLmV4YW1wbGUuY29tIiwic3ViIjoiYWx",
+            "pY2UiLCJ2ZXJzaW9uIjoyLCJhdWRfdmVyc2lvbiI6MSwiYWNyIjoicGFzc3dvcmQ",
+            "tdG90cCIsImF1dGhfdGltZSI6MTc5OTk5OTk0MCwiaWF0IjoxODAwMDAwMDAwLCJ",
+            "leHAiOjE4MDAwMDM2MDAsIm9yZyI6eyJuYW1lIjoiZXhhbXBsZSIsImFkbWluIjp",
+            "mYWxzZSwiZ3JvdXBzIjpbImVuZyJdfSwiZW1haWwiOiJhbGljZUBleGFtcGxlLmN",
+            "vbSJ9.qI8_QvjZLOSEKa79WbBPW0mJI3HJfARfve-LSCZaalb5m6E-dutID4O_0W",
+            "KX7pnwlg2ZqhmlrZ7IamJoHTD-GPHLBD6YcBG31fOJtSB1TXEjOIRdWpkuehkpQt",
+            "c3roZt",
+        ),
+    },
+    Vector{
+        alg : jwt::Algorithm::RS256,
+        priv_key : RSA_PRIV_KEY,
+        pub_key : RSA_PUB_KEY,
+        token : concat!(
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzI1NiJ9.eyJpc3MiOiJodHRwczovL2F1dGh",
+            "uLmV4YW1wbGUuY29tIiwiYXVkIjoiYXBwLmV4YW1wbGUuY29tIiwic3ViIjoiYWx",
+            "pY2UiLCJ2ZXJzaW9uIjoyLCJhdWRfdmVyc2lvbiI6MSwiYWNyIjoicGFzc3dvcmQ",
+            "tdG90cCIsImF1dGhfdGltZSI6MTc5OTk5OTk0MCwiaWF0IjoxODAwMDAwMDAwLCJ",
+            "leHAiOjE4MDAwMDM2MDAsIm9yZyI6eyJuYW1lIjoiZXhhbXBsZSIsImFkbWluIjp",
+            "mYWxzZSwiZ3JvdXBzIjpbImVuZyJdfSwiZW1haWwiOiJhbGljZUBleGFtcGxlLmN",
+            "vbSJ9.WafWcYr2zPKL3kz8ki7aO5IFk-xG_Ww5r0fhwcvyPIauZgiFZfOZY9nu04",
+            "blwfs50UYbJ_66lL5Cbj5lQL76y3CAZQ3FFtH6HUUZ2QWpJgBRs0KO6CdfRBk3IL",
+            "JU4zCM09lHrx0nacABZ295SGqeR5-YPN1pdw_mvhMSyPxyLigqAnVUNfoMybsJuJ",
+            "HwVDDMao6snxBu1LzaQ2U-7Ffcbstf1XA5u4rVpqALVJEvI_QylnMEJU3vBGKTiu",
+            "DpKlzxxgxwdto7fbX74TNk_DyW4xWMQ-QPoBuBtKCFF1VpRdUUjIXUJ5QrYfvaKk",
+            "PclFrO_ZkVuSvyae3uA8kyRrnsnA",
+        ),
+    },
+    Vector{
+        alg : jwt::Algorithm::RS384,
+        priv_key : RSA_PRIV_KEY,
+        pub_key : RSA_PUB_KEY,
+        token : concat!(
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzM4NCJ9.eyJpc3MiOiJodHRwczovL2F1dGh",
+            "uLmV4YW1wbGUuY29tIiwiYXVkIjoiYXBwLmV4YW1wbGUuY29tIiwic3ViIjoiYWx",
+            "pY2UiLCJ2ZXJzaW9uIjoyLCJhdWRfdmVyc2lvbiI6MSwiYWNyIjoicGFzc3dvcmQ",
+            "tdG90cCIsImF1dGhfdGltZSI6MTc5OTk5OTk0MCwiaWF0IjoxODAwMDAwMDAwLCJ",
+            "leHAiOjE4MDAwMDM2MDAsIm9yZyI6eyJuYW1lIjoiZXhhbXBsZSIsImFkbWluIjp",
+            "mYWxzZSwiZ3JvdXBzIjpbImVuZyJdfSwiZW1haWwiOiJhbGljZUBleGFtcGxlLmN",
+            "vbSJ9.NUJvIo4qRKcw-QrOZEky2239C5y65ERMcImkRSsZ32ygMacEdVQWXpzS1i",
+            "dnuFu30Pha9d--IEQEQY4Gw7_AgwPsUm6wMcQmMj2jlZmXA1jLGcYMNCrJBxFLip",
+            "dxUXxkMcIbFTORJewkqFT1XirxCvozJpxk1DA7BaurMxLsklRMNPaAdqujPGShdE",
+            "KrpxID8hKULM_glpW9S0cI2MkTJ_l2_aGQTZj0lr8bL3c41wWwpp-L-VuNFWeO1r",
+            "lhB2845MqPnHXxN5YnxmbvMQBdosFABvzj4YPrukYyONnbaVNTzmUBOrK2yJXcNb",
+            "vF7ne7QBg-9utnioOWC6A7HOYn9w",
+        ),
+    },
+    Vector{
+        alg : jwt::Algorithm::RS512,
+        priv_key : RSA_PRIV_KEY,
+        pub_key : RSA_PUB_KEY,
+        token : concat!(
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJSUzUxMiJ9.eyJpc3MiOiJodHRwczovL2F1dGh",
+            "uLmV4YW1wbGUuY29tIiwiYXVkIjoiYXBwLmV4YW1wbGUuY29tIiwic3ViIjoiYWx",
+            "pY2UiLCJ2ZXJzaW9uIjoyLCJhdWRfdmVyc2lvbiI6MSwiYWNyIjoicGFzc3dvcmQ",
+            "tdG90cCIsImF1dGhfdGltZSI6MTc5OTk5OTk0MCwiaWF0IjoxODAwMDAwMDAwLCJ",
+            "leHAiOjE4MDAwMDM2MDAsIm9yZyI6eyJuYW1lIjoiZXhhbXBsZSIsImFkbWluIjp",
+            "mYWxzZSwiZ3JvdXBzIjpbImVuZyJdfSwiZW1haWwiOiJhbGljZUBleGFtcGxlLmN",
+            "vbSJ9.Xzt5CQNPXVCzIlMXFL_6jBd6U1F0U9HJ4JB2G1pPtQa9hHJ7YzPzU1WJPC",
+            "2DKvP05XXBqeIXq0W8js8jwFnf3wwjlp_ExQ71ZDL3yhyaYLBi61vJWWwXTi9VZn",
+            "2L74DQjMGeHdUAAAR7akWsNf-OVNymLBraDNICcU2aFdb-e1BTOTXN1r3-JgxGIR",
+            "PXgHJKh5gy6NkMn1BmWigaZhgboFKf6gcgmoXSOzA7Dx0Vd2Nu-PcnrAHVp22Wy2",
+            "ba5mWQuXqN9W0v-zlbSb4j3p6V3Yy0bfufc__CpmmctEw5APN7JAft-FNcfWQ8JU",
+            "e1ovtX9Sic_LNQHGMDa0kaZgocpA",
+        ),
+    },
+    Vector{
+        alg : jwt::Algorithm::PS256,
+        priv_key : RSA_PRIV_KEY,
+        pub_key : RSA_PUB_KEY,
+        token : concat!(
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJQUzI1NiJ9.eyJpc3MiOiJodHRwczovL2F1dGh",
+            "uLmV4YW1wbGUuY29tIiwiYXVkIjoiYXBwLmV4YW1wbGUuY29tIiwic3ViIjoiYWx",
+            "pY2UiLCJ2ZXJzaW9uIjoyLCJhdWRfdmVyc2lvbiI6MSwiYWNyIjoicGFzc3dvcmQ",
+            "tdG90cCIsImF1dGhfdGltZSI6MTc5OTk5OTk0MCwiaWF0IjoxODAwMDAwMDAwLCJ",
+            "leHAiOjE4MDAwMDM2MDAsIm9yZyI6eyJuYW1lIjoiZXhhbXBsZSIsImFkbWluIjp",
+            "mYWxzZSwiZ3JvdXBzIjpbImVuZyJdfSwiZW1haWwiOiJhbGljZUBleGFtcGxlLmN",
+            "vbSJ9.Y7j6Bww-FV0q8MERWwy6wPs4XwbvYfTsO0u1dregidUrNF0BdnoA3X6pmh",
+            "qBYlJbe4Djd4x3QGFkoo2w0hpvjtjQ74g9ITdI7Q5hX1ZcXQIar3L_upa5wKcwHf",
+            "fBJ3nXokuej2PBJdS5sz3VIRszwbqyz2eIcWmTFdLZlpI5fURyI5COQNggtq7Ckb",
+            "fsGQWghlBGg0wEjdbClOhhIzDWmiECfyCRWIZ9qeK__LqZRUTSHTFgq-OcK8S3Tu",
+            "AP81fM5q-vIh_sW5omkZ_nl3lzWZPzaf-Uf5R8DDaLknHbGHj1Ptg58BBFpBfC23",
+            "NavwJTNgvEJVmfE_4C9KgqBIcxaA",
+        ),
+    },
+    Vector{
+        alg : jwt::Algorithm::PS384,
+        priv_key : RSA_PRIV_KEY,
+        pub_key : RSA_PUB_KEY,
+        token : concat!(
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJQUzM4NCJ9.eyJpc3MiOiJodHRwczovL2F1dGh",
+            "uLmV4YW1wbGUuY29tIiwiYXVkIjoiYXBwLmV4YW1wbGUuY29tIiwic3ViIjoiYWx",
+            "pY2UiLCJ2ZXJzaW9uIjoyLCJhdWRfdmVyc2lvbiI6MSwiYWNyIjoicGFzc3dvcmQ",
+            "tdG90cCIsImF1dGhfdGltZSI6MTc5OTk5OTk0MCwiaWF0IjoxODAwMDAwMDAwLCJ",
+            "leHAiOjE4MDAwMDM2MDAsIm9yZyI6eyJuYW1lIjoiZXhhbXBsZSIsImFkbWluIjp",
+            "mYWxzZSwiZ3JvdXBzIjpbImVuZyJdfSwiZW1haWwiOiJhbGljZUBleGFtcGxlLmN",
+            "vbSJ9.iAFje9sOrSa_Pyg5_Zyheqqmaf2kXbQaAzN0J_lYkGmy24fNBzYsFaGoXM",
+            "98BNsRaLk-uHl1qPXIBxGJgCJB6YRLPF8NMybte5EuD-7LVzDe8Nx4LD1DK7Qd4Y",
+            "YCaF8F56lIges2JJylKEudN3OZr-2iY1xOj97hy-_k0mz1vPTgOWCegXi3GmXmWm",
+            "-rdC6W--4NQeceINq9ubnWB9o-ZshQOG0_h6smuTDmLyyyMJsH3xnry9RGeOjNFR",
+            "zCMZniwfsUpxfKbd0_T4DiHQXgd8IbhRSKFNPAWKFHvsuOpVLjctdnKGjaX3nU0h",
+            "0cVV2zpBb30GsnTnajgy--8-sMWw",
+        ),
+    },
+    Vector{
+        alg : jwt::Algorithm::PS512,
+        priv_key : RSA_PRIV_KEY,
+        pub_key : RSA_PUB_KEY,
+        token : concat!(
+            "eyJ0eXAiOiJKV1QiLCJhbGciOiJQUzUxMiJ9.eyJpc3MiOiJodHRwczovL2F1dGh",
+            "uLmV4YW1wbGUuY29tIiwiYXVkIjoiYXBwLmV4YW1wbGUuY29tIiwic3ViIjoiYWx",
+            "pY2UiLCJ2ZXJzaW9uIjoyLCJhdWRfdmVyc2lvbiI6MSwiYWNyIjoicGFzc3dvcmQ",
+            "tdG90cCIsImF1dGhfdGltZSI6MTc5OTk5OTk0MCwiaWF0IjoxODAwMDAwMDAwLCJ",
+            "leHAiOjE4MDAwMDM2MDAsIm9yZyI6eyJuYW1lIjoiZXhhbXBsZSIsImFkbWluIjp",
+            "mYWxzZSwiZ3JvdXBzIjpbImVuZyJdfSwiZW1haWwiOiJhbGljZUBleGFtcGxlLmN",
+            "vbSJ9.hyJFjrOhajwRdZfpTm6Q0_CedhuMdQ5H0Fu4gj_Jin4JMHd1qRQzlX2f5i",
+            "RXxKu4whDFu17-22cBMsl7eM-q0gcl0CFJl24xZ9Qd9Eza9Fg_FVZ0fvroDDykVN",
+            "BhEHqLVEyVeNAJ8E4UVIrseCFQSxhg7KBTEZSRZLT51j5237ODs-cW8Q2HqZ0c4_",
+            "5tD9hfr9dOffD_cUx0HXkksp7zZYdos0SaFjJ6lqik-sB_CLrHlM1HqP_q8ooKhp",
+            "_O8mOfTbhJycun3FAbruBv-S7Hu6fmqpeayh8TyW8gjbbT-VOxU_mZkkU70ry9zK",
+            "vCE8jDB_8zJ9UdG_SYHrS_WTJlug",
+        ),
+    },
+];
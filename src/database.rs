@@ -1,13 +1,62 @@
 use rusqlite::types::FromSql;
-use rusqlite::{ffi, Connection};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use tokio::sync::Mutex;
+use rusqlite::{ffi, Connection, OpenFlags, OptionalExtension};
 
-use crate::server::Error;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::error::{Error, StorageError};
+use crate::listing::{Page, Sort};
+use crate::logging;
+use crate::metrics::LabeledHistogram;
+use crate::stats::StatKey;
 use crate::models;
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// number of most recent passwords, including the current one, which can
+/// not be reused
+pub const DEFAULT_PASSWORD_HISTORY : usize = 5;
+
+/// the hash of a used break glass password, which matches no password
+pub const USED_PASS_HASH : &str = "!";
+
+/// every migration by file name, in the order `sql/run-migrations.bash`
+/// applies them
+pub const MIGRATIONS : &[(&str, &str)] = &[
+    ("2021-09-17-init.sql", include_str!("../sql/migrations/2021-09-17-init.sql")),
+    ("2026-10-16-acknowledgments.sql", include_str!("../sql/migrations/2026-10-16-acknowledgments.sql")),
+    ("2026-10-16-audience-versions.sql", include_str!("../sql/migrations/2026-10-16-audience-versions.sql")),
+    ("2026-10-16-authorization-codes.sql", include_str!("../sql/migrations/2026-10-16-authorization-codes.sql")),
+    ("2026-10-16-break-glass.sql", include_str!("../sql/migrations/2026-10-16-break-glass.sql")),
+    ("2026-10-16-clients.sql", include_str!("../sql/migrations/2026-10-16-clients.sql")),
+    ("2026-10-16-device-authorizations.sql", include_str!("../sql/migrations/2026-10-16-device-authorizations.sql")),
+    ("2026-10-16-devices.sql", include_str!("../sql/migrations/2026-10-16-devices.sql")),
+    ("2026-10-16-impersonation.sql", include_str!("../sql/migrations/2026-10-16-impersonation.sql")),
+    ("2026-10-16-invites.sql", include_str!("../sql/migrations/2026-10-16-invites.sql")),
+    ("2026-10-16-login-countries.sql", include_str!("../sql/migrations/2026-10-16-login-countries.sql")),
+    ("2026-10-16-login-notifications.sql", include_str!("../sql/migrations/2026-10-16-login-notifications.sql")),
+    ("2026-10-16-org-versions.sql", include_str!("../sql/migrations/2026-10-16-org-versions.sql")),
+    ("2026-10-16-organizations.sql", include_str!("../sql/migrations/2026-10-16-organizations.sql")),
+    ("2026-10-16-password-history.sql", include_str!("../sql/migrations/2026-10-16-password-history.sql")),
+    ("2026-10-16-saml-requests.sql", include_str!("../sql/migrations/2026-10-16-saml-requests.sql")),
+    ("2026-10-16-schema-indexes.sql", include_str!("../sql/migrations/2026-10-16-schema-indexes.sql")),
+    ("2026-10-16-ssh-keys.sql", include_str!("../sql/migrations/2026-10-16-ssh-keys.sql")),
+    ("2026-10-16-stats.sql", include_str!("../sql/migrations/2026-10-16-stats.sql")),
+];
+
+fn increment_org_version(conn : &Connection, org : &str, name : &str) -> Result<()> {
+    conn.prepare_cached("
+        INSERT INTO org_versions (org, name, token_version) VALUES (?, ?, 1)
+        ON CONFLICT (org, name) DO UPDATE SET token_version = token_version + 1
+        ")?
+        .execute(rusqlite::params![org, name])?;
+
+    Ok(())
+}
+
 fn error_code_match(
     err : &rusqlite::Error,
     code : ffi::ErrorCode,
@@ -20,43 +69,178 @@ fn error_code_match(
             && i64::from(e.extended_code) == ext)
 }
 
+/// the tables and indexes of `conn`, as `table name` or `index name`,
+/// without sqlite's own
+fn schema(conn : &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("
+        SELECT type || ' ' || name FROM sqlite_master
+        WHERE type IN ('table', 'index') AND name NOT LIKE 'sqlite_%'
+        ORDER BY type, name
+        ")?;
+
+    let names = stmt.query_map(rusqlite::params![], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(names)
+}
+
+/// fails with `StorageError::SchemaOutdated` if `file` lacks tables or
+/// indexes the migrations create, so a database which wasn't migrated is
+/// refused at startup rather than failing queries later
+pub fn check_schema(file : &str) -> Result<()> {
+    let expected = {
+        let conn = Connection::open_in_memory()?;
+        for (_, migration) in MIGRATIONS {
+            conn.execute_batch(migration)?;
+        }
+        schema(&conn)?
+    };
+
+    let conn = Connection::open_with_flags(file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let actual = schema(&conn)?;
+
+    let missing = expected.into_iter()
+        .filter(|name| !actual.contains(name))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(StorageError::SchemaOutdated(missing).into())
+    }
+
+    Ok(())
+}
+
 
+/// defines an async method running `$body` with the write connection, or
+/// with a read connection when prefixed with `read`
 macro_rules! db_method {
-    ($name:ident (
+    (@with $acquire:ident $name:ident (
         &$self:ident,
-        $conn:ident,
-        $($pname:ident : $ptype:ty),*
+        $conn:ident
+        $(, $pname:ident : $ptype:ty)* $(,)?
     ) -> $ret:ty $body:block ) => {
         pub async fn $name (&$self, $( $pname : $ptype, )* ) -> $ret {
-            let $conn = $self.conn.lock().await;
-            tokio::task::block_in_place(|| $body)
+            let acquire = std::time::Instant::now();
+            let $conn = $self.$acquire().await;
+            let start = std::time::Instant::now();
+            let res = tokio::task::block_in_place(|| $body);
+            let elapsed = start.elapsed();
+            $self.latency.observe(stringify!($name), elapsed);
+            $self.log_if_slow(stringify!($name), start - acquire, elapsed);
+            res
         }
-    }
+    };
+    (read $($method:tt)*) => {
+        db_method!{ @with reader $($method)* }
+    };
+    ($($method:tt)*) => {
+        db_method!{ @with writer $($method)* }
+    };
 }
 
 
 pub struct Database {
-    conn : Mutex<Connection>
+    conn : Mutex<Connection>,
+    /// read only connections, reads use `conn` if there are none
+    readers : Vec<Mutex<Connection>>,
+    next_reader : AtomicUsize,
+    /// time spent in each method while holding the connection
+    latency : LabeledHistogram,
+    /// methods holding the connection longer are logged
+    slow_query : Option<Duration>,
 }
 
 impl Database {
     pub fn new(file : &str) -> Result<Self> {
+        Self::open(file, 0)
+    }
+
+    /// like `new`, with `readers` extra read only connections so reads
+    /// don't wait on each other or on writes. This switches the database
+    /// to wal mode, which is what lets readers run next to a writer.
+    pub fn open(file : &str, readers : usize) -> Result<Self> {
         let conn = Connection::open(file)?;
         conn.pragma_update(None, "foreign_keys", &"ON")?;
 
-        let conn = Mutex::new(conn);
+        if readers > 0 {
+            conn.pragma_update(None, "journal_mode", &"WAL")?;
+        }
+
+        let readers = (0..readers)
+            .map(|_| {
+                let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX
+                    | OpenFlags::SQLITE_OPEN_URI;
+
+                Ok(Mutex::new(Connection::open_with_flags(file, flags)?))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self{
+            conn : Mutex::new(conn),
+            readers,
+            next_reader : AtomicUsize::new(0),
+            latency : Default::default(),
+            slow_query : None,
+        })
+    }
+
+    /// logs methods which hold their connection for longer than
+    /// `threshold`, e.g. queries missing an index
+    pub fn with_slow_query(mut self, threshold : Duration) -> Self {
+        self.slow_query = Some(threshold);
+        self
+    }
+
+    /// `wait` is the time spent waiting for the connection, which points
+    /// at contention rather than the query
+    fn log_if_slow(&self, method : &'static str, wait : Duration, elapsed : Duration) {
+        if self.slow_query.is_some_and(|max| elapsed > max) {
+            logging::error!(
+                [
+                    ("method", serde_json::json!(method)),
+                    ("duration_ms", serde_json::json!(elapsed.as_millis() as u64)),
+                    ("wait_ms", serde_json::json!(wait.as_millis() as u64)),
+                ],
+                "slow database method {} took {}ms",
+                method,
+                elapsed.as_millis(),
+            );
+        }
+    }
+
+    async fn writer(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().await
+    }
+
+    /// the read connections take turns
+    async fn reader(&self) -> MutexGuard<'_, Connection> {
+        if self.readers.is_empty() {
+            return self.writer().await
+        }
+
+        let n = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        self.readers[n % self.readers.len()].lock().await
+    }
 
-        Ok(Self{ conn })
+    pub fn latency(&self) -> &LabeledHistogram {
+        &self.latency
     }
 
-    db_method!{ get_user_by_name(&self, conn, name : &str) -> Result<models::User> {
+    // answers while the writer connection isn't stuck, see `service`
+    db_method!{ ping(&self, conn) -> Result<()> {
+        conn.query_row("SELECT 1", rusqlite::params![], |_| Ok(()))?;
+
+        Ok(())
+    }}
+
+    db_method!{ read get_user_by_name(&self, conn, name : &str) -> Result<models::User> {
         let mut stmt = conn.prepare_cached("SELECT * FROM users WHERE users.name = ?")?;
 
         let mut rows = stmt.query(rusqlite::params![name])?;
 
-        let row = rows.next()?.ok_or(Error::UserNotFound(name.to_string()))?;
+        let row = rows.next()?.ok_or(StorageError::UserNotFound(name.to_string()))?;
 
-        Ok(row_parse(row)?)
+        row_parse(row)
     }}
 
     db_method!{ increment_token(&self, conn, name : &str) -> Result<()> {
@@ -70,6 +254,1219 @@ impl Database {
         Ok(())
     }}
 
+    db_method!{ read get_password_history(&self, conn, name : &str, n : usize) -> Result<Vec<String>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT pass_hash FROM users WHERE name = ?1
+            UNION ALL
+            SELECT pass_hash FROM (
+                SELECT pass_hash FROM password_history
+                WHERE name = ?1
+                ORDER BY created DESC, rowid DESC
+                LIMIT ?2
+            )
+            ")?;
+
+        let keep = n.saturating_sub(1) as i64;
+        let hashes = stmt.query_map(rusqlite::params![name, keep], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        if hashes.is_empty() {
+            return Err(StorageError::UserNotFound(name.to_string()).into())
+        }
+
+        Ok(hashes)
+    }}
+
+    db_method!{ update_user_pass(&self, conn, name : &str, pass_hash : &str, history : usize) -> Result<()> {
+        let tx = conn.unchecked_transaction()?;
+
+        tx.prepare_cached("
+            INSERT INTO password_history (name, pass_hash)
+            SELECT name, pass_hash FROM users WHERE name = ?
+            ")?
+            .execute(rusqlite::params![name])?;
+
+        let n = tx.prepare_cached("UPDATE users SET pass_hash = ? WHERE name = ?")?
+            .execute(rusqlite::params![pass_hash, name])?;
+
+        if n == 0 {
+            return Err(StorageError::UserNotFound(name.to_string()).into())
+        }
+
+        prune_password_history(&tx, history)?;
+
+        tx.commit()?;
+
+        Ok(())
+    }}
+
+    // replaces the hash of the same password, leaving the history be.
+    // Ok(false) if the hash isn't `old_hash` anymore.
+    db_method!{ rehash_user_pass(&self, conn, name : &str, old_hash : &str, pass_hash : &str) -> Result<bool> {
+        let n = conn.prepare_cached("UPDATE users SET pass_hash = ? WHERE name = ? AND pass_hash = ?")?
+            .execute(rusqlite::params![pass_hash, name, old_hash])?;
+
+        Ok(n > 0)
+    }}
+
+    // creates the break glass account `name` with a fresh one time
+    // password, or arms it again, which lets it be used once more and
+    // invalidates the tokens of the last use. Fails with `DuplicateName` if
+    // `name` is a user which isn't a break glass account, unless `force`
+    // turns them into one, replacing their password.
+    db_method!{ arm_break_glass(
+        &self,
+        conn,
+        name : &str,
+        pass_hash : &str,
+        roles : &str,
+        now : i64,
+        force : bool
+    ) -> Result<()> {
+        let tx = conn.unchecked_transaction()?;
+
+        let user = tx.prepare_cached("SELECT 1 FROM users WHERE name = ?")?
+            .exists(rusqlite::params![name])?;
+        let armed = tx.prepare_cached("SELECT 1 FROM break_glass WHERE name = ?")?
+            .exists(rusqlite::params![name])?;
+        if user && !armed && !force {
+            return Err(StorageError::DuplicateName(name.to_string()).into())
+        }
+
+        tx.prepare_cached("
+            INSERT INTO users (name, pass_hash, roles) VALUES (?1, ?2, ?3)
+            ON CONFLICT (name) DO UPDATE SET
+                pass_hash = excluded.pass_hash,
+                roles = excluded.roles,
+                token_version = token_version + 1
+            ")?
+            .execute(rusqlite::params![name, pass_hash, roles])?;
+
+        tx.prepare_cached("
+            INSERT INTO break_glass (name, armed, used) VALUES (?1, ?2, NULL)
+            ON CONFLICT (name) DO UPDATE SET armed = excluded.armed, used = NULL
+            ")?
+            .execute(rusqlite::params![name, now])?;
+
+        tx.commit()?;
+
+        Ok(())
+    }}
+
+    db_method!{ read get_break_glass(&self, conn, name : &str) -> Result<Option<models::BreakGlass>> {
+        let mut stmt = conn.prepare_cached("SELECT * FROM break_glass WHERE name = ?")?;
+        let mut rows = stmt.query(rusqlite::params![name])?;
+
+        rows.next()?.map(row_parse).transpose()
+    }}
+
+    db_method!{ read list_break_glass(&self, conn) -> Result<Vec<models::BreakGlass>> {
+        let mut stmt = conn.prepare_cached("SELECT * FROM break_glass ORDER BY name")?;
+        let mut rows = stmt.query(rusqlite::params![])?;
+
+        let mut accounts = Vec::new();
+        while let Some(row) = rows.next()? {
+            accounts.push(row_parse(row)?);
+        }
+
+        Ok(accounts)
+    }}
+
+    // marks the password of the break glass account `name` used and
+    // forgets its hash. Ok(false) if it was used already.
+    db_method!{ use_break_glass(&self, conn, name : &str, now : i64) -> Result<bool> {
+        let tx = conn.unchecked_transaction()?;
+
+        let n = tx.prepare_cached("UPDATE break_glass SET used = ? WHERE name = ? AND used IS NULL")?
+            .execute(rusqlite::params![now, name])?;
+        if n == 0 {
+            return Ok(false)
+        }
+
+        tx.prepare_cached("UPDATE users SET pass_hash = ? WHERE name = ?")?
+            .execute(rusqlite::params![USED_PASS_HASH, name])?;
+
+        tx.commit()?;
+
+        Ok(true)
+    }}
+
+    db_method!{ prune_password_history(&self, conn, history : usize) -> Result<()> {
+        prune_password_history(&conn, history)
+    }}
+
+    db_method!{ set_roles(&self, conn, name : &str, roles : &str) -> Result<()> {
+        let n = conn.prepare_cached("UPDATE users SET roles = ? WHERE name = ?")?
+            .execute(rusqlite::params![roles, name])?;
+
+        if n == 0 {
+            return Err(StorageError::UserNotFound(name.to_string()).into())
+        }
+
+        Ok(())
+    }}
+
+    db_method!{ insert_audit(
+        &self,
+        conn,
+        actor : Option<&str>,
+        action : &str,
+        subject : Option<&str>,
+        detail : Option<&str>
+    ) -> Result<models::AuditEntry> {
+        let mut stmt = conn.prepare_cached("
+            INSERT INTO audit (actor, action, subject, detail)
+            VALUES (?, ?, ?, ?)
+            RETURNING *
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![actor, action, subject, detail])?;
+
+        let row = rows.next()?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+        row_parse(row)
+    }}
+
+    db_method!{ set_notify_logins(&self, conn, name : &str, notify : bool) -> Result<()> {
+        let n = conn.prepare_cached("UPDATE users SET notify_logins = ? WHERE name = ?")?
+            .execute(rusqlite::params![notify, name])?;
+
+        if n == 0 {
+            return Err(StorageError::UserNotFound(name.to_string()).into())
+        }
+
+        Ok(())
+    }}
+
+    db_method!{ record_login(
+        &self,
+        conn,
+        name : &str,
+        aud : &str,
+        user_agent : &str,
+        country : Option<&str>,
+        now : i64
+    ) -> Result<models::LoginRecord> {
+        let last_any = conn.prepare_cached("SELECT max(last_login) FROM logins WHERE name = ?")?
+            .query_row(rusqlite::params![name], |row| row.get(0))?;
+
+        let last_aud = conn.prepare_cached("
+            SELECT last_login FROM logins
+            WHERE name = ? AND aud = ?
+            ")?
+            .query_row(rusqlite::params![name, aud], |row| row.get(0))
+            .optional()?;
+
+        conn.prepare_cached("
+            INSERT INTO logins (name, aud, last_login) VALUES (?, ?, ?)
+            ON CONFLICT (name, aud) DO UPDATE SET last_login = excluded.last_login
+            ")?
+            .execute(rusqlite::params![name, aud, now])?;
+
+        let new_client = conn.prepare_cached("
+            INSERT INTO clients (name, aud, user_agent, first_seen, last_seen)
+            VALUES (?1, ?2, ?3, ?4, ?4)
+            ON CONFLICT (name, aud, user_agent) DO UPDATE SET last_seen = excluded.last_seen
+            RETURNING first_seen = last_seen
+            ")?
+            .query_row(rusqlite::params![name, aud, user_agent, now], |row| row.get(0))?;
+
+        let new_country = match country {
+            Some(country) => {
+                let new_country = is_new_login_country(&conn, name, country)?;
+                conn.prepare_cached("
+                    INSERT INTO login_countries (name, country, first_seen, last_seen)
+                    VALUES (?1, ?2, ?3, ?3)
+                    ON CONFLICT (name, country) DO UPDATE SET last_seen = excluded.last_seen
+                    ")?
+                    .execute(rusqlite::params![name, country, now])?;
+
+                new_country
+            },
+            None => false,
+        };
+
+        Ok(models::LoginRecord{ last_any, last_aud, new_client, new_country })
+    }}
+
+    // whether the user logged in from other countries, but not `country`
+    db_method!{ read is_new_login_country(&self, conn, name : &str, country : &str) -> Result<bool> {
+        is_new_login_country(&conn, name, country)
+    }}
+
+    // the country the user was most recently seen in
+    db_method!{ read last_login_country(&self, conn, name : &str) -> Result<Option<models::LoginCountry>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM login_countries
+            WHERE name = ?
+            ORDER BY last_seen DESC, country
+            LIMIT 1
+            ")?;
+        let mut rows = stmt.query(rusqlite::params![name])?;
+
+        rows.next()?.map(row_parse).transpose()
+    }}
+
+    // most recently seen first
+    db_method!{ read list_login_countries(&self, conn, name : &str) -> Result<Vec<models::LoginCountry>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM login_countries
+            WHERE name = ?
+            ORDER BY last_seen DESC, country
+            ")?;
+        let mut rows = stmt.query(rusqlite::params![name])?;
+
+        let mut countries = Vec::new();
+        while let Some(row) = rows.next()? {
+            countries.push(row_parse(row)?);
+        }
+
+        Ok(countries)
+    }}
+
+    // filtered by `aud`, after the last seen time, audience and user agent
+    // of the cursor
+    db_method!{ read list_clients(&self, conn, name : &str, page : &Page<models::ClientKey>) -> Result<Vec<models::Client>> {
+        let cmp = match page.sort {
+            Sort::Asc => ">",
+            Sort::Desc => "<",
+        };
+        let mut stmt = conn.prepare_cached(&format!("
+            SELECT * FROM clients
+            WHERE name = ?1 AND (?2 IS NULL OR aud = ?2)
+            AND (?3 IS NULL OR (last_seen, aud, user_agent) {0} (?3, ?4, ?5))
+            ORDER BY last_seen {1}, aud {1}, user_agent {1}
+            LIMIT ?6
+            ", cmp, page.sort.sql()))?;
+
+        let (last_seen, aud, user_agent) = match &page.cursor {
+            Some((last_seen, aud, user_agent)) => (Some(last_seen), Some(aud), Some(user_agent)),
+            None => (None, None, None),
+        };
+        let mut rows = stmt.query(rusqlite::params![
+            name,
+            page.filter("aud"),
+            last_seen,
+            aud,
+            user_agent,
+            page.sql_limit(),
+        ])?;
+
+        let mut clients = Vec::new();
+        while let Some(row) = rows.next()? {
+            clients.push(row_parse(row)?);
+        }
+
+        Ok(clients)
+    }}
+
+    db_method!{ read get_audience_version(&self, conn, name : &str, aud : &str) -> Result<u32> {
+        let version = conn.prepare_cached("
+            SELECT token_version FROM audience_versions
+            WHERE name = ? AND aud = ?
+            ")?
+            .query_row(rusqlite::params![name, aud], |row| row.get(0))
+            .optional()?;
+
+        Ok(version.unwrap_or(0))
+    }}
+
+    // the user's token version and, if aud is given, their version for
+    // the audience, in a single lookup
+    db_method!{ read get_token_versions(&self, conn, name : &str, aud : Option<&str>) -> Result<(u32, u32)> {
+        conn.prepare_cached("
+            SELECT token_version, coalesce((
+                SELECT token_version FROM audience_versions
+                WHERE audience_versions.name = users.name AND aud = ?2
+            ), 0)
+            FROM users WHERE name = ?1
+            ")?
+            .query_row(rusqlite::params![name, aud], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?
+            .ok_or_else(|| StorageError::UserNotFound(name.to_string()).into())
+    }}
+
+    // the devices remembered for the audience go with its tokens, as they'd
+    // log straight back in otherwise
+    db_method!{ increment_audience_token(&self, conn, name : &str, aud : &str) -> Result<()> {
+        let tx = conn.unchecked_transaction()?;
+
+        tx.prepare_cached("
+            INSERT INTO audience_versions (name, aud, token_version) VALUES (?, ?, 1)
+            ON CONFLICT (name, aud) DO UPDATE SET token_version = token_version + 1
+            ")?
+            .execute(rusqlite::params![name, aud])
+            .map_err(|err| -> Error {
+                if error_code_match(
+                    &err,
+                    ffi::ErrorCode::ConstraintViolation,
+                    787
+                ) {
+                    StorageError::UserNotFound(name.to_string()).into()
+                } else {
+                    err.into()
+                }
+            })?;
+
+        tx.prepare_cached("DELETE FROM devices WHERE name = ? AND aud = ?")?
+            .execute(rusqlite::params![name, aud])?;
+
+        tx.commit()?;
+        Ok(())
+    }}
+
+    db_method!{ insert_device(
+        &self,
+        conn,
+        name : &str,
+        aud : &str,
+        token_hash : &str,
+        token_version : u32,
+        now : i64
+    ) -> Result<i64> {
+        conn.prepare_cached("
+            INSERT INTO devices (name, aud, token_hash, token_version, created)
+            VALUES (?, ?, ?, ?, ?)
+            ")?
+            .execute(rusqlite::params![name, aud, token_hash, token_version, now])?;
+
+        Ok(conn.last_insert_rowid())
+    }}
+
+    db_method!{ read get_device_by_hash(&self, conn, token_hash : &str) -> Result<Option<models::Device>> {
+        let mut stmt = conn.prepare_cached("SELECT * FROM devices WHERE token_hash = ?")?;
+
+        let mut rows = stmt.query(rusqlite::params![token_hash])?;
+
+        rows.next()?.map(row_parse).transpose()
+    }}
+
+    db_method!{ touch_device(&self, conn, id : i64, now : i64) -> Result<()> {
+        conn.prepare_cached("UPDATE devices SET last_used = ? WHERE id = ?")?
+            .execute(rusqlite::params![now, id])?;
+
+        Ok(())
+    }}
+
+    db_method!{ read list_devices(&self, conn, name : &str) -> Result<Vec<models::Device>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM devices
+            WHERE name = ?
+            ORDER BY id
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![name])?;
+
+        let mut devices = Vec::new();
+        while let Some(row) = rows.next()? {
+            devices.push(row_parse(row)?);
+        }
+
+        Ok(devices)
+    }}
+
+    // only the devices of aud, if given
+    db_method!{ delete_device(&self, conn, name : &str, aud : Option<&str>, id : i64) -> Result<()> {
+        let n = conn.prepare_cached("
+            DELETE FROM devices
+            WHERE name = ?1 AND id = ?2 AND (?3 IS NULL OR aud = ?3)
+            ")?
+            .execute(rusqlite::params![name, id, aud])?;
+
+        if n == 0 {
+            return Err(StorageError::DeviceNotFound(id).into())
+        }
+
+        Ok(())
+    }}
+
+    // adding a key again only updates its comment
+    db_method!{ add_ssh_key(
+        &self,
+        conn,
+        name : &str,
+        public_key : &str,
+        comment : &str,
+        now : i64
+    ) -> Result<i64> {
+        conn.prepare_cached("
+            INSERT INTO ssh_keys (name, public_key, comment, created)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (name, public_key) DO UPDATE SET comment = excluded.comment
+            RETURNING id
+            ")?
+            .query_row(rusqlite::params![name, public_key, comment, now], |row| row.get(0))
+            .map_err(|err| -> Error {
+                if error_code_match(
+                    &err,
+                    ffi::ErrorCode::ConstraintViolation,
+                    787
+                ) {
+                    StorageError::UserNotFound(name.to_string()).into()
+                } else {
+                    err.into()
+                }
+            })
+    }}
+
+    db_method!{ read get_ssh_key(&self, conn, id : i64) -> Result<Option<models::SshKey>> {
+        let mut stmt = conn.prepare_cached("SELECT * FROM ssh_keys WHERE id = ?")?;
+
+        let mut rows = stmt.query(rusqlite::params![id])?;
+
+        rows.next()?.map(row_parse).transpose()
+    }}
+
+    db_method!{ read find_ssh_key(&self, conn, name : &str, public_key : &str) -> Result<Option<models::SshKey>> {
+        let mut stmt = conn.prepare_cached("SELECT * FROM ssh_keys WHERE name = ? AND public_key = ?")?;
+
+        let mut rows = stmt.query(rusqlite::params![name, public_key])?;
+
+        rows.next()?.map(row_parse).transpose()
+    }}
+
+    db_method!{ read list_ssh_keys(&self, conn, name : &str) -> Result<Vec<models::SshKey>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM ssh_keys
+            WHERE name = ?
+            ORDER BY id
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![name])?;
+
+        let mut keys = Vec::new();
+        while let Some(row) = rows.next()? {
+            keys.push(row_parse(row)?);
+        }
+
+        Ok(keys)
+    }}
+
+    db_method!{ touch_ssh_key(&self, conn, id : i64, now : i64) -> Result<()> {
+        conn.prepare_cached("UPDATE ssh_keys SET last_used = ? WHERE id = ?")?
+            .execute(rusqlite::params![now, id])?;
+
+        Ok(())
+    }}
+
+    db_method!{ delete_ssh_key(&self, conn, name : &str, id : i64) -> Result<()> {
+        let n = conn.prepare_cached("DELETE FROM ssh_keys WHERE name = ? AND id = ?")?
+            .execute(rusqlite::params![name, id])?;
+
+        if n == 0 {
+            return Err(StorageError::SshKeyNotFound(id).into())
+        }
+
+        Ok(())
+    }}
+
+    db_method!{ insert_ssh_challenge(
+        &self,
+        conn,
+        challenge_hash : &str,
+        challenge : &models::SshChallenge
+    ) -> Result<()> {
+        conn.prepare_cached("
+            INSERT INTO ssh_challenges (challenge_hash, key_id, aud, expires)
+            VALUES (?, ?, ?, ?)
+            ")?
+            .execute(rusqlite::params![
+                challenge_hash,
+                challenge.key_id,
+                challenge.aud,
+                challenge.expires,
+            ])?;
+
+        Ok(())
+    }}
+
+    // removes the challenge so it can't be answered again, expired
+    // challenges are cleaned up along the way
+    db_method!{ take_ssh_challenge(
+        &self,
+        conn,
+        challenge_hash : &str,
+        now : i64
+    ) -> Result<Option<models::SshChallenge>> {
+        let tx = conn.unchecked_transaction()?;
+
+        let challenge = tx.prepare_cached("
+            DELETE FROM ssh_challenges
+            WHERE challenge_hash = ?
+            RETURNING *
+            ")?
+            .query(rusqlite::params![challenge_hash])?
+            .next()?
+            .map(row_parse)
+            .transpose()?;
+
+        tx.prepare_cached("DELETE FROM ssh_challenges WHERE expires < ?")?
+            .execute(rusqlite::params![now])?;
+
+        tx.commit()?;
+
+        Ok(challenge)
+    }}
+
+    db_method!{ add_stats(&self, conn, counts : &[(StatKey, i64)]) -> Result<()> {
+        let tx = conn.unchecked_transaction()?;
+
+        {
+            let mut stmt = tx.prepare_cached("
+                INSERT INTO stats (hour, counter, label, value)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT (hour, counter, label)
+                DO UPDATE SET value = value + excluded.value
+                ")?;
+
+            for (key, n) in counts {
+                stmt.execute(rusqlite::params![key.hour, key.counter, key.label, n])?;
+            }
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }}
+
+    // sums each (counter, label) pair over the hours starting at `hour`
+    db_method!{ read get_stats_since(&self, conn, hour : i64) -> Result<Vec<(String, String, i64)>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT counter, label, SUM(value) FROM stats
+            WHERE hour >= ?
+            GROUP BY counter, label
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![hour])?;
+
+        let mut stats = Vec::new();
+        while let Some(row) = rows.next()? {
+            stats.push((row.get(0)?, row.get(1)?, row.get(2)?));
+        }
+
+        Ok(stats)
+    }}
+
+    db_method!{ read count_users(&self, conn) -> Result<i64> {
+        Ok(conn.prepare_cached("SELECT COUNT(*) FROM users")?
+            .query_row(rusqlite::params![], |row| row.get(0))?)
+    }}
+
+    db_method!{ read list_users(&self, conn) -> Result<Vec<models::User>> {
+        let mut stmt = conn.prepare_cached("SELECT * FROM users ORDER BY name")?;
+
+        let mut rows = stmt.query(rusqlite::params![])?;
+
+        let mut users = Vec::new();
+        while let Some(row) = rows.next()? {
+            users.push(row_parse(row)?);
+        }
+
+        Ok(users)
+    }}
+
+    // filtered by `prefix` of the name and `role`, after the name of the
+    // cursor
+    db_method!{ read list_users_page(&self, conn, page : &Page<String>) -> Result<Vec<models::User>> {
+        let cmp = match page.sort {
+            Sort::Asc => ">",
+            Sort::Desc => "<",
+        };
+        let mut stmt = conn.prepare_cached(&format!("
+            SELECT * FROM users
+            WHERE (?1 IS NULL OR name {} ?1)
+            AND (?2 IS NULL OR substr(name, 1, length(?2)) = ?2)
+            AND (?3 IS NULL OR instr(' ' || roles || ' ', ' ' || ?3 || ' ') > 0)
+            ORDER BY name {}
+            LIMIT ?4
+            ", cmp, page.sort.sql()))?;
+
+        let mut rows = stmt.query(rusqlite::params![
+            page.cursor,
+            page.filter("prefix"),
+            page.filter("role"),
+            page.sql_limit(),
+        ])?;
+
+        let mut users = Vec::new();
+        while let Some(row) = rows.next()? {
+            users.push(row_parse(row)?);
+        }
+
+        Ok(users)
+    }}
+
+    // the newest `limit` entries with an id below `before`, newest first
+    db_method!{ read list_audit(&self, conn, before : Option<i64>, limit : u32) -> Result<Vec<models::AuditEntry>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM audit
+            WHERE id < ?
+            ORDER BY id DESC
+            LIMIT ?
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![before.unwrap_or(i64::MAX), limit])?;
+
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            entries.push(row_parse(row)?);
+        }
+
+        Ok(entries)
+    }}
+
+    // filtered by `actor`, `action` and `subject`, after the id of the
+    // cursor
+    db_method!{ read list_audit_page(&self, conn, page : &Page<i64>) -> Result<Vec<models::AuditEntry>> {
+        let cmp = match page.sort {
+            Sort::Asc => ">",
+            Sort::Desc => "<",
+        };
+        let mut stmt = conn.prepare_cached(&format!("
+            SELECT * FROM audit
+            WHERE (?1 IS NULL OR id {} ?1)
+            AND (?2 IS NULL OR actor = ?2)
+            AND (?3 IS NULL OR action = ?3)
+            AND (?4 IS NULL OR subject = ?4)
+            ORDER BY id {}
+            LIMIT ?5
+            ", cmp, page.sort.sql()))?;
+
+        let mut rows = stmt.query(rusqlite::params![
+            page.cursor,
+            page.filter("actor"),
+            page.filter("action"),
+            page.filter("subject"),
+            page.sql_limit(),
+        ])?;
+
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            entries.push(row_parse(row)?);
+        }
+
+        Ok(entries)
+    }}
+
+    db_method!{ insert_authorization_code(
+        &self,
+        conn,
+        code_hash : &str,
+        code : &models::AuthorizationCode
+    ) -> Result<()> {
+        conn.prepare_cached("
+            INSERT INTO authorization_codes (code_hash, client_id, name, redirect_uri, code_challenge, expires)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ")?
+            .execute(rusqlite::params![
+                code_hash,
+                code.client_id,
+                code.name,
+                code.redirect_uri,
+                code.code_challenge,
+                code.expires,
+            ])?;
+
+        Ok(())
+    }}
+
+    // removes the code so it can't be used again, expired codes are
+    // cleaned up along the way
+    db_method!{ take_authorization_code(
+        &self,
+        conn,
+        code_hash : &str,
+        now : i64
+    ) -> Result<Option<models::AuthorizationCode>> {
+        let tx = conn.unchecked_transaction()?;
+
+        let code = tx.prepare_cached("
+            DELETE FROM authorization_codes
+            WHERE code_hash = ?
+            RETURNING *
+            ")?
+            .query(rusqlite::params![code_hash])?
+            .next()?
+            .map(row_parse)
+            .transpose()?;
+
+        tx.prepare_cached("DELETE FROM authorization_codes WHERE expires < ?")?
+            .execute(rusqlite::params![now])?;
+
+        tx.commit()?;
+
+        Ok(code)
+    }}
+
+    // remembers a saml request until it expires, false if it was already,
+    // expired requests are cleaned up along the way
+    db_method!{ use_saml_request(
+        &self,
+        conn,
+        issuer : &str,
+        id : &str,
+        expires : i64,
+        now : i64
+    ) -> Result<bool> {
+        let tx = conn.unchecked_transaction()?;
+
+        tx.prepare_cached("DELETE FROM saml_requests WHERE expires < ?")?
+            .execute(rusqlite::params![now])?;
+
+        let inserted = tx.prepare_cached("
+            INSERT INTO saml_requests (issuer, id, expires) VALUES (?, ?, ?)
+            ON CONFLICT (issuer, id) DO NOTHING
+            ")?
+            .execute(rusqlite::params![issuer, id, expires])?;
+
+        tx.commit()?;
+
+        Ok(inserted == 1)
+    }}
+
+    db_method!{ insert_device_authorization(
+        &self,
+        conn,
+        device_code_hash : &str,
+        user_code : &str,
+        client_id : &str,
+        expires : i64
+    ) -> Result<()> {
+        conn.prepare_cached("
+            INSERT INTO device_authorizations (device_code_hash, user_code, client_id, expires)
+            VALUES (?, ?, ?, ?)
+            ")?
+            .execute(rusqlite::params![device_code_hash, user_code, client_id, expires])?;
+
+        Ok(())
+    }}
+
+    // a pending authorization the user can still approve
+    db_method!{ read get_device_authorization(
+        &self,
+        conn,
+        user_code : &str,
+        now : i64
+    ) -> Result<Option<models::DeviceAuthorization>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM device_authorizations
+            WHERE user_code = ? AND name IS NULL AND expires >= ?
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![user_code, now])?;
+
+        rows.next()?.map(row_parse).transpose()
+    }}
+
+    // returns whether the authorization was still pending
+    db_method!{ approve_device_authorization(
+        &self,
+        conn,
+        user_code : &str,
+        name : &str,
+        now : i64
+    ) -> Result<bool> {
+        let n = conn.prepare_cached("
+            UPDATE device_authorizations
+            SET name = ?
+            WHERE user_code = ? AND name IS NULL AND expires >= ?
+            ")?
+            .execute(rusqlite::params![name, user_code, now])?;
+
+        Ok(n == 1)
+    }}
+
+    // records the poll and returns the authorization as it was before it.
+    // Approved authorizations are removed so their device code is only
+    // exchanged once, expired ones are cleaned up along the way.
+    db_method!{ poll_device_authorization(
+        &self,
+        conn,
+        device_code_hash : &str,
+        now : i64
+    ) -> Result<Option<models::DeviceAuthorization>> {
+        let tx = conn.unchecked_transaction()?;
+
+        let auth : Option<models::DeviceAuthorization> = tx.prepare_cached("
+            SELECT * FROM device_authorizations
+            WHERE device_code_hash = ?
+            ")?
+            .query(rusqlite::params![device_code_hash])?
+            .next()?
+            .map(row_parse)
+            .transpose()?;
+
+        match &auth {
+            Some(auth) if auth.name.is_some() => {
+                tx.prepare_cached("DELETE FROM device_authorizations WHERE device_code_hash = ?")?
+                    .execute(rusqlite::params![device_code_hash])?;
+            },
+            Some(_) => {
+                tx.prepare_cached("UPDATE device_authorizations SET last_poll = ? WHERE device_code_hash = ?")?
+                    .execute(rusqlite::params![now, device_code_hash])?;
+            },
+            None => {},
+        }
+
+        tx.prepare_cached("DELETE FROM device_authorizations WHERE expires < ?")?
+            .execute(rusqlite::params![now])?;
+
+        tx.commit()?;
+
+        Ok(auth)
+    }}
+
+    // names of the migrations applied to the database
+    db_method!{ read applied_migrations(&self, conn) -> Result<Vec<String>> {
+        let mut stmt = conn.prepare_cached("SELECT name FROM migrations")?;
+
+        let mut rows = stmt.query(rusqlite::params![])?;
+
+        let mut names = Vec::new();
+        while let Some(row) = rows.next()? {
+            names.push(row.get(0)?);
+        }
+
+        Ok(names)
+    }}
+
+    // runs the migrations the database is missing, in order, and returns
+    // their names. A new database gets all of them.
+    db_method!{ migrate(&self, conn) -> Result<Vec<String>> {
+        let initialized : i64 = conn.prepare_cached("
+            SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'migrations'
+        ")?.query_row(rusqlite::params![], |row| row.get(0))?;
+
+        let mut applied = Vec::<String>::new();
+        if initialized > 0 {
+            let mut stmt = conn.prepare_cached("SELECT name FROM migrations")?;
+            let mut rows = stmt.query(rusqlite::params![])?;
+            while let Some(row) = rows.next()? {
+                applied.push(row.get(0)?);
+            }
+        }
+
+        let mut ran = Vec::new();
+        for (name, migration) in MIGRATIONS {
+            if !applied.iter().any(|a| a == name) {
+                conn.execute_batch(migration)?;
+                ran.push(name.to_string());
+            }
+        }
+
+        Ok(ran)
+    }}
+
+    // size of the database file in bytes
+    db_method!{ read size(&self, conn) -> Result<i64> {
+        Ok(conn.prepare_cached("
+            SELECT page_count * page_size
+            FROM pragma_page_count(), pragma_page_size()
+            ")?
+            .query_row(rusqlite::params![], |row| row.get(0))?)
+    }}
+
+    // accepting a version again keeps the time it was first accepted
+    db_method!{ insert_acknowledgment(
+        &self,
+        conn,
+        name : &str,
+        document : &str,
+        version : u32,
+        time : i64
+    ) -> Result<()> {
+        conn.prepare_cached("
+            INSERT OR IGNORE INTO acknowledgments (name, document, version, time)
+            VALUES (?, ?, ?, ?)
+            ")?
+            .execute(rusqlite::params![name, document, version, time])?;
+
+        Ok(())
+    }}
+
+    // the newest version of each document the user accepted
+    db_method!{ read get_acknowledged_versions(&self, conn, name : &str) -> Result<HashMap<String, u32>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT document, max(version) FROM acknowledgments
+            WHERE name = ?
+            GROUP BY document
+            ")?;
+
+        let versions = stmt.query_map(rusqlite::params![name], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(versions)
+    }}
+
+    // fails with `OrgNotFound` if the invite's org doesn't exist
+    db_method!{ insert_invite(
+        &self,
+        conn,
+        token_hash : &str,
+        invite : &models::Invite
+    ) -> Result<i64> {
+        let tx = conn.unchecked_transaction()?;
+
+        if let Some(org) = &invite.org {
+            let exists = tx.prepare_cached("SELECT 1 FROM orgs WHERE name = ?")?
+                .exists(rusqlite::params![org])?;
+            if !exists {
+                return Err(StorageError::OrgNotFound(org.to_string()).into())
+            }
+        }
+
+        tx.prepare_cached("
+            INSERT INTO invites (token_hash, name, roles, org, groups, created_by, expires)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ")?
+            .execute(rusqlite::params![
+                token_hash,
+                invite.name,
+                invite.roles,
+                invite.org,
+                invite.groups,
+                invite.created_by,
+                invite.expires,
+            ])?;
+
+        let id = tx.last_insert_rowid();
+        tx.commit()?;
+
+        Ok(id)
+    }}
+
+    db_method!{ read list_invites(&self, conn, now : i64) -> Result<Vec<models::Invite>> {
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM invites
+            WHERE expires >= ?
+            ORDER BY id
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![now])?;
+
+        let mut invites = Vec::new();
+        while let Some(row) = rows.next()? {
+            invites.push(row_parse(row)?);
+        }
+
+        Ok(invites)
+    }}
+
+    // returns whether the invite existed
+    db_method!{ delete_invite(&self, conn, id : i64) -> Result<bool> {
+        let n = conn.prepare_cached("DELETE FROM invites WHERE id = ?")?
+            .execute(rusqlite::params![id])?;
+
+        Ok(n > 0)
+    }}
+
+    // creates the user the invite is for, giving them its roles and org,
+    // and consumes the invite. `None` if there's no such invite, it expired,
+    // or it's for another name. Fails with `DuplicateName`, keeping the
+    // invite, if the name is taken.
+    db_method!{ accept_invite(
+        &self,
+        conn,
+        token_hash : &str,
+        name : &str,
+        pass_hash : &str,
+        now : i64
+    ) -> Result<Option<models::Invite>> {
+        let tx = conn.unchecked_transaction()?;
+
+        tx.prepare_cached("DELETE FROM invites WHERE expires < ?")?
+            .execute(rusqlite::params![now])?;
+
+        let invite : Option<models::Invite> = tx.prepare_cached("
+            DELETE FROM invites
+            WHERE token_hash = ? AND (name IS NULL OR name = ?)
+            RETURNING *
+            ")?
+            .query(rusqlite::params![token_hash, name])?
+            .next()?
+            .map(row_parse)
+            .transpose()?;
+
+        let invite = match invite {
+            Some(invite) => invite,
+            None => {
+                tx.commit()?;
+                return Ok(None)
+            },
+        };
+
+        let exists = tx.prepare_cached("SELECT 1 FROM users WHERE name = ?")?
+            .exists(rusqlite::params![name])?;
+        if exists {
+            return Err(StorageError::DuplicateName(name.to_string()).into())
+        }
+
+        tx.prepare_cached("INSERT INTO users (name, pass_hash, roles) VALUES (?, ?, ?)")?
+            .execute(rusqlite::params![name, pass_hash, invite.roles])?;
+
+        if let Some(org) = &invite.org {
+            tx.prepare_cached("
+                INSERT INTO org_members (org, name, groups)
+                VALUES (?, ?, ?)
+                ")?
+                .execute(rusqlite::params![org, name, invite.groups])?;
+        }
+
+        tx.commit()?;
+
+        Ok(Some(invite))
+    }}
+
+    db_method!{ insert_org(&self, conn, name : &str, created : i64) -> Result<()> {
+        conn.prepare_cached("INSERT INTO orgs (name, created) VALUES (?, ?)")?
+            .execute(rusqlite::params![name, created])
+            .map(|_| ())
+            .map_err(|err| {
+                if error_code_match(
+                    &err,
+                    ffi::ErrorCode::ConstraintViolation,
+                    1555
+                ) {
+                    StorageError::DuplicateName(name.to_string()).into()
+                } else {
+                    err.into()
+                }
+            })
+    }}
+
+    db_method!{ read list_orgs(&self, conn) -> Result<Vec<models::Org>> {
+        let mut stmt = conn.prepare_cached("SELECT * FROM orgs ORDER BY name")?;
+
+        let mut rows = stmt.query(rusqlite::params![])?;
+
+        let mut orgs = Vec::new();
+        while let Some(row) = rows.next()? {
+            orgs.push(row_parse(row)?);
+        }
+
+        Ok(orgs)
+    }}
+
+    // fails with `OrgNotFound` if the org doesn't exist, `None` means the
+    // user isn't a member
+    db_method!{ read get_org_member(&self, conn, org : &str, name : &str) -> Result<Option<models::OrgMember>> {
+        let exists = conn.prepare_cached("SELECT 1 FROM orgs WHERE name = ?")?
+            .exists(rusqlite::params![org])?;
+        if !exists {
+            return Err(StorageError::OrgNotFound(org.to_string()).into())
+        }
+
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM org_members
+            WHERE org = ? AND name = ?
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![org, name])?;
+
+        rows.next()?.map(row_parse).transpose()
+    }}
+
+    db_method!{ read list_org_members(&self, conn, org : &str) -> Result<Vec<models::OrgMember>> {
+        let exists = conn.prepare_cached("SELECT 1 FROM orgs WHERE name = ?")?
+            .exists(rusqlite::params![org])?;
+        if !exists {
+            return Err(StorageError::OrgNotFound(org.to_string()).into())
+        }
+
+        let mut stmt = conn.prepare_cached("
+            SELECT * FROM org_members
+            WHERE org = ?
+            ORDER BY name
+            ")?;
+
+        let mut rows = stmt.query(rusqlite::params![org])?;
+
+        let mut members = Vec::new();
+        while let Some(row) = rows.next()? {
+            members.push(row_parse(row)?);
+        }
+
+        Ok(members)
+    }}
+
+    // adds the user to the org or replaces their membership, returns
+    // whether they were already a member
+    db_method!{ set_org_member(
+        &self,
+        conn,
+        org : &str,
+        name : &str,
+        admin : bool,
+        groups : &str
+    ) -> Result<bool> {
+        let tx = conn.unchecked_transaction()?;
+
+        let exists = tx.prepare_cached("SELECT 1 FROM orgs WHERE name = ?")?
+            .exists(rusqlite::params![org])?;
+        if !exists {
+            return Err(StorageError::OrgNotFound(org.to_string()).into())
+        }
+
+        let exists = tx.prepare_cached("SELECT 1 FROM users WHERE name = ?")?
+            .exists(rusqlite::params![name])?;
+        if !exists {
+            return Err(StorageError::UserNotFound(name.to_string()).into())
+        }
+
+        let replaced = tx.prepare_cached("
+            DELETE FROM org_members
+            WHERE org = ? AND name = ?
+            ")?
+            .execute(rusqlite::params![org, name])? > 0;
+
+        tx.prepare_cached("
+            INSERT INTO org_members (org, name, admin, groups)
+            VALUES (?, ?, ?, ?)
+            ")?
+            .execute(rusqlite::params![org, name, admin, groups])?;
+
+        // tokens scoped to the org hold the old membership
+        if replaced {
+            increment_org_version(&tx, org, name)?;
+        }
+
+        tx.commit()?;
+
+        Ok(replaced)
+    }}
+
+    // returns whether the user was a member, whose tokens scoped to the org
+    // are then invalidated
+    db_method!{ delete_org_member(&self, conn, org : &str, name : &str) -> Result<bool> {
+        let tx = conn.unchecked_transaction()?;
+
+        let n = tx.prepare_cached("
+            DELETE FROM org_members
+            WHERE org = ? AND name = ?
+            ")?
+            .execute(rusqlite::params![org, name])?;
+
+        if n > 0 {
+            increment_org_version(&tx, org, name)?;
+        }
+
+        tx.commit()?;
+
+        Ok(n > 0)
+    }}
+
+    // the version of the user's tokens scoped to the org
+    db_method!{ read get_org_version(&self, conn, org : &str, name : &str) -> Result<u32> {
+        let version = conn.prepare_cached("
+            SELECT token_version FROM org_versions
+            WHERE org = ? AND name = ?
+            ")?
+            .query_row(rusqlite::params![org, name], |row| row.get(0))
+            .optional()?;
+
+        Ok(version.unwrap_or(0))
+    }}
+
     db_method!{ insert_user(&self, conn, name : &str, pass_hash : &str) -> Result<()> {
         conn.prepare_cached("INSERT INTO users (name, pass_hash) VALUES (?, ?)")?
             .execute(rusqlite::params![name, pass_hash])
@@ -80,7 +1477,7 @@ impl Database {
                     ffi::ErrorCode::ConstraintViolation,
                     2067
                 ) {
-                    Error::DuplicateName(name.to_string())
+                    StorageError::DuplicateName(name.to_string()).into()
                 } else {
                     err.into()
                 }
@@ -88,6 +1485,36 @@ impl Database {
     }}
 }
 
+fn is_new_login_country(conn : &Connection, name : &str, country : &str) -> Result<bool> {
+    let (any, this) : (bool, bool) = conn.prepare_cached("
+        SELECT count(*) > 0, coalesce(max(country = ?2), 0)
+        FROM login_countries WHERE name = ?1
+        ")?
+        .query_row(rusqlite::params![name, country], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+    Ok(any && !this)
+}
+
+/// keeps the `history - 1` most recent previous passwords for every user,
+/// the current password is stored in the users table
+fn prune_password_history(conn : &Connection, history : usize) -> Result<()> {
+    conn.prepare_cached("
+        DELETE FROM password_history WHERE rowid IN (
+            SELECT rowid FROM (
+                SELECT rowid, row_number() OVER (
+                    PARTITION BY name
+                    ORDER BY created DESC, rowid DESC
+                ) AS n
+                FROM password_history
+            )
+            WHERE n > ?
+        )
+        ")?
+        .execute(rusqlite::params![history.saturating_sub(1) as i64])?;
+
+    Ok(())
+}
+
 struct Row<'a> {
     off :   usize,
     inner : &'a rusqlite::Row<'a>,
@@ -192,6 +1619,54 @@ macro_rules! impl_from_row {
 }
 
 impl_from_row! {users, models::User {
-    name, pass_hash, token_version
+    name, pass_hash, token_version, notify_logins, roles
+}}
+
+impl_from_row! {clients, models::Client {
+    name, aud, user_agent, first_seen, last_seen
+}}
+
+impl_from_row! {break_glass, models::BreakGlass {
+    name, armed, used
+}}
+
+impl_from_row! {login_countries, models::LoginCountry {
+    name, country, first_seen, last_seen
+}}
+
+impl_from_row! {devices, models::Device {
+    id, name, aud, token_version, created, last_used
+}}
+
+impl_from_row! {ssh_keys, models::SshKey {
+    id, name, public_key, comment, created, last_used
+}}
+
+impl_from_row! {ssh_challenges, models::SshChallenge {
+    key_id, aud, expires
+}}
+
+impl_from_row! {authorization_codes, models::AuthorizationCode {
+    client_id, name, redirect_uri, code_challenge, expires
+}}
+
+impl_from_row! {device_authorizations, models::DeviceAuthorization {
+    user_code, client_id, name, expires, last_poll
+}}
+
+impl_from_row! {audit, models::AuditEntry {
+    id, time, actor, action, subject, detail
+}}
+
+impl_from_row! {invites, models::Invite {
+    id, name, roles, org, groups, created_by, expires
+}}
+
+impl_from_row! {orgs, models::Org {
+    name, created
+}}
+
+impl_from_row! {org_members, models::OrgMember {
+    org, name, admin, groups
 }}
 
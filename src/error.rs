@@ -0,0 +1,324 @@
+//! Errors of the server, grouped by the subsystem they come from. Every
+//! error has a stable code, `HTTP_ERRORS` maps the codes clients may see to
+//! a status and message, any other code is an internal error.
+
+use quick_from::QuickFrom;
+use http::StatusCode;
+use http_mux::mux;
+use jsonwebtoken as jwt;
+
+use crate::crypto;
+use crate::leaks;
+use crate::strength;
+
+#[derive(QuickFrom,Debug)]
+pub enum Error {
+    #[quick_from]
+    Auth(AuthError),
+
+    #[quick_from]
+    Storage(StorageError),
+
+    #[quick_from]
+    Config(ConfigError),
+
+    #[quick_from]
+    Transport(TransportError),
+
+    /// a handler panicked, holds the panic message
+    Panic(String),
+}
+
+/// credentials, tokens and permissions
+#[derive(QuickFrom,Debug)]
+pub enum AuthError {
+    LoginFailed,
+    /// a login hook vetoed the login, holds the reason
+    LoginDenied(String),
+    SessionExpired,
+    Unauthorized,
+    Forbidden,
+    /// too many failed logins, see `ratelimit::LoginLimiter`
+    TooManyAttempts,
+    TokenDurationTooBig,
+    /// the login needs a kerberos ticket, see `negotiate`
+    NegotiateRequired,
+    /// a new password scored below `strength::Config::min_score`
+    WeakPassword(strength::Estimate),
+    /// a new password matches one of the last
+    /// `server::Config::password_history`
+    PasswordReused,
+
+    #[quick_from]
+    Token(crypto::TokenError),
+
+    #[quick_from]
+    Jwt(jwt::errors::Error),
+
+    #[quick_from]
+    Argon2(argon2::Error),
+}
+
+/// the database and other files on disk
+#[derive(QuickFrom,Debug)]
+pub enum StorageError {
+    DuplicateName(String),
+    UserNotFound(String),
+    DeviceNotFound(i64),
+    SshKeyNotFound(i64),
+    OrgNotFound(String),
+    /// no invite matches, or it expired or was used
+    InviteNotFound,
+    /// the tables and indexes the database lacks, see
+    /// `database::check_schema`
+    SchemaOutdated(Vec<String>),
+
+    #[quick_from]
+    Rusqlite(rusqlite::Error),
+
+    #[quick_from]
+    Io(std::io::Error),
+}
+
+/// invalid server configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    AlgorithmNotAllowed(jwt::Algorithm),
+    MustUseHttps,
+    /// `external_url` isn't a url tokens can be issued by, see
+    /// `crypto::issuer_url`
+    InvalidExternalUrl,
+    /// the cert file holds no pem certificate
+    InvalidCertificate,
+    /// the leaf certificate in the cert file isn't for `pub_key_file`
+    CertificateKeyMismatch,
+    /// the private key couldn't be loaded for signing saml assertions
+    InvalidKey,
+    /// the gssapi library for `negotiate` couldn't be loaded or set up
+    Gssapi(String),
+    /// libpam couldn't be loaded, see `pam`
+    Pam(String),
+    /// a group of a `peer::Config` isn't in `/etc/group`
+    UnknownGroup(String),
+    /// a claim of `Config::claims` can't be renamed or omitted, or is
+    /// renamed to a name already taken, see `crypto::ClaimsMap`
+    InvalidClaimsMap(String),
+    /// a token signed with `priv_key_file` didn't validate with
+    /// `pub_key_file`, the keys aren't a pair or don't fit `alg`
+    KeyMismatch,
+    /// `server_path` has a `%` specifier other than `%t` or `%%`, see
+    /// `config::expand_path`
+    InvalidServerPath(String),
+    /// keys for the algorithm can't be generated, see
+    /// `crypto::generate_key_pair`
+    KeyGeneration(jwt::Algorithm),
+    /// an audit sink couldn't be set up, see `audit::SinkConfig`
+    InvalidAuditSink(String),
+    /// the maxmind database couldn't be read, see `geoip`
+    GeoIp(String),
+}
+
+/// routing, http and request or response bodies
+#[derive(QuickFrom,Debug)]
+pub enum TransportError {
+    /// the request body or query could not be parsed
+    BadRequest,
+    /// the client sent the request body too slowly, see `server::Timeouts`
+    RequestTimeout,
+    /// a caller made too many requests, see `ratelimit::DiscoveryLimiter`
+    /// and `middleware::LayerConfig::RateLimit`
+    TooManyRequests,
+    /// the handler took longer than `middleware::LayerConfig::Timeout`
+    HandlerTimeout,
+    /// the server is in maintenance mode and refuses changes, with the
+    /// message of the admin who turned it on
+    Maintenance(String),
+    /// the path exists, but not for the method, `allow` lists the methods
+    /// it does have
+    MethodNotAllowed{
+        method : http::Method,
+        path : String,
+        allow : Vec<http::Method>,
+    },
+    /// an audit sink refused or failed to take an entry, see `audit`
+    AuditSink(String),
+    /// a response held a secret, see `leaks`
+    SecretLeak(leaks::Leak),
+    /// the redis server failed or replied with an error, see `redis`
+    Redis(String),
+
+    #[quick_from]
+    Mux(mux::MuxError),
+
+    #[quick_from]
+    SerdeJson(serde_json::Error),
+
+    #[quick_from]
+    Hyper(hyper::Error),
+
+    #[quick_from]
+    InvalidUri(http::uri::InvalidUri),
+}
+
+/// lets `?` lift errors from dependencies straight into `Error`
+macro_rules! from_leaf {
+    ($($leaf:ty => $domain:ident),* $(,)?) => {
+        $(
+            impl From<$leaf> for Error {
+                fn from(err : $leaf) -> Self {
+                    Error::$domain(err.into())
+                }
+            }
+        )*
+    }
+}
+
+from_leaf!(
+    crypto::TokenError => Auth,
+    jwt::errors::Error => Auth,
+    argon2::Error => Auth,
+    rusqlite::Error => Storage,
+    std::io::Error => Storage,
+    mux::MuxError => Transport,
+    serde_json::Error => Transport,
+    hyper::Error => Transport,
+    http::uri::InvalidUri => Transport,
+);
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Auth(err) => err.code(),
+            Error::Storage(err) => err.code(),
+            Error::Config(err) => err.code(),
+            Error::Transport(err) => err.code(),
+            Error::Panic(_) => "panic",
+        }
+    }
+}
+
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        use AuthError::*;
+
+        match self {
+            LoginFailed => "auth.login_failed",
+            LoginDenied(_) => "auth.login_denied",
+            SessionExpired => "auth.session_expired",
+            Unauthorized => "auth.unauthorized",
+            Forbidden => "auth.forbidden",
+            TooManyAttempts => "auth.too_many_attempts",
+            TokenDurationTooBig => "auth.token_duration_too_big",
+            NegotiateRequired => "auth.negotiate_required",
+            WeakPassword(_) => "auth.weak_password",
+            PasswordReused => "auth.password_reused",
+            Token(_) => "auth.token",
+            Jwt(_) => "auth.jwt",
+            Argon2(_) => "auth.argon2",
+        }
+    }
+}
+
+impl StorageError {
+    pub fn code(&self) -> &'static str {
+        use StorageError::*;
+
+        match self {
+            DuplicateName(_) => "storage.duplicate_name",
+            UserNotFound(_) => "storage.user_not_found",
+            DeviceNotFound(_) => "storage.device_not_found",
+            SshKeyNotFound(_) => "storage.ssh_key_not_found",
+            OrgNotFound(_) => "storage.org_not_found",
+            InviteNotFound => "storage.invite_not_found",
+            SchemaOutdated(_) => "storage.schema_outdated",
+            Rusqlite(_) => "storage.sqlite",
+            Io(_) => "storage.io",
+        }
+    }
+}
+
+impl ConfigError {
+    pub fn code(&self) -> &'static str {
+        use ConfigError::*;
+
+        match self {
+            AlgorithmNotAllowed(_) => "config.algorithm_not_allowed",
+            MustUseHttps => "config.must_use_https",
+            InvalidExternalUrl => "config.invalid_external_url",
+            InvalidCertificate => "config.invalid_certificate",
+            CertificateKeyMismatch => "config.certificate_key_mismatch",
+            InvalidKey => "config.invalid_key",
+            Gssapi(_) => "config.gssapi",
+            Pam(_) => "config.pam",
+            UnknownGroup(_) => "config.unknown_group",
+            InvalidClaimsMap(_) => "config.invalid_claims_map",
+            KeyMismatch => "config.key_mismatch",
+            InvalidServerPath(_) => "config.invalid_server_path",
+            KeyGeneration(_) => "config.key_generation",
+            InvalidAuditSink(_) => "config.invalid_audit_sink",
+            GeoIp(_) => "config.geoip",
+        }
+    }
+}
+
+impl TransportError {
+    pub fn code(&self) -> &'static str {
+        use TransportError::*;
+
+        match self {
+            BadRequest => "transport.bad_request",
+            RequestTimeout => "transport.request_timeout",
+            TooManyRequests => "transport.too_many_requests",
+            HandlerTimeout => "transport.handler_timeout",
+            Maintenance(_) => "transport.maintenance",
+            MethodNotAllowed{ .. } => "transport.method_not_allowed",
+            AuditSink(_) => "transport.audit_sink",
+            SecretLeak(_) => "transport.secret_leak",
+            Redis(_) => "transport.redis",
+            Mux(mux::MuxError::NotFound(_)) => "transport.route_not_found",
+            Mux(mux::MuxError::MethodNotAllowed(_, _)) => "transport.method_not_allowed",
+            Mux(mux::MuxError::Parse(_, _)) => "transport.invalid_path",
+            SerdeJson(_) => "transport.json",
+            Hyper(_) => "transport.hyper",
+            InvalidUri(_) => "transport.invalid_uri",
+        }
+    }
+}
+
+/// code used in responses for errors missing from `HTTP_ERRORS`
+pub const INTERNAL_CODE : &str = "internal";
+
+/// the status and message rendered for each error code clients may see
+pub const HTTP_ERRORS : &[(&str, StatusCode, &str)] = &[
+    ("auth.login_failed", StatusCode::UNAUTHORIZED, "login failed"),
+    ("auth.login_denied", StatusCode::FORBIDDEN, "login denied"),
+    ("auth.session_expired", StatusCode::UNAUTHORIZED, "session expired"),
+    ("auth.unauthorized", StatusCode::UNAUTHORIZED, "unauthorized"),
+    ("auth.negotiate_required", StatusCode::UNAUTHORIZED, "unauthorized"),
+    ("auth.forbidden", StatusCode::FORBIDDEN, "forbidden"),
+    ("auth.too_many_attempts", StatusCode::TOO_MANY_REQUESTS, "too many attempts"),
+    ("auth.weak_password", StatusCode::BAD_REQUEST, "password too weak"),
+    ("auth.password_reused", StatusCode::BAD_REQUEST, "password used before"),
+    ("storage.duplicate_name", StatusCode::CONFLICT, "name taken"),
+    ("storage.user_not_found", StatusCode::NOT_FOUND, "user not found"),
+    ("storage.device_not_found", StatusCode::NOT_FOUND, "device not found"),
+    ("storage.ssh_key_not_found", StatusCode::NOT_FOUND, "ssh key not found"),
+    ("storage.org_not_found", StatusCode::NOT_FOUND, "organization not found"),
+    ("storage.invite_not_found", StatusCode::NOT_FOUND, "invite not found"),
+    ("transport.bad_request", StatusCode::BAD_REQUEST, "bad request"),
+    ("transport.request_timeout", StatusCode::REQUEST_TIMEOUT, "request timeout"),
+    ("transport.too_many_requests", StatusCode::TOO_MANY_REQUESTS, "too many requests"),
+    ("transport.handler_timeout", StatusCode::SERVICE_UNAVAILABLE, "request took too long"),
+    ("transport.maintenance", StatusCode::SERVICE_UNAVAILABLE, "down for maintenance"),
+    ("transport.route_not_found", StatusCode::NOT_FOUND, "route not found"),
+    ("transport.method_not_allowed", StatusCode::METHOD_NOT_ALLOWED, "method not defined for route"),
+    ("transport.invalid_path", StatusCode::BAD_REQUEST, "invalid path values"),
+];
+
+/// looks up the code, status and message a client sees for `code`
+pub fn http_error(code : &str) -> (&'static str, StatusCode, &'static str) {
+    HTTP_ERRORS.iter()
+        .find(|(c, _, _)| *c == code)
+        .copied()
+        .unwrap_or((INTERNAL_CODE, StatusCode::INTERNAL_SERVER_ERROR, "internal server error"))
+}
@@ -0,0 +1,102 @@
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+
+use crate::server::{Error, HookFuture};
+#[cfg(feature = "redis")]
+use crate::redis;
+
+/// how many events a slow `LocalBus` subscriber may fall behind before it
+/// starts missing them
+const LOCAL_CAPACITY : usize = 1024;
+
+/// Changes other server instances and token caches need to know about
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq,Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum Event {
+    /// tokens of `name` were invalidated, for every audience if `aud` is
+    /// `None`
+    TokensInvalidated {
+        name : String,
+        aud : Option<String>,
+    },
+    /// tokens of `name` scoped to `org` were invalidated
+    OrgTokensInvalidated {
+        name : String,
+        org : String,
+    },
+}
+
+/// Delivers events to every subscriber, possibly in other processes, e.g.
+/// through redis with `RedisBus`
+pub trait EventBus : Send + Sync {
+    fn publish(&self, event : Event) -> HookFuture<Result<(), Error>>;
+}
+
+impl<B> EventBus for std::sync::Arc<B>
+where
+    B : EventBus,
+{
+    fn publish(&self, event : Event) -> HookFuture<Result<(), Error>> {
+        B::publish(self, event)
+    }
+}
+
+/// A bus within the process, e.g. for caches embedded in the same binary
+pub struct LocalBus {
+    sender : broadcast::Sender<Event>,
+}
+
+impl Default for LocalBus {
+    fn default() -> Self {
+        Self{
+            sender : broadcast::channel(LOCAL_CAPACITY).0,
+        }
+    }
+}
+
+impl LocalBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl EventBus for LocalBus {
+    fn publish(&self, event : Event) -> HookFuture<Result<(), Error>> {
+        // sending only fails without subscribers, which isn't an error
+        let _ = self.sender.send(event);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A bus publishing the json of events to a redis channel, for the other
+/// servers and token caches subscribed to it
+#[cfg(feature = "redis")]
+pub struct RedisBus {
+    client : redis::Client,
+    channel : String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisBus {
+    /// a bus publishing to `config.events_channel`, `None` if it's unset
+    pub fn from_config(config : &redis::Config) -> Option<Self> {
+        Some(Self{
+            channel : config.events_channel.clone()?,
+            client : redis::Client::new(config.clone()),
+        })
+    }
+}
+
+#[cfg(feature = "redis")]
+impl EventBus for RedisBus {
+    fn publish(&self, event : Event) -> HookFuture<Result<(), Error>> {
+        let client = self.client.clone();
+        let channel = self.channel.clone();
+
+        Box::pin(async move {
+            let event = serde_json::to_string(&event)?;
+            client.command(&["PUBLISH", &channel, &event]).await.map(|_| ())
+        })
+    }
+}
@@ -0,0 +1,46 @@
+//! Locating logins. The client's address, the address of the tcp
+//! connection or the one `server::Config::trusted_proxies` put in
+//! `X-Forwarded-For`, is looked up in a maxmind country or city database,
+//! e.g. GeoLite2. Logins over the unix socket have none. Only the country
+//! is kept, in the user's login history.
+//!
+//! A login from a country the user never logged in from before is
+//! notified, with `notify::Reason::NewCountry`, and has
+//! `LoginAttempt::new_country` set, so a `LoginHook` can ask for more than
+//! a password, e.g. veto it unless the caller is known otherwise.
+
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::server::{ConfigError, Error};
+
+#[derive(Deserialize,Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// the `.mmdb` file
+    pub database : PathBuf,
+}
+
+pub struct GeoIp {
+    reader : maxminddb::Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(config : &Config) -> Result<Self, Error> {
+        let reader = maxminddb::Reader::open_readfile(&config.database)
+            .map_err(|err| ConfigError::GeoIp(err.to_string()))?;
+
+        Ok(Self{ reader })
+    }
+
+    /// the iso code of the country `addr` is in, if the database knows
+    pub fn country(&self, addr : IpAddr) -> Option<String> {
+        let record = self.reader.lookup::<maxminddb::geoip2::Country>(addr).ok()?;
+
+        record.country
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+}
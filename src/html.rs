@@ -0,0 +1,83 @@
+//! Pages and forms of the browser based login flows
+
+use hyper::body::Buf;
+use rand::{rngs::OsRng, RngCore};
+
+use crate::server::{
+    AuthError,
+    TransportError,
+    Request,
+    Response,
+    Result,
+};
+
+const CSRF_COOKIE : &str = "authn_csrf";
+
+/// parses a `application/x-www-form-urlencoded` body
+pub async fn read_form<T>(req : Request) -> Result<T>
+where
+    T : serde::de::DeserializeOwned,
+{
+    let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+    serde_urlencoded::from_reader(reader)
+        .map_err(|_| TransportError::BadRequest.into())
+}
+
+/// an html page which is never cached, only inline scripts and styles
+/// are allowed
+pub fn page(status : http::StatusCode, html : String) -> Response {
+    http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .header("content-security-policy", "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'")
+        .body(html.into())
+        .unwrap()
+}
+
+/// a random token tying a form to the browser it was served to. It's set
+/// as a cookie by `with_csrf` and posted back in the form, a page of
+/// another site can post the form but can't read or set the cookie.
+pub fn csrf_token() -> String {
+    let mut token = [0u8;32];
+    OsRng.fill_bytes(&mut token);
+
+    base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+}
+
+/// sets `token` as the csrf cookie of `page`. The cookie has no path, so
+/// it's sent to the pages next to this one, wherever a proxy mounts them.
+pub fn with_csrf(mut page : Response, token : &str) -> Response {
+    let cookie = format!("{}={}; HttpOnly; Secure; SameSite=Strict", CSRF_COOKIE, token);
+    page.headers_mut().append(
+        http::header::SET_COOKIE,
+        http::HeaderValue::from_str(&cookie).expect("tokens and paths are valid header values"),
+    );
+
+    page
+}
+
+/// checks `token`, as posted in a form, is a csrf cookie of `headers`. The
+/// forms of other paths may have set cookies of their own.
+pub fn check_csrf(headers : &http::HeaderMap, token : &str) -> Result<()> {
+    let valid = headers.get_all(http::header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .filter(|(name, value)| *name == CSRF_COOKIE && !value.is_empty())
+        .any(|(_, value)| ring::constant_time::verify_slices_are_equal(value.as_bytes(), token.as_bytes()).is_ok());
+
+    if valid {
+        Ok(())
+    } else {
+        Err(AuthError::Forbidden.into())
+    }
+}
+
+pub fn escape(s : &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,186 @@
+//! Onboarding by invite: an admin creates an invite with
+//! `POST /admin/invites`, or `authn-utils create-invite`, and passes it on,
+//! e.g. as a link to a registration page. The invitee picks their name and
+//! password with `POST /register/accept`, getting the roles, organization
+//! and groups of the invite. Invites expire, and are consumed when
+//! accepted.
+//!
+//! Creating an invite takes `policy::Action::CreateInvites`, and it can
+//! only hand out roles its creator holds. An invite to an organization
+//! also takes being an admin of it, or `policy::Action::ManageOrgMembers`.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+use hyper::Body;
+use hyper::body::Buf;
+use plumb::PipeExt;
+use http_mux::{route,mux};
+
+use crate::api::{
+    PostAdminInviteRequest,
+    PostAdminInviteResponse,
+    PostRegisterAcceptRequest,
+};
+use crate::crypto;
+use crate::models;
+use crate::orgs;
+use crate::policy::Action;
+use crate::server::{
+    AuthError,
+    TransportError,
+    StorageError,
+    Server,
+    Router,
+    Request,
+    Response,
+    unix_now,
+};
+
+/// the lifetime of invites made with `authn-utils`, and the default limit
+pub const DEFAULT_MAX_DURATION : u64 = 7 * 24 * 60 * 60;
+
+fn default_max_duration() -> u64 {
+    DEFAULT_MAX_DURATION
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// the registration page, invites are appended as `invite=` to its
+    /// query
+    #[serde(default)]
+    pub url : Option<String>,
+    /// seconds, longer invites are shortened to it
+    #[serde(default = "default_max_duration")]
+    pub max_duration : u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self{
+            url : None,
+            max_duration : DEFAULT_MAX_DURATION,
+        }
+    }
+}
+
+impl Config {
+    /// the link to the registration page with `invite`, if there's a page
+    pub fn link(&self, invite : &str) -> Option<String> {
+        let url = self.url.as_ref()?;
+        let sep = if url.contains('?') { '&' } else { '?' };
+
+        Some(format!("{}{}invite={}", url, sep, invite))
+    }
+}
+
+pub(crate) fn routes(server : &Arc<Server>, m : Router) -> Router {
+    let m = post_admin_invites(Arc::clone(server), m.named("post_admin_invites"));
+    post_register_accept(Arc::clone(server), m.named("post_register_accept"))
+}
+
+/// roles and groups are stored space separated
+fn valid_names(names : &[String]) -> bool {
+    names.iter().all(|name| !name.is_empty() && !name.contains(char::is_whitespace))
+}
+
+fn post_admin_invites(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "admin" / "invites"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let admin = server.authenticate_admin(&req, Action::CreateInvites, None).await?;
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostAdminInviteRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            if req.name.as_deref() == Some("") || !valid_names(&req.roles) || !valid_names(&req.groups) {
+                return Err(TransportError::BadRequest.into())
+            }
+
+            // invites for a name are checked against it too
+            if let Some(name) = &req.name {
+                server.authorize(&admin, Action::CreateInvites, Some(name)).await?;
+            }
+
+            // no one hands out more than they have
+            if !req.roles.iter().all(|role| admin.has_role(role)) {
+                return Err(AuthError::Forbidden.into())
+            }
+            if let Some(org) = &req.org {
+                orgs::authorize_org_admin(&server, &admin, org, Action::ManageOrgMembers, req.name.as_deref()).await?;
+            }
+
+            let token = crypto::new_device_token();
+            let invite = models::Invite{
+                // assigned by the database
+                id : 0,
+                name : req.name,
+                roles : req.roles.join(" "),
+                org : req.org,
+                groups : req.groups.join(" "),
+                created_by : Some(admin.name.clone()),
+                expires : unix_now() + req.duration.min(server.invites.max_duration) as i64,
+            };
+            server.database.insert_invite(&crypto::hash_device_token(&token), &invite).await?;
+
+            server.audit(
+                Some(&admin.name),
+                "create-invite",
+                invite.name.as_deref(),
+                invite.org.as_deref(),
+            ).await?;
+
+            let s = serde_json::to_string(&PostAdminInviteResponse{
+                url : server.invites.link(&token),
+                invite : token,
+                expires : invite.expires,
+            })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn post_register_accept(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "register" / "accept"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostRegisterAcceptRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            if req.name.is_empty() {
+                return Err(TransportError::BadRequest.into())
+            }
+
+            server.password_strength.check(req.pass.expose(), &[&req.name])
+                .map_err(AuthError::WeakPassword)?;
+
+            let pass_hash = server.hash_password(&req.pass)?;
+            let invite = server.database.accept_invite(
+                &crypto::hash_device_token(&req.invite),
+                &req.name,
+                &pass_hash,
+                unix_now(),
+            ).await?
+                .ok_or(StorageError::InviteNotFound)?;
+
+            server.audit(
+                invite.created_by.as_deref(),
+                "accept-invite",
+                Some(&req.name),
+                invite.org.as_deref(),
+            ).await?;
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
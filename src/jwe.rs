@@ -0,0 +1,163 @@
+//! Encrypts issued tokens for audiences which registered a key, as nested
+//! JWTs (RFC 7519 section 5.2): the signed token is the plaintext of a
+//! compact JWE using ECDH-ES key agreement and A256GCM (RFC 7518). Only the
+//! audience can read the claims, after decrypting it verifies the inner
+//! token as usual, and presents the inner token to this server's
+//! endpoints, which can't decrypt.
+
+use std::convert::TryFrom;
+
+use ring::{aead, agreement, digest, rand::{SecureRandom, SystemRandom}};
+use serde::Deserialize;
+
+use crate::crypto::TokenError;
+
+const ALG : &str = "ECDH-ES";
+const ENC : &str = "A256GCM";
+const P256_COORDINATE_LEN : usize = 32;
+/// bytes of an A256GCM key
+const CEK_LEN : usize = 32;
+
+/// the public P-256 key of an audience, as a JWK. The private half stays
+/// with the audience.
+#[derive(Deserialize,Debug,Clone)]
+#[serde(try_from = "Jwk")]
+pub struct Key {
+    kid : Option<String>,
+    /// the uncompressed point, as ring takes it
+    point : Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty : String,
+    crv : String,
+    x : String,
+    y : String,
+    #[serde(default)]
+    kid : Option<String>,
+}
+
+impl TryFrom<Jwk> for Key {
+    type Error = String;
+
+    fn try_from(jwk : Jwk) -> Result<Self, String> {
+        if jwk.kty != "EC" || jwk.crv != "P-256" {
+            return Err("only EC P-256 keys are supported".to_string())
+        }
+
+        let coordinate = |c : &str| base64::decode_config(c, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .filter(|c| c.len() == P256_COORDINATE_LEN)
+            .ok_or_else(|| "invalid key coordinate".to_string());
+
+        let mut point = vec![0x04];
+        point.extend(coordinate(&jwk.x)?);
+        point.extend(coordinate(&jwk.y)?);
+
+        let key = Self{
+            kid : jwk.kid,
+            point,
+        };
+
+        // ring only checks the point is on the curve when agreeing
+        key.agree().map_err(|_| "the key is not a P-256 point".to_string())?;
+
+        Ok(key)
+    }
+}
+
+impl Key {
+    /// an ephemeral key, in JWK form, and the content encryption key
+    /// agreed with it
+    fn agree(&self) -> Result<(serde_json::Value, Vec<u8>), TokenError> {
+        let rng = SystemRandom::new();
+        let private = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng)
+            .map_err(|_| TokenError::Encryption)?;
+        let public = private.compute_public_key()
+            .map_err(|_| TokenError::Encryption)?;
+
+        let (x, y) = public.as_ref()[1..].split_at(P256_COORDINATE_LEN);
+        let epk = serde_json::json!({
+            "kty" : "EC",
+            "crv" : "P-256",
+            "x" : base64::encode_config(x, base64::URL_SAFE_NO_PAD),
+            "y" : base64::encode_config(y, base64::URL_SAFE_NO_PAD),
+        });
+
+        let peer = agreement::UnparsedPublicKey::new(&agreement::ECDH_P256, &self.point);
+        let cek = agreement::agree_ephemeral(private, &peer, TokenError::Encryption, |z| Ok(concat_kdf(z, ENC, b"", b"", CEK_LEN)))?;
+
+        Ok((epk, cek))
+    }
+
+    /// wraps a signed token in a compact JWE only this key's owner can
+    /// decrypt
+    pub fn encrypt(&self, token : &str) -> Result<String, TokenError> {
+        let (epk, cek) = self.agree()?;
+
+        let mut header = serde_json::json!({
+            "alg" : ALG,
+            "enc" : ENC,
+            "cty" : "JWT",
+            "epk" : epk,
+        });
+        if let Some(kid) = &self.kid {
+            header["kid"] = kid.as_str().into();
+        }
+        let header = base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD);
+
+        let mut iv = [0; aead::NONCE_LEN];
+        SystemRandom::new().fill(&mut iv)
+            .map_err(|_| TokenError::Encryption)?;
+
+        let key = aead::UnboundKey::new(&aead::AES_256_GCM, &cek)
+            .map_err(|_| TokenError::Encryption)?;
+        let mut ciphertext = token.as_bytes().to_vec();
+        let tag = aead::LessSafeKey::new(key)
+            .seal_in_place_separate_tag(
+                aead::Nonce::assume_unique_for_key(iv),
+                aead::Aad::from(header.as_bytes()),
+                &mut ciphertext,
+            )
+            .map_err(|_| TokenError::Encryption)?;
+
+        // the encrypted key is empty with direct key agreement
+        Ok([
+            header.as_str(),
+            "",
+            &base64::encode_config(iv, base64::URL_SAFE_NO_PAD),
+            &base64::encode_config(ciphertext, base64::URL_SAFE_NO_PAD),
+            &base64::encode_config(tag, base64::URL_SAFE_NO_PAD),
+        ].join("."))
+    }
+}
+
+/// the Concat KDF of NIST SP 800-56A as RFC 7518 section 4.6.2 uses it,
+/// deriving `key_len` bytes for `alg_id` from the shared secret `z`.
+/// Tokens are encrypted without the party infos `apu` and `apv`.
+pub fn concat_kdf(z : &[u8], alg_id : &str, apu : &[u8], apv : &[u8], key_len : usize) -> Vec<u8> {
+    let field = |data : &[u8]| {
+        let mut out = (data.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    };
+
+    let mut key = Vec::with_capacity(key_len);
+    let mut round = 1u32;
+    while key.len() < key_len {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        ctx.update(&round.to_be_bytes());
+        ctx.update(z);
+        ctx.update(&field(alg_id.as_bytes()));
+        ctx.update(&field(apu));
+        ctx.update(&field(apv));
+        ctx.update(&((key_len * 8) as u32).to_be_bytes());
+
+        key.extend_from_slice(ctx.finish().as_ref());
+        round += 1;
+    }
+
+    key.truncate(key_len);
+    key
+}
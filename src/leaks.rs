@@ -0,0 +1,215 @@
+//! Keeping secrets out of responses and logs. With the guard on, responses
+//! holding private keys, the lines of the signing key or password hashes
+//! fail with `transport.secret_leak` instead, which is reported like any
+//! other internal error, and such log lines are withheld. A server has a
+//! guard in debug builds and with `Config::secret_guard`, the logs are
+//! guarded while any server with one is alive. The guard only keeps
+//! fingerprints of the key's lines, not the lines themselves.
+//!
+//! Streamed responses aren't scanned, nor is anything written to stderr
+//! directly, e.g. by `dbg!`, which clippy refuses instead.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+
+use hyper::Body;
+use hyper::body::HttpBody;
+
+use crate::middleware::{Layer, Next};
+use crate::server::{
+    Error,
+    HookFuture,
+    Request,
+    Response,
+    TransportError,
+};
+
+/// the shortest line of the signing key looked for, shorter ones are the
+/// last of the key and could show up by chance
+const MIN_KEY_LINE : usize = 32;
+
+/// the base of the rolling hash matching key lines
+const BASE : u64 = 257;
+
+/// the keys of the guards of the servers alive, for the logs
+static GUARDS : Mutex<Vec<Weak<Keys>>> = Mutex::new(Vec::new());
+
+/// What was found
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Leak {
+    /// a pem private key
+    PrivateKey,
+    /// a line of the server's signing key
+    SigningKey,
+    /// an argon2 password hash
+    PasswordHash,
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::PrivateKey => "a private key",
+            Self::SigningKey => "the signing key",
+            Self::PasswordHash => "a password hash",
+        })
+    }
+}
+
+/// the keys of the guards of the servers alive, dropping the others
+fn guards() -> Vec<Arc<Keys>> {
+    let mut guards = GUARDS.lock().unwrap();
+    guards.retain(|guard| guard.strong_count() > 0);
+    guards.iter().filter_map(Weak::upgrade).collect()
+}
+
+/// whether a server has a guard, so log lines are scanned
+pub fn enabled() -> bool {
+    !guards().is_empty()
+}
+
+fn contains(haystack : &[u8], needle : &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// the secrets any server would hold
+fn find_any(text : &[u8]) -> Option<Leak> {
+    if contains(text, b"PRIVATE KEY-----") {
+        return Some(Leak::PrivateKey)
+    }
+
+    let hashes : [&[u8]; 3] = [b"$argon2i$", b"$argon2d$", b"$argon2id$"];
+    if hashes.iter().any(|prefix| contains(text, prefix)) {
+        return Some(Leak::PasswordHash)
+    }
+
+    None
+}
+
+/// the first secret in `text`, if any, looking for the signing keys of
+/// every server with a guard
+pub fn find(text : &[u8]) -> Option<Leak> {
+    find_any(text).or_else(|| {
+        guards().iter()
+            .any(|keys| keys.holds_key(text))
+            .then_some(Leak::SigningKey)
+    })
+}
+
+fn rolling_hash(data : &[u8]) -> u64 {
+    data.iter().fold(0, |hash, b| hash.wrapping_mul(BASE).wrapping_add(*b as u64))
+}
+
+fn sha256(data : &[u8]) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA256, data).as_ref().to_vec()
+}
+
+/// A line of the signing key, by a rolling hash finding candidates in a
+/// single pass and a digest confirming them
+struct KeyLine {
+    len : usize,
+    rolling : u64,
+    digest : Vec<u8>,
+}
+
+/// Fails responses whose headers or body hold a secret, the server's own
+/// signing key included. Only bodies of a known size are scanned, they're
+/// buffered for it.
+#[derive(Clone)]
+pub(crate) struct Guard(Arc<Keys>);
+
+/// The lines of a signing key
+struct Keys {
+    key_lines : Vec<KeyLine>,
+    /// the lengths of `key_lines`, each one is a pass over the text
+    lens : Vec<usize>,
+}
+
+impl Guard {
+    /// a guard for the server signing with `pem`, which also guards the
+    /// logs until it's dropped
+    pub(crate) fn new(pem : &str) -> Self {
+        let key_lines = pem.lines()
+            .map(str::trim)
+            .filter(|line| line.len() >= MIN_KEY_LINE && !line.starts_with("-----"))
+            .map(|line| KeyLine{
+                len : line.len(),
+                rolling : rolling_hash(line.as_bytes()),
+                digest : sha256(line.as_bytes()),
+            })
+            .collect::<Vec<_>>();
+
+        let mut lens = key_lines.iter().map(|line| line.len).collect::<Vec<_>>();
+        lens.sort_unstable();
+        lens.dedup();
+
+        let keys = Arc::new(Keys{ key_lines, lens });
+        GUARDS.lock().unwrap().push(Arc::downgrade(&keys));
+
+        Self(keys)
+    }
+}
+
+impl Keys {
+    fn find(&self, text : &[u8]) -> Option<Leak> {
+        find_any(text).or_else(|| self.holds_key(text).then_some(Leak::SigningKey))
+    }
+
+    fn holds_key(&self, text : &[u8]) -> bool {
+        self.lens.iter().any(|len| self.holds_key_line(text, *len))
+    }
+
+    /// rolls a hash over every window of `len` bytes of `text`
+    fn holds_key_line(&self, text : &[u8], len : usize) -> bool {
+        if text.len() < len {
+            return false
+        }
+
+        // the weight of the byte leaving the window
+        let high = (0..len).fold(1u64, |high, _| high.wrapping_mul(BASE));
+        let mut hash = rolling_hash(&text[..len]);
+
+        for start in 0..=text.len() - len {
+            if start > 0 {
+                hash = hash.wrapping_mul(BASE)
+                    .wrapping_add(text[start + len - 1] as u64)
+                    .wrapping_sub((text[start - 1] as u64).wrapping_mul(high));
+            }
+
+            let window = &text[start..start + len];
+            let found = self.key_lines.iter()
+                .any(|line| line.len == len && line.rolling == hash && line.digest == sha256(window));
+            if found {
+                return true
+            }
+        }
+
+        false
+    }
+}
+
+impl Layer for Guard {
+    fn call(&self, req : Request, next : Next) -> HookFuture<Result<Response, Error>> {
+        let keys = Arc::clone(&self.0);
+
+        Box::pin(async move {
+            let res = next.run(req).await?;
+
+            let header = res.headers().values().find_map(|value| keys.find(value.as_bytes()));
+            if let Some(leak) = header {
+                return Err(TransportError::SecretLeak(leak).into())
+            }
+
+            if res.body().size_hint().exact().is_none() {
+                return Ok(res)
+            }
+
+            let (parts, body) = res.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            if let Some(leak) = keys.find(&body) {
+                return Err(TransportError::SecretLeak(leak).into())
+            }
+
+            Ok(Response::from_parts(parts, Body::from(body)))
+        })
+    }
+}
@@ -1,34 +1,113 @@
-use serde::{Serialize,Deserialize};
+// secrets printed to stderr get past `leaks`
+#![deny(clippy::dbg_macro)]
 
+pub mod api;
 pub mod models;
 
 #[cfg(feature = "server")]
 pub mod database;
 
+#[cfg(feature = "server")]
+pub mod error;
+
 #[cfg(feature = "server")]
 pub mod server;
 
-pub mod crypto;
-pub mod client;
+#[cfg(feature = "server")]
+pub mod notify;
+
+#[cfg(feature = "server")]
+pub mod ratelimit;
+
+#[cfg(feature = "server")]
+pub mod risk;
+
+#[cfg(feature = "server")]
+pub mod events;
+
+#[cfg(feature = "server")]
+pub mod audit;
+
+#[cfg(feature = "server")]
+pub mod middleware;
+
+#[cfg(feature = "server")]
+pub mod service;
+
+#[cfg(feature = "server")]
+pub mod leaks;
+
+#[cfg(feature = "server")]
+pub mod metrics;
+
+#[cfg(feature = "server")]
+pub mod stats;
+
+#[cfg(feature = "server")]
+pub mod logging;
+
+#[cfg(feature = "server")]
+pub mod peer;
+
+#[cfg(feature = "server")]
+pub mod container;
+
+#[cfg(feature = "server")]
+pub mod policy;
+
+#[cfg(any(feature = "server", feature = "client"))]
+pub mod listing;
+
+#[cfg(feature = "server")]
+mod orgs;
+
+#[cfg(feature = "server")]
+pub mod invites;
+
+#[cfg(feature = "server")]
+pub mod consent;
+
+#[cfg(feature = "server")]
+pub mod break_glass;
+
+#[cfg(feature = "server")]
+pub mod ssh;
+
+#[cfg(any(feature = "saml", feature = "oauth"))]
+mod html;
+
+#[cfg(feature = "saml")]
+pub mod saml;
+
+#[cfg(feature = "negotiate")]
+pub mod negotiate;
+
+#[cfg(feature = "pam")]
+pub mod pam;
 
+#[cfg(feature = "oauth")]
+pub mod oauth;
 
+#[cfg(feature = "jwe")]
+pub mod jwe;
 
-#[derive(Serialize,Deserialize)]
-pub struct PostLoginRequest {
-    pub aud : String,
-    pub duration : u64,
-    pub name : String,
-    pub pass : String,
-}
+#[cfg(feature = "geoip")]
+pub mod geoip;
 
-#[derive(Serialize,Deserialize)]
-pub struct PostLoginResponse {
-    pub token : String,
-}
+#[cfg(feature = "redis")]
+pub mod redis;
 
-#[derive(Serialize,Deserialize)]
-pub struct GetUserResponse {
-    name : String,
-    token_version : u32,
-}
+#[cfg(all(feature = "testing", unix))]
+pub mod testing;
+
+pub mod crypto;
+
+pub mod strength;
+
+pub mod config;
+
+#[cfg(feature = "client")]
+pub mod client;
 
+// the api types used to live at the crate root
+pub use api::*;
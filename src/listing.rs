@@ -0,0 +1,225 @@
+//! Query parameters shared by the list endpoints, `GET /admin/users`,
+//! `GET /admin/audit` and `GET /me/devices`:
+//!
+//! - `limit`, the most entries to return, at most `MAX_LIMIT`
+//! - `cursor`, the `next` of the previous page, pages continue after it
+//! - `sort`, `asc` or `desc` by the endpoint's key
+//! - any other key is a filter the endpoint documents, unknown filters are
+//!   refused
+//!
+//! e.g. `GET /admin/audit?limit=20&action=login`. A page which may be
+//! followed by more entries has a `next` cursor. Cursors are opaque, they
+//! hold the key of the last entry of the page, e.g. its id or time, and the
+//! next page starts after that key, rather than skipping entries, so pages
+//! stay stable while entries are added.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize,Deserialize};
+#[cfg(feature = "server")]
+use serde::de::DeserializeOwned;
+
+/// longer limits are shortened to it
+pub const MAX_LIMIT : u32 = 1000;
+
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sort {
+    Asc,
+    Desc,
+}
+
+impl Sort {
+    /// the sql keyword
+    pub fn sql(self) -> &'static str {
+        match self {
+            Sort::Asc => "ASC",
+            Sort::Desc => "DESC",
+        }
+    }
+}
+
+impl fmt::Display for Sort {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Sort::Asc => "asc",
+            Sort::Desc => "desc",
+        })
+    }
+}
+
+impl FromStr for Sort {
+    type Err = ();
+
+    fn from_str(s : &str) -> Result<Self, ()> {
+        match s {
+            "asc" => Ok(Sort::Asc),
+            "desc" => Ok(Sort::Desc),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The query of a list endpoint, e.g.
+/// `ListQuery::new().with_limit(20).with_filter("action", "login")`
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct ListQuery {
+    pub limit : Option<u32>,
+    pub cursor : Option<String>,
+    pub sort : Option<Sort>,
+    pub filters : BTreeMap<String, String>,
+}
+
+impl ListQuery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limit(mut self, limit : u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// continue after a page, with its `next`
+    pub fn with_cursor(mut self, cursor : &str) -> Self {
+        self.cursor = Some(cursor.to_string());
+        self
+    }
+
+    pub fn with_sort(mut self, sort : Sort) -> Self {
+        self.sort = Some(sort);
+        self
+    }
+
+    pub fn with_filter(mut self, key : &str, value : &str) -> Self {
+        self.filters.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// the query string, with its leading `?`, or empty without any
+    /// parameters
+    pub fn to_query(&self) -> String {
+        let mut pairs = Vec::new();
+        if let Some(limit) = self.limit {
+            pairs.push(("limit".to_string(), limit.to_string()));
+        }
+        if let Some(cursor) = &self.cursor {
+            pairs.push(("cursor".to_string(), cursor.clone()));
+        }
+        if let Some(sort) = self.sort {
+            pairs.push(("sort".to_string(), sort.to_string()));
+        }
+        for (key, value) in &self.filters {
+            pairs.push((key.clone(), value.clone()));
+        }
+
+        if pairs.is_empty() {
+            return String::new()
+        }
+
+        format!("?{}", serde_urlencoded::to_string(pairs).unwrap())
+    }
+
+    /// parses a query string without its `?`, `None` if it's malformed,
+    /// repeats a key or has a limit of 0
+    pub fn parse(query : &str) -> Option<Self> {
+        let pairs : Vec<(String, String)> = serde_urlencoded::from_str(query).ok()?;
+
+        let mut out = Self::new();
+        for (key, value) in pairs {
+            let repeated = match key.as_str() {
+                "limit" => out.limit.replace(value.parse().ok().filter(|n| *n > 0)?).is_some(),
+                "cursor" => out.cursor.replace(value).is_some(),
+                "sort" => out.sort.replace(value.parse().ok()?).is_some(),
+                _ => out.filters.insert(key, value).is_some(),
+            };
+
+            if repeated {
+                return None
+            }
+        }
+
+        Some(out)
+    }
+}
+
+/// A validated `ListQuery`, as passed to the database
+#[cfg(feature = "server")]
+pub struct Page<C> {
+    pub cursor : Option<C>,
+    /// `None` for every entry
+    pub limit : Option<u32>,
+    pub sort : Sort,
+    pub filters : BTreeMap<String, String>,
+}
+
+#[cfg(feature = "server")]
+impl<C> Page<C> {
+    pub fn filter(&self, key : &str) -> Option<&str> {
+        self.filters.get(key).map(String::as_str)
+    }
+
+    /// the limit as bound in sql, where -1 is no limit
+    pub fn sql_limit(&self) -> i64 {
+        self.limit.map(i64::from).unwrap_or(-1)
+    }
+
+    /// the cursor of the page after `entries`, if there may be one
+    pub fn next<T, K : Serialize>(&self, entries : &[T], key : impl Fn(&T) -> K) -> Option<String> {
+        match self.limit {
+            Some(limit) if entries.len() as u64 >= u64::from(limit) => {
+                entries.last().map(|entry| encode_cursor(&key(entry)))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// a key as base64url json
+#[cfg(feature = "server")]
+fn encode_cursor<K : Serialize>(key : &K) -> String {
+    base64::encode_config(serde_json::to_vec(key).unwrap(), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(feature = "server")]
+fn decode_cursor<K : DeserializeOwned>(cursor : &str) -> Option<K> {
+    let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+/// What a list endpoint accepts
+#[cfg(feature = "server")]
+pub(crate) struct Listing {
+    pub filters : &'static [&'static str],
+    pub sort : Sort,
+    /// `None` to return every entry without a `limit`
+    pub limit : Option<u32>,
+}
+
+#[cfg(feature = "server")]
+impl Listing {
+    /// the page asked for by the query of `req`
+    pub fn parse<C : DeserializeOwned>(&self, req : &crate::server::Request) -> Result<Page<C>, crate::server::TransportError> {
+        use crate::server::TransportError;
+
+        let query = ListQuery::parse(req.uri().query().unwrap_or(""))
+            .ok_or(TransportError::BadRequest)?;
+
+        if query.filters.keys().any(|key| !self.filters.contains(&key.as_str())) {
+            return Err(TransportError::BadRequest)
+        }
+
+        let cursor = query.cursor
+            .map(|s| decode_cursor(&s).ok_or(TransportError::BadRequest))
+            .transpose()?;
+
+        Ok(Page{
+            cursor,
+            limit : query.limit.or(self.limit).map(|n| n.min(MAX_LIMIT)),
+            sort : query.sort.unwrap_or(self.sort),
+            filters : query.filters,
+        })
+    }
+}
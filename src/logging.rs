@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use serde::{Serialize, Deserialize};
+
+const DEFAULT_KEEP : usize = 5;
+
+fn default_keep() -> usize {
+    DEFAULT_KEEP
+}
+
+fn default_syslog_path() -> PathBuf {
+    "/dev/log".into()
+}
+
+#[derive(Serialize,Debug,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Info,
+    Error,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum SinkConfig {
+    /// info on stdout, errors on stderr
+    Stdout,
+    /// like `Stdout`, as one json object per line like `File`, for
+    /// container log collectors
+    Json,
+    /// one json object per line
+    File {
+        path : PathBuf,
+        /// rotate once the file would grow past this many bytes
+        #[serde(default)]
+        max_size : Option<u64>,
+        /// rotate once the file has been written to for this many seconds
+        #[serde(default)]
+        max_age : Option<u64>,
+        /// number of rotated files kept as `path.1`, `path.2`, ...
+        #[serde(default = "default_keep")]
+        keep : usize,
+    },
+    /// a syslog socket, journald listens on the default one as well
+    Syslog {
+        #[serde(default = "default_syslog_path")]
+        path : PathBuf,
+    },
+}
+
+/// a key and value logged next to the message
+pub type Field = (&'static str, serde_json::Value);
+
+trait Sink : Send + Sync {
+    fn write(&self, level : Level, time : i64, msg : &str, fields : &[Field]);
+}
+
+struct Logger {
+    sinks : Vec<Box<dyn Sink>>,
+}
+
+static LOGGER : OnceLock<Logger> = OnceLock::new();
+
+/// sets up the sinks, an empty list logs to stdout. Only the first call has
+/// an effect, later ones are ignored.
+pub fn init(configs : &[SinkConfig]) -> io::Result<()> {
+    if LOGGER.get().is_some() {
+        return Ok(())
+    }
+
+    let mut sinks = Vec::<Box<dyn Sink>>::new();
+    for config in configs {
+        sinks.push(match config {
+            SinkConfig::Stdout => Box::new(StdoutSink),
+            SinkConfig::Json => Box::new(JsonSink),
+            SinkConfig::File{ path, max_size, max_age, keep } => {
+                Box::new(FileSink::new(path.clone(), *max_size, *max_age, *keep)?)
+            },
+            #[cfg(unix)]
+            SinkConfig::Syslog{ path } => Box::new(SyslogSink::new(path.clone())?),
+            #[cfg(not(unix))]
+            SinkConfig::Syslog{ .. } => return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "syslog is only available on unix",
+            )),
+        });
+    }
+
+    if sinks.is_empty() {
+        sinks.push(Box::new(StdoutSink));
+    }
+
+    let _ = LOGGER.set(Logger{ sinks });
+
+    // send panics, e.g. from handlers, through the sinks with a backtrace
+    // regardless of RUST_BACKTRACE
+    std::panic::set_hook(Box::new(|info| {
+        error!("{}\n{}", info, std::backtrace::Backtrace::force_capture());
+    }));
+
+    Ok(())
+}
+
+pub fn write(level : Level, args : fmt::Arguments<'_>) {
+    write_fields(level, &[], args)
+}
+
+/// like `write`, the file sink adds `fields` as keys of the json object,
+/// the others append them as `key=value`
+pub fn write_fields(level : Level, fields : &[Field], args : fmt::Arguments<'_>) {
+    let logger = LOGGER.get_or_init(|| Logger{
+        sinks : vec![Box::new(StdoutSink)],
+    });
+
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    let msg = args.to_string();
+
+    let leak = if crate::leaks::enabled() {
+        crate::leaks::find(msg.as_bytes())
+            .or_else(|| fields.iter().find_map(|(_, value)| crate::leaks::find(value.to_string().as_bytes())))
+    } else {
+        None
+    };
+    if let Some(leak) = leak {
+        let msg = format!("withheld a log line holding {}", leak);
+        for sink in &logger.sinks {
+            sink.write(Level::Error, time, &msg, &[]);
+        }
+        return
+    }
+
+    for sink in &logger.sinks {
+        sink.write(level, time, &msg, fields);
+    }
+}
+
+/// `info!("fmt", args)` or, with fields, `info!([("key", json!(v))], "fmt", args)`
+macro_rules! info {
+    ([$($field:expr),* $(,)?], $($arg:tt)*) => {
+        $crate::logging::write_fields($crate::logging::Level::Info, &[$($field),*], format_args!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! error {
+    ([$($field:expr),* $(,)?], $($arg:tt)*) => {
+        $crate::logging::write_fields($crate::logging::Level::Error, &[$($field),*], format_args!($($arg)*))
+    };
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use info;
+pub(crate) use error;
+
+/// `msg key=value ...`, values are json so strings with spaces stay
+/// readable
+fn with_fields(msg : &str, fields : &[Field]) -> String {
+    let mut line = msg.to_string();
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+
+    line
+}
+
+/// `{"time":..,"level":..,"msg":..}` with the fields as further keys
+fn json_line(level : Level, time : i64, msg : &str, fields : &[Field]) -> serde_json::Result<String> {
+    #[derive(Serialize)]
+    struct Line<'a> {
+        time : i64,
+        level : Level,
+        msg : &'a str,
+        #[serde(flatten)]
+        fields : BTreeMap<&'static str, &'a serde_json::Value>,
+    }
+
+    let fields = fields.iter().map(|(k, v)| (*k, v)).collect();
+    serde_json::to_string(&Line{ time, level, msg, fields })
+}
+
+struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn write(&self, level : Level, _ : i64, msg : &str, fields : &[Field]) {
+        let line = with_fields(msg, fields);
+        match level {
+            Level::Info => println!("{}", line),
+            Level::Error => eprintln!("{}", line),
+        }
+    }
+}
+
+struct JsonSink;
+
+impl Sink for JsonSink {
+    fn write(&self, level : Level, time : i64, msg : &str, fields : &[Field]) {
+        // the fields are json values already, so this can't fail
+        let line = json_line(level, time, msg, fields).unwrap();
+        match level {
+            Level::Info => println!("{}", line),
+            Level::Error => eprintln!("{}", line),
+        }
+    }
+}
+
+struct FileSink {
+    path : PathBuf,
+    max_size : Option<u64>,
+    max_age : Option<u64>,
+    keep : usize,
+    state : Mutex<FileState>,
+}
+
+struct FileState {
+    file : File,
+    size : u64,
+    opened : i64,
+}
+
+fn open_append(path : &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl FileSink {
+    fn new(
+        path : PathBuf,
+        max_size : Option<u64>,
+        max_age : Option<u64>,
+        keep : usize,
+    ) -> io::Result<Self> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+
+        let opened = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        Ok(Self{
+            path,
+            max_size,
+            max_age,
+            keep,
+            state : Mutex::new(FileState{ file, size, opened }),
+        })
+    }
+
+    fn rotated(&self, n : usize) -> PathBuf {
+        let mut s = self.path.clone().into_os_string();
+        s.push(format!(".{}", n));
+        s.into()
+    }
+
+    /// shifts `path.n` to `path.n+1`, dropping the oldest, and starts a
+    /// new file
+    fn rotate(&self, state : &mut FileState, time : i64) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        state.file = open_append(&self.path)?;
+        state.size = 0;
+        state.opened = time;
+
+        Ok(())
+    }
+
+    fn try_write(&self, level : Level, time : i64, msg : &str, fields : &[Field]) -> io::Result<()> {
+        let mut line = json_line(level, time, msg, fields)?;
+        line.push('\n');
+
+        let mut state = self.state.lock().unwrap();
+
+        let too_big = self.max_size
+            .map(|max| state.size > 0 && state.size + line.len() as u64 > max)
+            .unwrap_or(false);
+        let too_old = self.max_age
+            .map(|max| time - state.opened >= max as i64)
+            .unwrap_or(false);
+
+        if too_big || too_old {
+            self.rotate(&mut state, time)?;
+        }
+
+        state.file.write_all(line.as_bytes())?;
+        state.size += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&self, level : Level, time : i64, msg : &str, fields : &[Field]) {
+        // there's nowhere better to report a failing log file
+        if let Err(err) = self.try_write(level, time, msg, fields) {
+            eprintln!("failed to write log file {:?}: {:?}", self.path, err);
+        }
+    }
+}
+
+#[cfg(unix)]
+struct SyslogSink {
+    path : PathBuf,
+    socket : UnixDatagram,
+}
+
+#[cfg(unix)]
+impl SyslogSink {
+    fn new(path : PathBuf) -> io::Result<Self> {
+        Ok(Self{
+            path,
+            socket : UnixDatagram::unbound()?,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Sink for SyslogSink {
+    fn write(&self, level : Level, _ : i64, msg : &str, fields : &[Field]) {
+        // facility daemon (3), the timestamp is added by the receiver
+        let severity = match level {
+            Level::Info => 6,
+            Level::Error => 3,
+        };
+
+        let line = format!(
+            "<{}>authn[{}]: {}",
+            3 * 8 + severity,
+            std::process::id(),
+            with_fields(msg, fields),
+        );
+
+        if let Err(err) = self.socket.send_to(line.as_bytes(), &self.path) {
+            eprintln!("failed to write to syslog {:?}: {:?}", self.path, err);
+        }
+    }
+}
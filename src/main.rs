@@ -1,44 +1,180 @@
 use std::convert::Infallible;
+use std::time::Duration;
 
 use plumb::{Pipe,PipeExt};
-use authn::server::Config;
+use authn::server::{Config, Error, StorageError};
+use authn::config::Sources;
+use authn::{container, logging, service};
+#[cfg(unix)]
+use authn::peer::PeerCredentials;
+#[cfg(unix)]
 use hyperlocal::UnixServerExt;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
+#[cfg(windows)]
+use authn::server::RemoteAddr;
+#[cfg(windows)]
+use hyper::server::conn::AddrStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 
 
-#[tokio::main]
-async fn main() {
+fn main() {
+    #[allow(unused_mut)]
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    // started by the service manager, see `authn::service`
+    #[cfg(all(windows, feature = "windows-service"))]
+    if args.first().map(String::as_str) == Some("--service") {
+        args.remove(0);
+        if let Err(err) = service::run_windows_service(move || serve(args)) {
+            eprintln!("could not start the service: {}", err);
+            std::process::exit(1);
+        }
+        return
+    }
+
+    serve(args)
+}
+
+fn serve(args : Vec<String>) {
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(run(args))
+}
+
+async fn run(mut args : Vec<String>) {
     println!("starting server");
 
-    let args = std::env::args().collect::<Vec<_>>();
-    let config_file = match &args[..] {
-        [_, config] => config,
+    // see `authn::container`, the config file is optional
+    let container = args.first().map(String::as_str) == Some("--container");
+    if container {
+        args.remove(0);
+    }
+
+    let sources = Sources::default().with_env().with_flags(&mut args);
+    let sources = match (sources, &args[..]) {
+        (Ok(sources), [config]) => Sources{
+            file : Some(config.into()),
+            ..sources
+        },
+        (Ok(sources), []) if container => sources,
+        (Err(err), _) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
         _ => {
-            eprintln!("usage: ./authn config.json");
+            eprintln!("usage: ./authn [--set key=value]... config.json");
+            eprintln!("       ./authn --container [--set key=value]... [config.json]");
             std::process::exit(1);
         }
     };
+    let sources = if container {
+        sources.with_defaults(container::defaults())
+    } else {
+        sources
+    };
 
-    let config_string = std::fs::read_to_string(&config_file).unwrap();
-    let config : Config = serde_json::from_str(&config_string).unwrap();
-    let (server, path) = authn::server::new_server(config).unwrap();
-    let server = authn::server::routes(server);
+    let config : Config = match sources.load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        },
+    };
 
-    if path.exists() {
-        std::fs::remove_file(&path).unwrap();
+    if container {
+        if let Err(err) = logging::init(&config.log_sinks) {
+            eprintln!("could not set up logging: {}", err);
+            std::process::exit(1);
+        }
+        if let Err(err) = container::provision(&config).await {
+            eprintln!("could not provision the container: {:?}", err);
+            std::process::exit(1);
+        }
     }
+    let (server, path) = match authn::server::new_server(config) {
+        Ok(server) => server,
+        Err(Error::Storage(StorageError::SchemaOutdated(missing))) => {
+            eprintln!("the database lacks {}, run sql/run-migrations.bash", missing.join(", "));
+            std::process::exit(1);
+        },
+        Err(err) => {
+            eprintln!("could not start the server: {:?}", err);
+            std::process::exit(1);
+        },
+    };
+    let header_read = Duration::from_secs(server.timeouts().header_read);
+    let server = authn::server::routes(server);
 
     let pipe : &'static _= Box::leak(Box::new(
-        server.tuple().seq(|res| Ok::<_, Infallible>(res))
+        server.tuple().seq(Ok::<_, Infallible>)
     ));
 
-    let make_service = make_service_fn(move |_| async move {
-        Ok::<_, Infallible>(service_fn(move |req| {
-            pipe.run((req,))
-        }))
-    });
+    #[cfg(unix)]
+    {
+        if let Err(err) = authn::server::prepare_socket(&path) {
+            eprintln!("could not listen on {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+
+        let make_service = make_service_fn(move |conn : &UnixStream| {
+            let peer = PeerCredentials::of_stream(conn);
 
-    Server::bind_unix(path).unwrap().serve(make_service).await.unwrap();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut req : hyper::Request<hyper::Body>| {
+                    if let Some(peer) = peer {
+                        req.extensions_mut().insert(peer);
+                    }
+                    pipe.run((req,))
+                }))
+            }
+        });
+
+        let server = Server::bind_unix(path).unwrap()
+            .http1_header_read_timeout(header_read)
+            .serve(make_service)
+            .with_graceful_shutdown(stop());
+
+        service::ready();
+        server.await.unwrap();
+    }
+
+    // there are no unix sockets, so `server_path` is a loopback address
+    // and requests carry the client's address rather than peer credentials
+    #[cfg(windows)]
+    {
+        let addr = match path.to_str().and_then(|path| path.parse::<std::net::SocketAddr>().ok()) {
+            Some(addr) if addr.ip().is_loopback() => addr,
+            _ => {
+                eprintln!("server_path must be a loopback address on windows, e.g. 127.0.0.1:8080");
+                std::process::exit(1);
+            },
+        };
+
+        let make_service = make_service_fn(move |conn : &AddrStream| {
+            let remote = RemoteAddr(conn.remote_addr());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut req : hyper::Request<hyper::Body>| {
+                    req.extensions_mut().insert(remote);
+                    pipe.run((req,))
+                }))
+            }
+        });
+
+        let server = Server::bind(&addr)
+            .http1_header_read_timeout(header_read)
+            .serve(make_service)
+            .with_graceful_shutdown(stop());
+
+        service::ready();
+        server.await.unwrap();
+    }
+}
 
+/// resolves when the server should stop, then lets the service manager know
+/// it's finishing the requests it has
+async fn stop() {
+    service::shutdown().await;
+    service::stopping();
 }
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// upper bounds of the histogram buckets, in seconds
+const BUCKETS : &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025,
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// the samples `RollingQuantiles` keeps at most, the oldest go first
+const MAX_SAMPLES : usize = 4096;
+
+const DEFAULT_LATENCY_WINDOW : u64 = 5 * 60;
+
+fn default_latency_window() -> u64 {
+    DEFAULT_LATENCY_WINDOW
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LatencyConfig {
+    /// seconds of login and validation latencies the quantiles cover
+    #[serde(default = "default_latency_window")]
+    pub window : u64,
+    /// milliseconds, an error is logged when the p95 of logins or
+    /// validations goes past it, at most once per window
+    #[serde(default)]
+    pub warn_p95 : Option<u64>,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        Self{
+            window : DEFAULT_LATENCY_WINDOW,
+            warn_p95 : None,
+        }
+    }
+}
+
+/// A latency histogram rendered in the prometheus text format
+pub struct Histogram {
+    buckets : Vec<AtomicU64>,
+    count : AtomicU64,
+    sum_micros : AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self{
+            buckets : BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            count : AtomicU64::new(0),
+            sum_micros : AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&self, d : Duration) {
+        let secs = d.as_secs_f64();
+
+        if let Some(i) = BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// runs `f`, recording how long it took
+    pub fn time<T>(&self, f : impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let t = f();
+        self.observe(start.elapsed());
+        t
+    }
+
+    /// writes the histogram's series, `labels` is either empty or a comma
+    /// terminated list of labels
+    fn render_series(&self, name : &str, labels : &str, out : &mut String) {
+        let mut cumulative = 0;
+        for (le, n) in BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += n.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{{}le=\"{}\"}} {}", name, labels, le, cumulative).unwrap();
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, count).unwrap();
+
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+
+        writeln!(out, "{}_sum{} {}", name, labels, sum).unwrap();
+        writeln!(out, "{}_count{} {}", name, labels, count).unwrap();
+    }
+
+    pub fn render(&self, name : &str, help : &str, out : &mut String) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        self.render_series(name, "", out);
+    }
+}
+
+/// A set of histograms distinguished by a single label
+#[derive(Default)]
+pub struct LabeledHistogram {
+    inner : Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl LabeledHistogram {
+    pub fn observe(&self, label : &'static str, d : Duration) {
+        self.inner
+            .lock()
+            .unwrap()
+            .entry(label)
+            .or_default()
+            .observe(d);
+    }
+
+    pub fn render(&self, name : &str, help : &str, label_name : &str, out : &mut String) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+
+        for (label, h) in self.inner.lock().unwrap().iter() {
+            let labels = format!("{}=\"{}\",", label_name, label);
+            h.render_series(name, &labels, out);
+        }
+    }
+}
+
+/// Quantiles of the latencies in a window, zero without any
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq)]
+pub struct Quantiles {
+    pub count : usize,
+    pub p50 : Duration,
+    pub p95 : Duration,
+    pub p99 : Duration,
+}
+
+/// Latencies of the last `LatencyConfig::window`, kept in memory, with
+/// their quantiles computed when asked for
+pub struct RollingQuantiles {
+    window : Duration,
+    warn_p95 : Option<Duration>,
+    state : Mutex<RollingState>,
+}
+
+struct RollingState {
+    samples : VecDeque<(Instant, Duration)>,
+    /// when `observe` last reported the p95 past `warn_p95`
+    warned : Option<Instant>,
+}
+
+impl RollingQuantiles {
+    pub fn new(config : &LatencyConfig) -> Self {
+        Self{
+            window : Duration::from_secs(config.window),
+            warn_p95 : config.warn_p95.map(Duration::from_millis),
+            state : Mutex::new(RollingState{
+                samples : VecDeque::new(),
+                warned : None,
+            }),
+        }
+    }
+
+    fn prune(&self, samples : &mut VecDeque<(Instant, Duration)>, now : Instant) {
+        while samples.len() > MAX_SAMPLES
+            || samples.front().is_some_and(|(t, _)| now.duration_since(*t) > self.window)
+        {
+            samples.pop_front();
+        }
+    }
+
+    /// records `d`, returning the quantiles if their p95 went past
+    /// `LatencyConfig::warn_p95` and wasn't reported within the window
+    pub fn observe(&self, d : Duration) -> Option<Quantiles> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.samples.push_back((now, d));
+        self.prune(&mut state.samples, now);
+
+        // only a slow sample can push the p95 past the threshold
+        let threshold = self.warn_p95.filter(|max| d > *max)?;
+        if state.warned.is_some_and(|t| now.duration_since(t) < self.window) {
+            return None
+        }
+
+        let quantiles = quantiles(&state.samples);
+        if quantiles.p95 <= threshold {
+            return None
+        }
+
+        state.warned = Some(now);
+        Some(quantiles)
+    }
+
+    pub fn quantiles(&self) -> Quantiles {
+        let mut state = self.state.lock().unwrap();
+        self.prune(&mut state.samples, Instant::now());
+
+        quantiles(&state.samples)
+    }
+
+    /// renders the quantiles as a prometheus summary of the window
+    pub fn render(&self, name : &str, help : &str, out : &mut String) {
+        let mut state = self.state.lock().unwrap();
+        self.prune(&mut state.samples, Instant::now());
+        let q = quantiles(&state.samples);
+        let sum = state.samples.iter().map(|(_, d)| d.as_secs_f64()).sum::<f64>();
+        drop(state);
+
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} summary", name).unwrap();
+        for (quantile, d) in [("0.5", q.p50), ("0.95", q.p95), ("0.99", q.p99)].iter() {
+            writeln!(out, "{}{{quantile=\"{}\"}} {}", name, quantile, d.as_secs_f64()).unwrap();
+        }
+        writeln!(out, "{}_sum {}", name, sum).unwrap();
+        writeln!(out, "{}_count {}", name, q.count).unwrap();
+    }
+}
+
+/// nearest rank quantiles
+fn quantiles(samples : &VecDeque<(Instant, Duration)>) -> Quantiles {
+    let mut sorted = samples.iter().map(|(_, d)| *d).collect::<Vec<_>>();
+    if sorted.is_empty() {
+        return Quantiles::default()
+    }
+    sorted.sort_unstable();
+
+    let rank = |q : f64| sorted[((q * sorted.len() as f64).ceil() as usize).max(1) - 1];
+
+    Quantiles{
+        count : sorted.len(),
+        p50 : rank(0.5),
+        p95 : rank(0.95),
+        p99 : rank(0.99),
+    }
+}
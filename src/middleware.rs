@@ -0,0 +1,240 @@
+//! Layers around the routes. Every request passes through them in order,
+//! the first layer sees the request first and the response last:
+//!
+//! 1. request ids, see `server::RequestId`
+//! 2. the request log
+//! 3. `HEAD`, served with the `GET` route
+//! 4. errors, rendered to responses and reported, see `server::ErrorReporter`
+//! 5. the secret guard, if it's on, see `leaks`
+//! 6. maintenance mode, see `Server::set_maintenance`
+//! 7. the layers of `Config::layers`, then the ones of `Server::with_layer`
+//! 8. the body timeouts, see `server::Timeouts`
+//!
+//! A layer returns errors rather than rendering them, so they get the same
+//! codes and logging as the errors of the routes.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::ratelimit::{MemoryStore, RateLimitStore};
+use crate::server::{
+    discovery_caller,
+    Error,
+    ErrorHeaders,
+    HookFuture,
+    Request,
+    Response,
+    Server,
+    TransportError,
+};
+
+const DEFAULT_CORS_MAX_AGE : u64 = 600;
+
+fn default_cors_max_age() -> u64 {
+    DEFAULT_CORS_MAX_AGE
+}
+
+#[derive(Deserialize,Clone)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum LayerConfig {
+    /// lets browsers on `origins` call the server, `*` allows any origin
+    Cors {
+        origins : Vec<String>,
+        /// seconds browsers may cache a preflight
+        #[serde(default = "default_cors_max_age")]
+        max_age : u64,
+    },
+    /// refuses callers, by their address or uid, after `max_requests` in a
+    /// window of `window` seconds, counted in memory
+    RateLimit {
+        max_requests : u64,
+        window : u64,
+    },
+    /// fails requests whose handler takes more than `secs` seconds
+    Timeout {
+        secs : u64,
+    },
+    /// refuses requests without a valid token to paths starting with one
+    /// of `paths`
+    RequireToken {
+        paths : Vec<String>,
+    },
+}
+
+/// The rest of the chain, as passed to a `Layer`
+#[derive(Clone)]
+pub struct Next(Arc<dyn Fn(Request) -> HookFuture<Result<Response, Error>> + Send + Sync>);
+
+impl Next {
+    pub(crate) fn new<F>(f : F) -> Self
+    where
+        F : Fn(Request) -> HookFuture<Result<Response, Error>> + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub fn run(&self, req : Request) -> HookFuture<Result<Response, Error>> {
+        (self.0)(req)
+    }
+
+    /// runs `layer` before the chain
+    pub(crate) fn with(self, layer : Arc<dyn Layer>) -> Self {
+        Self::new(move |req| layer.call(req, self.clone()))
+    }
+}
+
+/// Wraps the routes, e.g. to refuse some requests or add headers to the
+/// responses, see `Server::with_layer`. Calling `next` passes the request
+/// on, not calling it answers the request right away.
+pub trait Layer : Send + Sync {
+    fn call(&self, req : Request, next : Next) -> HookFuture<Result<Response, Error>>;
+}
+
+impl<F> Layer for F
+where
+    F : Fn(Request, Next) -> HookFuture<Result<Response, Error>> + Send + Sync,
+{
+    fn call(&self, req : Request, next : Next) -> HookFuture<Result<Response, Error>> {
+        self(req, next)
+    }
+}
+
+/// the layers of the config
+pub(crate) fn open(server : &Arc<Server>, configs : &[LayerConfig]) -> Vec<Arc<dyn Layer>> {
+    configs.iter()
+        .map(|config| -> Arc<dyn Layer> {
+            match config.clone() {
+                LayerConfig::Cors{ origins, max_age } => Arc::new(Cors{ origins, max_age }),
+                LayerConfig::RateLimit{ max_requests, window } => Arc::new(RateLimit{
+                    store : Box::new(MemoryStore::default()),
+                    max_requests,
+                    window : Duration::from_secs(window),
+                }),
+                LayerConfig::Timeout{ secs } => Arc::new(Timeout(Duration::from_secs(secs))),
+                LayerConfig::RequireToken{ paths } => Arc::new(RequireToken{
+                    server : Arc::clone(server),
+                    paths,
+                }),
+            }
+        })
+        .collect()
+}
+
+struct Cors {
+    origins : Vec<String>,
+    max_age : u64,
+}
+
+impl Cors {
+    /// the `Origin` of the request, if it's allowed
+    fn origin(&self, req : &Request) -> Option<http::HeaderValue> {
+        let origin = req.headers().get(http::header::ORIGIN)?;
+        let allowed = self.origins.iter()
+            .any(|o| o == "*" || o.as_bytes() == origin.as_bytes());
+
+        if allowed {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl Layer for Cors {
+    fn call(&self, req : Request, next : Next) -> HookFuture<Result<Response, Error>> {
+        use http::header;
+
+        let origin = match self.origin(&req) {
+            Some(origin) => origin,
+            None => return next.run(req),
+        };
+
+        let preflight = req.method() == http::Method::OPTIONS
+            && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+        if preflight {
+            let res = http::Response::builder()
+                .status(http::StatusCode::NO_CONTENT)
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, HEAD, POST, PUT, PATCH, DELETE")
+                .header(header::ACCESS_CONTROL_ALLOW_HEADERS, "authorization, content-type")
+                .header(header::ACCESS_CONTROL_MAX_AGE, self.max_age)
+                .header(header::VARY, "origin")
+                .body(hyper::Body::empty())
+                .unwrap();
+
+            return Box::pin(async move { Ok(res) })
+        }
+
+        let add_headers = move |headers : &mut http::HeaderMap| {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, http::HeaderValue::from_static("x-request-id"));
+            headers.append(header::VARY, http::HeaderValue::from_static("origin"));
+        };
+        // errors, panics included, are rendered outside this layer
+        ErrorHeaders::of(&req).set(add_headers.clone());
+
+        Box::pin(async move {
+            let mut res = next.run(req).await?;
+            add_headers(res.headers_mut());
+
+            Ok(res)
+        })
+    }
+}
+
+struct RateLimit {
+    store : Box<dyn RateLimitStore>,
+    max_requests : u64,
+    window : Duration,
+}
+
+impl Layer for RateLimit {
+    fn call(&self, req : Request, next : Next) -> HookFuture<Result<Response, Error>> {
+        let count = self.store.incr(&format!("requests:{}", discovery_caller(&req)), self.window);
+        let max_requests = self.max_requests;
+
+        Box::pin(async move {
+            if count.await? > max_requests {
+                return Err(TransportError::TooManyRequests.into())
+            }
+
+            next.run(req).await
+        })
+    }
+}
+
+struct Timeout(Duration);
+
+impl Layer for Timeout {
+    fn call(&self, req : Request, next : Next) -> HookFuture<Result<Response, Error>> {
+        let timeout = self.0;
+
+        Box::pin(async move {
+            tokio::time::timeout(timeout, next.run(req)).await
+                .unwrap_or_else(|_| Err(TransportError::HandlerTimeout.into()))
+        })
+    }
+}
+
+struct RequireToken {
+    server : Arc<Server>,
+    paths : Vec<String>,
+}
+
+impl Layer for RequireToken {
+    fn call(&self, req : Request, next : Next) -> HookFuture<Result<Response, Error>> {
+        let path = req.uri().path();
+        if !self.paths.iter().any(|prefix| path.starts_with(prefix.as_str())) {
+            return next.run(req)
+        }
+
+        let server = Arc::clone(&self.server);
+        Box::pin(async move {
+            server.authenticate(&req).await?;
+
+            next.run(req).await
+        })
+    }
+}
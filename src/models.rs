@@ -2,4 +2,157 @@ pub struct User {
     pub name : String,
     pub pass_hash : String,
     pub token_version : u32,
+    pub notify_logins : bool,
+    pub roles : String,
+}
+
+impl User {
+    pub fn has_role(&self, role : &str) -> bool {
+        self.roles.split_whitespace().any(|r| r == role)
+    }
+}
+
+/// A user's login history prior to the current login, as unix timestamps
+pub struct LoginRecord {
+    /// most recent login to any audience
+    pub last_any : Option<i64>,
+    /// most recent login to the current audience
+    pub last_aud : Option<i64>,
+    /// first login through the current audience and user agent
+    pub new_client : bool,
+    /// first login from the current country, after logins from others
+    pub new_country : bool,
+}
+
+/// A country a user logged in from, see `geoip`
+pub struct LoginCountry {
+    pub name : String,
+    /// iso code
+    pub country : String,
+    pub first_seen : i64,
+    pub last_seen : i64,
+}
+
+/// An emergency account whose password works once, see `break_glass`
+pub struct BreakGlass {
+    pub name : String,
+    /// unix time the password was set
+    pub armed : i64,
+    /// unix time the password was used, `None` while it's unused
+    pub used : Option<i64>,
+}
+
+/// A distinct (audience, user agent) pair a user logged in through
+pub struct Client {
+    pub name : String,
+    pub aud : String,
+    pub user_agent : String,
+    pub first_seen : i64,
+    pub last_seen : i64,
+}
+
+/// The last seen time, audience and user agent of a `Client`, in the order
+/// clients are listed by
+pub type ClientKey = (i64, String, String);
+
+/// A long lived "remember me" credential
+pub struct Device {
+    pub id : i64,
+    pub name : String,
+    pub aud : String,
+    pub token_version : u32,
+    pub created : i64,
+    pub last_used : Option<i64>,
+}
+
+/// A public key a user logs in with by signing a challenge, see `ssh`
+pub struct SshKey {
+    pub id : i64,
+    pub name : String,
+    /// base64 of the key in the ssh wire format, as in authorized_keys
+    pub public_key : String,
+    pub comment : String,
+    pub created : i64,
+    pub last_used : Option<i64>,
+}
+
+/// A challenge handed out for an `SshKey`, waiting to be signed
+pub struct SshChallenge {
+    pub key_id : i64,
+    /// the audience of the login it's for
+    pub aud : String,
+    /// unix time
+    pub expires : i64,
+}
+
+/// An oauth2 authorization code, waiting to be exchanged for a token
+pub struct AuthorizationCode {
+    pub client_id : String,
+    pub name : String,
+    /// as given in the authorization request, the token request has to
+    /// repeat it
+    pub redirect_uri : Option<String>,
+    /// base64url sha256 of the pkce code verifier
+    pub code_challenge : String,
+    /// unix time
+    pub expires : i64,
+}
+
+/// An oauth2 device authorization, pending until a user approves it
+pub struct DeviceAuthorization {
+    /// without the dash shown to users
+    pub user_code : String,
+    pub client_id : String,
+    /// the user who approved it
+    pub name : Option<String>,
+    /// unix time
+    pub expires : i64,
+    /// unix time the device last asked for its token
+    pub last_poll : Option<i64>,
+}
+
+/// An entry of the audit log of privileged actions
+pub struct AuditEntry {
+    pub id : i64,
+    /// unix time
+    pub time : i64,
+    /// the user who acted, `None` for `authn-utils`
+    pub actor : Option<String>,
+    pub action : String,
+    pub subject : Option<String>,
+    pub detail : Option<String>,
+}
+
+/// An organization, e.g. one customer, whose members are managed by its
+/// own admins
+pub struct Org {
+    pub name : String,
+    /// unix time
+    pub created : i64,
+}
+
+/// A user's membership of an organization
+pub struct OrgMember {
+    pub org : String,
+    pub name : String,
+    /// may manage the organization's members
+    pub admin : bool,
+    /// space separated, like `User::roles`
+    pub groups : String,
+}
+
+/// An invite to register, see `invites`
+pub struct Invite {
+    pub id : i64,
+    /// the only name the invitee may register, any if `None`
+    pub name : Option<String>,
+    /// given to the new user, space separated
+    pub roles : String,
+    /// the new user joins it with `groups`
+    pub org : Option<String>,
+    pub groups : String,
+    /// `None` for invites made with `authn-utils`
+    pub created_by : Option<String>,
+    /// unix time
+    pub expires : i64,
 }
@@ -0,0 +1,257 @@
+//! Kerberos logins over SPNEGO, the `Negotiate` http authentication scheme
+//! of RFC 4559. A client holding a ticket for the server's service
+//! principal sends it in the `authorization` header of `POST /login`, the
+//! principal is mapped to the local user of the same name.
+//!
+//! GSSAPI is loaded at runtime, so the server builds without kerberos
+//! headers and only needs the library on hosts where this is configured.
+
+use std::ffi::{c_void, CString};
+use std::os::raw::c_char;
+use std::ptr;
+
+use serde::Deserialize;
+use libloading::Library;
+
+use crate::server::{
+    Error,
+    AuthError,
+    ConfigError,
+    TransportError,
+    Request,
+};
+use crate::logging;
+
+const DEFAULT_LIBRARY : &str = "libgssapi_krb5.so.2";
+
+fn default_library() -> String {
+    DEFAULT_LIBRARY.to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// only principals of this realm may log in, as the user named by the
+    /// principal without the realm
+    pub realm : String,
+    /// keytab holding the key of the server's service principal, usually
+    /// `HTTP/<host>@<realm>`. The default keytab is used if unset.
+    #[serde(default)]
+    pub keytab : Option<String>,
+    /// file name or path of the GSSAPI library
+    #[serde(default = "default_library")]
+    pub library : String,
+}
+
+type OmUint32 = u32;
+
+#[repr(C)]
+struct Buffer {
+    length : usize,
+    value : *mut c_void,
+}
+
+impl Buffer {
+    fn empty() -> Self {
+        Self{
+            length : 0,
+            value : ptr::null_mut(),
+        }
+    }
+}
+
+type AcceptSecContext = unsafe extern "C" fn(
+    minor : *mut OmUint32,
+    context : *mut *mut c_void,
+    acceptor_cred : *mut c_void,
+    input_token : *mut Buffer,
+    channel_bindings : *mut c_void,
+    src_name : *mut *mut c_void,
+    mech_type : *mut *mut c_void,
+    output_token : *mut Buffer,
+    ret_flags : *mut OmUint32,
+    time_rec : *mut OmUint32,
+    delegated_cred : *mut *mut c_void,
+) -> OmUint32;
+type DisplayName = unsafe extern "C" fn(*mut OmUint32, *mut c_void, *mut Buffer, *mut *mut c_void) -> OmUint32;
+type ReleaseBuffer = unsafe extern "C" fn(*mut OmUint32, *mut Buffer) -> OmUint32;
+type ReleaseName = unsafe extern "C" fn(*mut OmUint32, *mut *mut c_void) -> OmUint32;
+type DeleteSecContext = unsafe extern "C" fn(*mut OmUint32, *mut *mut c_void, *mut Buffer) -> OmUint32;
+type RegisterAcceptorIdentity = unsafe extern "C" fn(*const c_char) -> OmUint32;
+
+/// set in the calling and routine error bits of a major status
+const GSS_ERROR_MASK : OmUint32 = 0xffff_0000;
+const GSS_ROUTINE_ERROR_MASK : OmUint32 = 0x00ff_0000;
+const GSS_S_CONTINUE_NEEDED : OmUint32 = 1;
+/// routine errors for tokens which aren't a kerberos ticket at all, a bad
+/// request rather than a failed login
+const GSS_S_BAD_MECH : OmUint32 = 1 << 16;
+const GSS_S_DEFECTIVE_TOKEN : OmUint32 = 9 << 16;
+
+/// Accepts SPNEGO tokens with the host's GSSAPI library
+pub struct Negotiator {
+    realm : String,
+    accept_sec_context : AcceptSecContext,
+    display_name : DisplayName,
+    release_buffer : ReleaseBuffer,
+    release_name : ReleaseName,
+    delete_sec_context : DeleteSecContext,
+    /// keeps the functions above loaded
+    _library : Library,
+}
+
+/// A successful negotiation
+pub struct Accepted {
+    /// the local user the principal maps to
+    pub name : String,
+    /// the token for mutual authentication, sent back to the client in
+    /// the `www-authenticate` header
+    pub response : Option<Vec<u8>>,
+}
+
+fn load_error(err : libloading::Error) -> Error {
+    ConfigError::Gssapi(err.to_string()).into()
+}
+
+impl Negotiator {
+    pub fn new(config : Config) -> Result<Self, Error> {
+        // SAFETY: the library's initializers have no preconditions, and
+        // the symbols are declared with their signatures from gssapi.h
+        unsafe {
+            let library = Library::new(&config.library).map_err(load_error)?;
+
+            if let Some(keytab) = &config.keytab {
+                // the acceptor identity is process wide, this is the only
+                // place it is set
+                let register = library.get::<RegisterAcceptorIdentity>(b"krb5_gss_register_acceptor_identity\0")
+                    .map_err(load_error)?;
+                let keytab = CString::new(keytab.as_str())
+                    .map_err(|_| ConfigError::Gssapi("keytab path contains a nul byte".to_string()))?;
+                if register(keytab.as_ptr()) != 0 {
+                    return Err(ConfigError::Gssapi("failed to register the keytab".to_string()).into())
+                }
+            }
+
+            Ok(Self{
+                realm : config.realm,
+                accept_sec_context : *library.get(b"gss_accept_sec_context\0").map_err(load_error)?,
+                display_name : *library.get(b"gss_display_name\0").map_err(load_error)?,
+                release_buffer : *library.get(b"gss_release_buffer\0").map_err(load_error)?,
+                release_name : *library.get(b"gss_release_name\0").map_err(load_error)?,
+                delete_sec_context : *library.get(b"gss_delete_sec_context\0").map_err(load_error)?,
+                _library : library,
+            })
+        }
+    }
+
+    /// accepts a client's token, only single round trip (kerberos)
+    /// negotiations are supported. This blocks on the keytab and replay
+    /// cache, so run it on a blocking thread.
+    pub fn accept(&self, token : &[u8]) -> Result<Accepted, Error> {
+        let mut minor = 0;
+        let mut context = ptr::null_mut();
+        let mut src_name = ptr::null_mut();
+        let mut output = Buffer::empty();
+        let mut input = Buffer{
+            length : token.len(),
+            value : token.as_ptr() as *mut c_void,
+        };
+
+        // SAFETY: the input buffer outlives the call and isn't written
+        // to, every output is released before returning
+        unsafe {
+            let major = (self.accept_sec_context)(
+                &mut minor,
+                &mut context,
+                ptr::null_mut(),
+                &mut input,
+                ptr::null_mut(),
+                &mut src_name,
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+
+            // before the releases below overwrite the minor status
+            let principal = if major & GSS_ERROR_MASK == 0 && major & GSS_S_CONTINUE_NEEDED == 0 {
+                Ok(self.principal(src_name))
+            } else {
+                logging::error!("gss_accept_sec_context failed: major {:#x}, minor {}", major, minor);
+                match major & GSS_ROUTINE_ERROR_MASK {
+                    GSS_S_BAD_MECH | GSS_S_DEFECTIVE_TOKEN => Err(TransportError::BadRequest),
+                    _ => Ok(None),
+                }
+            };
+
+            let response = if output.length > 0 {
+                Some(std::slice::from_raw_parts(output.value as *const u8, output.length).to_vec())
+            } else {
+                None
+            };
+            (self.release_buffer)(&mut minor, &mut output);
+
+            if !src_name.is_null() {
+                (self.release_name)(&mut minor, &mut src_name);
+            }
+            if !context.is_null() {
+                (self.delete_sec_context)(&mut minor, &mut context, ptr::null_mut());
+            }
+
+            let name = principal?
+                .and_then(|principal| self.local_name(&principal))
+                .ok_or(AuthError::LoginFailed)?;
+
+            Ok(Accepted{ name, response })
+        }
+    }
+
+    /// the principal's display name, e.g. `alice@EXAMPLE.COM`
+    unsafe fn principal(&self, name : *mut c_void) -> Option<String> {
+        let mut minor = 0;
+        let mut buf = Buffer::empty();
+
+        if (self.display_name)(&mut minor, name, &mut buf, ptr::null_mut()) & GSS_ERROR_MASK != 0 {
+            return None
+        }
+
+        let principal = std::slice::from_raw_parts(buf.value as *const u8, buf.length).to_vec();
+        (self.release_buffer)(&mut minor, &mut buf);
+
+        String::from_utf8(principal).ok()
+    }
+
+    /// maps a user principal of the configured realm to its user name,
+    /// service principals (`service/host@REALM`) are refused
+    fn local_name(&self, principal : &str) -> Option<String> {
+        let (name, realm) = principal.rsplit_once('@')?;
+
+        if realm != self.realm || name.is_empty() || name.contains('/') {
+            return None
+        }
+
+        Some(name.to_string())
+    }
+}
+
+/// the first byte of an initial GSS-API token, RFC 2743 3.1
+const GSS_TOKEN_TAG : u8 = 0x60;
+
+/// the decoded token of a `Negotiate` authorization header, if the request
+/// has one. A token which isn't base64, or not framed as a GSS-API token,
+/// is a bad request, the library would only fail the login.
+pub fn header_token(req : &Request) -> Option<Result<Vec<u8>, Error>> {
+    let value = req.headers()
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Negotiate ")?;
+
+    let token = match base64::decode(value.trim()) {
+        Ok(token) if token.first() == Some(&GSS_TOKEN_TAG) => Ok(token),
+        _ => Err(TransportError::BadRequest.into()),
+    };
+
+    Some(token)
+}
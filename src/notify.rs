@@ -0,0 +1,104 @@
+use std::convert::TryInto;
+
+use serde::{Serialize,Deserialize};
+
+use crate::models::LoginRecord;
+use crate::logging;
+use crate::server::{self, Error, WebhookClient};
+
+const DEFAULT_INACTIVITY : u64 = 60 * 60 * 24 * 90;
+
+fn default_inactivity() -> u64 {
+    DEFAULT_INACTIVITY
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// https url which receives a json POST for every notification
+    pub webhook : String,
+    /// seconds without any login after which the next login is reported
+    #[serde(default = "default_inactivity")]
+    pub inactivity : u64,
+}
+
+#[derive(Serialize,Debug,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    NewAudience,
+    NewClient,
+    /// see `geoip`
+    NewCountry,
+    Inactivity,
+    /// sent for every login, see `break_glass`
+    BreakGlass,
+}
+
+#[derive(Serialize)]
+pub struct LoginNotification {
+    pub name : String,
+    pub aud : String,
+    pub reason : Reason,
+    pub time : i64,
+    /// iso code of the country of the login, see `geoip`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country : Option<String>,
+}
+
+pub struct Notifier {
+    webhook : hyper::Uri,
+    inactivity : i64,
+    client : WebhookClient,
+}
+
+impl Notifier {
+    pub fn new(config : Config) -> Result<Self, Error> {
+        let (webhook, client) = server::webhook(&config.webhook)?;
+
+        Ok(Self{
+            webhook,
+            inactivity : config.inactivity.try_into().unwrap_or(i64::MAX),
+            client,
+        })
+    }
+
+    /// decides whether a login warrants a notification, given the user's
+    /// login history prior to it
+    pub fn reason(&self, record : &LoginRecord, now : i64) -> Option<Reason> {
+        // the very first login has nothing to be compared against
+        let last_any = record.last_any?;
+
+        if record.new_country {
+            Some(Reason::NewCountry)
+        } else if record.last_aud.is_none() {
+            Some(Reason::NewAudience)
+        } else if record.new_client {
+            Some(Reason::NewClient)
+        } else if now - last_any > self.inactivity {
+            Some(Reason::Inactivity)
+        } else {
+            None
+        }
+    }
+
+    /// delivers the notification in the background, failures are logged
+    /// but never fail the login itself
+    pub fn send(&self, notification : LoginNotification) {
+        let req = http::Request::builder()
+            .uri(self.webhook.clone())
+            .method("POST")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&notification).unwrap().into())
+            .unwrap();
+
+        let client = self.client.clone();
+
+        tokio::spawn(async move {
+            match client.request(req).await {
+                Ok(res) if res.status().is_success() => {},
+                Ok(res) => logging::error!("login notification failed: {}", res.status()),
+                Err(err) => logging::error!("login notification failed: {:?}", err),
+            }
+        });
+    }
+}
@@ -0,0 +1,883 @@
+//! The OAuth 2.0 authorization code grant (RFC 6749) for public clients,
+//! with PKCE (RFC 7636) required. `GET /authorize` shows a login page
+//! asking the user to let the client in, a successful login redirects
+//! back to the client with a short lived code, which the client exchanges
+//! at `POST /token` for a token whose audience is the client id. The login
+//! form is tied to the browser it was served to with a csrf cookie, see
+//! `html::csrf_token`.
+//!
+//! Clients without a browser, like CLIs and TVs, use the device
+//! authorization grant (RFC 8628) instead: `POST /device_authorization`
+//! gives them a code for the user to enter at `GET /device` on another
+//! device, while they poll `POST /token` until the user has logged in. The
+//! user is shown which client they're letting in before they log in, lest
+//! they enter a code an attacker's device showed them, see RFC 8628
+//! section 5.4.
+//!
+//! Services use the token exchange grant (RFC 8693) on `POST /token` to
+//! trade a user's token for their own audience for one for another
+//! audience, when `Config::token_exchange` allows it.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+use plumb::PipeExt;
+use http_mux::{route,mux};
+use rand::{Rng, rngs::OsRng};
+
+use crate::api::{
+    PostTokenResponse,
+    PostDeviceAuthorizationResponse,
+};
+use crate::crypto::{self, Secret};
+use crate::html;
+#[cfg(feature = "jwe")]
+use crate::jwe;
+use crate::models;
+use crate::server::{
+    TransportError,
+    Server,
+    Router,
+    LoginOutcome,
+    Request,
+    Response,
+    AuthError,
+    StorageError,
+    Error,
+    Result,
+    query_param,
+    user_agent,
+    client_addr,
+    unix_now,
+};
+
+const DEFAULT_CODE_LIFETIME : u64 = 60;
+const DEFAULT_TOKEN_DURATION : u64 = 60 * 60;
+const DEFAULT_DEVICE_CODE_LIFETIME : u64 = 10 * 60;
+const DEFAULT_DEVICE_POLL_INTERVAL : u64 = 5;
+
+/// the `grant_type` of device code polls
+pub const DEVICE_CODE_GRANT : &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// the `grant_type` of token exchanges
+pub const TOKEN_EXCHANGE_GRANT : &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+/// the type of the tokens this server issues, the only type it exchanges
+pub const JWT_TOKEN_TYPE : &str = "urn:ietf:params:oauth:token-type:jwt";
+const ACCESS_TOKEN_TYPE : &str = "urn:ietf:params:oauth:token-type:access_token";
+
+fn default_code_lifetime() -> u64 {
+    DEFAULT_CODE_LIFETIME
+}
+
+fn default_token_duration() -> u64 {
+    DEFAULT_TOKEN_DURATION
+}
+
+fn default_device_code_lifetime() -> u64 {
+    DEFAULT_DEVICE_CODE_LIFETIME
+}
+
+fn default_device_poll_interval() -> u64 {
+    DEFAULT_DEVICE_POLL_INTERVAL
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub clients : Vec<Client>,
+    /// seconds a code can be exchanged for a token after it's issued
+    #[serde(default = "default_code_lifetime")]
+    pub code_lifetime : u64,
+    /// lifetime of the issued tokens in seconds
+    #[serde(default = "default_token_duration")]
+    pub token_duration : u64,
+    /// public url of `GET /device`, shown to users by device clients. The
+    /// device authorization grant is turned off if unset.
+    #[serde(default)]
+    pub verification_uri : Option<String>,
+    /// seconds a device has to get its user to log in
+    #[serde(default = "default_device_code_lifetime")]
+    pub device_code_lifetime : u64,
+    /// minimum seconds between a device's polls of `POST /token`
+    #[serde(default = "default_device_poll_interval")]
+    pub device_poll_interval : u64,
+    /// which audiences' tokens may be exchanged for which, no exchanges
+    /// are allowed by default
+    #[serde(default)]
+    pub token_exchange : Vec<ExchangeRule>,
+}
+
+/// Allows exchanging tokens for one audience for tokens for others
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExchangeRule {
+    /// the audience of the exchanged tokens
+    pub from : String,
+    /// the audiences the new tokens may be for
+    pub to : Vec<String>,
+}
+
+/// A registered client, without a secret since PKCE is required
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Client {
+    pub client_id : String,
+    /// shown on the login page, the client id if unset
+    #[serde(default)]
+    pub name : Option<String>,
+    /// exact urls the client may be redirected to, the `redirect_uri`
+    /// parameter can be left out if there's only one. Device clients
+    /// don't need any.
+    #[serde(default)]
+    pub redirect_uris : Vec<String>,
+    /// tokens for the client's audience are encrypted to this key
+    #[cfg(feature = "jwe")]
+    #[serde(default)]
+    pub encryption_key : Option<jwe::Key>,
+}
+
+impl Config {
+    pub(crate) fn client(&self, client_id : &str) -> Option<&Client> {
+        self.clients.iter().find(|c| c.client_id == client_id)
+    }
+
+    fn may_exchange(&self, from : &str, to : &str) -> bool {
+        self.token_exchange.iter()
+            .any(|rule| rule.from == from && rule.to.iter().any(|aud| aud == to))
+    }
+}
+
+/// the query of `GET /authorize`, also carried through the login form
+#[derive(Deserialize)]
+struct AuthorizeParams {
+    #[serde(default)]
+    response_type : String,
+    client_id : String,
+    redirect_uri : Option<String>,
+    code_challenge : Option<String>,
+    code_challenge_method : Option<String>,
+    state : Option<String>,
+    scope : Option<String>,
+}
+
+/// the fields posted to `POST /authorize`
+#[derive(Deserialize)]
+struct AuthorizeForm {
+    #[serde(flatten)]
+    params : AuthorizeParams,
+    /// see `html::csrf_token`
+    csrf : String,
+    name : String,
+    pass : Secret,
+}
+
+/// the fields posted to `POST /token`
+#[derive(Deserialize)]
+struct TokenForm {
+    grant_type : String,
+    client_id : Option<String>,
+    code : Option<String>,
+    redirect_uri : Option<String>,
+    code_verifier : Option<String>,
+    device_code : Option<String>,
+    subject_token : Option<String>,
+    subject_token_type : Option<String>,
+    audience : Option<String>,
+    requested_token_type : Option<String>,
+}
+
+/// the fields posted to `POST /device_authorization`
+#[derive(Deserialize)]
+struct DeviceAuthorizationForm {
+    client_id : String,
+}
+
+/// the fields posted to `POST /device`
+#[derive(Deserialize)]
+struct DeviceForm {
+    user_code : String,
+    /// see `html::csrf_token`
+    csrf : String,
+    name : String,
+    pass : Secret,
+}
+
+/// A validated authorization request
+struct Authorization<'a> {
+    client : &'a Client,
+    redirect_uri : &'a str,
+    code_challenge : String,
+}
+
+/// An error of the authorization endpoint, only sent to the client once
+/// its redirect uri is known to be registered
+enum AuthorizeError {
+    /// shown to the user, the redirect uri can't be trusted
+    Page(&'static str),
+    /// sent to the client's redirect uri
+    Redirect(String, &'static str),
+}
+
+fn config<'a>(server : &'a Server, req : &Request) -> Result<&'a Config> {
+    server.oauth.as_ref()
+        .ok_or_else(|| mux::MuxError::NotFound(req.uri().path().to_string()).into())
+}
+
+fn validate<'a>(config : &'a Config, params : &AuthorizeParams) -> std::result::Result<Authorization<'a>, AuthorizeError> {
+    let client = config.client(&params.client_id)
+        .ok_or(AuthorizeError::Page("unknown client"))?;
+
+    let redirect_uri = match (&params.redirect_uri, client.redirect_uris.as_slice()) {
+        (Some(uri), uris) => uris.iter().find(|u| *u == uri),
+        (None, [uri]) => Some(uri),
+        (None, _) => None,
+    }.ok_or(AuthorizeError::Page("invalid redirect uri"))?;
+
+    let redirect = |error| AuthorizeError::Redirect(redirect_uri.clone(), error);
+
+    if params.response_type != "code" {
+        return Err(redirect("unsupported_response_type"))
+    }
+
+    // only S256 is accepted, plain challenges protect nothing against an
+    // attacker who can read the authorization request
+    let code_challenge = match (&params.code_challenge, params.code_challenge_method.as_deref()) {
+        (Some(challenge), Some("S256")) if is_pkce_string(challenge) => challenge.clone(),
+        _ => return Err(redirect("invalid_request")),
+    };
+
+    Ok(Authorization{
+        client,
+        redirect_uri,
+        code_challenge,
+    })
+}
+
+/// RFC 7636 allows 43 to 128 unreserved characters in a verifier, an
+/// S256 challenge is always 43
+fn is_pkce_string(s : &str) -> bool {
+    (43..=128).contains(&s.len()) &&
+        s.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b))
+}
+
+/// `uri` with `params` added to its query
+fn with_query(uri : &str, params : &[(&str, &str)]) -> String {
+    let sep = if uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", uri, sep, serde_urlencoded::to_string(params).unwrap())
+}
+
+fn redirect(location : &str) -> Response {
+    http::Response::builder()
+        .status(http::StatusCode::SEE_OTHER)
+        .header(http::header::LOCATION, location)
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .body(hyper::Body::empty())
+        .unwrap()
+}
+
+fn authorize_error(err : AuthorizeError, state : Option<&str>) -> Response {
+    match err {
+        AuthorizeError::Page(msg) => {
+            html::page(http::StatusCode::BAD_REQUEST, format!(
+                "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>error</title></head><body><p>{}</p></body></html>",
+                msg,
+            ))
+        },
+        AuthorizeError::Redirect(uri, error) => {
+            let mut params = vec![("error", error)];
+            if let Some(state) = state {
+                params.push(("state", state));
+            }
+
+            redirect(&with_query(&uri, &params))
+        },
+    }
+}
+
+pub(crate) fn routes(server : &Arc<Server>, m : Router) -> Router {
+    let m = get_authorize(Arc::clone(server), m.named("get_authorize"));
+    let m = post_authorize(Arc::clone(server), m.named("post_authorize"));
+    let m = post_token(Arc::clone(server), m.named("post_token"));
+    let m = post_device_authorization(Arc::clone(server), m.named("post_device_authorization"));
+    let m = get_device(Arc::clone(server), m.named("get_device"));
+    post_device(Arc::clone(server), m.named("post_device"))
+}
+
+fn get_authorize(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "authorize"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .and_then(|req : Request, server : Arc<Server>| {
+            let config = config(&server, &req)?;
+            let params : AuthorizeParams = serde_urlencoded::from_str(req.uri().query().unwrap_or(""))
+                .map_err(|_| TransportError::BadRequest)?;
+
+            match validate(config, &params) {
+                Ok(authorization) => Ok(login_page(&authorization, &params, None, http::StatusCode::OK)),
+                Err(err) => Ok(authorize_error(err, params.state.as_deref())),
+            }
+        })
+    )
+}
+
+fn post_authorize(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "authorize"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let config = config(&server, &req)?;
+            let user_agent = user_agent(&req);
+            let addr = client_addr(&req);
+            let headers = req.headers().clone();
+            let form : AuthorizeForm = html::read_form(req).await?;
+
+            let authorization = match validate(config, &form.params) {
+                Ok(authorization) => authorization,
+                Err(err) => return Ok(authorize_error(err, form.params.state.as_deref())),
+            };
+
+            if let Err(err) = html::check_csrf(&headers, &form.csrf) {
+                let (_, status, message) = crate::error::http_error(err.code());
+                return Ok(login_page(&authorization, &form.params, Some(message), status))
+            }
+
+            let attempt = server.login_attempt(form.name.clone(), authorization.client.client_id.clone(), user_agent, addr).await?;
+
+            let login = async {
+                let mut user = server.check_password(&form.name, &form.pass).await?;
+
+                let now = unix_now();
+                server.record_login(&mut user, &attempt, now, true).await?;
+
+                let code = crypto::new_device_token();
+                server.database.insert_authorization_code(
+                    &crypto::hash_device_token(&code),
+                    &models::AuthorizationCode{
+                        client_id : attempt.aud.clone(),
+                        name : user.name,
+                        redirect_uri : form.params.redirect_uri.clone(),
+                        code_challenge : authorization.code_challenge.clone(),
+                        expires : now + config.code_lifetime as i64,
+                    },
+                ).await?;
+
+                Ok(code)
+            };
+
+            let res = server.hook_login(&attempt, login).await;
+            match (LoginOutcome::from(&res), res) {
+                (_, Ok(code)) => {
+                    let mut params = vec![("code", code.as_str())];
+                    if let Some(state) = &form.params.state {
+                        params.push(("state", state));
+                    }
+
+                    Ok(redirect(&with_query(authorization.redirect_uri, &params)))
+                },
+                (LoginOutcome::Failed, Err(err)) | (LoginOutcome::Denied, Err(err)) => {
+                    let (_, status, message) = crate::error::http_error(err.code());
+                    Ok(login_page(&authorization, &form.params, Some(message), status))
+                },
+                (_, Err(err)) => Err(err),
+            }
+        })
+    )
+}
+
+fn post_token(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "token"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let config = config(&server, &req)?;
+            let form : TokenForm = match html::read_form(req).await {
+                Ok(form) => form,
+                Err(_) => return Ok(token_error("invalid_request")),
+            };
+
+            let grant_type = form.grant_type.clone();
+            let grant = match grant_type.as_str() {
+                "authorization_code" => exchange_code(&server, config, form).await?,
+                DEVICE_CODE_GRANT => exchange_device_code(&server, config, form).await?,
+                TOKEN_EXCHANGE_GRANT => exchange_token(&server, config, form).await?,
+                _ => Err("unsupported_grant_type"),
+            };
+
+            let (token, user, duration) = match grant {
+                Ok(grant) => grant,
+                Err(error) => return Ok(token_error(error)),
+            };
+
+            let body = serde_json::to_string(&PostTokenResponse{
+                access_token : server.issue_token(&user, token, duration).await?,
+                token_type : "Bearer".to_string(),
+                expires_in : duration,
+                issued_token_type : Some(JWT_TOKEN_TYPE.to_string())
+                    .filter(|_| grant_type == TOKEN_EXCHANGE_GRANT),
+            })?;
+
+            Ok(http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header(http::header::CACHE_CONTROL, "no-store")
+                .body(body.into())
+                .unwrap())
+        })
+    )
+}
+
+/// the token a grant issues, its subject and its lifetime, or the grant's
+/// error code
+type Grant = std::result::Result<(crypto::Token, models::User, u64), &'static str>;
+
+/// a token for a user who just logged in to a client, unless they were
+/// deleted since
+async fn login_grant(server : &Server, config : &Config, name : String, client_id : String) -> Result<Grant> {
+    let user = match server.database.get_user_by_name(&name).await {
+        Ok(user) => user,
+        Err(Error::Storage(StorageError::UserNotFound(_))) => return Ok(Err("invalid_grant")),
+        Err(err) => return Err(err),
+    };
+    let aud_version = server.database.get_audience_version(&name, &client_id).await?;
+
+    Ok(Ok((crypto::Token{
+        iss : server.issuer.to_string(),
+        aud : client_id,
+        sub : name,
+        version : user.token_version,
+        aud_version,
+        acr : crypto::Assurance::Password,
+        act : None,
+        auth_time : None,
+        org : None,
+        extra : Default::default(),
+    }, user, config.token_duration)))
+}
+
+async fn exchange_code(server : &Server, config : &Config, form : TokenForm) -> Result<Grant> {
+    let (client_id, code, code_verifier) = match (form.client_id, form.code, form.code_verifier) {
+        (Some(client_id), Some(code), Some(code_verifier)) => (client_id, code, code_verifier),
+        _ => return Ok(Err("invalid_request")),
+    };
+
+    if config.client(&client_id).is_none() {
+        return Ok(Err("invalid_client"))
+    }
+
+    let now = unix_now();
+    let code = match server.database.take_authorization_code(&crypto::hash_device_token(&code), now).await? {
+        Some(code) => code,
+        None => return Ok(Err("invalid_grant")),
+    };
+
+    let challenge = ring::digest::digest(&ring::digest::SHA256, code_verifier.as_bytes());
+    let challenge = base64::encode_config(challenge, base64::URL_SAFE_NO_PAD);
+
+    // RFC 6749 section 4.1.3, the redirect uri is repeated if the
+    // authorization request had one, otherwise it's the client's only one
+    let redirect_uri = match (&code.redirect_uri, &form.redirect_uri) {
+        (Some(expected), Some(uri)) => uri == expected,
+        (Some(_), None) => false,
+        (None, Some(uri)) => config.client(&code.client_id)
+            .is_some_and(|client| client.redirect_uris.contains(uri)),
+        (None, None) => true,
+    };
+
+    let valid = code.expires >= now &&
+        code.client_id == client_id &&
+        redirect_uri &&
+        is_pkce_string(&code_verifier) &&
+        ring::constant_time::verify_slices_are_equal(challenge.as_bytes(), code.code_challenge.as_bytes()).is_ok();
+
+    if !valid || config.client(&code.client_id).is_none() {
+        return Ok(Err("invalid_grant"))
+    }
+
+    login_grant(server, config, code.name, code.client_id).await
+}
+
+async fn exchange_device_code(server : &Server, config : &Config, form : TokenForm) -> Result<Grant> {
+    let (client_id, device_code) = match (form.client_id, form.device_code) {
+        (Some(client_id), Some(device_code)) => (client_id, device_code),
+        _ => return Ok(Err("invalid_request")),
+    };
+
+    if config.client(&client_id).is_none() {
+        return Ok(Err("invalid_client"))
+    }
+
+    let now = unix_now();
+    let auth = match server.database.poll_device_authorization(&crypto::hash_device_token(&device_code), now).await? {
+        Some(auth) if auth.client_id == client_id => auth,
+        _ => return Ok(Err("invalid_grant")),
+    };
+
+    if auth.expires < now {
+        return Ok(Err("expired_token"))
+    }
+
+    match auth.name {
+        Some(name) => login_grant(server, config, name, auth.client_id).await,
+        None if auth.last_poll.is_some_and(|last| now - last < config.device_poll_interval as i64) => {
+            Ok(Err("slow_down"))
+        },
+        None => Ok(Err("authorization_pending")),
+    }
+}
+
+/// exchanges a valid token for a token for another audience, which is no
+/// stronger than it: the assurance, actor and session start are kept, and
+/// it expires no later than the exchanged token
+async fn exchange_token(server : &Server, config : &Config, form : TokenForm) -> Result<Grant> {
+    let (subject_token, audience) = match (form.subject_token, form.audience) {
+        (Some(subject_token), Some(audience)) => (subject_token, audience),
+        _ => return Ok(Err("invalid_request")),
+    };
+
+    let is_jwt = |ty : &str| ty == JWT_TOKEN_TYPE || ty == ACCESS_TOKEN_TYPE;
+    if !form.subject_token_type.as_deref().is_some_and(is_jwt) ||
+        !form.requested_token_type.as_deref().into_iter().all(is_jwt) {
+        return Ok(Err("invalid_request"))
+    }
+
+    let (subject, user) = match server.validate_token(&subject_token).await {
+        Ok(validated) => validated,
+        Err(Error::Auth(_)) => return Ok(Err("invalid_grant")),
+        Err(err) => return Err(err),
+    };
+
+    if !config.may_exchange(&subject.aud, &audience) {
+        return Ok(Err("invalid_target"))
+    }
+
+    #[derive(Deserialize)]
+    struct Exp {
+        exp : u64,
+    }
+
+    // validation checked the expiry, but doesn't return it
+    let exp = jsonwebtoken::dangerous_insecure_decode::<Exp>(&subject_token)
+        .map_err(|_| AuthError::Unauthorized)?
+        .claims
+        .exp;
+
+    let auth_time = subject.auth_time.unwrap_or(0);
+    let expires = exp.min(auth_time.saturating_add(server.max_session));
+    let now = unix_now() as u64;
+    if expires <= now {
+        return Ok(Err("invalid_grant"))
+    }
+
+    let aud_version = server.database.get_audience_version(&subject.sub, &audience).await?;
+    let org = match &subject.org {
+        Some(org) => Some(server.org_claim(&org.name, &subject.sub).await?),
+        None => None,
+    };
+
+    Ok(Ok((crypto::Token{
+        iss : server.issuer.to_string(),
+        aud : audience,
+        sub : subject.sub,
+        version : user.token_version,
+        aud_version,
+        acr : subject.acr,
+        act : subject.act,
+        auth_time : Some(auth_time),
+        org,
+        extra : Default::default(),
+    }, user, config.token_duration.min(expires - now))))
+}
+
+fn post_device_authorization(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "device_authorization"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let config = config(&server, &req)?;
+            let verification_uri = config.verification_uri.as_ref()
+                .ok_or_else(|| mux::MuxError::NotFound(req.uri().path().to_string()))?;
+
+            let form : DeviceAuthorizationForm = match html::read_form(req).await {
+                Ok(form) => form,
+                Err(_) => return Ok(token_error("invalid_request")),
+            };
+
+            if config.client(&form.client_id).is_none() {
+                return Ok(token_error("invalid_client"))
+            }
+
+            let device_code = crypto::new_device_token();
+            let user_code = new_user_code();
+
+            server.database.insert_device_authorization(
+                &crypto::hash_device_token(&device_code),
+                &user_code,
+                &form.client_id,
+                unix_now() + config.device_code_lifetime as i64,
+            ).await?;
+
+            let user_code = display_user_code(&user_code);
+            let body = serde_json::to_string(&PostDeviceAuthorizationResponse{
+                device_code,
+                verification_uri_complete : with_query(verification_uri, &[("user_code", &user_code)]),
+                user_code,
+                verification_uri : verification_uri.clone(),
+                expires_in : config.device_code_lifetime,
+                interval : config.device_poll_interval,
+            })?;
+
+            Ok(http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header(http::header::CACHE_CONTROL, "no-store")
+                .body(body.into())
+                .unwrap())
+        })
+    )
+}
+
+fn get_device(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "device"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let config = config(&server, &req)?;
+            if config.verification_uri.is_none() {
+                return Err(mux::MuxError::NotFound(req.uri().path().to_string()).into())
+            }
+
+            // the code is checked before the login form is shown, so it
+            // can show which client is asking. Whatever users type in
+            // besides letters is dropped.
+            let user_code = match query_param(&req, "user_code") {
+                Some(user_code) if !user_code.is_empty() => user_code,
+                _ => return Ok(user_code_page(None, http::StatusCode::OK)),
+            };
+
+            match server.database.get_device_authorization(&normalize_user_code(&user_code), unix_now()).await? {
+                Some(auth) => Ok(device_page(config, &auth, None, http::StatusCode::OK)),
+                None => Ok(user_code_page(Some("invalid or expired code"), http::StatusCode::BAD_REQUEST)),
+            }
+        })
+    )
+}
+
+fn post_device(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "device"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let config = config(&server, &req)?;
+            if config.verification_uri.is_none() {
+                return Err(mux::MuxError::NotFound(req.uri().path().to_string()).into())
+            }
+
+            let user_agent = user_agent(&req);
+            let addr = client_addr(&req);
+            let headers = req.headers().clone();
+            let form : DeviceForm = html::read_form(req).await?;
+
+            let user_code = normalize_user_code(&form.user_code);
+            let auth = match server.database.get_device_authorization(&user_code, unix_now()).await? {
+                Some(auth) => auth,
+                None => {
+                    return Ok(user_code_page(Some("invalid or expired code"), http::StatusCode::BAD_REQUEST))
+                },
+            };
+
+            if let Err(err) = html::check_csrf(&headers, &form.csrf) {
+                let (_, status, message) = crate::error::http_error(err.code());
+                return Ok(device_page(config, &auth, Some(message), status))
+            }
+
+            let attempt = server.login_attempt(form.name.clone(), auth.client_id.clone(), user_agent, addr).await?;
+
+            let login = async {
+                let mut user = server.check_password(&form.name, &form.pass).await?;
+
+                let now = unix_now();
+                server.record_login(&mut user, &attempt, now, true).await?;
+
+                server.database.approve_device_authorization(&user_code, &user.name, now).await
+            };
+
+            let res = server.hook_login(&attempt, login).await;
+            match (LoginOutcome::from(&res), res) {
+                (_, Ok(true)) => Ok(html::page(http::StatusCode::OK, format!(
+                    concat!(
+                        "<!DOCTYPE html>\n",
+                        r#"<html><head><meta charset="utf-8"><title>device approved</title></head><body>"#,
+                        r#"<p><b>{}</b> is logged in, you can go back to your device</p>"#,
+                        r#"</body></html>"#,
+                    ),
+                    html::escape(client_name(config, &auth.client_id)),
+                ))),
+                // expired while the user was logging in
+                (_, Ok(false)) => {
+                    Ok(user_code_page(Some("invalid or expired code"), http::StatusCode::BAD_REQUEST))
+                },
+                (LoginOutcome::Failed, Err(err)) | (LoginOutcome::Denied, Err(err)) => {
+                    let (_, status, message) = crate::error::http_error(err.code());
+                    Ok(device_page(config, &auth, Some(message), status))
+                },
+                (_, Err(err)) => Err(err),
+            }
+        })
+    )
+}
+
+/// user codes use consonants only, so they don't spell words, see RFC 8628
+/// section 6.1
+const USER_CODE_CHARS : &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LEN : usize = 8;
+
+fn new_user_code() -> String {
+    (0..USER_CODE_LEN)
+        .map(|_| USER_CODE_CHARS[OsRng.gen_range(0..USER_CODE_CHARS.len())] as char)
+        .collect()
+}
+
+/// splits the code in two halves, e.g. `WDJB-MJHT`
+fn display_user_code(code : &str) -> String {
+    let (a, b) = code.split_at(code.len() / 2);
+    format!("{}-{}", a, b)
+}
+
+/// undoes `display_user_code`, and whatever users type instead of it
+fn normalize_user_code(code : &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+fn client_name<'a>(config : &'a Config, client_id : &'a str) -> &'a str {
+    config.client(client_id)
+        .and_then(|client| client.name.as_deref())
+        .unwrap_or(client_id)
+}
+
+/// an error of the token endpoint, as RFC 6749 section 5.2 formats them
+fn token_error(error : &str) -> Response {
+    http::Response::builder()
+        .status(http::StatusCode::BAD_REQUEST)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .body(serde_json::json!({ "error" : error }).to_string().into())
+        .unwrap()
+}
+
+/// the login form, posting to `POST /authorize` along with the request's
+/// parameters and a fresh csrf token
+fn login_page(
+    authorization : &Authorization,
+    params : &AuthorizeParams,
+    error : Option<&str>,
+    status : http::StatusCode,
+) -> Response {
+    let error = error
+        .map(|msg| format!(r#"<p style="color: #b00">{}</p>"#, html::escape(msg)))
+        .unwrap_or_default();
+
+    let fields = [
+        ("response_type", Some(&params.response_type)),
+        ("client_id", Some(&params.client_id)),
+        ("redirect_uri", params.redirect_uri.as_ref()),
+        ("code_challenge", params.code_challenge.as_ref()),
+        ("code_challenge_method", params.code_challenge_method.as_ref()),
+        ("state", params.state.as_ref()),
+        ("scope", params.scope.as_ref()),
+    ];
+    let hidden = fields.iter()
+        .filter_map(|(name, value)| Some((name, (*value)?)))
+        .map(|(name, value)| format!(r#"<input type="hidden" name="{}" value="{}">"#, name, html::escape(value)))
+        .collect::<String>();
+
+    let client = authorization.client;
+    let csrf = html::csrf_token();
+
+    let page = html::page(status, format!(
+        concat!(
+            "<!DOCTYPE html>\n",
+            r#"<html><head><meta charset="utf-8"><title>log in</title></head><body>"#,
+            r#"<p>log in to continue to <b>{client}</b></p>"#,
+            r#"{error}"#,
+            r#"<form method="post" action="authorize">"#,
+            r#"{hidden}"#,
+            r#"<input type="hidden" name="csrf" value="{csrf}">"#,
+            r#"<input name="name" placeholder="name" autocomplete="username" required autofocus>"#,
+            r#"<input name="pass" type="password" placeholder="password" autocomplete="current-password" required>"#,
+            r#"<button>log in</button>"#,
+            r#"</form></body></html>"#,
+        ),
+        client = html::escape(client.name.as_deref().unwrap_or(&client.client_id)),
+        error = error,
+        hidden = hidden,
+        csrf = csrf,
+    ));
+
+    html::with_csrf(page, &csrf)
+}
+
+/// the form asking for the code shown by the device, submitted to
+/// `GET /device`
+fn user_code_page(error : Option<&str>, status : http::StatusCode) -> Response {
+    let error = error
+        .map(|msg| format!(r#"<p style="color: #b00">{}</p>"#, html::escape(msg)))
+        .unwrap_or_default();
+
+    html::page(status, format!(
+        concat!(
+            "<!DOCTYPE html>\n",
+            r#"<html><head><meta charset="utf-8"><title>log in a device</title></head><body>"#,
+            r#"<p>enter the code shown on your device</p>"#,
+            r#"{error}"#,
+            r#"<form method="get" action="device">"#,
+            r#"<input name="user_code" placeholder="code" autocomplete="off" required autofocus>"#,
+            r#"<button>continue</button>"#,
+            r#"</form></body></html>"#,
+        ),
+        error = error,
+    ))
+}
+
+/// the login form for the pending authorization `auth`, naming the client
+/// asking for it, with a fresh csrf token
+fn device_page(
+    config : &Config,
+    auth : &models::DeviceAuthorization,
+    error : Option<&str>,
+    status : http::StatusCode,
+) -> Response {
+    let error = error
+        .map(|msg| format!(r#"<p style="color: #b00">{}</p>"#, html::escape(msg)))
+        .unwrap_or_default();
+    let csrf = html::csrf_token();
+
+    let page = html::page(status, format!(
+        concat!(
+            "<!DOCTYPE html>\n",
+            r#"<html><head><meta charset="utf-8"><title>log in a device</title></head><body>"#,
+            r#"<p>log in to let <b>{client}</b> in on the device showing <b>{user_code}</b>. "#,
+            r#"Only continue if you started this on that device.</p>"#,
+            r#"{error}"#,
+            r#"<form method="post" action="device">"#,
+            r#"<input type="hidden" name="user_code" value="{user_code}">"#,
+            r#"<input type="hidden" name="csrf" value="{csrf}">"#,
+            r#"<input name="name" placeholder="name" autocomplete="username" required autofocus>"#,
+            r#"<input name="pass" type="password" placeholder="password" autocomplete="current-password" required>"#,
+            r#"<button>log in</button>"#,
+            r#"</form></body></html>"#,
+        ),
+        client = html::escape(client_name(config, &auth.client_id)),
+        user_code = display_user_code(&auth.user_code),
+        error = error,
+        csrf = csrf,
+    ));
+
+    html::with_csrf(page, &csrf)
+}
@@ -0,0 +1,180 @@
+//! Organizations group users, e.g. those of one customer, and are managed
+//! by their own admins rather than the server's. Members have groups within
+//! their organization, and tokens scoped to one with `PostLoginRequest::org`
+//! carry an `org` claim holding the member's place in it.
+//!
+//! Organizations are created with `authn-utils add-org`, their members are
+//! managed under `/orgs/:org/members` by admins of the organization, or by
+//! anyone the `policy` allows. Unlike the admin routes, these aren't
+//! restricted by `Config::admin_peers`. Org admins only change existing
+//! members, new ones join through an invite for the organization.
+//!
+//! Changing or removing a member invalidates their tokens scoped to the
+//! organization, through the claim's `crypto::Org::version`, and leaves their
+//! other tokens alone.
+
+use std::sync::Arc;
+
+use hyper::Body;
+use hyper::body::Buf;
+use plumb::PipeExt;
+use http_mux::{route,mux};
+
+use crate::api::{
+    OrgMemberInfo,
+    GetOrgMembersResponse,
+    PutOrgMemberRequest,
+};
+use crate::events::Event;
+use crate::models;
+use crate::policy::Action;
+use crate::server::{
+    TransportError,
+    StorageError,
+    Server,
+    Router,
+    Request,
+    Response,
+    AuthError,
+    Error,
+    Result,
+    Segment,
+};
+
+pub(crate) fn routes(server : &Arc<Server>, m : Router) -> Router {
+    let m = get_org_members(Arc::clone(server), m.named("get_org_members"));
+    let m = put_org_member(Arc::clone(server), m.named("put_org_member"));
+    delete_org_member(Arc::clone(server), m.named("delete_org_member"))
+}
+
+/// authenticates the request, requiring the user to be an admin of `org`
+/// or the policy to allow `action`. Returns whether they're an org admin.
+async fn authenticate_org_admin(
+    server : &Server,
+    req : &Request,
+    org : &str,
+    action : Action,
+    target : Option<&str>,
+) -> Result<(models::User, bool)> {
+    let (token, user) = server.authenticate(req).await?;
+
+    if token.act.is_some() {
+        return Err(AuthError::Forbidden.into())
+    }
+
+    let org_admin = authorize_org_admin(server, &user, org, action, target).await?;
+
+    Ok((user, org_admin))
+}
+
+/// requires `user` to be an admin of `org` or the policy to allow
+/// `action`. Returns whether they're an org admin.
+pub(crate) async fn authorize_org_admin(
+    server : &Server,
+    user : &models::User,
+    org : &str,
+    action : Action,
+    target : Option<&str>,
+) -> Result<bool> {
+    // a missing org is only reported to those who may manage it
+    match server.database.get_org_member(org, &user.name).await {
+        Ok(Some(member)) if member.admin => return Ok(true),
+        Ok(_) | Err(Error::Storage(StorageError::OrgNotFound(_))) => {},
+        Err(err) => return Err(err),
+    }
+
+    server.authorize(user, action, target).await?;
+
+    Ok(false)
+}
+
+fn get_org_members(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "orgs" / Segment / "members"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, Segment(org) : Segment, server : Arc<Server>| async move {
+            let (user, org_admin) = authenticate_org_admin(&server, &req, &org, Action::ListOrgMembers, None).await?;
+
+            let mut members = Vec::new();
+            for member in server.database.list_org_members(&org).await? {
+                // only the members the policy lets a non member see
+                if !org_admin && !server.is_authorized(&user, Action::ListOrgMembers, Some(&member.name)).await? {
+                    continue
+                }
+
+                members.push(OrgMemberInfo{
+                    groups : member.groups.split_whitespace().map(String::from).collect(),
+                    name : member.name,
+                    admin : member.admin,
+                });
+            }
+
+            let s = serde_json::to_string(&GetOrgMembersResponse{ members })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn put_org_member(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(PUT / "orgs" / Segment / "members" / Segment),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, Segment(org) : Segment, Segment(name) : Segment, server : Arc<Server>| async move {
+            let (admin, org_admin) = authenticate_org_admin(&server, &req, &org, Action::ManageOrgMembers, Some(&name)).await?;
+
+            // org admins change existing members, new ones join through
+            // invites, unless the policy lets the admin add them
+            let member = server.database.get_org_member(&org, &name).await?;
+            if member.is_none() && org_admin && !server.is_authorized(&admin, Action::ManageOrgMembers, Some(&name)).await? {
+                return Err(AuthError::Forbidden.into())
+            }
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PutOrgMemberRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            if req.groups.iter().any(|group| group.is_empty() || group.contains(char::is_whitespace)) {
+                return Err(TransportError::BadRequest.into())
+            }
+
+            let groups = req.groups.join(" ");
+            let replaced = server.database.set_org_member(&org, &name, req.admin, &groups).await?;
+
+            server.audit(Some(&admin.name), "set-org-member", Some(&name), Some(&org)).await?;
+
+            // tokens scoped to the org hold the old membership
+            if replaced {
+                server.publish(Event::OrgTokensInvalidated{ name, org }).await;
+            }
+
+            Ok(no_content())
+        })
+    )
+}
+
+fn delete_org_member(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(DELETE / "orgs" / Segment / "members" / Segment),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, Segment(org) : Segment, Segment(name) : Segment, server : Arc<Server>| async move {
+            let (admin, _) = authenticate_org_admin(&server, &req, &org, Action::ManageOrgMembers, Some(&name)).await?;
+
+            if server.database.delete_org_member(&org, &name).await? {
+                server.audit(Some(&admin.name), "remove-org-member", Some(&name), Some(&org)).await?;
+                server.publish(Event::OrgTokensInvalidated{ name, org }).await;
+            }
+
+            Ok(no_content())
+        })
+    )
+}
+
+fn no_content() -> Response {
+    http::response::Builder::new()
+        .status(http::StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
@@ -0,0 +1,257 @@
+//! Checks passwords against the host's PAM stack instead of the password
+//! hashes in the database, so system accounts can log in with their unix
+//! password. Users still need a row in the database for their roles and
+//! token versions, `Config::create_users` adds it on their first login.
+//!
+//! Privileges: most stacks end in `pam_unix`, which needs to read
+//! `/etc/shadow` to check anyone's password but the caller's own. Rather
+//! than running the server as root, run it in the `shadow` group, or point
+//! `service` at a stack which doesn't need local files (e.g. `pam_sss`).
+//! The service file (`/etc/pam.d/<service>`) only needs `auth` and
+//! `account` entries, sessions are never opened. PAM modules may also
+//! lock accounts after failed logins, on top of `ratelimit`.
+//!
+//! Password hashes: while PAM is configured, the hashes in the database
+//! are only checked for break glass accounts. Users created by PAM logins
+//! get `PASS_HASH`, which is never rehashed with
+//! `server::Config::password_hash`, since the password isn't checked
+//! against it, and never matches a password in the
+//! `server::Config::password_history`. Passwords set over the api or with
+//! `authn-utils` are hashed and kept in the history as usual, but only
+//! take effect if PAM is turned off. Changing a unix password is up to
+//! the host.
+//!
+//! libpam is loaded at runtime, so building this needs no PAM headers.
+
+use std::ffi::{c_void, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+use serde::Deserialize;
+use libloading::Library;
+use zeroize::Zeroize;
+
+use crate::server::{
+    Error,
+    AuthError,
+    ConfigError,
+};
+use crate::logging;
+
+/// the password hash of users created by PAM logins, no password matches
+/// it if PAM is turned off
+pub const PASS_HASH : &str = "!";
+
+const DEFAULT_SERVICE : &str = "authn";
+const DEFAULT_LIBRARY : &str = "libpam.so.0";
+
+fn default_service() -> String {
+    DEFAULT_SERVICE.to_string()
+}
+
+fn default_library() -> String {
+    DEFAULT_LIBRARY.to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// name of the PAM service, i.e. the file in `/etc/pam.d`
+    #[serde(default = "default_service")]
+    pub service : String,
+    /// add users missing from the database when PAM accepts their
+    /// password, otherwise they have to be added with `authn-utils`
+    #[serde(default)]
+    pub create_users : bool,
+    /// file name or path of libpam
+    #[serde(default = "default_library")]
+    pub library : String,
+}
+
+#[repr(C)]
+struct Message {
+    style : c_int,
+    msg : *const c_char,
+}
+
+#[repr(C)]
+struct Reply {
+    resp : *mut c_char,
+    retcode : c_int,
+}
+
+type ConvFn = extern "C" fn(c_int, *mut *const Message, *mut *mut Reply, *mut c_void) -> c_int;
+
+#[repr(C)]
+struct Conversation {
+    conv : ConvFn,
+    appdata : *mut c_void,
+}
+
+type Start = unsafe extern "C" fn(*const c_char, *const c_char, *const Conversation, *mut *mut c_void) -> c_int;
+type Authenticate = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type AcctMgmt = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type End = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+
+const PAM_SUCCESS : c_int = 0;
+const PAM_BUF_ERR : c_int = 5;
+const PAM_CONV_ERR : c_int = 19;
+const PAM_PROMPT_ECHO_OFF : c_int = 1;
+const PAM_PROMPT_ECHO_ON : c_int = 2;
+const PAM_ERROR_MSG : c_int = 3;
+const PAM_TEXT_INFO : c_int = 4;
+const PAM_SILENT : c_int = 0x8000;
+const PAM_DISALLOW_NULL_AUTHTOK : c_int = 0x1;
+
+/// Checks credentials with the host's PAM stack
+pub struct Pam {
+    service : CString,
+    create_users : bool,
+    start : Start,
+    authenticate : Authenticate,
+    acct_mgmt : AcctMgmt,
+    end : End,
+    /// keeps the functions above loaded
+    _library : Library,
+}
+
+fn load_error(err : libloading::Error) -> Error {
+    ConfigError::Pam(err.to_string()).into()
+}
+
+/// what the conversation answers prompts with
+struct Credentials {
+    name : CString,
+    pass : CString,
+}
+
+/// answers password prompts with the password and other prompts with the
+/// user name, messages are ignored. Replies are allocated with `calloc`
+/// since PAM frees them.
+extern "C" fn conversation(
+    n : c_int,
+    messages : *mut *const Message,
+    replies : *mut *mut Reply,
+    appdata : *mut c_void,
+) -> c_int {
+    if n <= 0 || messages.is_null() || replies.is_null() || appdata.is_null() {
+        return PAM_CONV_ERR
+    }
+
+    // SAFETY: PAM passes `n` messages and the `Credentials` given to
+    // `pam_start`, which outlive the transaction
+    unsafe {
+        let credentials = &*(appdata as *const Credentials);
+        let out = libc::calloc(n as usize, std::mem::size_of::<Reply>()) as *mut Reply;
+        if out.is_null() {
+            return PAM_BUF_ERR
+        }
+
+        for i in 0..n as usize {
+            let message = &**messages.add(i);
+            let answer = match message.style {
+                PAM_PROMPT_ECHO_OFF => Some(&credentials.pass),
+                PAM_PROMPT_ECHO_ON => Some(&credentials.name),
+                PAM_ERROR_MSG | PAM_TEXT_INFO => None,
+                _ => {
+                    free_replies(out, i);
+                    return PAM_CONV_ERR
+                },
+            };
+
+            if let Some(answer) = answer {
+                let resp = libc::strdup(answer.as_ptr());
+                if resp.is_null() {
+                    free_replies(out, i);
+                    return PAM_BUF_ERR
+                }
+                (*out.add(i)).resp = resp;
+            }
+        }
+
+        *replies = out;
+    }
+
+    PAM_SUCCESS
+}
+
+/// frees the first `n` replies and the array, wiping the answers
+unsafe fn free_replies(replies : *mut Reply, n : usize) {
+    for i in 0..n {
+        let resp = (*replies.add(i)).resp;
+        if !resp.is_null() {
+            ptr::write_bytes(resp, 0, libc::strlen(resp));
+            libc::free(resp as *mut c_void);
+        }
+    }
+    libc::free(replies as *mut c_void);
+}
+
+impl Pam {
+    pub fn new(config : Config) -> Result<Self, Error> {
+        let service = CString::new(config.service)
+            .map_err(|_| ConfigError::Pam("service name contains a nul byte".to_string()))?;
+
+        // SAFETY: libpam has no initializers, and the symbols are declared
+        // with their signatures from security/pam_appl.h
+        unsafe {
+            let library = Library::new(&config.library).map_err(load_error)?;
+
+            Ok(Self{
+                service,
+                create_users : config.create_users,
+                start : *library.get(b"pam_start\0").map_err(load_error)?,
+                authenticate : *library.get(b"pam_authenticate\0").map_err(load_error)?,
+                acct_mgmt : *library.get(b"pam_acct_mgmt\0").map_err(load_error)?,
+                end : *library.get(b"pam_end\0").map_err(load_error)?,
+                _library : library,
+            })
+        }
+    }
+
+    pub fn create_users(&self) -> bool {
+        self.create_users
+    }
+
+    /// checks the password and that the account may log in (not expired
+    /// or locked). PAM modules may sleep after a failure, so run this on a
+    /// blocking thread.
+    pub fn check(&self, name : &str, pass : &str) -> Result<(), Error> {
+        let credentials = CString::new(name)
+            .and_then(|name| Ok(Credentials{ name, pass : CString::new(pass)? }))
+            .map_err(|_| AuthError::LoginFailed)?;
+
+        let conv = Conversation{
+            conv : conversation,
+            appdata : &credentials as *const Credentials as *mut c_void,
+        };
+
+        let mut handle = ptr::null_mut();
+
+        // SAFETY: `conv` and `credentials` outlive the transaction, which
+        // ends before returning
+        let status = unsafe {
+            let status = (self.start)(self.service.as_ptr(), credentials.name.as_ptr(), &conv, &mut handle);
+            if status != PAM_SUCCESS {
+                logging::error!("pam_start failed with {}", status);
+                return Err(AuthError::LoginFailed.into())
+            }
+
+            let mut status = (self.authenticate)(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            if status == PAM_SUCCESS {
+                status = (self.acct_mgmt)(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            }
+
+            (self.end)(handle, status);
+            status
+        };
+
+        credentials.pass.into_bytes().zeroize();
+
+        if status != PAM_SUCCESS {
+            return Err(AuthError::LoginFailed.into())
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+//! The local identity of unix socket callers, from the kernel rather than
+//! a token. The listener stores the `PeerCredentials` of each connection in
+//! its requests' extensions, where handlers and `Policy` read them. On
+//! windows there are none, so a `Policy` refuses every request.
+
+use serde::Deserialize;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use crate::server::{
+    Error,
+    AuthError,
+    ConfigError,
+    Request,
+};
+
+const GROUP_FILE : &str = "/etc/group";
+
+/// The process on the other end of a unix socket connection, as it was
+/// when it connected
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct PeerCredentials {
+    pub uid : u32,
+    /// the primary group, supplementary groups aren't passed over sockets
+    pub gid : u32,
+    /// not available on every platform
+    pub pid : Option<i32>,
+}
+
+impl PeerCredentials {
+    #[cfg(unix)]
+    pub fn of_stream(stream : &UnixStream) -> Option<Self> {
+        let cred = stream.peer_cred().ok()?;
+
+        Some(Self{
+            uid : cred.uid(),
+            gid : cred.gid(),
+            pid : cred.pid(),
+        })
+    }
+
+    /// the credentials the listener stored in the request, none for
+    /// requests which didn't come over a socket, e.g. `LocalTransport`
+    pub fn of_request(req : &Request) -> Option<Self> {
+        req.extensions().get::<Self>().copied()
+    }
+}
+
+/// Who may call a set of routes, matching either their user or group
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub uids : Vec<u32>,
+    /// group names from `/etc/group`, or numeric ids
+    #[serde(default)]
+    pub groups : Vec<String>,
+}
+
+/// A `Config` with its groups resolved
+pub struct Policy {
+    uids : Vec<u32>,
+    gids : Vec<u32>,
+}
+
+impl Policy {
+    pub fn new(config : Config) -> Result<Self, Error> {
+        let needs_lookup = config.groups.iter().any(|group| group.parse::<u32>().is_err());
+        let group_file = if needs_lookup {
+            std::fs::read_to_string(GROUP_FILE)?
+        } else {
+            String::new()
+        };
+
+        let gids = config.groups.iter()
+            .map(|group| group.parse().ok()
+                .or_else(|| group_id(&group_file, group))
+                .ok_or_else(|| ConfigError::UnknownGroup(group.clone()).into()))
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self{
+            uids : config.uids,
+            gids,
+        })
+    }
+
+    pub fn allows(&self, peer : &PeerCredentials) -> bool {
+        self.uids.contains(&peer.uid) || self.gids.contains(&peer.gid)
+    }
+
+    /// fails with `AuthError::Forbidden` unless the request came from an
+    /// allowed peer
+    pub fn check(&self, req : &Request) -> Result<(), Error> {
+        match PeerCredentials::of_request(req) {
+            Some(peer) if self.allows(&peer) => Ok(()),
+            _ => Err(AuthError::Forbidden.into()),
+        }
+    }
+}
+
+/// the id of `name` in the contents of `/etc/group`
+fn group_id(group_file : &str, name : &str) -> Option<u32> {
+    group_file.lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.first() == Some(&name))
+        .and_then(|fields| fields.get(2)?.parse().ok())
+}
@@ -0,0 +1,236 @@
+//! Decides who may take the actions of the admin routes. Built in, the
+//! `admin` role may take every action but impersonation, which takes the
+//! `impersonate` role. `Rule`s in the config grant more, e.g. letting a
+//! helpdesk role reset passwords of the users of one realm:
+//!
+//! ```json
+//! { "roles" : ["helpdesk"], "actions" : ["reset-password"], "users" : ["*@eng.example.com"] }
+//! ```
+//!
+//! No rule lets anyone reset the password of, or revoke the tokens of, a
+//! user holding a role they lack, since that would let them take over the
+//! role.
+//!
+//! An `Evaluator`, e.g. a client of an external policy engine, is asked
+//! before the rules and may allow or deny outright, or leave the decision
+//! to them.
+
+use serde::Deserialize;
+
+use crate::server::{
+    Result,
+    AuthError,
+    HookFuture,
+    ADMIN_ROLE,
+    IMPERSONATE_ROLE,
+};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    ReadStats,
+    ReadAudit,
+    ListUsers,
+    ResetPassword,
+    RevokeTokens,
+    Impersonate,
+    /// of an organization, see `orgs`
+    ListOrgMembers,
+    ManageOrgMembers,
+    /// see `invites`, the target is the name an invite is for
+    CreateInvites,
+    /// turning maintenance mode on or off
+    Maintenance,
+}
+
+impl Action {
+    /// whether the action takes over the target, so the actor must hold
+    /// all of its roles
+    pub fn takes_over(&self) -> bool {
+        matches!(self, Action::ResetPassword | Action::RevokeTokens)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::ReadStats => "read-stats",
+            Action::ReadAudit => "read-audit",
+            Action::ListUsers => "list-users",
+            Action::ResetPassword => "reset-password",
+            Action::RevokeTokens => "revoke-tokens",
+            Action::Impersonate => "impersonate",
+            Action::ListOrgMembers => "list-org-members",
+            Action::ManageOrgMembers => "manage-org-members",
+            Action::CreateInvites => "create-invites",
+            Action::Maintenance => "maintenance",
+        }
+    }
+}
+
+/// An action someone wants to take, as passed to `Evaluator`s
+#[derive(Debug,Clone)]
+pub struct AccessRequest {
+    /// the name of the authenticated user
+    pub actor : String,
+    pub roles : Vec<String>,
+    pub action : Action,
+    /// the user acted on. For `ListUsers` and `ListOrgMembers` each listed
+    /// user is checked in turn, and none means any user.
+    pub target : Option<String>,
+    /// the roles of the target, if `Action::takes_over` it, empty otherwise
+    pub target_roles : Vec<String>,
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+    /// leave the decision to the rules
+    Abstain,
+}
+
+/// Decides access before the rules, typically by asking an external
+/// policy engine. Errors fail the request rather than falling back to the
+/// rules.
+pub trait Evaluator : Send + Sync {
+    fn evaluate(&self, req : &AccessRequest) -> HookFuture<Result<Decision>>;
+}
+
+impl<F, Fut> Evaluator for F
+where
+    F : Fn(AccessRequest) -> Fut + Send + Sync,
+    Fut : std::future::Future<Output = Result<Decision>> + Send + 'static,
+{
+    fn evaluate(&self, req : &AccessRequest) -> HookFuture<Result<Decision>> {
+        Box::pin(self(req.clone()))
+    }
+}
+
+/// Lets users with any of `roles` take `actions`
+#[derive(Debug,Clone,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub roles : Vec<String>,
+    pub actions : Vec<Action>,
+    /// patterns of the users which may be acted on, where `*` matches any
+    /// run of characters. Any user if empty.
+    #[serde(default)]
+    pub users : Vec<String>,
+}
+
+impl Rule {
+    fn allows(&self, req : &AccessRequest) -> bool {
+        self.actions.contains(&req.action)
+            && self.roles.iter().any(|role| req.roles.contains(role))
+            && match &req.target {
+                Some(target) => self.users.is_empty()
+                    || self.users.iter().any(|pattern| glob(pattern, target)),
+                None => true,
+            }
+            && req.target_roles.iter().all(|role| req.roles.contains(role))
+    }
+}
+
+#[derive(Debug,Clone,Default,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// grants on top of the built in ones
+    #[serde(default)]
+    pub rules : Vec<Rule>,
+}
+
+/// The built in grants and those of a `Config`
+pub struct Policy {
+    rules : Vec<Rule>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl Policy {
+    pub fn new(config : Config) -> Self {
+        let mut rules = vec![
+            Rule{
+                roles : vec![ADMIN_ROLE.to_string()],
+                actions : vec![
+                    Action::ReadStats,
+                    Action::ReadAudit,
+                    Action::ListUsers,
+                    Action::ResetPassword,
+                    Action::RevokeTokens,
+                    Action::ListOrgMembers,
+                    Action::ManageOrgMembers,
+                    Action::CreateInvites,
+                    Action::Maintenance,
+                ],
+                users : vec![],
+            },
+            Rule{
+                roles : vec![IMPERSONATE_ROLE.to_string()],
+                actions : vec![Action::Impersonate],
+                users : vec![],
+            },
+        ];
+        rules.extend(config.rules);
+
+        Self{ rules }
+    }
+
+    pub fn allows(&self, req : &AccessRequest) -> bool {
+        self.rules.iter().any(|rule| rule.allows(req))
+    }
+
+    /// asks `evaluator` first, if any, then the rules
+    pub async fn decide(&self, evaluator : Option<&dyn Evaluator>, req : &AccessRequest) -> Result<bool> {
+        let decision = match evaluator {
+            Some(evaluator) => evaluator.evaluate(req).await?,
+            None => Decision::Abstain,
+        };
+
+        Ok(match decision {
+            Decision::Allow => true,
+            Decision::Deny => false,
+            Decision::Abstain => self.allows(req),
+        })
+    }
+
+    /// like `decide`, failing with `AuthError::Forbidden` if access is
+    /// denied
+    pub async fn check(&self, evaluator : Option<&dyn Evaluator>, req : &AccessRequest) -> Result<()> {
+        if !self.decide(evaluator, req).await? {
+            return Err(AuthError::Forbidden.into())
+        }
+
+        Ok(())
+    }
+}
+
+/// matches `name` against `pattern`, where `*` matches any run of
+/// characters
+fn glob(pattern : &str, name : &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one part
+    let first = parts.next().unwrap();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // no `*`
+        None => return rest.is_empty(),
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.len() >= last.len() && rest.ends_with(last)
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::logging;
+#[cfg(feature = "redis")]
+use crate::redis;
+use crate::server::{
+    AuthError,
+    HookFuture,
+    LoginAttempt,
+    LoginHook,
+    LoginOutcome,
+    Error,
+};
+
+const DEFAULT_MAX_FAILURES : u64 = 10;
+const DEFAULT_WINDOW : u64 = 60 * 15;
+const DEFAULT_DISCOVERY_MAX_REQUESTS : u64 = 60;
+const DEFAULT_DISCOVERY_WINDOW : u64 = 60;
+
+fn default_max_failures() -> u64 {
+    DEFAULT_MAX_FAILURES
+}
+
+fn default_window() -> u64 {
+    DEFAULT_WINDOW
+}
+
+fn default_discovery_max_requests() -> u64 {
+    DEFAULT_DISCOVERY_MAX_REQUESTS
+}
+
+fn default_discovery_window() -> u64 {
+    DEFAULT_DISCOVERY_WINDOW
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// failed logins for a user from one address after which further
+    /// logins from there are refused
+    #[serde(default = "default_max_failures")]
+    pub max_failures : u64,
+    /// seconds from the first failure after which the count starts over
+    #[serde(default = "default_window")]
+    pub window : u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// anonymous requests to the discovery routes a caller may make per
+    /// window
+    #[serde(default = "default_discovery_max_requests")]
+    pub max_requests : u64,
+    /// seconds from a caller's first request after which the count starts
+    /// over
+    #[serde(default = "default_discovery_window")]
+    pub window : u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self{
+            max_requests : DEFAULT_DISCOVERY_MAX_REQUESTS,
+            window : DEFAULT_DISCOVERY_WINDOW,
+        }
+    }
+}
+
+/// Counters shared by every server using the same store. A counter starts
+/// at the first `incr` and expires `ttl` later, the same as an `INCR` and
+/// `EXPIRE` in redis.
+pub trait RateLimitStore : Send + Sync {
+    /// adds one to `key`, returning the new count
+    fn incr(&self, key : &str, ttl : Duration) -> HookFuture<Result<u64, Error>>;
+
+    /// the count of `key`, 0 if it expired or was never incremented
+    fn get(&self, key : &str) -> HookFuture<Result<u64, Error>>;
+
+    fn reset(&self, key : &str) -> HookFuture<Result<(), Error>>;
+}
+
+impl<S> RateLimitStore for Arc<S>
+where
+    S : RateLimitStore + ?Sized,
+{
+    fn incr(&self, key : &str, ttl : Duration) -> HookFuture<Result<u64, Error>> {
+        S::incr(self, key, ttl)
+    }
+
+    fn get(&self, key : &str) -> HookFuture<Result<u64, Error>> {
+        S::get(self, key)
+    }
+
+    fn reset(&self, key : &str) -> HookFuture<Result<(), Error>> {
+        S::reset(self, key)
+    }
+}
+
+/// how often `MemoryStore` drops expired counters
+const PRUNE_INTERVAL : Duration = Duration::from_secs(10);
+
+/// A store local to the process, counters aren't shared with other servers
+#[derive(Default)]
+pub struct MemoryStore {
+    counters : Mutex<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    counts : HashMap<String, (u64, Instant)>,
+    /// when expired counters are next dropped, none before the first incr
+    next_prune : Option<Instant>,
+}
+
+impl Counters {
+    /// drops expired counters every `PRUNE_INTERVAL`, so keys which are
+    /// never read again don't pile up, without walking all of them on
+    /// every call
+    fn prune(&mut self, now : Instant) {
+        if self.next_prune.is_some_and(|next| next > now) {
+            return
+        }
+
+        self.counts.retain(|_, (_, expires)| *expires > now);
+        self.next_prune = Some(now + PRUNE_INTERVAL);
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn incr(&self, key : &str, ttl : Duration) -> HookFuture<Result<u64, Error>> {
+        let now = Instant::now();
+        let mut counters = self.counters.lock().unwrap();
+        counters.prune(now);
+
+        let (count, expires) = counters.counts.entry(key.to_string())
+            .or_insert((0, now + ttl));
+        // expired since the last prune
+        if *expires <= now {
+            *count = 0;
+            *expires = now + ttl;
+        }
+        *count += 1;
+
+        let count = *count;
+        Box::pin(async move { Ok(count) })
+    }
+
+    fn get(&self, key : &str) -> HookFuture<Result<u64, Error>> {
+        let now = Instant::now();
+        let count = self.counters.lock().unwrap()
+            .counts
+            .get(key)
+            .filter(|(_, expires)| *expires > now)
+            .map(|(count, _)| *count)
+            .unwrap_or(0);
+
+        Box::pin(async move { Ok(count) })
+    }
+
+    fn reset(&self, key : &str) -> HookFuture<Result<(), Error>> {
+        self.counters.lock().unwrap().counts.remove(key);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A store on a redis server, shared by every server using it. A counter
+/// is made with its expiry by `SET NX PX`, which `INCR` keeps.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    client : redis::Client,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    pub fn new(config : redis::Config) -> Self {
+        Self{
+            client : redis::Client::new(config),
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+impl RateLimitStore for RedisStore {
+    fn incr(&self, key : &str, ttl : Duration) -> HookFuture<Result<u64, Error>> {
+        let client = self.client.clone();
+        let key = key.to_string();
+        let ttl = ttl.as_millis().max(1).to_string();
+
+        Box::pin(async move {
+            let replies = client.pipeline(&[
+                &["SET", &key, "0", "PX", &ttl, "NX"],
+                &["INCR", &key],
+            ]).await?;
+
+            Ok(replies[1].int()? as u64)
+        })
+    }
+
+    fn get(&self, key : &str) -> HookFuture<Result<u64, Error>> {
+        let client = self.client.clone();
+        let key = key.to_string();
+
+        Box::pin(async move {
+            Ok(client.command(&["GET", &key]).await?.int()? as u64)
+        })
+    }
+
+    fn reset(&self, key : &str) -> HookFuture<Result<(), Error>> {
+        let client = self.client.clone();
+        let key = key.to_string();
+
+        Box::pin(async move {
+            client.command(&["DEL", &key]).await.map(|_| ())
+        })
+    }
+}
+
+/// Refuses logins for a user from an address after too many failures
+/// from there, until the window of the first failure passes or a login
+/// succeeds. Failures are counted per address so that guessing from one
+/// address doesn't lock the user out everywhere, logins over the unix
+/// socket share one count.
+pub struct LoginLimiter<S> {
+    store : S,
+    max_failures : u64,
+    window : Duration,
+}
+
+impl<S> LoginLimiter<S>
+where
+    S : RateLimitStore,
+{
+    pub fn new(store : S, config : &Config) -> Self {
+        Self{
+            store,
+            max_failures : config.max_failures,
+            window : Duration::from_secs(config.window),
+        }
+    }
+
+    fn key(attempt : &LoginAttempt) -> String {
+        match attempt.addr {
+            Some(addr) => format!("login-failures:{}:{}", attempt.name, addr),
+            None => format!("login-failures:{}:local", attempt.name),
+        }
+    }
+}
+
+impl<S> LoginHook for LoginLimiter<S>
+where
+    S : RateLimitStore,
+{
+    fn pre_login(&self, attempt : &LoginAttempt) -> HookFuture<Result<(), Error>> {
+        let failures = self.store.get(&Self::key(attempt));
+        let max_failures = self.max_failures;
+
+        Box::pin(async move {
+            if failures.await? >= max_failures {
+                return Err(AuthError::TooManyAttempts.into())
+            }
+
+            Ok(())
+        })
+    }
+
+    fn post_login(&self, attempt : &LoginAttempt, outcome : LoginOutcome) -> HookFuture<()> {
+        let key = Self::key(attempt);
+        let res : HookFuture<Result<(), Error>> = match outcome {
+            LoginOutcome::Failed => {
+                let incr = self.store.incr(&key, self.window);
+                Box::pin(async move { incr.await.map(|_| ()) })
+            },
+            LoginOutcome::Success => self.store.reset(&key),
+            _ => return Box::pin(async {}),
+        };
+
+        Box::pin(async move {
+            if let Err(err) = res.await {
+                logging::error!("failed to update {}: {:?}", key, err);
+            }
+        })
+    }
+}
+
+/// Counts anonymous requests to the discovery routes, e.g. `GET /pub-key`,
+/// by caller, see `Server::with_discovery_limiter`
+pub struct DiscoveryLimiter {
+    store : Box<dyn RateLimitStore>,
+    max_requests : u64,
+    window : Duration,
+}
+
+impl DiscoveryLimiter {
+    pub fn new<S>(store : S, config : &DiscoveryConfig) -> Self
+    where
+        S : RateLimitStore + 'static,
+    {
+        Self{
+            store : Box::new(store),
+            max_requests : config.max_requests,
+            window : Duration::from_secs(config.window),
+        }
+    }
+
+    /// counts a request of `caller`, returning by how many requests it's
+    /// over the limit, 0 if it isn't
+    pub async fn check(&self, caller : &str) -> Result<u64, Error> {
+        let count = self.store.incr(&format!("discovery:{}", caller), self.window).await?;
+
+        Ok(count.saturating_sub(self.max_requests))
+    }
+}
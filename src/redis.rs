@@ -0,0 +1,191 @@
+//! A minimal redis client speaking RESP over a connection kept between
+//! commands, enough for the counters of `ratelimit::RedisStore`, which
+//! servers sharing a redis server also share, and the events of
+//! `events::RedisBus`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+use crate::server::{Error, TransportError};
+
+/// how long a round trip may take before it fails
+const COMMAND_TIMEOUT : Duration = Duration::from_secs(5);
+
+#[derive(Deserialize,Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// `host:port` of the server
+    pub addr : String,
+    /// sent with `AUTH` on every new connection
+    #[serde(default)]
+    pub password : Option<String>,
+    /// publish `events::Event`s to this channel, see `events::RedisBus`
+    #[serde(default)]
+    pub events_channel : Option<String>,
+}
+
+/// A reply to a command
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Int(i64),
+    /// `None` for a missing key
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    /// the integer of the reply, `GET` returns counters as bulk strings
+    /// and missing ones as a nil, which counts as 0
+    pub fn int(&self) -> Result<i64, Error> {
+        match self {
+            Reply::Int(n) => Ok(*n),
+            Reply::Bulk(None) => Ok(0),
+            Reply::Bulk(Some(s)) => std::str::from_utf8(s)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| redis_error("expected an integer")),
+            reply => Err(redis_error(&format!("expected an integer, got {:?}", reply))),
+        }
+    }
+}
+
+type Conn = tokio::io::BufStream<tokio::net::TcpStream>;
+
+/// A connection to a redis server, made on the first command and again
+/// after it fails
+#[derive(Clone)]
+pub struct Client {
+    config : Arc<Config>,
+    conn : Arc<tokio::sync::Mutex<Option<Conn>>>,
+}
+
+fn redis_error(msg : &str) -> Error {
+    TransportError::Redis(msg.to_string()).into()
+}
+
+impl Client {
+    pub fn new(config : Config) -> Self {
+        Self{
+            config : Arc::new(config),
+            conn : Default::default(),
+        }
+    }
+
+    /// sends `commands` in one round trip, returning their replies. A
+    /// connection the server dropped since is replaced once.
+    pub async fn pipeline(&self, commands : &[&[&str]]) -> Result<Vec<Reply>, Error> {
+        let mut conn = self.conn.lock().await;
+
+        let mut retried = false;
+        loop {
+            if conn.is_none() {
+                *conn = Some(connect(&self.config).await?);
+            }
+
+            let round_trip = round_trip(conn.as_mut().unwrap(), commands);
+            let res = match tokio::time::timeout(COMMAND_TIMEOUT, round_trip).await {
+                Ok(res) => res,
+                Err(_) => Err(redis_error("redis timed out")),
+            };
+
+            match res {
+                Ok(replies) => return Ok(replies),
+                Err(err) if retried => {
+                    *conn = None;
+                    return Err(err)
+                },
+                Err(_) => {
+                    *conn = None;
+                    retried = true;
+                },
+            }
+        }
+    }
+
+    /// runs a single command, failing on an error reply
+    pub async fn command(&self, command : &[&str]) -> Result<Reply, Error> {
+        let reply = self.pipeline(&[command]).await?.remove(0);
+
+        match reply {
+            Reply::Error(err) => Err(redis_error(&err)),
+            reply => Ok(reply),
+        }
+    }
+}
+
+async fn connect(config : &Config) -> Result<Conn, Error> {
+    let mut conn = tokio::io::BufStream::new(tokio::net::TcpStream::connect(&config.addr).await?);
+
+    if let Some(password) = &config.password {
+        if let Reply::Error(err) = round_trip(&mut conn, &[&["AUTH", password]]).await?.remove(0) {
+            return Err(redis_error(&err))
+        }
+    }
+
+    Ok(conn)
+}
+
+async fn round_trip(conn : &mut Conn, commands : &[&[&str]]) -> Result<Vec<Reply>, Error> {
+    let mut buf = Vec::new();
+    for command in commands {
+        buf.extend(format!("*{}\r\n", command.len()).as_bytes());
+        for arg in command.iter() {
+            buf.extend(format!("${}\r\n", arg.len()).as_bytes());
+            buf.extend(arg.as_bytes());
+            buf.extend(b"\r\n");
+        }
+    }
+    conn.write_all(&buf).await?;
+    conn.flush().await?;
+
+    let mut replies = Vec::with_capacity(commands.len());
+    for _ in commands {
+        replies.push(read_reply(conn).await?);
+    }
+
+    Ok(replies)
+}
+
+fn read_reply(conn : &mut Conn) -> Pin<Box<dyn Future<Output = Result<Reply, Error>> + Send + '_>> {
+    Box::pin(async move {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await? == 0 {
+            return Err(redis_error("redis closed the connection"))
+        }
+
+        let line = line.trim_end();
+        let mut chars = line.chars();
+        let kind = chars.next();
+        let rest = chars.as_str();
+        let len = || rest.parse::<i64>().map_err(|_| redis_error(&format!("invalid reply: {}", line)));
+
+        Ok(match kind {
+            Some('+') => Reply::Status(rest.to_string()),
+            Some('-') => Reply::Error(rest.to_string()),
+            Some(':') => Reply::Int(len()?),
+            Some('$') if len()? < 0 => Reply::Bulk(None),
+            Some('$') => {
+                let mut data = vec![0; len()? as usize + 2];
+                conn.read_exact(&mut data).await?;
+                data.truncate(data.len() - 2);
+                Reply::Bulk(Some(data))
+            },
+            Some('*') if len()? < 0 => Reply::Bulk(None),
+            Some('*') => {
+                let mut items = Vec::new();
+                for _ in 0..len()? {
+                    items.push(read_reply(conn).await?);
+                }
+                Reply::Array(items)
+            },
+            _ => return Err(redis_error(&format!("invalid reply: {}", line))),
+        })
+    })
+}
@@ -0,0 +1,289 @@
+//! Scoring logins. Every login attempt is scored by a `RiskEngine`, from 0
+//! to `MAX_SCORE`, before the `LoginHook`s see it. Attempts scoring
+//! `Config::block_score` or more are denied before the hooks run and the
+//! credentials are checked. The server has no second factor itself,
+//! so ones scoring `Config::step_up_score` or more only have
+//! `LoginAttempt::step_up` set, for a hook to ask for more than a password,
+//! e.g. veto the login unless the caller is known otherwise. Any score
+//! above 0 is written to the audit log, as a `login-risk` entry with the
+//! reasons.
+//!
+//! `Rules` is the engine of `Config`. It adds up the scores of the rules an
+//! attempt breaks: too many attempts from one address, an address in a
+//! network of bad repute, or a country other than the user's last one too
+//! soon after it, i.e. impossible travel, which needs `geoip`. The address
+//! is the one `server::client_addr` trusts, the tcp peer's or one set by
+//! `server::Config::trusted_proxies`. Attempts without one, e.g. over the
+//! unix socket without proxies, break none of the rules.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::ratelimit::RateLimitStore;
+use crate::server::{
+    Error,
+    HookFuture,
+    LoginAttempt,
+};
+
+pub const MAX_SCORE : u32 = 100;
+
+const DEFAULT_STEP_UP_SCORE : u32 = 50;
+const DEFAULT_BLOCK_SCORE : u32 = 80;
+
+fn default_step_up_score() -> u32 {
+    DEFAULT_STEP_UP_SCORE
+}
+
+fn default_block_score() -> u32 {
+    DEFAULT_BLOCK_SCORE
+}
+
+#[derive(Deserialize,Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// attempts scoring at least this have `LoginAttempt::step_up` set
+    #[serde(default = "default_step_up_score")]
+    pub step_up_score : u32,
+    /// attempts scoring at least this are denied
+    #[serde(default = "default_block_score")]
+    pub block_score : u32,
+    #[serde(default)]
+    pub velocity : Option<VelocityConfig>,
+    /// addresses of bad repute, the scores of all networks holding the
+    /// address add up
+    #[serde(default)]
+    pub networks : Vec<NetworkConfig>,
+    #[serde(default)]
+    pub impossible_travel : Option<TravelConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self{
+            step_up_score : DEFAULT_STEP_UP_SCORE,
+            block_score : DEFAULT_BLOCK_SCORE,
+            velocity : None,
+            networks : Vec::new(),
+            impossible_travel : None,
+        }
+    }
+}
+
+impl Config {
+    pub fn verdict(&self, risk : &Risk) -> Verdict {
+        if risk.score >= self.block_score {
+            Verdict::Block
+        } else if risk.score >= self.step_up_score {
+            Verdict::StepUp
+        } else {
+            Verdict::Allow
+        }
+    }
+}
+
+/// scores attempts past `max_attempts` from one address within `window`
+/// seconds of the first. Attempts without an address aren't counted, lest
+/// anyone could lock a user out by trying their name.
+#[derive(Deserialize,Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VelocityConfig {
+    pub max_attempts : u64,
+    pub window : u64,
+    pub score : u32,
+}
+
+#[derive(Deserialize,Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// e.g. `192.0.2.0/24`, a plain address is a network of one
+    pub network : Network,
+    pub score : u32,
+}
+
+/// scores attempts from a country other than the one the user last logged
+/// in from, if that was less than `window` seconds ago
+#[derive(Deserialize,Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TravelConfig {
+    pub window : u64,
+    pub score : u32,
+}
+
+/// An address and prefix length
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Network {
+    addr : IpAddr,
+    prefix : u32,
+}
+
+impl Network {
+    pub fn contains(&self, addr : IpAddr) -> bool {
+        fn masked(bits : u128, width : u32, prefix : u32) -> u128 {
+            match width - prefix {
+                128 => 0,
+                host => bits >> host,
+            }
+        }
+
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) =>
+                masked(u32::from(net).into(), 32, self.prefix) == masked(u32::from(addr).into(), 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(addr)) =>
+                masked(net.into(), 128, self.prefix) == masked(addr.into(), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, String> {
+        let invalid = || format!("invalid network: {}", s);
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>().ok()
+                .filter(|prefix| *prefix <= width)
+                .ok_or_else(invalid)?,
+            None => width,
+        };
+
+        Ok(Self{ addr, prefix })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D : Deserializer<'de>>(deserializer : D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// How risky an attempt looks
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct Risk {
+    /// from 0 to `MAX_SCORE`
+    pub score : u32,
+    /// why it scored, for the audit log
+    pub reasons : Vec<String>,
+}
+
+impl Risk {
+    /// adds `score` for `reason`, up to `MAX_SCORE`
+    pub fn add(&mut self, score : u32, reason : String) {
+        self.score = (self.score + score).min(MAX_SCORE);
+        self.reasons.push(reason);
+    }
+}
+
+/// What's done with an attempt, see `Config::verdict`
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Verdict {
+    Allow,
+    StepUp,
+    Block,
+}
+
+impl Verdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::StepUp => "step-up",
+            Self::Block => "block",
+        }
+    }
+}
+
+/// Scores login attempts, e.g. by asking a reputation service. An error
+/// fails the login.
+pub trait RiskEngine : Send + Sync {
+    fn score(&self, attempt : &LoginAttempt) -> HookFuture<Result<Risk, Error>>;
+}
+
+/// The engine of `Config`, counting attempts in `store`
+pub struct Rules<S> {
+    store : S,
+    velocity : Option<VelocityConfig>,
+    networks : Vec<NetworkConfig>,
+    impossible_travel : Option<TravelConfig>,
+}
+
+impl<S> Rules<S>
+where
+    S : RateLimitStore,
+{
+    pub fn new(store : S, config : &Config) -> Self {
+        Self{
+            store,
+            velocity : config.velocity.clone(),
+            networks : config.networks.clone(),
+            impossible_travel : config.impossible_travel.clone(),
+        }
+    }
+
+
+    /// the rules which don't count attempts
+    fn score_attempt(&self, attempt : &LoginAttempt) -> Risk {
+        let mut risk = Risk::default();
+
+        if let Some(addr) = attempt.addr {
+            for net in self.networks.iter().filter(|net| net.network.contains(addr)) {
+                risk.add(net.score, format!("address in {}", net.network));
+            }
+        }
+
+        let travel = self.impossible_travel.as_ref()
+            .zip(attempt.country.as_ref())
+            .zip(attempt.last_country.as_ref());
+        if let Some(((travel, country), last)) = travel {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+            if &last.country != country && now - last.last_seen < travel.window as i64 {
+                risk.add(travel.score, format!("from {} {}s after {}", country, now - last.last_seen, last.country));
+            }
+        }
+
+        risk
+    }
+}
+
+impl<S> RiskEngine for Rules<S>
+where
+    S : RateLimitStore,
+{
+    fn score(&self, attempt : &LoginAttempt) -> HookFuture<Result<Risk, Error>> {
+        let mut risk = self.score_attempt(attempt);
+
+        let velocity = self.velocity.clone().zip(attempt.addr);
+        let attempts = velocity.as_ref()
+            .map(|(velocity, addr)| self.store.incr(&format!("login-attempts:{}", addr), Duration::from_secs(velocity.window)));
+
+        Box::pin(async move {
+            if let Some(((velocity, _), attempts)) = velocity.zip(attempts) {
+                let attempts = attempts.await?;
+                if attempts > velocity.max_attempts {
+                    risk.add(velocity.score, format!("{} attempts in {}s", attempts, velocity.window));
+                }
+            }
+
+            Ok(risk)
+        })
+    }
+}
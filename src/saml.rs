@@ -0,0 +1,685 @@
+//! A minimal SAML 2.0 identity provider, so applications that only speak
+//! SAML can log users in against the same user store. Only the HTTP-POST
+//! binding is supported: the service provider posts an `AuthnRequest` to
+//! `POST /saml/sso`, the user logs in with their password and the browser
+//! posts a signed assertion back to the service provider's assertion
+//! consumer service. Metadata for the service providers is served at
+//! `GET /saml/metadata`.
+//!
+//! A request is answered at most once, and only within
+//! `Config::request_lifetime` seconds of its `IssueInstant`, so a captured
+//! request can't be replayed for another assertion. The login form is tied
+//! to the browser it was served to with a csrf cookie, so another site
+//! can't log the user in as someone else.
+//!
+//! The xml is written directly in its exclusive canonical form, so the
+//! digest and signature can be computed without an xml library.
+
+use std::sync::Arc;
+
+use serde::Deserialize;
+use plumb::PipeExt;
+use http_mux::{route,mux};
+use jsonwebtoken as jwt;
+use quick_xml::events::Event as XmlEvent;
+use rand::{rngs::OsRng, RngCore};
+use ring::signature::{self, EcdsaKeyPair, RsaKeyPair};
+
+use crate::crypto::{pem_block, Secret};
+use crate::html;
+use crate::server::{
+    Error,
+    ConfigError,
+    TransportError,
+    Server,
+    Router,
+    LoginOutcome,
+    Request,
+    Response,
+    Result,
+    user_agent,
+    client_addr,
+};
+
+const DEFAULT_ASSERTION_LIFETIME : u64 = 60 * 5;
+const DEFAULT_REQUEST_LIFETIME : u64 = 60 * 5;
+
+/// seconds the clock of a service provider may be ahead of ours
+const CLOCK_SKEW : i64 = 60;
+
+fn default_assertion_lifetime() -> u64 {
+    DEFAULT_ASSERTION_LIFETIME
+}
+
+fn default_request_lifetime() -> u64 {
+    DEFAULT_REQUEST_LIFETIME
+}
+
+const NS_ASSERTION : &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const NS_PROTOCOL : &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const NS_METADATA : &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+const NS_DSIG : &str = "http://www.w3.org/2000/09/xmldsig#";
+const BINDING_POST : &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+const NAME_ID_UNSPECIFIED : &str = "urn:oasis:names:tc:SAML:1.1:nameid-format:unspecified";
+const EXC_C14N : &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// the identity provider's entity id, the issuer of assertions
+    pub entity_id : String,
+    /// public url of `POST /saml/sso`, as advertised in the metadata
+    pub sso_url : String,
+    pub service_providers : Vec<ServiceProvider>,
+    /// seconds an assertion may be used for after it's issued
+    #[serde(default = "default_assertion_lifetime")]
+    pub assertion_lifetime : u64,
+    /// seconds after its `IssueInstant` a request is answered for
+    #[serde(default = "default_request_lifetime")]
+    pub request_lifetime : u64,
+}
+
+/// A service provider allowed to request assertions. Its entity id is the
+/// audience of the assertions and of the login, as seen by login hooks.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceProvider {
+    pub entity_id : String,
+    /// where assertions are posted to, requests asking for any other url
+    /// are refused
+    pub acs_url : String,
+}
+
+enum Signer {
+    Ecdsa(EcdsaKeyPair),
+    Rsa(RsaKeyPair, &'static dyn signature::RsaEncoding),
+}
+
+pub struct Idp {
+    config : Config,
+    signer : Signer,
+    /// xmldsig uri of the signature algorithm
+    signature_method : &'static str,
+    /// base64 der of the signing certificate
+    cert : String,
+}
+
+impl Idp {
+    /// the key must be pkcs8 (or pkcs1 for rsa), the leaf certificate of
+    /// `cert_pem` is included in the metadata and signatures
+    pub fn new(
+        config : Config,
+        alg : jwt::Algorithm,
+        priv_key_pem : &str,
+        cert_pem : Option<&str>,
+    ) -> std::result::Result<Self, Error> {
+        use jwt::Algorithm::*;
+
+        let cert = cert_pem
+            .and_then(|pem| pem_block(pem, "CERTIFICATE"))
+            .ok_or(ConfigError::InvalidCertificate)?;
+
+        let (label, key) = pem_block(priv_key_pem, "PRIVATE KEY")
+            .map(|key| ("PRIVATE KEY", key))
+            .or_else(|| pem_block(priv_key_pem, "RSA PRIVATE KEY").map(|key| ("RSA PRIVATE KEY", key)))
+            .ok_or(ConfigError::InvalidKey)?;
+
+        let rsa = |encoding| -> std::result::Result<_, ConfigError> {
+            let pair = match label {
+                "RSA PRIVATE KEY" => RsaKeyPair::from_der(&key),
+                _ => RsaKeyPair::from_pkcs8(&key),
+            }.map_err(|_| ConfigError::InvalidKey)?;
+
+            Ok(Signer::Rsa(pair, encoding))
+        };
+
+        let (signer, signature_method) = match alg {
+            ES256 => (
+                Signer::Ecdsa(EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &key)
+                    .map_err(|_| ConfigError::InvalidKey)?),
+                "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256",
+            ),
+            ES384 => (
+                Signer::Ecdsa(EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P384_SHA384_FIXED_SIGNING, &key)
+                    .map_err(|_| ConfigError::InvalidKey)?),
+                "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha384",
+            ),
+            RS256 => (rsa(&signature::RSA_PKCS1_SHA256)?, "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256"),
+            RS384 => (rsa(&signature::RSA_PKCS1_SHA384)?, "http://www.w3.org/2001/04/xmldsig-more#rsa-sha384"),
+            RS512 => (rsa(&signature::RSA_PKCS1_SHA512)?, "http://www.w3.org/2001/04/xmldsig-more#rsa-sha512"),
+            alg => return Err(ConfigError::AlgorithmNotAllowed(alg).into()),
+        };
+
+        Ok(Self{
+            config,
+            signer,
+            signature_method,
+            cert : base64::encode(cert),
+        })
+    }
+
+    /// when `request` stops being answered, until then its id is remembered
+    fn request_expires(&self, request : &AuthnRequest) -> i64 {
+        request.issue_instant.saturating_add(self.config.request_lifetime as i64)
+    }
+
+    fn service_provider(&self, entity_id : &str) -> Option<&ServiceProvider> {
+        self.config.service_providers.iter()
+            .find(|sp| sp.entity_id == entity_id)
+    }
+
+    fn sign(&self, data : &[u8]) -> Result<Vec<u8>> {
+        let rng = ring::rand::SystemRandom::new();
+
+        match &self.signer {
+            Signer::Ecdsa(pair) => pair.sign(&rng, data)
+                .map(|sig| sig.as_ref().to_vec())
+                .map_err(|_| ConfigError::InvalidKey.into()),
+            Signer::Rsa(pair, encoding) => {
+                let mut sig = vec![0; pair.public_modulus_len()];
+                pair.sign(*encoding, &rng, data, &mut sig)
+                    .map_err(|_| ConfigError::InvalidKey)?;
+
+                Ok(sig)
+            },
+        }
+    }
+
+    fn key_info(&self) -> String {
+        format!(
+            r#"<ds:KeyInfo xmlns:ds="{}"><ds:X509Data><ds:X509Certificate>{}</ds:X509Certificate></ds:X509Data></ds:KeyInfo>"#,
+            NS_DSIG,
+            self.cert,
+        )
+    }
+
+    fn metadata(&self) -> String {
+        format!(
+            concat!(
+                r#"<md:EntityDescriptor xmlns:md="{ns}" entityID="{entity_id}">"#,
+                r#"<md:IDPSSODescriptor WantAuthnRequestsSigned="false" protocolSupportEnumeration="{protocol}">"#,
+                r#"<md:KeyDescriptor use="signing">{key_info}</md:KeyDescriptor>"#,
+                r#"<md:NameIDFormat>{name_id}</md:NameIDFormat>"#,
+                r#"<md:SingleSignOnService Binding="{binding}" Location="{sso_url}"></md:SingleSignOnService>"#,
+                r#"</md:IDPSSODescriptor>"#,
+                r#"</md:EntityDescriptor>"#,
+            ),
+            ns = NS_METADATA,
+            entity_id = xml_attr(&self.config.entity_id),
+            protocol = NS_PROTOCOL,
+            key_info = self.key_info(),
+            name_id = NAME_ID_UNSPECIFIED,
+            binding = BINDING_POST,
+            sso_url = xml_attr(&self.config.sso_url),
+        )
+    }
+
+    /// a successful `Response` for `request`, with an assertion for `user`
+    /// signed with an enveloped signature
+    fn response(
+        &self,
+        request : &AuthnRequest,
+        acs_url : &str,
+        user : &crate::models::User,
+        now : i64,
+    ) -> Result<String> {
+        let assertion_id = new_id();
+        let issue_instant = xml_time(now);
+        let not_on_or_after = xml_time(now + self.config.assertion_lifetime as i64);
+        let issuer = format!(
+            r#"<saml:Issuer>{}</saml:Issuer>"#,
+            xml_text(&self.config.entity_id),
+        );
+
+        let roles = user.roles.split_whitespace()
+            .map(|role| format!("<saml:AttributeValue>{}</saml:AttributeValue>", xml_text(role)))
+            .collect::<String>();
+
+        let body = format!(
+            concat!(
+                r#"<saml:Subject>"#,
+                r#"<saml:NameID Format="{name_id}">{name}</saml:NameID>"#,
+                r#"<saml:SubjectConfirmation Method="urn:oasis:names:tc:SAML:2.0:cm:bearer">"#,
+                r#"<saml:SubjectConfirmationData InResponseTo="{in_response_to}" NotOnOrAfter="{not_on_or_after}" Recipient="{acs_url}"></saml:SubjectConfirmationData>"#,
+                r#"</saml:SubjectConfirmation>"#,
+                r#"</saml:Subject>"#,
+                r#"<saml:Conditions NotBefore="{issue_instant}" NotOnOrAfter="{not_on_or_after}">"#,
+                r#"<saml:AudienceRestriction><saml:Audience>{audience}</saml:Audience></saml:AudienceRestriction>"#,
+                r#"</saml:Conditions>"#,
+                r#"<saml:AuthnStatement AuthnInstant="{issue_instant}" SessionIndex="{assertion_id}">"#,
+                r#"<saml:AuthnContext><saml:AuthnContextClassRef>urn:oasis:names:tc:SAML:2.0:ac:classes:PasswordProtectedTransport</saml:AuthnContextClassRef></saml:AuthnContext>"#,
+                r#"</saml:AuthnStatement>"#,
+                r#"<saml:AttributeStatement>"#,
+                r#"<saml:Attribute Name="roles" NameFormat="urn:oasis:names:tc:SAML:2.0:attrname-format:basic">{roles}</saml:Attribute>"#,
+                r#"</saml:AttributeStatement>"#,
+            ),
+            name_id = NAME_ID_UNSPECIFIED,
+            name = xml_text(&user.name),
+            in_response_to = xml_attr(&request.id),
+            not_on_or_after = not_on_or_after,
+            acs_url = xml_attr(acs_url),
+            issue_instant = issue_instant,
+            audience = xml_text(&request.issuer),
+            assertion_id = assertion_id,
+            roles = roles,
+        );
+
+        let assertion_start = format!(
+            r#"<saml:Assertion xmlns:saml="{}" ID="{}" IssueInstant="{}" Version="2.0">"#,
+            NS_ASSERTION,
+            assertion_id,
+            issue_instant,
+        );
+
+        // the enveloped signature transform removes the signature, so the
+        // digest is of the assertion without it
+        let digest = ring::digest::digest(
+            &ring::digest::SHA256,
+            format!("{}{}{}</saml:Assertion>", assertion_start, issuer, body).as_bytes(),
+        );
+
+        let signed_info = format!(
+            concat!(
+                r#"<ds:SignedInfo xmlns:ds="{ns}">"#,
+                r#"<ds:CanonicalizationMethod Algorithm="{c14n}"></ds:CanonicalizationMethod>"#,
+                r#"<ds:SignatureMethod Algorithm="{signature_method}"></ds:SignatureMethod>"#,
+                r##"<ds:Reference URI="#{assertion_id}">"##,
+                r#"<ds:Transforms>"#,
+                r#"<ds:Transform Algorithm="http://www.w3.org/2000/09/xmldsig#enveloped-signature"></ds:Transform>"#,
+                r#"<ds:Transform Algorithm="{c14n}"></ds:Transform>"#,
+                r#"</ds:Transforms>"#,
+                r#"<ds:DigestMethod Algorithm="http://www.w3.org/2001/04/xmlenc#sha256"></ds:DigestMethod>"#,
+                r#"<ds:DigestValue>{digest}</ds:DigestValue>"#,
+                r#"</ds:Reference>"#,
+                r#"</ds:SignedInfo>"#,
+            ),
+            ns = NS_DSIG,
+            c14n = EXC_C14N,
+            signature_method = self.signature_method,
+            assertion_id = assertion_id,
+            digest = base64::encode(digest),
+        );
+
+        let signature = format!(
+            r#"<ds:Signature xmlns:ds="{}">{}<ds:SignatureValue>{}</ds:SignatureValue>{}</ds:Signature>"#,
+            NS_DSIG,
+            signed_info,
+            base64::encode(self.sign(signed_info.as_bytes())?),
+            self.key_info(),
+        );
+
+        Ok(format!(
+            concat!(
+                r#"<samlp:Response xmlns:samlp="{protocol}" xmlns:saml="{assertion}" Destination="{acs_url}" ID="{id}" InResponseTo="{in_response_to}" IssueInstant="{issue_instant}" Version="2.0">"#,
+                r#"{issuer}"#,
+                r#"<samlp:Status><samlp:StatusCode Value="urn:oasis:names:tc:SAML:2.0:status:Success"></samlp:StatusCode></samlp:Status>"#,
+                r#"{assertion_start}{issuer}{signature}{body}</saml:Assertion>"#,
+                r#"</samlp:Response>"#,
+            ),
+            protocol = NS_PROTOCOL,
+            assertion = NS_ASSERTION,
+            acs_url = xml_attr(acs_url),
+            id = new_id(),
+            in_response_to = xml_attr(&request.id),
+            issue_instant = issue_instant,
+            issuer = issuer,
+            assertion_start = assertion_start,
+            signature = signature,
+            body = body,
+        ))
+    }
+}
+
+/// The parts of an `AuthnRequest` the identity provider uses
+struct AuthnRequest {
+    id : String,
+    /// entity id of the service provider
+    issuer : String,
+    acs_url : Option<String>,
+    /// unix time
+    issue_instant : i64,
+}
+
+impl AuthnRequest {
+    /// parses the base64 encoded request of the HTTP-POST binding
+    fn parse(encoded : &str) -> Result<Self> {
+        let encoded = encoded.split_whitespace().collect::<String>();
+        let xml = base64::decode(encoded).ok()
+            .and_then(|xml| String::from_utf8(xml).ok())
+            .ok_or(TransportError::BadRequest)?;
+
+        Self::parse_xml(&xml).ok_or_else(|| TransportError::BadRequest.into())
+    }
+
+    fn parse_xml(xml : &str) -> Option<Self> {
+        let mut reader = quick_xml::Reader::from_str(xml);
+        let mut buf = Vec::new();
+
+        let mut id = None;
+        let mut issuer = None;
+        let mut acs_url = None;
+        let mut issue_instant = None;
+        let mut depth = 0;
+        let mut in_issuer = false;
+
+        loop {
+            match reader.read_event(&mut buf).ok()? {
+                XmlEvent::Start(e) => {
+                    depth += 1;
+
+                    match (depth, e.local_name()) {
+                        (1, b"AuthnRequest") => {
+                            for attr in e.attributes() {
+                                let attr = attr.ok()?;
+                                match attr.key {
+                                    b"ID" => id = Some(attr.unescape_and_decode_value(&reader).ok()?),
+                                    b"AssertionConsumerServiceURL" => {
+                                        acs_url = Some(attr.unescape_and_decode_value(&reader).ok()?)
+                                    },
+                                    b"IssueInstant" => {
+                                        issue_instant = parse_xml_time(&attr.unescape_and_decode_value(&reader).ok()?)
+                                    },
+                                    _ => {},
+                                }
+                            }
+                        },
+                        (1, _) => return None,
+                        (2, b"Issuer") => in_issuer = true,
+                        _ => {},
+                    }
+                },
+                XmlEvent::Text(e) if in_issuer => {
+                    issuer = Some(e.unescape_and_decode(&reader).ok()?.trim().to_string());
+                },
+                XmlEvent::End(_) => {
+                    depth -= 1;
+                    in_issuer = false;
+                },
+                // entities are never expanded, refuse them outright
+                XmlEvent::DocType(_) => return None,
+                XmlEvent::Eof => break,
+                _ => {},
+            }
+
+            buf.clear();
+        }
+
+        Some(Self{
+            id : id?,
+            issuer : issuer?,
+            acs_url,
+            issue_instant : issue_instant?,
+        })
+    }
+}
+
+/// the fields posted to `POST /saml/sso`
+#[derive(Deserialize)]
+struct SsoForm {
+    #[serde(rename = "SAMLRequest")]
+    saml_request : String,
+    #[serde(rename = "RelayState", default)]
+    relay_state : String,
+}
+
+/// the fields posted to `POST /saml/login`, the request is carried through
+/// the login form
+#[derive(Deserialize)]
+struct LoginForm {
+    #[serde(flatten)]
+    sso : SsoForm,
+    /// see `html::csrf_token`
+    csrf : String,
+    name : String,
+    pass : Secret,
+}
+
+fn idp<'a>(server : &'a Server, req : &Request) -> Result<&'a Idp> {
+    server.saml.as_ref()
+        .ok_or_else(|| mux::MuxError::NotFound(req.uri().path().to_string()).into())
+}
+
+/// checks the request is fresh and comes from a known service provider,
+/// and returns the url to post the response to
+fn acs_url<'a>(idp : &'a Idp, request : &AuthnRequest, now : i64) -> Result<&'a str> {
+    let sp = idp.service_provider(&request.issuer)
+        .ok_or(TransportError::BadRequest)?;
+
+    if request.issue_instant - CLOCK_SKEW > now || now >= idp.request_expires(request) {
+        return Err(TransportError::BadRequest.into())
+    }
+
+    match &request.acs_url {
+        Some(url) if *url != sp.acs_url => Err(TransportError::BadRequest.into()),
+        _ => Ok(&sp.acs_url),
+    }
+}
+
+pub(crate) fn routes(server : &Arc<Server>, m : Router) -> Router {
+    let m = get_saml_metadata(Arc::clone(server), m.named("get_saml_metadata"));
+    let m = post_saml_sso(Arc::clone(server), m.named("post_saml_sso"));
+    post_saml_login(Arc::clone(server), m.named("post_saml_login"))
+}
+
+fn get_saml_metadata(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "saml" / "metadata"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            server.check_discovery(&req).await?;
+            let idp = idp(&server, &req)?;
+
+            Ok(http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "application/samlmetadata+xml")
+                .body(idp.metadata().into())
+                .unwrap())
+        })
+    )
+}
+
+fn post_saml_sso(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "saml" / "sso"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let idp = idp(&server, &req)?;
+            let form : SsoForm = html::read_form(req).await?;
+
+            let request = AuthnRequest::parse(&form.saml_request)?;
+            acs_url(idp, &request, unix_time(&server))?;
+
+            Ok(login_page(&form, None, http::StatusCode::OK))
+        })
+    )
+}
+
+fn post_saml_login(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "saml" / "login"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let idp = idp(&server, &req)?;
+            let user_agent = user_agent(&req);
+            let addr = client_addr(&req);
+            let headers = req.headers().clone();
+            let form : LoginForm = html::read_form(req).await?;
+
+            let request = AuthnRequest::parse(&form.sso.saml_request)?;
+            let acs_url = acs_url(idp, &request, unix_time(&server))?;
+
+            if let Err(err) = html::check_csrf(&headers, &form.csrf) {
+                let (_, status, message) = crate::error::http_error(err.code());
+                return Ok(login_page(&form.sso, Some(message), status))
+            }
+
+            let attempt = server.login_attempt(form.name.clone(), request.issuer.clone(), user_agent, addr).await?;
+
+            let login = async {
+                let mut user = server.check_password(&form.name, &form.pass).await?;
+
+                let now = unix_time(&server);
+                if !server.database.use_saml_request(&request.issuer, &request.id, idp.request_expires(&request), now).await? {
+                    return Err(TransportError::BadRequest.into())
+                }
+                server.record_login(&mut user, &attempt, now, true).await?;
+
+                idp.response(&request, acs_url, &user, now)
+            };
+
+            let res = server.hook_login(&attempt, login).await;
+            match (LoginOutcome::from(&res), res) {
+                (_, Ok(response)) => Ok(post_page(acs_url, &response, &form.sso.relay_state)),
+                (LoginOutcome::Failed, Err(err)) | (LoginOutcome::Denied, Err(err)) => {
+                    let (_, status, message) = crate::error::http_error(err.code());
+                    Ok(login_page(&form.sso, Some(message), status))
+                },
+                (_, Err(err)) => Err(err),
+            }
+        })
+    )
+}
+
+fn unix_time(server : &Server) -> i64 {
+    server.clock.now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// the password form, posting to `POST /saml/login` along with the request
+/// and a fresh csrf token
+fn login_page(form : &SsoForm, error : Option<&str>, status : http::StatusCode) -> Response {
+    let csrf = html::csrf_token();
+    let error = error
+        .map(|msg| format!(r#"<p style="color: #b00">{}</p>"#, html::escape(msg)))
+        .unwrap_or_default();
+
+    let page = html::page(status, format!(
+        concat!(
+            "<!DOCTYPE html>\n",
+            r#"<html><head><meta charset="utf-8"><title>log in</title></head><body>"#,
+            r#"{error}"#,
+            r#"<form method="post" action="login">"#,
+            r#"<input type="hidden" name="SAMLRequest" value="{saml_request}">"#,
+            r#"<input type="hidden" name="RelayState" value="{relay_state}">"#,
+            r#"<input type="hidden" name="csrf" value="{csrf}">"#,
+            r#"<input name="name" placeholder="name" autocomplete="username" required autofocus>"#,
+            r#"<input name="pass" type="password" placeholder="password" autocomplete="current-password" required>"#,
+            r#"<button>log in</button>"#,
+            r#"</form></body></html>"#,
+        ),
+        error = error,
+        saml_request = html::escape(&form.saml_request),
+        relay_state = html::escape(&form.relay_state),
+        csrf = csrf,
+    ));
+
+    html::with_csrf(page, &csrf)
+}
+
+/// a form posting the response to the service provider, submitted as soon
+/// as the page loads
+fn post_page(acs_url : &str, response : &str, relay_state : &str) -> Response {
+    let relay_state = if relay_state.is_empty() {
+        String::new()
+    } else {
+        format!(r#"<input type="hidden" name="RelayState" value="{}">"#, html::escape(relay_state))
+    };
+
+    html::page(http::StatusCode::OK, format!(
+        concat!(
+            "<!DOCTYPE html>\n",
+            r#"<html><head><meta charset="utf-8"><title>logging in</title></head>"#,
+            r#"<body onload="document.forms[0].submit()">"#,
+            r#"<form method="post" action="{acs_url}">"#,
+            r#"<input type="hidden" name="SAMLResponse" value="{response}">"#,
+            r#"{relay_state}"#,
+            r#"<noscript><button>continue</button></noscript>"#,
+            r#"</form></body></html>"#,
+        ),
+        acs_url = html::escape(acs_url),
+        response = base64::encode(response),
+        relay_state = relay_state,
+    ))
+}
+
+fn new_id() -> String {
+    let mut id = [0u8;20];
+    OsRng.fill_bytes(&mut id);
+
+    // ids must not start with a digit
+    std::iter::once("_".to_string())
+        .chain(id.iter().map(|b| format!("{:02x}", b)))
+        .collect()
+}
+
+/// a unix timestamp as an xml `dateTime` in utc
+fn xml_time(unix : i64) -> String {
+    let days = unix.div_euclid(86400);
+    let secs = unix.rem_euclid(86400);
+
+    // days to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day,
+        secs / 3600, secs / 60 % 60, secs % 60,
+    )
+}
+
+/// parses an xml `dateTime` in utc, e.g. `2026-10-16T12:00:00.5Z`, to a
+/// unix timestamp, dropping fractions of seconds
+fn parse_xml_time(s : &str) -> Option<i64> {
+    let s = s.strip_suffix('Z')?;
+    let (date, time) = s.split_once('T')?;
+    let time = time.split('.').next()?;
+
+    let mut date = date.splitn(3, '-').map(|part| part.parse::<u32>().ok().map(i64::from));
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.splitn(3, ':').map(|part| part.parse::<u32>().ok().map(i64::from));
+    let (hour, min, sec) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None
+    }
+
+    // a civil date to days, from Howard Hinnant's `days_from_civil`
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some(days * 86400 + hour * 3600 + min * 60 + sec)
+}
+
+/// escapes text content as canonical xml does
+fn xml_text(s : &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\r', "&#xD;")
+}
+
+/// escapes an attribute value as canonical xml does
+fn xml_attr(s : &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('"', "&quot;")
+        .replace('\t', "&#x9;")
+        .replace('\n', "&#xA;")
+        .replace('\r', "&#xD;")
+}
@@ -1,283 +1,3243 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::convert::TryFrom;
 
-use serde::{Serialize,Deserialize};
+use serde::Deserialize;
 use plumb::{Pipe,PipeExt};
-use quick_from::QuickFrom;
+use plumb::tuple_utils::Merge;
 use hyper::Body;
-use hyper::body::Buf;
+use hyper::body::{Buf, HttpBody};
 use http_mux::{route,mux};
 use jsonwebtoken as jwt;
+use zeroize::Zeroizing;
 
-use crate::database::Database;
+use crate::database::{Database, DEFAULT_PASSWORD_HISTORY, USED_PASS_HASH};
+pub use crate::error::{
+    Error,
+    AuthError,
+    StorageError,
+    ConfigError,
+    TransportError,
+};
+use crate::models;
 use crate::crypto;
-use crate::{PostLoginRequest, PostLoginResponse};
+use crate::notify::{self, Notifier, LoginNotification};
+use crate::ratelimit::{self, RateLimitStore};
+use crate::risk::{self, RiskEngine};
+use crate::events::{Event, EventBus};
+use crate::audit::{self, AuditSink};
+use crate::middleware::{self, Layer, Next};
+use crate::service;
+use crate::leaks;
+use crate::listing::{Listing, Sort};
+use crate::metrics::{self, Histogram, RollingQuantiles};
+use crate::stats::{self, Stats};
+use crate::logging;
+use crate::peer;
+use crate::orgs;
+use crate::invites;
+use crate::consent;
+use crate::ssh;
+use crate::break_glass;
+use crate::strength;
+use crate::policy::{self, Action, AccessRequest};
+#[cfg(feature = "saml")]
+use crate::saml;
+#[cfg(feature = "negotiate")]
+use crate::negotiate;
+#[cfg(feature = "negotiate")]
+use crate::api::PostNegotiateLoginRequest;
+#[cfg(feature = "pam")]
+use crate::pam;
+#[cfg(feature = "oauth")]
+use crate::oauth;
+#[cfg(feature = "geoip")]
+use crate::geoip;
+#[cfg(feature = "redis")]
+use crate::redis;
+use crate::api::{
+    PostLoginRequest,
+    PostLoginResponse,
+    PostLogoutRequest,
+    PostRenewRequest,
+    PostDeviceLoginRequest,
+    DeviceInfo,
+    GetDevicesResponse,
+    ClientInfo,
+    GetMeDevicesResponse,
+    GetUserResponse,
+    PostAdminImpersonateRequest,
+    GetAdminStatsResponse,
+    LatencyQuantiles,
+    AdminUserInfo,
+    GetAdminUsersResponse,
+    AuditEntry,
+    GetAdminAuditResponse,
+    PostAdminPasswordRequest,
+    PostPasswordEstimateRequest,
+    PostPasswordEstimateResponse,
+    PutAdminMaintenanceRequest,
+    GetVersionResponse,
+};
 
-const MAX_DURATION : u64 = 60 * 60 * 24 * 30;
+/// the longest lifetime of an issued token, so also how long a retired
+/// key has to validate the tokens it signed, see `RetiredKey`
+pub const MAX_DURATION : u64 = 60 * 60 * 24 * 30;
+const DEFAULT_MAX_SESSION : u64 = 60 * 60 * 24 * 90;
 
-type Result<T> = std::result::Result<T, Error>;
-type Request = http::Request<Body>;
-type Response = http::Response<Body>;
-type Mux = mux::Mux<Error, (), Body, Response>;
+fn default_max_session() -> u64 {
+    DEFAULT_MAX_SESSION
+}
+
+fn default_password_salt_len() -> usize {
+    crypto::DEFAULT_SALT_LEN
+}
+
+fn default_password_history() -> usize {
+    DEFAULT_PASSWORD_HISTORY
+}
+
+const DEFAULT_HEADER_READ_TIMEOUT : u64 = 30;
+const DEFAULT_BODY_READ_TIMEOUT : u64 = 30;
+const DEFAULT_MIN_BODY_RATE : u64 = 1024;
+
+/// how long a request body may be read before `min_body_rate` applies
+const MIN_BODY_RATE_GRACE : std::time::Duration = std::time::Duration::from_secs(1);
 
-#[derive(QuickFrom,Debug)]
-pub enum Error {
-    DuplicateName(String),
-    UserNotFound(String),
-    TokenDurationTooBig,
-    BadRequest,
-    AlgorithmNotAllowed(jwt::Algorithm),
-    LoginFailed,
+fn default_header_read_timeout() -> u64 {
+    DEFAULT_HEADER_READ_TIMEOUT
+}
 
-    MustUseHttps,
+fn default_body_read_timeout() -> u64 {
+    DEFAULT_BODY_READ_TIMEOUT
+}
 
-    #[quick_from]
-    Token(crypto::TokenError),
+fn default_min_body_rate() -> u64 {
+    DEFAULT_MIN_BODY_RATE
+}
 
-    #[quick_from]
-    Jwt(jwt::errors::Error),
+/// Limits on how slowly clients may send requests, so they can't hold
+/// connections open indefinitely
+#[derive(Deserialize,Debug,Clone,Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    /// seconds a client has to send the headers of a request, enforced by
+    /// the listener for HTTP/1 only. hyper has no such timeout for HTTP/2,
+    /// so a client can hold an HTTP/2 connection open by never finishing
+    /// the headers of a request. The body limits below apply to both.
+    #[serde(default = "default_header_read_timeout")]
+    pub header_read : u64,
+    /// seconds a request body may go without new data
+    #[serde(default = "default_body_read_timeout")]
+    pub body_read : u64,
+    /// bytes per second a request body must arrive at on average, after
+    /// its first second. 0 turns this off.
+    #[serde(default = "default_min_body_rate")]
+    pub min_body_rate : u64,
+}
 
-    #[quick_from]
-    Io(std::io::Error),
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self{
+            header_read : DEFAULT_HEADER_READ_TIMEOUT,
+            body_read : DEFAULT_BODY_READ_TIMEOUT,
+            min_body_rate : DEFAULT_MIN_BODY_RATE,
+        }
+    }
+}
 
-    #[quick_from]
-    Rusqlite(rusqlite::Error),
+/// The public key of a signing key `authn-utils rotate-keys` replaced,
+/// tokens it signed still validate until they could have expired. Only one
+/// is kept, rotating again within `MAX_DURATION` invalidates them.
+#[derive(Deserialize,Debug,Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetiredKey {
+    pub pub_key_file : String,
+    pub alg : jwt::Algorithm,
+    /// the `kid` header of tokens signed with the key, they had none if
+    /// unset
+    #[serde(default)]
+    pub kid : Option<String>,
+    /// unix time after which the key is ignored, `MAX_DURATION` after the
+    /// rotation
+    pub until : u64,
+}
 
-    #[quick_from]
-    Mux(mux::MuxError),
+/// the key and validation of a `RetiredKey` still in use
+struct RetiredDecodingKey {
+    kid : Option<String>,
+    until : u64,
+    key : jwt::DecodingKey<'static>,
+    validation : jwt::Validation,
+}
 
-    #[quick_from]
-    SerdeJson(serde_json::Error),
+/// Routes to leave out of a deployment, by the name of their function as
+/// in the request log, e.g. `post_register_accept` or `get_pub_key`. The
+/// name `admin` stands for every `/admin` route.
+#[derive(Deserialize,Debug,Clone,Default)]
+#[serde(deny_unknown_fields)]
+pub struct RoutesConfig {
+    #[serde(default)]
+    pub disabled : Vec<String>,
+    /// left out unless the server listens on a unix socket, e.g. on windows
+    #[serde(default)]
+    pub unix_only : Vec<String>,
+}
 
-    #[quick_from]
-    Argon2(argon2::Error),
+impl RoutesConfig {
+    /// the routes left out on this platform
+    fn disabled(&self) -> Vec<String> {
+        let mut disabled = self.disabled.clone();
+        if cfg!(not(unix)) {
+            disabled.extend(self.unix_only.iter().cloned());
+        }
 
-    #[quick_from]
-    Hyper(hyper::Error),
+        disabled
+    }
 }
 
+/// the cargo features of the crate, and whether the build has them
+const FEATURES : &[(&str, bool)] = &[
+    ("server", cfg!(feature = "server")),
+    ("client", cfg!(feature = "client")),
+    ("client-offline", cfg!(feature = "client-offline")),
+    ("cli", cfg!(feature = "cli")),
+    ("keyring", cfg!(feature = "keyring")),
+    ("admin-ui", cfg!(feature = "admin-ui")),
+    ("saml", cfg!(feature = "saml")),
+    ("negotiate", cfg!(feature = "negotiate")),
+    ("pam", cfg!(feature = "pam")),
+    ("oauth", cfg!(feature = "oauth")),
+    ("jwe", cfg!(feature = "jwe")),
+    ("nats", cfg!(feature = "nats")),
+    ("kafka", cfg!(feature = "kafka")),
+    ("redis", cfg!(feature = "redis")),
+    ("geoip", cfg!(feature = "geoip")),
+    ("testing", cfg!(feature = "testing")),
+];
+
+/// role required to mint tokens for other users
+pub const IMPERSONATE_ROLE : &str = "impersonate";
+
+/// role required to read server statistics
+pub const ADMIN_ROLE : &str = "admin";
+
+/// how often in-memory stats are written to the database
+const STATS_FLUSH_INTERVAL : u64 = 60;
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+pub(crate) type Request = http::Request<Body>;
+pub(crate) type Response = http::Response<Body>;
+type Mux = mux::Mux<Error, (), Body, Response>;
+
+/// methods checked when listing the ones a path allows
+const METHODS : &[http::Method] = &[
+    http::Method::GET,
+    http::Method::POST,
+    http::Method::PUT,
+    http::Method::PATCH,
+    http::Method::DELETE,
+];
+
+
 
 #[derive(Deserialize)]
 pub struct Config {
     pub server_name : String,
+    /// public url of the server, e.g. `https://auth.example.com`. If set,
+    /// tokens are issued by it rather than `server_name`, as OIDC requires.
+    #[serde(default)]
+    pub external_url : Option<String>,
+    /// the audience of tokens for the server itself, which the admin and
+    /// account routes require, the issuer if unset
+    #[serde(default)]
+    pub audience : Option<String>,
+    /// the unix socket to listen on, e.g. `%t/authn/authn.sock`, see
+    /// `config::expand_path` and `prepare_socket`. A loopback address on
+    /// windows.
     pub server_path : String,
     pub alg : jwt::Algorithm,
     pub priv_key_file : String,
     pub pub_key_file : String,
+    /// json file of further fields, e.g. `priv_key_file` or
+    /// `login_notifications`, which only the server should read, see
+    /// `config`
+    #[serde(default)]
+    pub secrets_file : Option<String>,
+    /// pem certificate chain for the signing key, leaf first, served at
+    /// `GET /cert`. The server doesn't start if the leaf isn't for
+    /// `pub_key_file`.
+    #[serde(default)]
+    pub cert_file : Option<String>,
     pub database : String,
+    /// milliseconds, database methods taking longer are logged, see
+    /// `Database::with_slow_query`
+    #[serde(default)]
+    pub slow_query : Option<u64>,
+    /// extra read only database connections, see `Database::open`
+    #[serde(default)]
+    pub read_connections : usize,
+    pub login_notifications : Option<notify::Config>,
+    /// refuse logins after too many failures, counted in memory or in
+    /// `redis`
+    pub login_rate_limit : Option<ratelimit::Config>,
+    /// score logins, denying risky ones, see `risk`
+    #[serde(default)]
+    pub risk : Option<risk::Config>,
+    /// refuse anonymous callers of the discovery routes, e.g.
+    /// `GET /pub-key`, after too many requests, counted in memory or in
+    /// `redis`
+    #[serde(default)]
+    pub discovery_rate_limit : Option<ratelimit::DiscoveryConfig>,
+    /// keep the counters of the rate limits and `risk` in redis, shared
+    /// with the other servers using it, rather than in memory, and publish
+    /// events there given `events_channel`
+    #[cfg(feature = "redis")]
+    #[serde(default)]
+    pub redis : Option<redis::Config>,
+    /// seconds after a login past which tokens can no longer be renewed
+    #[serde(default = "default_max_session")]
+    pub max_session : u64,
+    /// extra jwt header fields of issued tokens
+    #[serde(default)]
+    pub token_header : crypto::HeaderFields,
+    /// the key before the last rotation, written by `authn-utils
+    /// rotate-keys`
+    #[serde(default)]
+    pub retired_key : Option<RetiredKey>,
+    /// claims of issued tokens to rename or omit, for all or some
+    /// audiences
+    #[serde(default)]
+    pub claims : crypto::ClaimsMapping,
+    /// where logs are written, stdout if empty. Used to be `log`.
+    #[serde(default)]
+    pub log_sinks : Vec<logging::SinkConfig>,
+    /// where audit entries are shipped besides the database, see `audit`
+    #[serde(default)]
+    pub audit_sinks : Vec<audit::SinkConfig>,
+    #[serde(default)]
+    pub timeouts : Timeouts,
+    /// layers around the routes, in the order requests pass them, see
+    /// `middleware`
+    #[serde(default)]
+    pub layers : Vec<middleware::LayerConfig>,
+    /// routes to leave out
+    #[serde(default)]
+    pub routes : RoutesConfig,
+    /// fail responses and withhold log lines holding secrets, always on
+    /// in debug builds, see `leaks`
+    #[serde(default)]
+    pub secret_guard : bool,
+    /// the window of the login and validation latency quantiles, and when
+    /// to warn about them
+    #[serde(default)]
+    pub latency : metrics::LatencyConfig,
+    /// only these local processes may call the admin routes, on top of
+    /// the admin role. Anyone with the role may if unset.
+    #[serde(default)]
+    pub admin_peers : Option<peer::Config>,
+    /// how many proxies in front of the server append to
+    /// `X-Forwarded-For`, see `client_addr`. The header is ignored if 0,
+    /// the default.
+    #[serde(default)]
+    pub trusted_proxies : usize,
+    /// who may take which admin actions, on top of the admin and
+    /// impersonate roles
+    #[serde(default)]
+    pub policy : policy::Config,
+    #[serde(default)]
+    pub invites : invites::Config,
+    /// policy documents users accept, e.g. terms of service
+    #[serde(default)]
+    pub consent : consent::Config,
+    /// how strong passwords set over the api must be
+    #[serde(default)]
+    pub password_strength : strength::Config,
+    /// how new password hashes are made. Hashes made otherwise are made
+    /// again on login, e.g. to move them to argon2id.
+    #[serde(default)]
+    pub password_hash : crypto::Argon2Params,
+    /// the length of the salts of new password hashes
+    #[serde(default = "default_password_salt_len")]
+    pub password_salt_len : usize,
+    /// the number of previous passwords a user can't set again
+    #[serde(default = "default_password_history")]
+    pub password_history : usize,
+    /// how long emergency access lasts
+    #[serde(default)]
+    pub break_glass : break_glass::Config,
+    /// act as a saml identity provider, requires `cert_file`
+    #[cfg(feature = "saml")]
+    #[serde(default)]
+    pub saml : Option<saml::Config>,
+    /// accept kerberos tickets on `POST /login`
+    #[cfg(feature = "negotiate")]
+    #[serde(default)]
+    pub negotiate : Option<negotiate::Config>,
+    /// check passwords with PAM instead of the database
+    #[cfg(feature = "pam")]
+    #[serde(default)]
+    pub pam : Option<pam::Config>,
+    /// clients of the oauth2 authorization code grant
+    #[cfg(feature = "oauth")]
+    #[serde(default)]
+    pub oauth : Option<oauth::Config>,
+    /// locate logins by the client's address
+    #[cfg(feature = "geoip")]
+    #[serde(default)]
+    pub geoip : Option<geoip::Config>,
+}
+
+impl Config {
+    /// the `iss` of issued tokens, the normalized `external_url` if it's
+    /// set, otherwise `server_name`
+    pub fn issuer(&self) -> std::result::Result<String, Error> {
+        match &self.external_url {
+            Some(url) => crypto::issuer_url(url).map_err(|err| match err {
+                crypto::IssuerUrlError::NotHttps => ConfigError::MustUseHttps.into(),
+                crypto::IssuerUrlError::Invalid => ConfigError::InvalidExternalUrl.into(),
+            }),
+            None => Ok(self.server_name.clone()),
+        }
+    }
 }
 
 pub struct Server {
-    server_name : String,
-    alg : jwt::Algorithm,
+    /// the `iss` of issued tokens, see `Config::issuer`
+    pub(crate) issuer : String,
+    /// see `Config::audience`
+    pub(crate) audience : String,
+    /// header of issued tokens, including the signing algorithm
+    header : jwt::Header,
+    claims : crypto::ClaimsMapping,
     priv_key : jwt::EncodingKey,
     pub_key : String,
-    database : Database,
+    cert : Option<String>,
+    pub_dec_key : jwt::DecodingKey<'static>,
+    validation : jwt::Validation,
+    retired_key : Option<RetiredDecodingKey>,
+    pub(crate) database : Database,
+    pub(crate) notifier : Option<Notifier>,
+    pub(crate) max_session : u64,
+    timeouts : Timeouts,
+    admin_peers : Option<peer::Policy>,
+    trusted_proxies : usize,
+    policy : policy::Policy,
+    policy_evaluator : Option<Box<dyn policy::Evaluator>>,
+    pub(crate) invites : invites::Config,
+    pub(crate) consent : consent::Config,
+    pub(crate) password_strength : strength::Config,
+    password_hash : crypto::Argon2Params,
+    password_salt_len : usize,
+    password_history : usize,
+    break_glass : break_glass::Config,
+    claims_enricher : Option<Box<dyn ClaimsEnricher>>,
+    error_reporter : Option<Box<dyn ErrorReporter>>,
+    event_bus : Option<Box<dyn EventBus>>,
+    /// see `Config::secret_guard`
+    leak_guard : Option<leaks::Guard>,
+    audit_sinks : Vec<Box<dyn AuditSink>>,
+    layer_configs : Vec<middleware::LayerConfig>,
+    layers : Vec<Box<dyn Layer>>,
+    routes : RoutesConfig,
+    /// the message of maintenance mode, if it's on
+    maintenance : std::sync::RwLock<Option<String>>,
+    pub(crate) clock : Box<dyn crypto::Clock>,
+    login_hooks : Vec<Box<dyn LoginHook>>,
+    risk_engine : Option<Box<dyn RiskEngine>>,
+    /// the thresholds the scores of `risk_engine` are held against
+    risk : risk::Config,
+    discovery_limiter : Option<ratelimit::DiscoveryLimiter>,
+    argon2_latency : Histogram,
+    jwt_sign_latency : Histogram,
+    jwt_verify_latency : Histogram,
+    login_latency : RollingQuantiles,
+    validate_latency : RollingQuantiles,
+    stats : Stats,
+    #[cfg(feature = "saml")]
+    pub(crate) saml : Option<saml::Idp>,
+    #[cfg(feature = "negotiate")]
+    negotiator : Option<Arc<negotiate::Negotiator>>,
+    #[cfg(feature = "pam")]
+    pam : Option<Arc<pam::Pam>>,
+    #[cfg(feature = "oauth")]
+    pub(crate) oauth : Option<oauth::Config>,
+    #[cfg(feature = "geoip")]
+    geoip : Option<geoip::GeoIp>,
 }
 
     pub fn new_server(config : Config) -> std::result::Result<(Server, PathBuf), Error> {
-        let priv_key_string = std::fs::read_to_string(config.priv_key_file)?;
+        logging::init(&config.log_sinks)?;
+
+        for path in config.secrets_file.iter().chain(Some(&config.priv_key_file)) {
+            warn_if_world_readable(path);
+        }
+
+        let issuer = config.issuer()?;
+        let priv_key_string = Zeroizing::new(std::fs::read_to_string(config.priv_key_file)?);
+
+        let leak_guard = (config.secret_guard || cfg!(debug_assertions))
+            .then(|| leaks::Guard::new(&priv_key_string));
 
         use jwt::Algorithm::*;
         let priv_key = match config.alg {
             ES256 | ES384 => jwt::EncodingKey::from_ec_pem(priv_key_string.as_bytes())?,
             RS256 | RS384 | RS512 |
             PS256 | PS384 | PS512 => jwt::EncodingKey::from_rsa_pem(priv_key_string.as_bytes())?,
-            alg => return Err(Error::AlgorithmNotAllowed(alg))
+            alg => return Err(ConfigError::AlgorithmNotAllowed(alg).into())
         };
 
+        if let Some(claim) = config.claims.invalid_claim() {
+            return Err(ConfigError::InvalidClaimsMap(claim.to_string()).into())
+        }
+
         let pub_key = std::fs::read_to_string(config.pub_key_file)?;
 
-        let server = Server{
-            server_name : config.server_name,
-            database : Database::new(&config.database)?,
-            alg : config.alg,
+        let cert = config.cert_file
+            .map(std::fs::read_to_string)
+            .transpose()?;
+        if let Some(cert) = &cert {
+            if !cert.contains("-----BEGIN CERTIFICATE-----") {
+                return Err(ConfigError::InvalidCertificate.into())
+            }
+            // relying parties would refuse the signatures
+            if !crypto::cert_matches_key(cert, &pub_key) {
+                return Err(ConfigError::CertificateKeyMismatch.into())
+            }
+        }
+
+        #[cfg(feature = "saml")]
+        let alg = config.alg;
+        #[cfg(feature = "saml")]
+        let saml = config.saml
+            .map(|saml| saml::Idp::new(saml, alg, &priv_key_string, cert.as_deref()))
+            .transpose()?;
+
+        let pub_dec_key = match config.alg {
+            ES256 | ES384 => jwt::DecodingKey::from_ec_pem(pub_key.as_bytes())?,
+            _ => jwt::DecodingKey::from_rsa_pem(pub_key.as_bytes())?,
+        }.into_static();
+
+        // tokens for any audience are accepted when authenticating requests
+        // to the server itself
+        let validation = jwt::Validation{
+            validate_exp : true,
+            iss : Some(issuer.clone()),
+            algorithms : vec![config.alg],
+            ..Default::default()
+        };
+
+        let header = config.token_header.header(config.alg);
+        self_test(&issuer, &header, &priv_key, &pub_dec_key, &validation)?;
+
+        // the file may be gone once the key is no longer needed
+        let retired_key = match config.retired_key {
+            Some(retired) if retired.until > unix_now() as u64 => {
+                let pem = std::fs::read(&retired.pub_key_file)?;
+                Some(RetiredDecodingKey{
+                    key : crypto::decoding_key(retired.alg, &pem)?,
+                    validation : jwt::Validation{
+                        algorithms : vec![retired.alg],
+                        ..validation.clone()
+                    },
+                    kid : retired.kid,
+                    until : retired.until,
+                })
+            },
+            _ => None,
+        };
+
+        let mut database = Database::open(&config.database, config.read_connections)?;
+        crate::database::check_schema(&config.database)?;
+        if let Some(ms) = config.slow_query {
+            database = database.with_slow_query(std::time::Duration::from_millis(ms));
+        }
+
+        #[cfg(feature = "redis")]
+        let event_bus = config.redis.as_ref()
+            .and_then(crate::events::RedisBus::from_config)
+            .map(|bus| Box::new(bus) as Box<dyn EventBus>);
+        #[cfg(not(feature = "redis"))]
+        let event_bus = None;
+
+        // one store for every counter, their keys don't overlap
+        #[cfg(feature = "redis")]
+        let counters : Arc<dyn RateLimitStore> = match config.redis {
+            Some(redis) => Arc::new(ratelimit::RedisStore::new(redis)),
+            None => Arc::new(ratelimit::MemoryStore::default()),
+        };
+        #[cfg(not(feature = "redis"))]
+        let counters : Arc<dyn RateLimitStore> = Arc::new(ratelimit::MemoryStore::default());
+
+        let mut server = Server{
+            audience : config.audience.unwrap_or_else(|| issuer.clone()),
+            issuer,
+            database,
+            header,
+            claims : config.claims,
             priv_key,
             pub_key,
+            cert,
+            pub_dec_key,
+            validation,
+            retired_key,
+            notifier : config.login_notifications.map(Notifier::new).transpose()?,
+            max_session : config.max_session,
+            timeouts : config.timeouts,
+            admin_peers : config.admin_peers.map(peer::Policy::new).transpose()?,
+            trusted_proxies : config.trusted_proxies,
+            policy : policy::Policy::new(config.policy),
+            policy_evaluator : None,
+            invites : config.invites,
+            consent : config.consent,
+            password_strength : config.password_strength,
+            password_hash : config.password_hash,
+            password_salt_len : config.password_salt_len,
+            password_history : config.password_history,
+            break_glass : config.break_glass,
+            claims_enricher : None,
+            error_reporter : None,
+            event_bus,
+            leak_guard,
+            audit_sinks : audit::open(&config.audit_sinks)?,
+            layer_configs : config.layers,
+            layers : Vec::new(),
+            routes : config.routes,
+            maintenance : Default::default(),
+            clock : Box::new(crypto::SystemClock),
+            login_hooks : Vec::new(),
+            risk_engine : config.risk.as_ref()
+                .map(|risk| Box::new(risk::Rules::new(Arc::clone(&counters), risk)) as Box<dyn RiskEngine>),
+            risk : config.risk.unwrap_or_default(),
+            discovery_limiter : config.discovery_rate_limit.as_ref()
+                .map(|limit| ratelimit::DiscoveryLimiter::new(Arc::clone(&counters), limit)),
+            argon2_latency : Default::default(),
+            jwt_sign_latency : Default::default(),
+            jwt_verify_latency : Default::default(),
+            login_latency : RollingQuantiles::new(&config.latency),
+            validate_latency : RollingQuantiles::new(&config.latency),
+            stats : Default::default(),
+            #[cfg(feature = "saml")]
+            saml,
+            #[cfg(feature = "negotiate")]
+            negotiator : config.negotiate
+                .map(negotiate::Negotiator::new)
+                .transpose()?
+                .map(Arc::new),
+            #[cfg(feature = "oauth")]
+            oauth : config.oauth,
+            #[cfg(feature = "pam")]
+            pam : config.pam
+                .map(pam::Pam::new)
+                .transpose()?
+                .map(Arc::new),
+            #[cfg(feature = "geoip")]
+            geoip : config.geoip
+                .as_ref()
+                .map(geoip::GeoIp::open)
+                .transpose()?,
         };
 
-        Ok((server, config.server_path.into()))
+        if let Some(limit) = &config.login_rate_limit {
+            server = server.with_login_hook(
+                ratelimit::LoginLimiter::new(counters, limit)
+            );
+        }
+
+        let path = crate::config::expand_path(&config.server_path)
+            .ok_or(ConfigError::InvalidServerPath(config.server_path))?;
+
+        let info = server.build_info();
+        logging::info!(
+            [
+                ("version", serde_json::json!(info.version)),
+                ("commit", serde_json::json!(info.commit)),
+                ("built", serde_json::json!(info.built)),
+                ("features", serde_json::json!(info.features)),
+                ("alg", serde_json::json!(info.alg)),
+            ],
+            "authn {} ({}) starting",
+            info.version,
+            info.commit.as_deref().unwrap_or("unknown commit"),
+        );
+
+        Ok((server, path))
     }
 
-pub fn routes(server : Server) -> impl Pipe<Input = (Request,), Output = Response> {
+/// readies `path` for binding the server's socket: creates its directory,
+/// and removes a socket left behind by a server which exited. Refuses to
+/// remove a socket another process is listening on, or anything but a
+/// socket.
+#[cfg(unix)]
+pub fn prepare_socket(path : &std::path::Path) -> std::io::Result<()> {
+    use std::io;
+    use std::os::unix::fs::FileTypeExt;
 
-    macro_rules! register_routes {
-        ($($route:ident,)*) => {
-            {
-                let server = Arc::new(server);
-                let mux = http_mux::mux::new_mux::<Error, _, _>();
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
 
-                $(let mux = $route(Arc::clone(&server), mux);)*
+    let meta = match std::fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ))
+    }
 
-                mux
-            }
+    // nobody accepts connections on a stale socket
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another process is listening on {}", path.display()),
+        )),
+        Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(err) => Err(err),
+    }
+}
+
+/// secrets should only be readable by the server
+#[cfg(unix)]
+fn warn_if_world_readable(path : &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(meta) = std::fs::metadata(path) {
+        if meta.permissions().mode() & 0o004 != 0 {
+            logging::error!("{} is readable by everyone, it should only be readable by the server", path);
         }
     }
+}
 
-    let mux = register_routes!{
-        post_login,
-        get_user,
-        get_pub_key,
+/// windows has no mode bits to check
+#[cfg(not(unix))]
+fn warn_if_world_readable(_ : &str) {}
+
+/// signs a probe token and validates it, so keys which aren't a pair or
+/// don't fit the algorithm fail at startup rather than on the first token
+fn self_test(
+    issuer : &str,
+    header : &jwt::Header,
+    priv_key : &jwt::EncodingKey,
+    pub_dec_key : &jwt::DecodingKey<'_>,
+    validation : &jwt::Validation,
+) -> std::result::Result<(), Error> {
+    let probe = crypto::Token{
+        iss : issuer.to_string(),
+        aud : "self-test".to_string(),
+        sub : "self-test".to_string(),
+        version : 0,
+        aud_version : 0,
+        acr : crypto::Assurance::default(),
+        act : None,
+        auth_time : None,
+        org : None,
+        extra : Default::default(),
+    };
+
+    let s = probe.issue_with(&crypto::SystemClock, priv_key, header, std::time::Duration::from_secs(60))
+        .map_err(|_| ConfigError::KeyMismatch)?;
+    crypto::Token::validate(&s, validation, pub_dec_key)
+        .map_err(|_| ConfigError::KeyMismatch)?;
+
+    Ok(())
+}
+
+/// Adds application specific claims, e.g. a plan tier or organization id,
+/// to every token the server issues. Claims colliding with the ones set by
+/// the server are dropped.
+pub trait ClaimsEnricher : Send + Sync {
+    fn enrich(&self, sub : String, aud : String) -> ClaimsFuture;
+}
+
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+pub type ClaimsFuture = Pin<Box<dyn Future<Output = Result<Claims>> + Send>>;
+
+impl<F, Fut> ClaimsEnricher for F
+where
+    F : Fn(String, String) -> Fut + Send + Sync,
+    Fut : Future<Output = Result<Claims>> + Send + 'static,
+{
+    fn enrich(&self, sub : String, aud : String) -> ClaimsFuture {
+        Box::pin(self(sub, aud))
     }
-    .tuple()
-    .seq(|res : Result<Response>| {
-        match res {
-            Ok(res) => res,
-            Err(err)  => render_error(err),
-        }
-    });
+}
 
+/// An internal error which produced a 5xx response, as passed to the
+/// `ErrorReporter`. Only the path of the request is kept, never its
+/// headers or body.
+#[derive(Debug,Clone)]
+pub struct ErrorReport {
+    pub request_id : String,
+    pub method : String,
+    pub path : String,
+    pub error : String,
+}
 
-    log_middleware(mux)
+/// Receives internal errors, e.g. to forward them to an error tracking
+/// service. Reporting runs on the request path so slow reporters should
+/// hand the report off to a background task.
+pub trait ErrorReporter : Send + Sync {
+    fn report(&self, report : ErrorReport);
 }
 
-fn post_login(server : Arc<Server>, m : Mux) -> Mux {
-    m.handle(
-        route!(POST / "login"),
-        mux::new_handler()
-        .map_bind(server.clone())
-        .aand_then(|req : Request, server : Arc<Server>| async move {
-            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
-            let req : PostLoginRequest = serde_json::from_reader(reader)
-                .map_err(|_| Error::BadRequest)?;
+impl<F> ErrorReporter for F
+where
+    F : Fn(ErrorReport) + Send + Sync,
+{
+    fn report(&self, report : ErrorReport) {
+        self(report)
+    }
+}
 
-            let user = server.database.get_user_by_name(&req.name).await?;
+/// Identifies a request in the logs and error reports, it's added to the
+/// request's extensions and echoed in the `x-request-id` response header
+#[derive(Debug,Clone,Default)]
+pub struct RequestId(pub String);
 
-            if !crypto::verify_password(&user.pass_hash, req.pass.as_bytes())? {
-                return Err(Error::LoginFailed)
-            }
+/// Details of a request filled in while it's handled, `log_layer` adds it to the request's extensions and logs it with the response
+#[derive(Clone,Default)]
+struct RequestLog(Arc<std::sync::Mutex<RequestLogFields>>);
 
-            let token = crypto::Token{
-                iss : server.server_name.to_string(),
-                aud : req.aud,
-                sub : req.name,
-                version : user.token_version,
-            }.issue(
-                &server.priv_key,
-                server.alg,
-                std::time::Duration::from_secs(req.duration.min(MAX_DURATION)),
-            )?;
+#[derive(Default)]
+struct RequestLogFields {
+    /// the name of the route's function
+    route : Option<&'static str>,
+    /// the subject of the request's token
+    subject : Option<String>,
+    /// the code of the error the handler returned
+    error : Option<&'static str>,
+}
 
-            let s = serde_json::to_string(&PostLoginResponse{ token })?;
-            Ok(Response::new(s.into()))
-        })
-    )
+impl RequestLog {
+    /// the request's log, a detached one if it has none
+    fn of(req : &Request) -> Self {
+        req.extensions().get::<Self>().cloned().unwrap_or_default()
+    }
 
+    fn set<F : FnOnce(&mut RequestLogFields)>(&self, f : F) {
+        f(&mut self.0.lock().unwrap())
+    }
 }
 
-fn get_user(server : Arc<Server>, m : Mux) -> Mux {
-    #[derive(Serialize)]
-    struct Res {
-        name : String,
-        token_version : u32,
+/// Headers for the response of a request if it fails, `error_layer` adds
+/// it to the request's extensions and appends them once the error is
+/// rendered, so layers inside it can e.g. add CORS headers to errors
+#[derive(Clone,Default)]
+pub(crate) struct ErrorHeaders(Arc<std::sync::Mutex<http::HeaderMap>>);
+
+impl ErrorHeaders {
+    /// the request's error headers, detached ones if it has none
+    pub(crate) fn of(req : &Request) -> Self {
+        req.extensions().get::<Self>().cloned().unwrap_or_default()
     }
 
-    m.handle(
-        route!(GET / "user" / String),
-        mux::new_handler()
-        .map_bind(server.clone())
-        .aand_then(|_, user : String, server : Arc<Server>| async move {
-            let user = server.database.get_user_by_name(&user).await?;
-            let s = serde_json::to_string(&Res{
-                name : user.name,
-                token_version : user.token_version,
-            })?;
+    pub(crate) fn set<F : FnOnce(&mut http::HeaderMap)>(&self, f : F) {
+        f(&mut self.0.lock().unwrap())
+    }
 
-            Ok(Response::new(s.into()))
-        })
-    )
+    fn append_to(&self, res : &mut Response) {
+        for (name, value) in self.0.lock().unwrap().iter() {
+            res.headers_mut().append(name.clone(), value.clone());
+        }
+    }
 }
 
-fn get_pub_key(server : Arc<Server>, m : Mux) -> Mux {
-    m.handle(
-        route!(GET / "pub-key"),
-        mux::new_handler()
-        .map_bind(server.clone())
-        .map(|_, server : Arc<Server>| {
-            Response::new(server.pub_key.clone().into())
-        })
-    )
+/// A login attempt, as passed to `LoginHook`s
+pub struct LoginAttempt {
+    pub name : String,
+    pub aud : String,
+    pub user_agent : String,
+    /// the client's address, see `client_addr`
+    pub addr : Option<IpAddr>,
+    /// iso code of the country `addr` is in, see `geoip`
+    pub country : Option<String>,
+    /// the user logged in before, but never from `country`
+    pub new_country : bool,
+    /// the country the user last logged in from, if `country` is known
+    pub last_country : Option<models::LoginCountry>,
+    /// the score of the `RiskEngine`, if there is one
+    pub risk : Option<risk::Risk>,
+    /// the attempt scored `risk::Config::step_up_score` or more, so a
+    /// `LoginHook` may ask for more than a password
+    pub step_up : bool,
 }
 
-fn render_error(err : Error) -> Response {
-    use http::StatusCode as S;
-    use Error::*;
-
-    eprintln!("{:?}", &err);
-
-    let status;
-    let body;
-
-    match err {
-        UserNotFound(_) => {
-            status = S::NOT_FOUND;
-            body   = "user not found";
-        },
-        BadRequest => {
-            status = S::BAD_REQUEST;
-            body = "bad request";
-        },
-        LoginFailed => {
-            status = S::UNAUTHORIZED;
-            body = "login failed";
-        },
-        Mux(mux::MuxError::NotFound(_)) => {
-            status = S::NOT_FOUND;
-            body = "route not found";
-        },
-        Mux(mux::MuxError::MethodNotAllowed(_, _)) => {
-            status = S::METHOD_NOT_ALLOWED;
-            body = "method not defined for route";
-        },
-        Mux(mux::MuxError::Parse(_, _)) => {
-            status = S::BAD_REQUEST;
-            body = "invalid path values";
-        },
-        _ => {
-            status = S::INTERNAL_SERVER_ERROR;
-            body   = "internal server error";
-        }
-    }
-
-    let body = format!("{{ \"error\": \"{}\" }}", body);
-
-   http::response::Builder::new()
-       .status(status)
-       .body(body.into())
-       .unwrap()
-}
-
-fn log_middleware<P>(next : P) -> impl Pipe<Input = (Request,), Output = P::Output>
-where
-    P : Pipe<Input = (Request,), Output = Response> + Send + Sync + 'static,
-{
-    // TODO: probably not ideal?
-    // bettern than cloning the whole mux
-    let next = Arc::new(next);
+/// The address of the client of a request over tcp
+#[derive(Debug,Clone,Copy)]
+pub struct RemoteAddr(pub SocketAddr);
 
-    plumb::id()
-    .aseq(|req : Request| async move {
-        let pre_details = format!(
-            "{} {}",
-            req.method(),
-            req.uri().path(),
-        );
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum LoginOutcome {
+    Success,
+    /// wrong credentials or unknown user
+    Failed,
+    /// a hook vetoed the login
+    Denied,
+    /// the login failed for reasons unrelated to the credentials
+    Error,
+}
 
-        let start = tokio::time::Instant::now();
+impl<T> From<&Result<T>> for LoginOutcome {
+    fn from(res : &Result<T>) -> Self {
+        match res {
+            Ok(_) => LoginOutcome::Success,
+            Err(Error::Auth(AuthError::LoginFailed)) | Err(Error::Storage(StorageError::UserNotFound(_))) => LoginOutcome::Failed,
+            Err(Error::Auth(AuthError::LoginDenied(_) | AuthError::TooManyAttempts)) => LoginOutcome::Denied,
+            Err(_) => LoginOutcome::Error,
+        }
+    }
+}
 
-        let res = next.run((req,)).await;
+pub type HookFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
 
-        let end = tokio::time::Instant::now();
-        let delta = end - start;
+/// Hooks run around every login. `pre_login` runs before the credentials
+/// are checked and can veto the login by returning an error, typically
+/// `AuthError::LoginDenied`. `post_login` observes the outcome. Hooks run in
+/// the order they were added.
+pub trait LoginHook : Send + Sync {
+    fn pre_login(&self, _attempt : &LoginAttempt) -> HookFuture<Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
 
-        println!(
-            "{} {} {:?}",
-            res.status(),
-            pre_details,
-            delta
-        );
+    fn post_login(&self, _attempt : &LoginAttempt, _outcome : LoginOutcome) -> HookFuture<()> {
+        Box::pin(async {})
+    }
+}
+
+impl Server {
+    pub fn with_login_hook<H>(mut self, hook : H) -> Self
+    where
+        H : LoginHook + 'static,
+    {
+        self.login_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// runs `login` unless a pre login hook vetoes it, then reports the
+    /// outcome to the post login hooks
+    pub(crate) async fn hook_login<T, F>(&self, attempt : &LoginAttempt, login : F) -> Result<T>
+    where
+        F : Future<Output = Result<T>>,
+    {
+        let res = async {
+            self.check_risk(attempt).await?;
+
+            for hook in &self.login_hooks {
+                hook.pre_login(attempt).await?;
+            }
+
+            login.await
+        }.await;
+
+        let outcome = LoginOutcome::from(&res);
+        match outcome {
+            LoginOutcome::Success => self.stats.incr(unix_now(), stats::LOGIN, ""),
+            LoginOutcome::Failed => self.stats.incr(unix_now(), stats::FAILED_LOGIN, ""),
+            _ => {},
+        }
+
+        for hook in &self.login_hooks {
+            hook.post_login(attempt, outcome).await;
+        }
 
         res
-    })
-}
+    }
+
+    /// replaces the `risk::Rules` of `Config::risk`, its thresholds still
+    /// apply, or the defaults if it's unset
+    pub fn with_risk_engine<E>(mut self, engine : E) -> Self
+    where
+        E : RiskEngine + 'static,
+    {
+        self.risk_engine = Some(Box::new(engine));
+        self
+    }
+
+    /// audits any risk `attempt` was scored with, and fails it if it's too
+    /// risky
+    async fn check_risk(&self, attempt : &LoginAttempt) -> Result<()> {
+        let risk = match &attempt.risk {
+            Some(risk) if risk.score > 0 => risk,
+            _ => return Ok(()),
+        };
+
+        let verdict = self.risk.verdict(risk);
+        let detail = format!("{} {}: {}", verdict.as_str(), risk.score, risk.reasons.join(", "));
+        self.audit(None, "login-risk", Some(&attempt.name), Some(&detail)).await?;
+
+        match verdict {
+            risk::Verdict::Allow | risk::Verdict::StepUp => Ok(()),
+            risk::Verdict::Block => Err(AuthError::LoginDenied(format!("risk score {}", risk.score)).into()),
+        }
+    }
+
+    pub fn with_claims_enricher<E>(mut self, enricher : E) -> Self
+    where
+        E : ClaimsEnricher + 'static,
+    {
+        self.claims_enricher = Some(Box::new(enricher));
+        self
+    }
+
+    pub fn with_error_reporter<R>(mut self, reporter : R) -> Self
+    where
+        R : ErrorReporter + 'static,
+    {
+        self.error_reporter = Some(Box::new(reporter));
+        self
+    }
+
+    /// replaces the limiter of `Config::discovery_rate_limit`, e.g. with one
+    /// sharing its counters with other instances
+    pub fn with_discovery_limiter(mut self, limiter : ratelimit::DiscoveryLimiter) -> Self {
+        self.discovery_limiter = Some(limiter);
+        self
+    }
+
+    /// counts a request to a discovery route as anonymous, unless it has a
+    /// valid token, and refuses anonymous callers over the limit. The
+    /// first refused request of a window is audited.
+    pub(crate) async fn check_discovery(&self, req : &Request) -> Result<()> {
+        let now = unix_now();
+
+        if req.headers().contains_key(http::header::AUTHORIZATION) && self.authenticate_any(req).await.is_ok() {
+            self.stats.incr(now, stats::DISCOVERY, stats::DISCOVERY_AUTHENTICATED);
+            return Ok(())
+        }
+        self.stats.incr(now, stats::DISCOVERY, stats::DISCOVERY_ANONYMOUS);
+
+        let limiter = match &self.discovery_limiter {
+            Some(limiter) => limiter,
+            None => return Ok(()),
+        };
+
+        let caller = discovery_caller(req);
+        let over = limiter.check(&caller).await?;
+        if over == 0 {
+            return Ok(())
+        }
+
+        self.stats.incr(now, stats::DISCOVERY, stats::DISCOVERY_LIMITED);
+        if over == 1 {
+            let detail = format!("{} {}", caller, req.uri().path());
+            self.audit(None, "discovery-rate-limited", None, Some(&detail)).await?;
+        }
+
+        Err(TransportError::TooManyRequests.into())
+    }
+
+    /// publishes token invalidations, so other instances and caches can
+    /// drop the tokens right away, on `bus` rather than the one of
+    /// `Config::redis`
+    pub fn with_event_bus<B>(mut self, bus : B) -> Self
+    where
+        B : EventBus + 'static,
+    {
+        self.event_bus = Some(Box::new(bus));
+        self
+    }
+
+    /// publishes on the event bus, if any. The change was already made, so
+    /// failures are only logged.
+    pub(crate) async fn publish(&self, event : Event) {
+        if let Some(bus) = &self.event_bus {
+            if let Err(err) = bus.publish(event).await {
+                logging::error!("failed to publish event: {:?}", err);
+            }
+        }
+    }
+
+    /// ships audit entries to `sink` too, after the sinks of the config
+    pub fn with_audit_sink<S>(mut self, sink : S) -> Self
+    where
+        S : AuditSink + 'static,
+    {
+        self.audit_sinks.push(Box::new(sink));
+        self
+    }
+
+    /// adds an entry to the audit log, and hands it to the audit sinks
+    pub(crate) async fn audit(
+        &self,
+        actor : Option<&str>,
+        action : &str,
+        subject : Option<&str>,
+        detail : Option<&str>,
+    ) -> Result<()> {
+        let entry = self.database.insert_audit(actor, action, subject, detail).await?;
+        audit::deliver(&self.audit_sinks, &entry.into());
+
+        Ok(())
+    }
+
+    /// runs `layer` around the routes, after the layers of the config
+    pub fn with_layer<L>(mut self, layer : L) -> Self
+    where
+        L : Layer + 'static,
+    {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    /// turns maintenance mode on with a message for clients, or off with
+    /// `None`, as `PUT /admin/maintenance` does
+    pub fn set_maintenance(&self, message : Option<String>) {
+        *self.maintenance.write().unwrap() = message;
+    }
+
+    /// the message of maintenance mode, `None` unless it's on
+    pub fn maintenance(&self) -> Option<String> {
+        self.maintenance.read().unwrap().clone()
+    }
+
+    /// what the server was built from, as served by `GET /version`
+    pub fn build_info(&self) -> GetVersionResponse {
+        GetVersionResponse{
+            version : env!("CARGO_PKG_VERSION").to_string(),
+            commit : option_env!("AUTHN_GIT_COMMIT")
+                .filter(|commit| !commit.is_empty())
+                .map(str::to_string),
+            built : option_env!("AUTHN_BUILD_TIME").and_then(|time| time.parse().ok()),
+            features : FEATURES.iter()
+                .filter(|(_, on)| *on)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            alg : format!("{:?}", self.header.alg),
+        }
+    }
+
+    /// replaces `Config::routes`
+    pub fn with_routes(mut self, routes : RoutesConfig) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    /// replaces the limits on slow clients from the config
+    pub fn with_timeouts(mut self, timeouts : Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// replaces `Config::admin_peers`
+    pub fn with_admin_peers(mut self, policy : peer::Policy) -> Self {
+        self.admin_peers = Some(policy);
+        self
+    }
+
+    /// replaces `Config::trusted_proxies`
+    pub fn with_trusted_proxies(mut self, n : usize) -> Self {
+        self.trusted_proxies = n;
+        self
+    }
+
+    /// replaces `Config::policy`
+    pub fn with_policy(mut self, policy : policy::Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// replaces `Config::claims`, panics if `mapping` omits or renames a
+    /// claim the config couldn't, see `crypto::ClaimsMapping::invalid_claim`
+    pub fn with_claims_mapping(mut self, mapping : crypto::ClaimsMapping) -> Self {
+        if let Some(claim) = mapping.invalid_claim() {
+            panic!("the claims mapping can't omit or rename {}", claim);
+        }
+
+        self.claims = mapping;
+        self
+    }
+
+    /// replaces `Config::consent`
+    pub fn with_consent(mut self, consent : consent::Config) -> Self {
+        self.consent = consent;
+        self
+    }
+
+    /// replaces `Config::geoip`
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip(mut self, geoip : geoip::GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// replaces `Config::password_hash`
+    pub fn with_password_hash(mut self, params : crypto::Argon2Params) -> Self {
+        self.password_hash = params;
+        self
+    }
+
+    /// replaces `Config::password_strength`
+    pub fn with_password_strength(mut self, password_strength : strength::Config) -> Self {
+        self.password_strength = password_strength;
+        self
+    }
+
+    /// consults `evaluator` before the policy's rules
+    pub fn with_policy_evaluator<E>(mut self, evaluator : E) -> Self
+    where
+        E : policy::Evaluator + 'static,
+    {
+        self.policy_evaluator = Some(Box::new(evaluator));
+        self
+    }
+
+    /// the limits on slow clients, the listener applies `header_read` to
+    /// HTTP/1 connections
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    /// replaces the clock used to issue and validate tokens
+    pub fn with_clock<C>(mut self, clock : C) -> Self
+    where
+        C : crypto::Clock + 'static,
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// signs the token, after adding claims from the enricher and the
+    /// documents the subject has yet to accept, expiring after `duration`
+    /// seconds (or `MAX_DURATION` if that's shorter). Claims are mapped as
+    /// `Config::claims` says for the audience.
+    pub(crate) async fn issue_token(&self, user : &models::User, mut token : crypto::Token, duration : u64) -> Result<String> {
+        if let Some(enricher) = &self.claims_enricher {
+            let claims = enricher.enrich(token.sub.clone(), token.aud.clone()).await?;
+            token.extra.extend(claims);
+        }
+
+        token.extra.remove(crypto::PENDING_CONSENT_CLAIM);
+        let pending = self.consent.pending(self, &token.sub).await?;
+        if !pending.is_empty() {
+            token.extra.insert(crypto::PENDING_CONSENT_CLAIM.to_string(), pending.into());
+        }
+
+        let mut duration = duration.min(MAX_DURATION);
+        if let Some(end) = self.break_glass.session_end(self, user).await? {
+            let left = end.saturating_sub(unix_now());
+            if left <= 0 {
+                return Err(AuthError::SessionExpired.into())
+            }
+            duration = duration.min(left as u64);
+        }
+
+        let duration = std::time::Duration::from_secs(duration);
+
+        let map = self.claims.for_audience(&token.aud);
+        let s = self.jwt_sign_latency.time(|| {
+            token.issue_mapped(self.clock.as_ref(), &self.priv_key, &self.header, duration, map)
+        })?;
+
+        #[cfg(feature = "jwe")]
+        let s = match self.oauth.as_ref()
+            .and_then(|oauth| oauth.client(&token.aud))
+            .and_then(|client| client.encryption_key.as_ref())
+        {
+            Some(key) => key.encrypt(&s)?,
+            None => s,
+        };
+
+        self.stats.incr(unix_now(), stats::TOKEN_ISSUED, &token.aud);
+
+        Ok(s)
+    }
+
+    /// looks up the user and checks `pass` against their password hash,
+    /// or with PAM if it's configured, unless it's a break glass account.
+    /// Hashes aren't rehashed after PAM logins, see `pam`.
+    pub(crate) async fn check_password(&self, name : &str, pass : &crypto::Secret) -> Result<models::User> {
+        // break glass accounts are checked here even with pam, which may
+        // be what's down. The hash of a used password doesn't parse.
+        let break_glass = self.database.get_break_glass(name).await?;
+        if let Some(account) = &break_glass {
+            self.break_glass.check(account)?;
+        }
+
+        #[cfg(feature = "pam")]
+        if let (Some(pam), None) = (&self.pam, &break_glass) {
+            return self.check_pam_password(Arc::clone(pam), name, pass).await
+        }
+
+        let user = self.database.get_user_by_name(name).await?;
+
+        let verified = self.argon2_latency.time(|| {
+            crypto::verify_password(&user.pass_hash, pass.expose().as_bytes())
+        })?;
+
+        if !verified {
+            return Err(AuthError::LoginFailed.into())
+        }
 
+        // the hash of a break glass password is dropped on login anyway
+        if break_glass.is_none() && crypto::needs_rehash(&user.pass_hash, self.password_hash, self.password_salt_len) {
+            self.rehash_password(&user, pass).await;
+        }
+
+        Ok(user)
+    }
+
+    /// hashes `pass` as `Config::password_hash` says
+    pub(crate) fn hash_password(&self, pass : &crypto::Secret) -> Result<String> {
+        Ok(crypto::encode_password_with(
+            &mut rand::rngs::OsRng,
+            self.password_salt_len,
+            self.password_hash,
+            pass.expose().as_bytes(),
+        )?)
+    }
+
+    /// refuses `pass` as the new password of `name` if it's their current
+    /// one or among the `Config::password_history` before it
+    async fn check_password_history(&self, name : &str, pass : &crypto::Secret) -> Result<()> {
+        let old_hashes = self.database.get_password_history(name, self.password_history).await?;
+
+        // the hash of a used break glass password doesn't parse, nor match
+        for old_hash in old_hashes {
+            if crypto::verify_password(&old_hash, pass.expose().as_bytes()).unwrap_or(false) {
+                return Err(AuthError::PasswordReused.into())
+            }
+        }
+
+        Ok(())
+    }
+
+    /// replaces the hash of `user`, whose password `pass` just verified,
+    /// with one made as `Config::password_hash` says. Failures are logged,
+    /// the login goes ahead with the old hash.
+    async fn rehash_password(&self, user : &models::User, pass : &crypto::Secret) {
+        let res = match self.argon2_latency.time(|| self.hash_password(pass)) {
+            Ok(pass_hash) => self.database.rehash_user_pass(&user.name, &user.pass_hash, &pass_hash).await,
+            Err(err) => Err(err),
+        };
+
+        let from = crypto::HashInfo::parse(&user.pass_hash)
+            .map(|info| info.params.variant.as_str())
+            .unwrap_or("unknown");
+
+        match res {
+            Ok(true) => logging::info!(
+                [("subject", serde_json::json!(user.name))],
+                "rehashed the password, it was {}",
+                from,
+            ),
+            // changed since it was read
+            Ok(false) => {},
+            Err(err) => logging::error!(
+                [("subject", serde_json::json!(user.name))],
+                "could not rehash the password: {:?}",
+                err,
+            ),
+        }
+    }
+
+    #[cfg(feature = "pam")]
+    async fn check_pam_password(
+        &self,
+        pam : Arc<pam::Pam>,
+        name : &str,
+        pass : &crypto::Secret,
+    ) -> Result<models::User> {
+        let create_users = pam.create_users();
+
+        {
+            let name = name.to_string();
+            let pass = pass.clone();
+            tokio::task::spawn_blocking(move || pam.check(&name, pass.expose()))
+                .await
+                .map_err(|err| Error::Panic(err.to_string()))??;
+        }
+
+        match self.database.get_user_by_name(name).await {
+            Err(Error::Storage(StorageError::UserNotFound(_))) if create_users => {
+                match self.database.insert_user(name, pam::PASS_HASH).await {
+                    // a concurrent login created it
+                    Ok(()) | Err(Error::Storage(StorageError::DuplicateName(_))) => {},
+                    Err(err) => return Err(err),
+                }
+                self.audit(None, "create-user", Some(name), Some("pam")).await?;
+
+                self.database.get_user_by_name(name).await
+            },
+            res => res,
+        }
+    }
+
+    /// an attempt to log in as `name` from `addr`, located with
+    /// `Config::geoip`
+    pub(crate) async fn login_attempt(
+        &self,
+        name : String,
+        aud : String,
+        user_agent : String,
+        addr : Option<IpAddr>,
+    ) -> Result<LoginAttempt> {
+        let country = addr.and_then(|addr| self.country(addr));
+        let (new_country, last_country) = match &country {
+            Some(country) => (
+                self.database.is_new_login_country(&name, country).await?,
+                self.database.last_login_country(&name).await?,
+            ),
+            None => (false, None),
+        };
+
+        let mut attempt = LoginAttempt{
+            name,
+            aud,
+            user_agent,
+            addr,
+            country,
+            new_country,
+            last_country,
+            risk : None,
+            step_up : false,
+        };
+
+        if let Some(engine) = &self.risk_engine {
+            let risk = engine.score(&attempt).await?;
+            attempt.step_up = self.risk.verdict(&risk) == risk::Verdict::StepUp;
+            attempt.risk = Some(risk);
+        }
+
+        Ok(attempt)
+    }
+
+    #[cfg(feature = "geoip")]
+    fn country(&self, addr : IpAddr) -> Option<String> {
+        self.geoip.as_ref()?.country(addr)
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    fn country(&self, _ : IpAddr) -> Option<String> {
+        None
+    }
+
+    /// records a login with the user's credentials, notifying the user if
+    /// it looks unusual. Break glass accounts may only log in
+    /// `with_password`, which is used up, as `user` then shows.
+    pub(crate) async fn record_login(
+        &self,
+        user : &mut models::User,
+        attempt : &LoginAttempt,
+        now : i64,
+        with_password : bool,
+    ) -> Result<()> {
+        // notified regardless of the user's setting
+        let break_glass = self.database.get_break_glass(&user.name).await?.is_some();
+        if break_glass {
+            if !with_password {
+                return Err(AuthError::LoginFailed.into())
+            }
+
+            self.break_glass.login(self, user, attempt, now).await?;
+            user.pass_hash = USED_PASS_HASH.to_string();
+        }
+
+        let record = self.database.record_login(
+            &user.name,
+            &attempt.aud,
+            &attempt.user_agent,
+            attempt.country.as_deref(),
+            now,
+        ).await?;
+
+        if let Some(notifier) = self.notifier.as_ref().filter(|_| user.notify_logins && !break_glass) {
+            if let Some(reason) = notifier.reason(&record, now) {
+                notifier.send(LoginNotification{
+                    name : user.name.clone(),
+                    aud : attempt.aud.clone(),
+                    reason,
+                    time : now,
+                    country : attempt.country.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// writes the in-memory stats to the database, they are kept for the
+    /// next flush if that fails
+    async fn flush_stats(&self) -> Result<()> {
+        let counts = self.stats.take();
+        if counts.is_empty() {
+            return Ok(())
+        }
+
+        if let Err(err) = self.database.add_stats(&counts).await {
+            self.stats.restore(counts);
+            return Err(err)
+        }
+
+        Ok(())
+    }
+
+    /// validates a token against the server's key and the user's current
+    /// token version
+    pub(crate) async fn validate_token(&self, token : &str) -> Result<(crypto::Token, models::User)> {
+        let start = std::time::Instant::now();
+        let res = self.check_token(token).await;
+        self.observe_latency("validation", &self.validate_latency, start.elapsed());
+
+        res
+    }
+
+    /// records a login or validation latency, logging when the p95 goes
+    /// past `LatencyConfig::warn_p95`
+    fn observe_latency(&self, what : &str, latency : &RollingQuantiles, d : std::time::Duration) {
+        if let Some(q) = latency.observe(d) {
+            logging::error!(
+                [
+                    ("p50", serde_json::json!(q.p50.as_secs_f64())),
+                    ("p95", serde_json::json!(q.p95.as_secs_f64())),
+                    ("p99", serde_json::json!(q.p99.as_secs_f64())),
+                    ("count", serde_json::json!(q.count)),
+                ],
+                "{} p95 latency is {}ms, argon2 or the database may be saturated",
+                what,
+                q.p95.as_millis(),
+            );
+        }
+    }
+
+    /// the key `token` was signed with, by its `kid`, and how to validate
+    /// it
+    fn verification_key(&self, token : &str) -> (&jwt::Validation, &jwt::DecodingKey<'static>) {
+        let kid = match jwt::decode_header(token) {
+            Ok(header) => header.kid,
+            Err(_) => return (&self.validation, &self.pub_dec_key),
+        };
+
+        let now = self.clock.now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        match &self.retired_key {
+            Some(retired) if kid != self.header.kid && kid == retired.kid && now < retired.until => {
+                (&retired.validation, &retired.key)
+            },
+            _ => (&self.validation, &self.pub_dec_key),
+        }
+    }
+
+    async fn check_token(&self, token : &str) -> Result<(crypto::Token, models::User)> {
+        let (validation, pub_dec_key) = self.verification_key(token);
+        let token = self.jwt_verify_latency
+            .time(|| crypto::Token::validate_mapped(
+                self.clock.as_ref(),
+                token,
+                validation,
+                pub_dec_key,
+                &self.claims,
+            ))
+            .map_err(|_| AuthError::Unauthorized)?;
+
+        let user = match self.database.get_user_by_name(&token.sub).await {
+            Ok(user) => user,
+            Err(Error::Storage(StorageError::UserNotFound(_))) => return Err(AuthError::Unauthorized.into()),
+            Err(err) => return Err(err),
+        };
+
+        let aud_version = self.database.get_audience_version(&token.sub, &token.aud).await?;
+
+        if user.token_version != token.version || aud_version != token.aud_version {
+            return Err(AuthError::Unauthorized.into())
+        }
+
+        if let Some(org) = &token.org {
+            match self.database.get_org_version(&org.name, &token.sub).await {
+                Ok(version) if version == org.version => {},
+                Ok(_) => return Err(AuthError::Unauthorized.into()),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok((token, user))
+    }
+
+    /// validates the request's bearer token against the server's key and
+    /// the user's current token version, it must be for `Config::audience`
+    pub(crate) async fn authenticate(&self, req : &Request) -> Result<(crypto::Token, models::User)> {
+        let (token, user) = self.authenticate_any(req).await?;
+        self.check_audience(&token)?;
+
+        Ok((token, user))
+    }
+
+    /// like `authenticate`, for routes which only act on the token itself,
+    /// e.g. renewing it, so a token for any audience will do
+    pub(crate) async fn authenticate_any(&self, req : &Request) -> Result<(crypto::Token, models::User)> {
+        let token = req.headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .ok_or(AuthError::Unauthorized)?;
+
+        let (token, user) = self.validate_token(token).await?;
+
+        if let Some(actor) = &token.act {
+            self.audit(
+                Some(&actor.sub),
+                "impersonated-request",
+                Some(&token.sub),
+                Some(req.uri().path()),
+            ).await?;
+        }
+
+        RequestLog::of(req).set(|log| log.subject = Some(token.sub.clone()));
+
+        Ok((token, user))
+    }
+
+    /// refuses tokens for other audiences than `Config::audience`
+    fn check_audience(&self, token : &crypto::Token) -> Result<()> {
+        if token.aud != self.audience {
+            return Err(AuthError::Unauthorized.into())
+        }
+
+        Ok(())
+    }
+
+    /// the `org` claim of `name`'s tokens scoped to `org`, fails with
+    /// `AuthError::Forbidden` if they aren't a member
+    pub(crate) async fn org_claim(&self, org : &str, name : &str) -> Result<crypto::Org> {
+        let member = self.database.get_org_member(org, name).await?
+            .ok_or(AuthError::Forbidden)?;
+        let version = self.database.get_org_version(org, name).await?;
+
+        Ok(crypto::Org{
+            name : member.org,
+            admin : member.admin,
+            groups : member.groups.split_whitespace().map(String::from).collect(),
+            version,
+        })
+    }
+
+    /// refuses callers not allowed by `Config::admin_peers`
+    fn check_admin_peer(&self, req : &Request) -> Result<()> {
+        match &self.admin_peers {
+            Some(policy) => policy.check(req),
+            None => Ok(()),
+        }
+    }
+
+    /// like `authenticate`, also requiring the policy to allow `action`.
+    /// Impersonated tokens are refused so admin actions can't be done on
+    /// someone's behalf.
+    pub(crate) async fn authenticate_admin(&self, req : &Request, action : Action, target : Option<&str>) -> Result<models::User> {
+        self.check_admin_peer(req)?;
+
+        let (token, user) = self.authenticate(req).await?;
+
+        if token.act.is_some() {
+            return Err(AuthError::Forbidden.into())
+        }
+
+        self.authorize(&user, action, target).await?;
+
+        Ok(user)
+    }
+
+    async fn access_request(&self, user : &models::User, action : Action, target : Option<&str>) -> Result<AccessRequest> {
+        let target_roles = match target {
+            Some(target) if action.takes_over() => match self.database.get_user_by_name(target).await {
+                Ok(target) => target.roles.split_whitespace().map(String::from).collect(),
+                Err(Error::Storage(StorageError::UserNotFound(_))) => Vec::new(),
+                Err(err) => return Err(err),
+            },
+            _ => Vec::new(),
+        };
+
+        Ok(AccessRequest{
+            actor : user.name.clone(),
+            roles : user.roles.split_whitespace().map(String::from).collect(),
+            action,
+            target : target.map(String::from),
+            target_roles,
+        })
+    }
+
+    /// fails with `AuthError::Forbidden` unless the policy allows `user`
+    /// to take `action`
+    pub(crate) async fn authorize(&self, user : &models::User, action : Action, target : Option<&str>) -> Result<()> {
+        let req = self.access_request(user, action, target).await?;
+        self.policy.check(self.policy_evaluator.as_deref(), &req).await
+    }
+
+    pub(crate) async fn is_authorized(&self, user : &models::User, action : Action, target : Option<&str>) -> Result<bool> {
+        let req = self.access_request(user, action, target).await?;
+        self.policy.decide(self.policy_evaluator.as_deref(), &req).await
+    }
+}
+
+/// A `Mux` which also keeps every route in a second mux with no-op
+/// handlers, so the methods allowed on a path can be listed without running
+/// any handler.
+pub(crate) struct Router {
+    mux : Mux,
+    probe : ProbeMux,
+    /// name of the routes being added, for the request log
+    name : &'static str,
+    /// names of the routes to leave out, see `RoutesConfig`
+    disabled : Vec<String>,
+    /// names of the routes added or left out so far
+    names : Vec<&'static str>,
+}
+
+type ProbeMux = mux::Mux<mux::MuxError, (), (), &'static str>;
+
+/// the handler of `Router::probe`, it returns the name of its route
+struct Probe<I>(&'static str, std::marker::PhantomData<fn(I)>);
+
+impl<I> Pipe for Probe<I> {
+    type Input = I;
+    type Output = std::result::Result<&'static str, mux::MuxError>;
+
+    fn run(&self, _ : I) -> Pin<Box<dyn Future<Output = Self::Output> + Send>> {
+        let name = self.0;
+        Box::pin(async move { Ok(name) })
+    }
+}
+
+fn probe_request(method : &http::Method, path : &str) -> http::Request<()> {
+    http::Request::builder()
+        .method(method.clone())
+        .uri(path)
+        .body(())
+        .unwrap()
+}
+
+impl Router {
+    fn new(disabled : Vec<String>) -> Self {
+        Self{
+            mux : mux::new_mux(),
+            probe : mux::new_mux(),
+            name : "",
+            disabled,
+            names : Vec::new(),
+        }
+    }
+
+    fn is_disabled(&self, name : &str) -> bool {
+        self.disabled.iter()
+            .any(|d| d == name || (d == "admin" && name.contains("_admin_")))
+    }
+
+    pub(crate) fn named(mut self, name : &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub(crate) fn handle<T, P>(mut self, route : route::Route<T>, pipe : P) -> Self
+    where
+        (Request,) : Merge<T>,
+        (http::Request<()>,) : Merge<T>,
+        P : Pipe<
+            Input = <(Request,) as Merge<T>>::Output,
+            Output = Result<Response>,
+        > + Send + Sync + 'static,
+        T : for<'a, 'b> TryFrom<route::PathParser<'a, 'b>, Error = route::PathParseError> + 'static,
+    {
+        self.names.push(self.name);
+        if self.is_disabled(self.name) {
+            return self
+        }
+
+        Self{
+            probe : self.probe.handle(route.clone(), Probe(self.name, Default::default())),
+            mux : self.mux.handle(route, pipe),
+            ..self
+        }
+    }
+}
+
+/// the methods `probe` has a route for on `path`, including `HEAD` and
+/// `OPTIONS`
+async fn allowed_methods(probe : &ProbeMux, path : &str) -> Vec<http::Method> {
+    let mut allowed = Vec::new();
+
+    for method in METHODS {
+        // a path variable failing to parse still means the route exists
+        match probe.serve((probe_request(method, path),)).await {
+            Ok(_) | Err(mux::MuxError::Parse(_, _)) => allowed.push(method.clone()),
+            Err(_) => {},
+        }
+    }
+
+    // `head_middleware` serves every `GET` route
+    if allowed.contains(&http::Method::GET) {
+        allowed.push(http::Method::HEAD);
+    }
+
+    allowed.push(http::Method::OPTIONS);
+    allowed
+}
+
+/// runs the router, recording the matched route in the request log.
+/// Answers `OPTIONS` with the methods a path allows and lists them in the
+/// `Allow` header of `405`s.
+fn route_middleware(router : Router) -> impl Pipe<Input = (Request,), Output = Result<Response>> {
+    let router = Arc::new(router);
+
+    plumb::id()
+    .aseq(move |req : Request| {
+        let router = Arc::clone(&router);
+
+        async move {
+            let is_options = req.method() == http::Method::OPTIONS;
+
+            if let Ok(name) = router.probe.serve((probe_request(req.method(), req.uri().path()),)).await {
+                RequestLog::of(&req).set(|log| log.route = Some(name));
+            }
+
+            match router.mux.run((req,)).await {
+                Err(Error::Transport(TransportError::Mux(mux::MuxError::MethodNotAllowed(method, path)))) => {
+                    let allow = allowed_methods(&router.probe, &path).await;
+
+                    if !is_options {
+                        return Err(TransportError::MethodNotAllowed{ method, path, allow }.into())
+                    }
+
+                    Ok(http::Response::builder()
+                        .status(http::StatusCode::NO_CONTENT)
+                        .header(http::header::ALLOW, allow_header(&allow))
+                        .body(Body::empty())
+                        .unwrap())
+                },
+                res => res,
+            }
+        }
+    })
+}
+
+fn allow_header(methods : &[http::Method]) -> String {
+    methods.iter()
+        .map(|m| m.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn routes(mut server : Server) -> impl Pipe<Input = (Request,), Output = Response> {
+    let layers = std::mem::take(&mut server.layers);
+    let server = Arc::new(server);
+
+    {
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            let period = std::time::Duration::from_secs(STATS_FLUSH_INTERVAL);
+            let mut interval = tokio::time::interval(period);
+
+            loop {
+                interval.tick().await;
+                if let Err(err) = server.flush_stats().await {
+                    logging::error!("failed to persist stats: {:?}", err);
+                }
+            }
+        });
+    }
+
+    service::spawn_watchdog(Arc::clone(&server));
+
+    macro_rules! register_routes {
+        ($($route:ident,)*) => {
+            {
+                let mux = Router::new(server.routes.disabled());
+
+                $(let mux = $route(Arc::clone(&server), mux.named(stringify!($route)));)*
+
+                mux
+            }
+        }
+    }
+
+    let mux = register_routes!{
+        post_login,
+        post_device_login,
+        get_devices,
+        delete_device,
+        get_me_devices,
+        post_logout,
+        post_renew,
+        get_user,
+        get_user_version,
+        get_pub_key,
+        get_cert,
+        get_version,
+        post_password_estimate,
+        get_metrics,
+        post_admin_impersonate,
+        get_admin_stats,
+        get_admin_users,
+        post_admin_password,
+        post_admin_revoke,
+        get_admin_audit,
+        put_admin_maintenance,
+        delete_admin_maintenance,
+        get_admin_ui,
+    };
+
+    let mux = orgs::routes(&server, mux);
+    let mux = invites::routes(&server, mux);
+    let mux = consent::routes(&server, mux);
+    let mux = ssh::routes(&server, mux);
+
+    #[cfg(feature = "saml")]
+    let mux = saml::routes(&server, mux);
+
+    #[cfg(feature = "oauth")]
+    let mux = oauth::routes(&server, mux);
+
+    for name in server.routes.disabled.iter().chain(&server.routes.unix_only) {
+        if name != "admin" && !mux.names.contains(&name.as_str()) {
+            logging::error!("config.routes names an unknown route: {}", name);
+        }
+    }
+
+    let router = route_middleware(mux);
+    let routes = Next::new(move |req| router.run((req,)));
+
+    let timeouts = server.timeouts;
+    let trusted_proxies = server.trusted_proxies;
+    let errors = Arc::clone(&server);
+    let mut chain : Vec<Arc<dyn Layer>> = vec![
+        Arc::new(request_id_layer),
+        Arc::new(move |req : Request, next : Next| client_addr_layer(trusted_proxies, req, next)),
+        Arc::new(log_layer),
+        Arc::new(head_layer),
+        Arc::new(move |req : Request, next : Next| error_layer(Arc::clone(&errors), req, next)),
+    ];
+    if let Some(guard) = &server.leak_guard {
+        chain.push(Arc::new(guard.clone()));
+    }
+    chain.push(Arc::new(maintenance_layer(Arc::clone(&server))));
+    chain.extend(middleware::open(&server, &server.layer_configs));
+    chain.extend(layers.into_iter().map(Arc::from));
+    chain.push(Arc::new(move |req : Request, next : Next| body_layer(timeouts, req, next)));
+
+    let next = chain.into_iter().rev().fold(routes, Next::with);
+
+    plumb::id()
+    .aseq(move |req : Request| {
+        let next = next.clone();
+
+        async move {
+            next.run(req).await.unwrap_or_else(|err| render_error(&err))
+        }
+    })
+}
+
+fn post_login(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "login"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            #[cfg(feature = "negotiate")]
+            if let (Some(negotiator), Some(token)) = (&server.negotiator, negotiate::header_token(&req)) {
+                return negotiate_login(&server, Arc::clone(negotiator), req, token?).await
+            }
+
+            let log = RequestLog::of(&req);
+            let user_agent = user_agent(&req);
+            let addr = client_addr(&req);
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostLoginRequest = match serde_json::from_reader(reader) {
+                Ok(req) => req,
+                // a body without credentials, ask for a kerberos ticket
+                #[cfg(feature = "negotiate")]
+                Err(_) if server.negotiator.is_some() => return Err(AuthError::NegotiateRequired.into()),
+                Err(_) => return Err(TransportError::BadRequest.into()),
+            };
+
+            let attempt = server.login_attempt(req.name.clone(), req.aud.clone(), user_agent, addr).await?;
+
+            let start = std::time::Instant::now();
+            let login = password_login(&server, req, &attempt);
+            let res = server.hook_login(&attempt, login).await;
+            server.observe_latency("login", &server.login_latency, start.elapsed());
+            if res.is_ok() {
+                log.set(|log| log.subject = Some(attempt.name.clone()));
+            }
+
+            res
+        })
+    )
+
+}
+
+async fn password_login(
+    server : &Server,
+    req : PostLoginRequest,
+    attempt : &LoginAttempt,
+) -> Result<Response> {
+    let mut user = server.check_password(&req.name, &req.pass).await?;
+    let aud_version = server.database.get_audience_version(&req.name, &req.aud).await?;
+    let org = match &req.org {
+        Some(org) => Some(server.org_claim(org, &req.name).await?),
+        None => None,
+    };
+
+    let now = unix_now();
+    server.record_login(&mut user, attempt, now, true).await?;
+
+    // a device would let a break glass account log in again without its
+    // one time password, which is used up by now
+    let (device_token, device_id) = if req.remember && user.pass_hash != USED_PASS_HASH {
+        let device_token = crypto::new_device_token();
+        let device_id = server.database.insert_device(
+            &req.name,
+            &req.aud,
+            &crypto::hash_device_token(&device_token),
+            user.token_version,
+            now,
+        ).await?;
+
+        (Some(device_token), Some(device_id))
+    } else {
+        (None, None)
+    };
+
+    let token = server.issue_token(&user, crypto::Token{
+        iss : server.issuer.to_string(),
+        aud : req.aud,
+        sub : req.name,
+        version : user.token_version,
+        aud_version,
+        acr : crypto::Assurance::Password,
+        act : None,
+        auth_time : None,
+        org,
+        extra : Default::default(),
+    }, req.duration).await?;
+
+    let s = serde_json::to_string(&PostLoginResponse{ token, device_token, device_id })?;
+    Ok(Response::new(s.into()))
+}
+
+/// a login with the kerberos ticket of a `Negotiate` authorization header
+#[cfg(feature = "negotiate")]
+async fn negotiate_login(
+    server : &Server,
+    negotiator : Arc<negotiate::Negotiator>,
+    req : Request,
+    token : Vec<u8>,
+) -> Result<Response> {
+    let log = RequestLog::of(&req);
+    let user_agent = user_agent(&req);
+    let addr = client_addr(&req);
+    let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+    let req : PostNegotiateLoginRequest = serde_json::from_reader(reader)
+        .map_err(|_| TransportError::BadRequest)?;
+
+    let negotiate::Accepted{ name, response } = tokio::task::spawn_blocking(move || negotiator.accept(&token))
+        .await
+        .map_err(|err| Error::Panic(err.to_string()))??;
+
+    let duration = req.duration;
+    let attempt = server.login_attempt(name, req.aud, user_agent, addr).await?;
+
+    let login = async {
+        let mut user = server.database.get_user_by_name(&attempt.name).await?;
+        let aud_version = server.database.get_audience_version(&attempt.name, &attempt.aud).await?;
+
+        server.record_login(&mut user, &attempt, unix_now(), false).await?;
+
+        let token = server.issue_token(&user, crypto::Token{
+            iss : server.issuer.to_string(),
+            aud : attempt.aud.clone(),
+            sub : attempt.name.clone(),
+            version : user.token_version,
+            aud_version,
+            acr : crypto::Assurance::Kerberos,
+            act : None,
+            auth_time : None,
+            org : None,
+            extra : Default::default(),
+        }, duration).await?;
+
+        let s = serde_json::to_string(&PostLoginResponse{
+            token,
+            device_token : None,
+            device_id : None,
+        })?;
+
+        let mut res = Response::new(s.into());
+        if let Some(response) = response {
+            let value = format!("Negotiate {}", base64::encode(response));
+            res.headers_mut().insert(
+                http::header::WWW_AUTHENTICATE,
+                http::HeaderValue::from_str(&value).unwrap(),
+            );
+        }
+
+        Ok(res)
+    };
+
+    let res = server.hook_login(&attempt, login).await;
+    if res.is_ok() {
+        log.set(|log| log.subject = Some(attempt.name.clone()));
+    }
+
+    res
+}
+
+fn post_device_login(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "device" / "login"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let log = RequestLog::of(&req);
+            let user_agent = user_agent(&req);
+            let addr = client_addr(&req);
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostDeviceLoginRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            let token_hash = crypto::hash_device_token(&req.device_token);
+            let device = server.database.get_device_by_hash(&token_hash).await?
+                .ok_or(AuthError::LoginFailed)?;
+
+            let attempt = server.login_attempt(device.name.clone(), device.aud.clone(), user_agent, addr).await?;
+
+            let login = device_login(&server, device, req.duration, &attempt);
+            let res = server.hook_login(&attempt, login).await;
+            if res.is_ok() {
+                log.set(|log| log.subject = Some(attempt.name.clone()));
+            }
+
+            res
+        })
+    )
+}
+
+async fn device_login(
+    server : &Server,
+    device : models::Device,
+    duration : u64,
+    attempt : &LoginAttempt,
+) -> Result<Response> {
+    let mut user = server.database.get_user_by_name(&device.name).await?;
+
+    // invalidating all of a user's tokens also forgets their devices
+    if user.token_version != device.token_version {
+        return Err(AuthError::LoginFailed.into())
+    }
+
+    let aud_version = server.database.get_audience_version(&device.name, &device.aud).await?;
+
+    let now = unix_now();
+    // break glass accounts can't log in with a device
+    server.record_login(&mut user, attempt, now, false).await?;
+    server.database.touch_device(device.id, now).await?;
+
+    let token = server.issue_token(&user, crypto::Token{
+        iss : server.issuer.to_string(),
+        aud : device.aud,
+        sub : device.name,
+        version : user.token_version,
+        aud_version,
+        acr : crypto::Assurance::Password,
+        act : None,
+        auth_time : None,
+        org : None,
+        extra : Default::default(),
+    }, duration).await?;
+
+    let s = serde_json::to_string(&PostLoginResponse{
+        token,
+        device_token : None,
+        device_id : None,
+    })?;
+    Ok(Response::new(s.into()))
+}
+
+fn get_devices(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "devices"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let (token, _) = server.authenticate(&req).await?;
+
+            let devices = server.database.list_devices(&token.sub).await?
+                .into_iter()
+                .map(|device| DeviceInfo{
+                    id : device.id,
+                    aud : device.aud,
+                    created : device.created,
+                    last_used : device.last_used,
+                })
+                .collect();
+
+            let s = serde_json::to_string(&GetDevicesResponse{ devices })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn delete_device(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(DELETE / "devices" / i64),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, id : i64, server : Arc<Server>| async move {
+            // an app's token may revoke the devices remembered for the app,
+            // e.g. when its user logs out
+            let (token, _) = server.authenticate_any(&req).await?;
+            let aud = Some(token.aud.as_str()).filter(|aud| *aud != server.audience);
+
+            server.database.delete_device(&token.sub, aud, id).await?;
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
+
+const ME_DEVICES_LISTING : Listing = Listing{
+    filters : &["aud"],
+    sort : Sort::Desc,
+    limit : None,
+};
+
+fn get_me_devices(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "me" / "devices"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let (token, _) = server.authenticate(&req).await?;
+            let page = ME_DEVICES_LISTING.parse::<models::ClientKey>(&req)?;
+
+            let clients = server.database.list_clients(&token.sub, &page).await?;
+            let next = page.next(&clients, |client| (client.last_seen, client.aud.clone(), client.user_agent.clone()));
+
+            let devices = clients.into_iter()
+                .map(|client| ClientInfo{
+                    aud : client.aud,
+                    user_agent : client.user_agent,
+                    first_seen : client.first_seen,
+                    last_seen : client.last_seen,
+                })
+                .collect();
+
+            let s = serde_json::to_string(&GetMeDevicesResponse{ devices, next })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn post_logout(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "logout"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let (token, _) = server.authenticate_any(&req).await?;
+
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let req : PostLogoutRequest = if body.is_empty() {
+                Default::default()
+            } else {
+                serde_json::from_slice(&body).map_err(|_| TransportError::BadRequest)?
+            };
+
+            // a token for one audience may only log that audience out
+            let aud = if req.everywhere {
+                server.check_audience(&token)?;
+                server.database.increment_token(&token.sub).await?;
+                None
+            } else {
+                server.database.increment_audience_token(&token.sub, &token.aud).await?;
+                Some(token.aud)
+            };
+
+            server.publish(Event::TokensInvalidated{ name : token.sub, aud }).await;
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
+
+fn post_renew(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "renew"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let (token, user) = server.authenticate_any(&req).await?;
+
+            // impersonation lasts as long as the admin asked for, renewals
+            // would turn it into a login as the user
+            if token.act.is_some() {
+                return Err(AuthError::Forbidden.into())
+            }
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostRenewRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            // renewal can't extend the session past max_session from the
+            // original login
+            let auth_time = token.auth_time.unwrap_or(0);
+            let session_end = auth_time.saturating_add(server.max_session);
+            let now = unix_now() as u64;
+            if session_end <= now {
+                return Err(AuthError::SessionExpired.into())
+            }
+
+            let duration = req.duration.min(session_end - now);
+
+            // the membership may have changed since the token was issued
+            let org = match &token.org {
+                Some(org) => Some(server.org_claim(&org.name, &token.sub).await?),
+                None => None,
+            };
+
+            let token = server.issue_token(&user, crypto::Token{
+                iss : server.issuer.to_string(),
+                aud : token.aud,
+                sub : token.sub,
+                version : user.token_version,
+                aud_version : token.aud_version,
+                acr : token.acr,
+                act : token.act,
+                auth_time : Some(auth_time),
+                org,
+                extra : Default::default(),
+            }, duration).await?;
+
+            let s = serde_json::to_string(&PostLoginResponse{
+                token,
+                device_token : None,
+                device_id : None,
+            })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn get_user(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "user" / Segment),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, Segment(user) : Segment, server : Arc<Server>| async move {
+            let aud = query_param(&req, "aud");
+            let (token_version, aud_version) = server.database.get_token_versions(&user, aud.as_deref()).await?;
+
+            // the versions are all that changes, so validators polling
+            // them mostly get a 304 without a body
+            let etag = format!("\"{}.{}\"", token_version, aud_version);
+            if if_none_match(&req, &etag) {
+                return Ok(not_modified(&etag))
+            }
+
+            let s = serde_json::to_string(&GetUserResponse{
+                name : user,
+                token_version,
+                aud_version,
+            })?;
+
+            Ok(http::Response::builder()
+                .header(http::header::ETAG, etag)
+                .header(http::header::CACHE_CONTROL, "no-cache")
+                .body(s.into())
+                .unwrap())
+        })
+    )
+}
+
+/// just the versions tokens are checked against, as plain text:
+/// `token_version`, or `token_version.aud_version` given `?aud=`, followed
+/// by `.org_version` given `?org=`
+fn get_user_version(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "user" / Segment / "version"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, Segment(user) : Segment, server : Arc<Server>| async move {
+            let aud = query_param(&req, "aud");
+            let (token_version, aud_version) = server.database.get_token_versions(&user, aud.as_deref()).await?;
+
+            let mut version = match aud {
+                Some(_) => format!("{}.{}", token_version, aud_version),
+                None => token_version.to_string(),
+            };
+            if let Some(org) = query_param(&req, "org") {
+                let org_version = server.database.get_org_version(&org, &user).await?;
+                version = format!("{}.{}", version, org_version);
+            }
+
+            let etag = format!("\"{}\"", version);
+            if if_none_match(&req, &etag) {
+                return Ok(not_modified(&etag))
+            }
+
+            Ok(http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "text/plain")
+                .header(http::header::ETAG, etag)
+                .header(http::header::CACHE_CONTROL, "no-cache")
+                .body(version.into())
+                .unwrap())
+        })
+    )
+}
+
+/// whether the request's `If-None-Match` matches `etag`, weakly as RFC
+/// 9110 section 13.1.2 compares them
+fn if_none_match(req : &Request, etag : &str) -> bool {
+    fn opaque(tag : &str) -> &str {
+        tag.trim().trim_start_matches("W/")
+    }
+
+    req.headers()
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+fn not_modified(etag : &str) -> Response {
+    http::Response::builder()
+        .status(http::StatusCode::NOT_MODIFIED)
+        .header(http::header::ETAG, etag)
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// The address of the client of a request, added by `client_addr_layer`
+#[derive(Debug,Clone,Copy)]
+struct ClientAddr(Option<IpAddr>);
+
+/// the address of the client, as found by `client_addr_layer`
+pub(crate) fn client_addr(req : &Request) -> Option<IpAddr> {
+    match req.extensions().get::<ClientAddr>() {
+        Some(addr) => addr.0,
+        None => req.extensions().get::<RemoteAddr>().map(|addr| addr.0.ip()),
+    }
+}
+
+/// the address of the client behind `trusted_proxies` proxies. Each proxy
+/// appends the address it was called from to `X-Forwarded-For`, so only the
+/// last `trusted_proxies` hops were set by them and the client is the
+/// first of those, anything before it is up to the client. Without trusted
+/// proxies it's the address of the tcp connection, none over the unix
+/// socket.
+fn forwarded_addr(req : &Request, trusted_proxies : usize) -> Option<IpAddr> {
+    if trusted_proxies == 0 {
+        return req.extensions().get::<RemoteAddr>().map(|addr| addr.0.ip())
+    }
+
+    let hops = req.headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .map(|h| h.to_str().ok())
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .flat_map(|h| h.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+
+    let i = hops.len().checked_sub(trusted_proxies)?;
+    hops[i].parse().ok()
+}
+
+/// adds the `ClientAddr` of the request to its extensions
+fn client_addr_layer(trusted_proxies : usize, mut req : Request, next : Next) -> HookFuture<Result<Response>> {
+    let addr = forwarded_addr(&req, trusted_proxies);
+    req.extensions_mut().insert(ClientAddr(addr));
+
+    next.run(req)
+}
+
+/// who an anonymous request comes from, for `ratelimit::DiscoveryLimiter`:
+/// the client's address, see `client_addr`, or the uid of the local process
+pub(crate) fn discovery_caller(req : &Request) -> String {
+    if let Some(addr) = client_addr(req) {
+        return addr.to_string()
+    }
+
+    match req.extensions().get::<peer::PeerCredentials>() {
+        Some(peer) => format!("uid:{}", peer.uid),
+        None => "unknown".to_string(),
+    }
+}
+
+/// A client for webhooks, see `webhook`
+pub(crate) type WebhookClient = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+
+/// the url of a webhook, which must be https since the requests carry user
+/// names, and a client verifying the certificate of its host
+pub(crate) fn webhook(url : &str) -> Result<(hyper::Uri, WebhookClient)> {
+    let url : hyper::Uri = url.parse()?;
+    if url.scheme() != Some(&http::uri::Scheme::HTTPS) {
+        return Err(ConfigError::MustUseHttps.into())
+    }
+
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_only()
+        .enable_http1()
+        .build();
+
+    Ok((url, hyper::Client::builder().build(connector)))
+}
+
+fn get_pub_key(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "pub-key"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            server.check_discovery(&req).await?;
+
+            Ok(Response::new(server.pub_key.clone().into()))
+        })
+    )
+}
+
+fn get_cert(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "cert"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            server.check_discovery(&req).await?;
+
+            let cert = server.cert.clone()
+                .ok_or_else(|| mux::MuxError::NotFound(req.uri().path().to_string()))?;
+
+            Ok(http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "application/pem-certificate-chain")
+                .body(cert.into())
+                .unwrap())
+        })
+    )
+}
+
+/// estimates a password before it's set, anonymous callers are limited
+/// like the discovery routes since each estimate takes some work
+fn post_password_estimate(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "password" / "estimate"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            server.check_discovery(&req).await?;
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostPasswordEstimateRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            let user_inputs = req.user_inputs.iter().map(String::as_str).collect::<Vec<_>>();
+            let s = serde_json::to_string(&PostPasswordEstimateResponse{
+                estimate : strength::estimate(req.pass.expose(), &user_inputs),
+                min_score : server.password_strength.min_score,
+            })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn get_version(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "version"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            server.check_discovery(&req).await?;
+
+            Ok(http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&server.build_info())?.into())
+                .unwrap())
+        })
+    )
+}
+
+fn get_metrics(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "metrics"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .map(|_, server : Arc<Server>| {
+            let mut out = String::new();
+
+            server.argon2_latency.render(
+                "authn_argon2_seconds",
+                "Time spent verifying password hashes.",
+                &mut out,
+            );
+            server.jwt_sign_latency.render(
+                "authn_jwt_sign_seconds",
+                "Time spent signing tokens.",
+                &mut out,
+            );
+            server.jwt_verify_latency.render(
+                "authn_jwt_verify_seconds",
+                "Time spent validating bearer tokens.",
+                &mut out,
+            );
+            server.login_latency.render(
+                "authn_login_latency_seconds",
+                "Latency of password logins over the latency window.",
+                &mut out,
+            );
+            server.validate_latency.render(
+                "authn_validate_latency_seconds",
+                "Latency of bearer token validations over the latency window.",
+                &mut out,
+            );
+            server.database.latency().render(
+                "authn_db_seconds",
+                "Time spent in database methods, excluding waiting for the connection.",
+                "method",
+                &mut out,
+            );
+
+            let mut res = Response::new(out.into());
+            res.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            res
+        })
+    )
+}
+
+/// issues a token for another user with the actor in its `act` claim.
+/// The issue and every request to this server with the token are audited,
+/// its uses at apps validating it themselves aren't.
+fn post_admin_impersonate(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "admin" / "impersonate"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            server.check_admin_peer(&req)?;
+
+            let (actor, admin) = server.authenticate(&req).await?;
+
+            // impersonation can't be chained
+            if actor.act.is_some() {
+                return Err(AuthError::Forbidden.into())
+            }
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostAdminImpersonateRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            server.authorize(&admin, Action::Impersonate, Some(&req.sub)).await?;
+
+            let user = server.database.get_user_by_name(&req.sub).await?;
+            let aud_version = server.database.get_audience_version(&req.sub, &req.aud).await?;
+
+            let token = server.issue_token(&user, crypto::Token{
+                iss : server.issuer.to_string(),
+                aud : req.aud.clone(),
+                sub : req.sub,
+                version : user.token_version,
+                aud_version,
+                // the actor's stronger authentication isn't the user's,
+                // apps requiring more to step up refuse the token
+                acr : crypto::Assurance::Password,
+                act : Some(crypto::Actor{ sub : actor.sub }),
+                auth_time : None,
+                org : None,
+                extra : Default::default(),
+            }, req.duration).await?;
+
+            server.audit(
+                Some(&admin.name),
+                "impersonate",
+                Some(&user.name),
+                Some(&req.aud),
+            ).await?;
+
+            let s = serde_json::to_string(&PostLoginResponse{
+                token,
+                device_token : None,
+                device_id : None,
+            })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn get_admin_stats(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "admin" / "stats"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            server.authenticate_admin(&req, Action::ReadStats, None).await?;
+
+            server.flush_stats().await?;
+
+            let mut res = GetAdminStatsResponse{
+                users : server.database.count_users().await?,
+                logins : 0,
+                failed_logins : 0,
+                failure_rate : 0.0,
+                tokens_issued : BTreeMap::new(),
+                discovery : BTreeMap::new(),
+                database_size : server.database.size().await?,
+                login_latency : latency_quantiles(&server.login_latency),
+                validate_latency : latency_quantiles(&server.validate_latency),
+            };
+
+            // the current hour and the 23 before it
+            let since = unix_now() / 3600 - 23;
+            for (counter, label, n) in server.database.get_stats_since(since).await? {
+                match counter.as_str() {
+                    stats::LOGIN => res.logins += n,
+                    stats::FAILED_LOGIN => res.failed_logins += n,
+                    stats::TOKEN_ISSUED => { res.tokens_issued.insert(label, n); },
+                    stats::DISCOVERY => { res.discovery.insert(label, n); },
+                    _ => {},
+                }
+            }
+
+            let attempts = res.logins + res.failed_logins;
+            if attempts > 0 {
+                res.failure_rate = res.failed_logins as f64 / attempts as f64;
+            }
+
+            Ok(Response::new(serde_json::to_string(&res)?.into()))
+        })
+    )
+}
+
+fn latency_quantiles(latency : &RollingQuantiles) -> LatencyQuantiles {
+    let q = latency.quantiles();
+
+    LatencyQuantiles{
+        count : q.count,
+        p50 : q.p50.as_secs_f64(),
+        p95 : q.p95.as_secs_f64(),
+        p99 : q.p99.as_secs_f64(),
+    }
+}
+
+const ADMIN_USERS_LISTING : Listing = Listing{
+    filters : &["prefix", "role"],
+    sort : Sort::Asc,
+    limit : None,
+};
+
+fn get_admin_users(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "admin" / "users"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let admin = server.authenticate_admin(&req, Action::ListUsers, None).await?;
+            let mut page = ADMIN_USERS_LISTING.parse::<String>(&req)?;
+            let limit = page.limit;
+            let full = |users : &[AdminUserInfo]| limit.is_some_and(|limit| users.len() as u64 >= u64::from(limit));
+
+            // only the users the admin may see, reading on past the others
+            // so neither the page nor its cursor gives them away
+            let mut users = Vec::new();
+            loop {
+                let listed = server.database.list_users_page(&page).await?;
+                let more = page.next(&listed, |user| user.name.clone()).is_some();
+                if let Some(last) = listed.last() {
+                    page.cursor = Some(last.name.clone());
+                }
+
+                for user in listed {
+                    if full(&users) {
+                        break
+                    }
+                    if !server.is_authorized(&admin, Action::ListUsers, Some(&user.name)).await? {
+                        continue
+                    }
+
+                    users.push(AdminUserInfo{
+                        roles : user.roles.split_whitespace().map(String::from).collect(),
+                        name : user.name,
+                        token_version : user.token_version,
+                    });
+                }
+
+                if !more || full(&users) {
+                    break
+                }
+            }
+            let next = page.next(&users, |user| user.name.clone());
+
+            let s = serde_json::to_string(&GetAdminUsersResponse{ users, next })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn post_admin_password(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "admin" / "users" / Segment / "password"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, Segment(name) : Segment, server : Arc<Server>| async move {
+            let admin = server.authenticate_admin(&req, Action::ResetPassword, Some(&name)).await?;
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostAdminPasswordRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            server.password_strength.check(req.pass.expose(), &[&name])
+                .map_err(AuthError::WeakPassword)?;
+            server.check_password_history(&name, &req.pass).await?;
+
+            let pass_hash = server.hash_password(&req.pass)?;
+            server.database.update_user_pass(&name, &pass_hash, server.password_history).await?;
+            server.database.increment_token(&name).await?;
+
+            server.audit(Some(&admin.name), "reset-password", Some(&name), None).await?;
+            server.publish(Event::TokensInvalidated{ name, aud : None }).await;
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
+
+fn post_admin_revoke(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "admin" / "users" / Segment / "revoke"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, Segment(name) : Segment, server : Arc<Server>| async move {
+            let admin = server.authenticate_admin(&req, Action::RevokeTokens, Some(&name)).await?;
+
+            server.database.increment_token(&name).await?;
+
+            server.audit(Some(&admin.name), "revoke-tokens", Some(&name), None).await?;
+            server.publish(Event::TokensInvalidated{ name, aud : None }).await;
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
+
+fn put_admin_maintenance(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(PUT / "admin" / "maintenance"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let admin = server.authenticate_admin(&req, Action::Maintenance, None).await?;
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PutAdminMaintenanceRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            server.audit(Some(&admin.name), "maintenance-on", None, Some(&req.message)).await?;
+            server.set_maintenance(Some(req.message));
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
+
+fn delete_admin_maintenance(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(DELETE / "admin" / "maintenance"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let admin = server.authenticate_admin(&req, Action::Maintenance, None).await?;
+
+            server.set_maintenance(None);
+            server.audit(Some(&admin.name), "maintenance-off", None, None).await?;
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
+
+/// entries per page of `GET /admin/audit` without a limit
+const AUDIT_PAGE : u32 = 100;
+
+const ADMIN_AUDIT_LISTING : Listing = Listing{
+    filters : &["actor", "action", "subject", "before"],
+    sort : Sort::Desc,
+    limit : Some(AUDIT_PAGE),
+};
+
+fn get_admin_audit(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "admin" / "audit"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            server.authenticate_admin(&req, Action::ReadAudit, None).await?;
+
+            let mut page = ADMIN_AUDIT_LISTING.parse::<i64>(&req)?;
+
+            // the cursor used to be `before`
+            if let Some(before) = page.filters.remove("before") {
+                if page.cursor.is_some() {
+                    return Err(TransportError::BadRequest.into())
+                }
+                page.cursor = Some(before.parse().map_err(|_| TransportError::BadRequest)?);
+            }
+
+            let listed = server.database.list_audit_page(&page).await?;
+            let next = page.next(&listed, |entry| entry.id);
+
+            let entries = listed.into_iter()
+                .map(AuditEntry::from)
+                .collect();
+
+            let s = serde_json::to_string(&GetAdminAuditResponse{ entries, next })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+/// a single page using the admin api, only built in with the admin-ui
+/// feature
+#[cfg(feature = "admin-ui")]
+const ADMIN_UI : Option<&str> = Some(include_str!("admin-ui.html"));
+#[cfg(not(feature = "admin-ui"))]
+const ADMIN_UI : Option<&str> = None;
+
+fn get_admin_ui(_ : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "admin" / "ui"),
+        mux::new_handler()
+        .and_then(|req : Request| {
+            let page = ADMIN_UI
+                .ok_or_else(|| mux::MuxError::NotFound(req.uri().path().to_string()))?;
+
+            Ok(http::Response::builder()
+                .header(http::header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .header("content-security-policy", "default-src 'self'; script-src 'unsafe-inline'; style-src 'unsafe-inline'")
+                .body(page.into())
+                .unwrap())
+        })
+    )
+}
+
+pub(crate) fn user_agent(req : &Request) -> String {
+    req.headers()
+        .get(http::header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("")
+        .to_string()
+}
+
+/// returns the first value for `key` in the request's query string,
+/// decoded
+pub(crate) fn query_param(req : &Request, key : &str) -> Option<String> {
+    let pairs : Vec<(String, String)> = serde_urlencoded::from_str(req.uri().query()?).ok()?;
+
+    pairs.into_iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v)
+}
+
+/// a percent-decoded segment of the request path, for routes taking a
+/// name, which `route!` would hand over as sent
+pub(crate) struct Segment(pub String);
+
+impl std::str::FromStr for Segment {
+    type Err = std::str::Utf8Error;
+
+    fn from_str(s : &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Segment(percent_encoding::percent_decode_str(s).decode_utf8()?.into_owned()))
+    }
+}
+
+pub(crate) fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn render_error(err : &Error) -> Response {
+    let (code, status, message) = crate::error::http_error(err.code());
+
+    let message = match err {
+        Error::Transport(TransportError::Maintenance(message)) => message.as_str(),
+        _ => message,
+    };
+
+    let mut body = serde_json::json!({
+        "error" : message,
+        "code" : code,
+    });
+
+    // what the user can do about it
+    if let Error::Auth(AuthError::WeakPassword(estimate)) = err {
+        body["score"] = estimate.score.into();
+        body["warning"] = serde_json::json!(estimate.warning);
+        body["suggestions"] = estimate.suggestions.clone().into();
+    }
+
+    let mut res = http::response::Builder::new()
+        .status(status);
+
+    if let Error::Transport(TransportError::MethodNotAllowed{ allow, .. }) = err {
+        res = res.header(http::header::ALLOW, allow_header(allow));
+    }
+
+    if let Error::Auth(AuthError::NegotiateRequired) = err {
+        res = res.header(http::header::WWW_AUTHENTICATE, "Negotiate");
+    }
+
+    res.body(body.to_string().into()).unwrap()
+}
+
+/// renders handler errors, reporting the ones which produce a 5xx.
+/// Handlers run on their own task so a panic becomes an `Error::Panic`
+/// rather than dropping the connection.
+fn error_layer(server : Arc<Server>, mut req : Request, next : Next) -> HookFuture<Result<Response>> {
+    let error_headers = ErrorHeaders::default();
+    req.extensions_mut().insert(error_headers.clone());
+
+    Box::pin(async move {
+        let request_id = req.extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_default();
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+        let log = RequestLog::of(&req);
+
+        let res = tokio::spawn(next.run(req)).await;
+
+        let err = match res {
+            Ok(Ok(res)) => return Ok(res),
+            Ok(Err(err)) => err,
+            Err(err) if err.is_panic() => {
+                let payload = err.into_panic();
+                let msg = payload.downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+
+                Error::Panic(msg)
+            },
+            Err(err) => Error::Panic(err.to_string()),
+        };
+
+        log.set(|log| log.error = Some(err.code()));
+
+        let error = format!("{:?}", err);
+        logging::error!("{} {}", request_id.0, error);
+
+        let mut res = render_error(&err);
+        error_headers.append_to(&mut res);
+
+        if let (true, Some(reporter)) = (res.status().is_server_error(), &server.error_reporter) {
+            reporter.report(ErrorReport{
+                request_id : request_id.0,
+                method,
+                path,
+                error,
+            });
+        }
+
+        Ok(res)
+    })
+}
+
+/// refuses everything but reads and `/admin/maintenance` itself while
+/// maintenance mode is on
+fn maintenance_layer(server : Arc<Server>) -> impl Layer {
+    move |req : Request, next : Next| -> HookFuture<Result<Response>> {
+        let read = matches!(*req.method(), http::Method::GET | http::Method::HEAD | http::Method::OPTIONS);
+        if read || req.uri().path() == "/admin/maintenance" {
+            return next.run(req)
+        }
+
+        match server.maintenance() {
+            Some(message) => Box::pin(async move { Err(TransportError::Maintenance(message).into()) }),
+            None => next.run(req),
+        }
+    }
+}
+
+/// enforces `Timeouts` on request bodies. The body is read on its own task,
+/// which gives up on a slow client, failing the request with a 408 and
+/// dropping the connection.
+fn body_layer(timeouts : Timeouts, mut req : Request, next : Next) -> HookFuture<Result<Response>> {
+    if HttpBody::is_end_stream(req.body()) {
+        return next.run(req)
+    }
+
+    Box::pin(async move {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let (sender, body) = Body::channel();
+        let incoming = std::mem::replace(req.body_mut(), body);
+        tokio::spawn(read_body(timeouts, incoming, sender, Arc::clone(&timed_out)));
+
+        let res = next.run(req).await;
+
+        if timed_out.load(Ordering::SeqCst) {
+            return Err(TransportError::RequestTimeout.into())
+        }
+
+        res
+    })
+}
+
+/// forwards `incoming` to `sender` until it ends, or the client is too slow
+async fn read_body(timeouts : Timeouts, mut incoming : Body, mut sender : hyper::body::Sender, timed_out : Arc<AtomicBool>) {
+    let read_timeout = std::time::Duration::from_secs(timeouts.body_read);
+    let start = tokio::time::Instant::now();
+    let mut received = 0;
+
+    loop {
+        let chunk = match tokio::time::timeout(read_timeout, incoming.data()).await {
+            Ok(Some(Ok(chunk))) => chunk,
+            Ok(None) => return,
+            Ok(Some(Err(_))) => return sender.abort(),
+            Err(_) => {
+                timed_out.store(true, Ordering::SeqCst);
+                return sender.abort()
+            },
+        };
+
+        received += chunk.len() as u64;
+
+        let rated = start.elapsed().saturating_sub(MIN_BODY_RATE_GRACE).as_secs_f64();
+        if (received as f64) < rated * timeouts.min_body_rate as f64 {
+            timed_out.store(true, Ordering::SeqCst);
+            return sender.abort()
+        }
+
+        // the handler dropped the body
+        if sender.send_data(chunk).await.is_err() {
+            return
+        }
+    }
+}
+
+/// serves `HEAD` with the `GET` route, keeping the headers and the length
+/// of the body but not the body itself
+fn head_layer(mut req : Request, next : Next) -> HookFuture<Result<Response>> {
+    if req.method() != http::Method::HEAD {
+        return next.run(req)
+    }
+
+    *req.method_mut() = http::Method::GET;
+
+    Box::pin(async move {
+        let res = next.run(req).await?;
+        let (mut parts, body) = res.into_parts();
+
+        let len = hyper::body::HttpBody::size_hint(&body).exact();
+        if let (Some(len), false) = (len, parts.headers.contains_key(http::header::CONTENT_LENGTH)) {
+            parts.headers.insert(http::header::CONTENT_LENGTH, len.into());
+        }
+
+        Ok(http::Response::from_parts(parts, Body::empty()))
+    })
+}
+
+/// adds a `RequestId` to the request and echoes it in the response
+fn request_id_layer(mut req : Request, next : Next) -> HookFuture<Result<Response>> {
+    let request_id = format!("{:016x}", rand::random::<u64>());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    Box::pin(async move {
+        let mut res = next.run(req).await?;
+
+        if let Ok(v) = http::HeaderValue::from_str(&request_id) {
+            res.headers_mut().insert("x-request-id", v);
+        }
+
+        Ok(res)
+    })
+}
+
+/// logs every request with its response, and the details of its
+/// `RequestLog`
+fn log_layer(mut req : Request, next : Next) -> HookFuture<Result<Response>> {
+    let request_id = req.extensions()
+        .get::<RequestId>()
+        .cloned()
+        .unwrap_or_default();
+
+    let log = RequestLog::default();
+    req.extensions_mut().insert(log.clone());
+    let client = user_agent(&req);
+
+    let pre_details = format!(
+        "{} {}",
+        req.method(),
+        req.uri().path(),
+    );
+
+    Box::pin(async move {
+        let start = tokio::time::Instant::now();
+
+        let res = next.run(req).await?;
+
+        let end = tokio::time::Instant::now();
+        let delta = end - start;
+
+        let outcome = if res.status().is_server_error() {
+            "server_error"
+        } else if res.status().is_client_error() {
+            "client_error"
+        } else {
+            "success"
+        };
+
+        let size = hyper::body::HttpBody::size_hint(res.body()).exact();
+
+        let fields = log.0.lock().unwrap();
+        logging::info!(
+            [
+                ("route", serde_json::json!(fields.route)),
+                ("subject", serde_json::json!(fields.subject)),
+                ("client", serde_json::json!(client)),
+                ("size", serde_json::json!(size)),
+                ("outcome", serde_json::json!(outcome)),
+                ("error", serde_json::json!(fields.error)),
+            ],
+            "{} {} {} {:?}",
+            request_id.0,
+            res.status(),
+            pre_details,
+            delta
+        );
+        drop(fields);
+
+        Ok(res)
+    })
+}
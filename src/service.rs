@@ -0,0 +1,231 @@
+//! Telling service managers how the server is doing. Under systemd, with
+//! `Type=notify`, the server reports `READY=1` once its database is open,
+//! its keys are loaded and its socket is bound, and `STOPPING=1` on the way
+//! out. With `WatchdogSec=` it also pings the watchdog while the database
+//! answers, so a wedged server is restarted.
+//!
+//! With the `windows-service` feature, `authn --service config.json` runs
+//! under the windows service manager instead, see `run_windows_service`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::logging;
+use crate::server::Server;
+
+/// sends `state` to systemd, e.g. `READY=1`. `Ok(false)` if the server
+/// wasn't started by it.
+#[cfg(unix)]
+pub fn notify(state : &str) -> std::io::Result<bool> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) if !path.is_empty() => path,
+        _ => return Ok(false),
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(name) => {
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        },
+        _ => {
+            socket.send_to(state.as_bytes(), &path)?;
+        },
+    }
+
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn notify(_ : &str) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// the server is ready to take requests
+pub fn ready() {
+    if let Err(err) = notify("READY=1") {
+        logging::error!("could not notify systemd: {}", err);
+    }
+
+    #[cfg(all(windows, feature = "windows-service"))]
+    windows::set_status(windows_service::service::ServiceState::Running);
+}
+
+/// the server stopped taking requests, and finishes the ones it has
+pub fn stopping() {
+    if let Err(err) = notify("STOPPING=1") {
+        logging::error!("could not notify systemd: {}", err);
+    }
+
+    #[cfg(all(windows, feature = "windows-service"))]
+    windows::set_status(windows_service::service::ServiceState::StopPending);
+}
+
+/// resolves once the server should stop: on ctrl-c, `SIGTERM`, or a stop
+/// from the windows service manager
+pub async fn shutdown() {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => { term.recv().await; },
+            Err(_) => std::future::pending().await,
+        }
+    };
+
+    #[cfg(all(windows, feature = "windows-service"))]
+    let terminate = windows::stopped();
+
+    #[cfg(all(windows, not(feature = "windows-service")))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate => {},
+    }
+}
+
+/// half of systemd's `WatchdogSec`, if it watches this process
+fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+
+    match std::env::var("WATCHDOG_PID").ok() {
+        Some(pid) if pid != std::process::id().to_string() => None,
+        _ => Some(Duration::from_micros(usec / 2)),
+    }
+}
+
+/// pings systemd's watchdog while the database answers, if it's on
+pub(crate) fn spawn_watchdog(server : Arc<Server>) {
+    let interval = match watchdog_interval() {
+        Some(interval) if !interval.is_zero() => interval,
+        _ => return,
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+
+        loop {
+            interval.tick().await;
+
+            // a missed ping gets the server restarted
+            if let Err(err) = server.database.ping().await {
+                logging::error!("skipping the watchdog, the database failed: {:?}", err);
+                continue
+            }
+
+            if let Err(err) = notify("WATCHDOG=1") {
+                logging::error!("could not notify systemd: {}", err);
+            }
+        }
+    });
+}
+
+/// runs `main` as the windows service `authn`, returning once it stopped.
+/// `main` is called on a thread of the service manager, it should serve
+/// until `shutdown` resolves.
+#[cfg(all(windows, feature = "windows-service"))]
+pub fn run_windows_service<F>(main : F) -> windows_service::Result<()>
+where
+    F : FnOnce() + Send + 'static,
+{
+    windows::run(Box::new(main))
+}
+
+#[cfg(all(windows, feature = "windows-service"))]
+mod windows {
+    use std::ffi::OsString;
+    use std::sync::{Mutex, OnceLock};
+    use std::time::Duration;
+
+    use tokio::sync::Notify;
+    use windows_service::define_windows_service;
+    use windows_service::service::{
+        ServiceControl,
+        ServiceControlAccept,
+        ServiceExitCode,
+        ServiceState,
+        ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::service_dispatcher;
+
+    const SERVICE_NAME : &str = "authn";
+
+    /// how long starting or stopping may take before the service manager
+    /// gives up
+    const PENDING_HINT : Duration = Duration::from_secs(30);
+
+    type Main = Box<dyn FnOnce() + Send>;
+
+    static MAIN : Mutex<Option<Main>> = Mutex::new(None);
+    static STATUS : OnceLock<ServiceStatusHandle> = OnceLock::new();
+    static STOP : OnceLock<Notify> = OnceLock::new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub(super) fn run(main : Main) -> windows_service::Result<()> {
+        *MAIN.lock().unwrap() = Some(main);
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+    }
+
+    fn service_main(_ : Vec<OsString>) {
+        let handle = service_control_handler::register(SERVICE_NAME, |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                STOP.get_or_init(Notify::new).notify_one();
+                ServiceControlHandlerResult::NoError
+            },
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        });
+
+        match handle {
+            Ok(handle) => { let _ = STATUS.set(handle); },
+            Err(_) => return,
+        }
+
+        set_status(ServiceState::StartPending);
+        if let Some(main) = MAIN.lock().unwrap().take() {
+            main();
+        }
+        set_status(ServiceState::Stopped);
+    }
+
+    pub(super) async fn stopped() {
+        STOP.get_or_init(Notify::new).notified().await
+    }
+
+    pub(super) fn set_status(state : ServiceState) {
+        let handle = match STATUS.get() {
+            Some(handle) => handle,
+            None => return,
+        };
+
+        let pending = matches!(state, ServiceState::StartPending | ServiceState::StopPending);
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+
+        let _ = handle.set_service_status(ServiceStatus{
+            service_type : ServiceType::OWN_PROCESS,
+            current_state : state,
+            controls_accepted,
+            exit_code : ServiceExitCode::NO_ERROR,
+            checkpoint : 0,
+            wait_hint : if pending { PENDING_HINT } else { Duration::ZERO },
+            process_id : None,
+        });
+    }
+}
@@ -0,0 +1,618 @@
+//! Passwordless logins with ssh keys. Users add the keys they log in with
+//! as lines of authorized_keys with `POST /ssh-keys`, using a token from a
+//! login in the last few minutes, or an admin adds them with
+//! `authn-utils add-ssh-key`.
+//!
+//! A login takes two requests, like ssh's own public key authentication:
+//! `POST /login/ssh/challenge` with the name and key hands out a challenge
+//! if the key is one of the user's, and `POST /login/ssh` answers it with
+//! the signature of `signed_data`, for a token at
+//! `crypto::Assurance::SshKey`. A challenge is answered at most once, within
+//! a minute. `login` does both with the keys of an ssh agent, as
+//! `authn-utils login user duration --ssh` does.
+//!
+//! Ed25519, ECDSA P-256 and P-384, and RSA keys signing with SHA-2 are
+//! supported, RSA signatures with SHA-1 (`ssh-rsa`) aren't. Break glass
+//! accounts can't log in with a key.
+
+use std::sync::Arc;
+
+use hyper::Body;
+use hyper::body::Buf;
+use plumb::PipeExt;
+use http_mux::{route,mux};
+use ring::{digest, signature};
+
+use crate::api::{
+    PostLoginResponse,
+    PostSshChallengeRequest,
+    PostSshChallengeResponse,
+    PostSshLoginRequest,
+    PostSshKeyRequest,
+    PostSshKeyResponse,
+    SshKeyInfo,
+    GetSshKeysResponse,
+};
+use crate::crypto;
+use crate::models;
+use crate::server::{
+    AuthError,
+    TransportError,
+    LoginAttempt,
+    Server,
+    Router,
+    Request,
+    Response,
+    Result,
+    unix_now,
+    user_agent,
+    client_addr,
+};
+
+/// the namespace of `signed_data`, as in `ssh-keygen -Y sign -n`
+pub const NAMESPACE : &str = "authn";
+
+/// seconds a challenge can be answered in
+const CHALLENGE_DURATION : i64 = 60;
+
+/// seconds after a login its tokens may add ssh keys
+const RECENT_LOGIN : u64 = 5 * 60;
+
+/// What an ssh key signs to answer `challenge`, laid out like OpenSSH's
+/// SSHSIG (PROTOCOL.sshsig) in `NAMESPACE`, so it can't pass for the
+/// signature of an ssh session or a git commit. The `issuer` keeps another
+/// server from relaying the challenge to the client.
+pub fn signed_data(issuer : &str, challenge : &str) -> Vec<u8> {
+    let message = format!("{} {}", issuer, challenge);
+    let hash = digest::digest(&digest::SHA512, message.as_bytes());
+
+    let mut data = b"SSHSIG".to_vec();
+    put_string(&mut data, NAMESPACE.as_bytes());
+    // reserved
+    put_string(&mut data, b"");
+    put_string(&mut data, b"sha512");
+    put_string(&mut data, hash.as_ref());
+    data
+}
+
+/// the key, in the ssh wire format, and comment of a line of
+/// authorized_keys, `None` if it has options or the key isn't one logins
+/// support
+pub fn parse_authorized_key(line : &str) -> Option<(Vec<u8>, String)> {
+    let mut fields = line.split_whitespace();
+    let name = fields.next()?;
+    let key = base64::decode(fields.next()?).ok()?;
+    let comment = fields.collect::<Vec<_>>().join(" ");
+
+    if PublicKey::parse(&key)?.name() != name {
+        return None
+    }
+
+    Some((key, comment))
+}
+
+/// the `SHA256:...` fingerprint of a key in the ssh wire format, as
+/// `ssh-keygen -l` prints it
+pub fn fingerprint(public_key : &[u8]) -> String {
+    let hash = digest::digest(&digest::SHA256, public_key);
+
+    format!("SHA256:{}", base64::encode_config(hash.as_ref(), base64::STANDARD_NO_PAD))
+}
+
+/// whether `signature`, in the ssh wire format, is `public_key`'s over
+/// `data`
+pub fn verify(public_key : &[u8], data : &[u8], signature : &[u8]) -> bool {
+    let key = match PublicKey::parse(public_key) {
+        Some(key) => key,
+        None => return false,
+    };
+
+    let mut reader = Reader(signature);
+    let (alg, sig) = match (reader.string(), reader.string()) {
+        (Some(alg), Some(sig)) if reader.end() => (alg, sig),
+        _ => return false,
+    };
+
+    // only RSA keys sign with another algorithm than their type's
+    let name = key.name().as_bytes();
+    match key {
+        PublicKey::Ed25519(point) if alg == name => {
+            signature::UnparsedPublicKey::new(&signature::ED25519, point)
+                .verify(data, sig)
+                .is_ok()
+        },
+        PublicKey::EcdsaP256(point) if alg == name => {
+            verify_ecdsa(&signature::ECDSA_P256_SHA256_FIXED, 32, point, data, sig)
+        },
+        PublicKey::EcdsaP384(point) if alg == name => {
+            verify_ecdsa(&signature::ECDSA_P384_SHA384_FIXED, 48, point, data, sig)
+        },
+        PublicKey::Rsa{ n, e } => {
+            let params = match alg {
+                b"rsa-sha2-256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                b"rsa-sha2-512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                _ => return false,
+            };
+
+            signature::RsaPublicKeyComponents{ n, e }
+                .verify(params, data, sig)
+                .is_ok()
+        },
+        _ => false,
+    }
+}
+
+/// ssh encodes ECDSA signatures as two mpints, ring takes them as fixed
+/// width big endian integers
+fn verify_ecdsa(
+    alg : &'static signature::EcdsaVerificationAlgorithm,
+    width : usize,
+    point : &[u8],
+    data : &[u8],
+    sig : &[u8],
+) -> bool {
+    let mut reader = Reader(sig);
+    let (r, s) = match (reader.mpint(), reader.mpint()) {
+        (Some(r), Some(s)) if reader.end() && r.len() <= width && s.len() <= width => (r, s),
+        _ => return false,
+    };
+
+    let mut fixed = vec![0u8; 2 * width];
+    fixed[width - r.len()..width].copy_from_slice(r);
+    fixed[2 * width - s.len()..].copy_from_slice(s);
+
+    signature::UnparsedPublicKey::new(alg, point)
+        .verify(data, &fixed)
+        .is_ok()
+}
+
+/// A public key in the ssh wire format (RFC 4253 and RFC 5656), of the
+/// types logins support
+enum PublicKey<'a> {
+    Ed25519(&'a [u8]),
+    /// the uncompressed point
+    EcdsaP256(&'a [u8]),
+    EcdsaP384(&'a [u8]),
+    Rsa{ n : &'a [u8], e : &'a [u8] },
+}
+
+impl<'a> PublicKey<'a> {
+    fn parse(blob : &'a [u8]) -> Option<Self> {
+        let mut reader = Reader(blob);
+
+        let key = match reader.string()? {
+            b"ssh-ed25519" => Self::Ed25519(reader.string()?),
+            b"ecdsa-sha2-nistp256" if reader.string()? == b"nistp256" => Self::EcdsaP256(reader.string()?),
+            b"ecdsa-sha2-nistp384" if reader.string()? == b"nistp384" => Self::EcdsaP384(reader.string()?),
+            b"ssh-rsa" => {
+                let e = reader.mpint()?;
+                let n = reader.mpint()?;
+                Self::Rsa{ n, e }
+            },
+            _ => return None,
+        };
+
+        if !reader.end() {
+            return None
+        }
+
+        Some(key)
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Ed25519(_) => "ssh-ed25519",
+            Self::EcdsaP256(_) => "ecdsa-sha2-nistp256",
+            Self::EcdsaP384(_) => "ecdsa-sha2-nistp384",
+            Self::Rsa{ .. } => "ssh-rsa",
+        }
+    }
+}
+
+/// reads the ssh wire format of RFC 4251
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n : usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None
+        }
+
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    #[cfg(unix)]
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes(4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let n = self.u32()? as usize;
+        self.bytes(n)
+    }
+
+    /// an mpint without its leading zeros, `None` if it's negative
+    fn mpint(&mut self) -> Option<&'a [u8]> {
+        let bytes = self.string()?;
+        if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+            return None
+        }
+
+        let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+        Some(&bytes[start..])
+    }
+
+    fn end(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn put_string(buf : &mut Vec<u8>, s : &[u8]) {
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+#[cfg(unix)]
+pub use agent::{Agent, Identity};
+
+#[cfg(all(unix, feature = "client"))]
+pub use agent::login;
+
+/// The client side, talking to the ssh agent at `$SSH_AUTH_SOCK`
+/// (draft-miller-ssh-agent)
+#[cfg(unix)]
+mod agent {
+    use std::io;
+    use std::path::Path;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    use super::{Reader, put_string};
+
+    const AGENT_FAILURE : u8 = 5;
+    const AGENTC_REQUEST_IDENTITIES : u8 = 11;
+    const AGENT_IDENTITIES_ANSWER : u8 = 12;
+    const AGENTC_SIGN_REQUEST : u8 = 13;
+    const AGENT_SIGN_RESPONSE : u8 = 14;
+
+    /// sign with rsa-sha2-256 rather than ssh-rsa's SHA-1
+    const AGENT_RSA_SHA2_256 : u32 = 2;
+
+    /// responses are refused past this, as OpenSSH does
+    const MAX_MESSAGE : usize = 256 * 1024;
+
+    /// A connection to an ssh agent
+    pub struct Agent {
+        stream : UnixStream,
+    }
+
+    /// A key an ssh agent holds
+    pub struct Identity {
+        /// the key in the ssh wire format
+        pub public_key : Vec<u8>,
+        pub comment : String,
+    }
+
+    fn invalid(msg : &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    impl Agent {
+        /// connects to the agent at `$SSH_AUTH_SOCK`
+        pub async fn from_env() -> io::Result<Self> {
+            let path = std::env::var_os("SSH_AUTH_SOCK")
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "SSH_AUTH_SOCK isn't set"))?;
+
+            Self::connect(path).await
+        }
+
+        pub async fn connect<P : AsRef<Path>>(path : P) -> io::Result<Self> {
+            Ok(Self{
+                stream : UnixStream::connect(path).await?,
+            })
+        }
+
+        async fn request(&mut self, msg : &[u8]) -> io::Result<Vec<u8>> {
+            self.stream.write_all(&(msg.len() as u32).to_be_bytes()).await?;
+            self.stream.write_all(msg).await?;
+
+            let mut len = [0u8;4];
+            self.stream.read_exact(&mut len).await?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len > MAX_MESSAGE {
+                return Err(invalid("ssh agent response too long"))
+            }
+
+            let mut res = vec![0u8;len];
+            self.stream.read_exact(&mut res).await?;
+            Ok(res)
+        }
+
+        /// the keys the agent holds
+        pub async fn identities(&mut self) -> io::Result<Vec<Identity>> {
+            let res = self.request(&[AGENTC_REQUEST_IDENTITIES]).await?;
+
+            parse_identities(&res)
+                .ok_or_else(|| invalid("invalid ssh agent identities"))
+        }
+
+        /// signs `data` with one of the agent's keys, RSA keys sign with
+        /// SHA-256
+        pub async fn sign(&mut self, public_key : &[u8], data : &[u8]) -> io::Result<Vec<u8>> {
+            let mut msg = vec![AGENTC_SIGN_REQUEST];
+            put_string(&mut msg, public_key);
+            put_string(&mut msg, data);
+            msg.extend_from_slice(&AGENT_RSA_SHA2_256.to_be_bytes());
+
+            let res = self.request(&msg).await?;
+
+            let mut reader = Reader(&res);
+            match (reader.u8(), reader.string()) {
+                (Some(AGENT_SIGN_RESPONSE), Some(signature)) => Ok(signature.to_vec()),
+                (Some(AGENT_FAILURE), _) => Err(io::Error::other("the ssh agent refused to sign")),
+                _ => Err(invalid("invalid ssh agent signature")),
+            }
+        }
+    }
+
+    fn parse_identities(res : &[u8]) -> Option<Vec<Identity>> {
+        let mut reader = Reader(res);
+        if reader.u8()? != AGENT_IDENTITIES_ANSWER {
+            return None
+        }
+
+        let n = reader.u32()?;
+        let mut identities = Vec::new();
+        for _ in 0..n {
+            identities.push(Identity{
+                public_key : reader.string()?.to_vec(),
+                comment : String::from_utf8_lossy(reader.string()?).into_owned(),
+            });
+        }
+
+        Some(identities)
+    }
+
+    /// logs in as `name` with the first of the agent's keys the server
+    /// takes, failing with `client::Error::Api("login failed")` if it
+    /// takes none
+    #[cfg(feature = "client")]
+    pub async fn login(
+        client : &crate::client::Client,
+        agent : &mut Agent,
+        name : &str,
+        duration : std::time::Duration,
+    ) -> Result<String, crate::client::Error> {
+        use crate::client::Error;
+
+        for identity in agent.identities().await? {
+            if super::PublicKey::parse(&identity.public_key).is_none() {
+                continue
+            }
+
+            let challenge = match client.ssh_challenge(name, &base64::encode(&identity.public_key)).await {
+                Ok(challenge) => challenge,
+                // not one of the user's keys
+                Err(Error::Api(msg)) if msg == "login failed" => continue,
+                Err(err) => return Err(err),
+            };
+
+            let data = super::signed_data(client.issuer(), &challenge.challenge);
+            let signature = agent.sign(&identity.public_key, &data).await?;
+
+            return client.login_ssh(&challenge.challenge, &base64::encode(signature), duration).await
+        }
+
+        Err(Error::Api("login failed".to_string()))
+    }
+}
+
+pub(crate) fn routes(server : &Arc<Server>, m : Router) -> Router {
+    let m = get_ssh_keys(Arc::clone(server), m.named("get_ssh_keys"));
+    let m = post_ssh_keys(Arc::clone(server), m.named("post_ssh_keys"));
+    let m = delete_ssh_key(Arc::clone(server), m.named("delete_ssh_key"));
+    let m = post_login_ssh_challenge(Arc::clone(server), m.named("post_login_ssh_challenge"));
+    post_login_ssh(Arc::clone(server), m.named("post_login_ssh"))
+}
+
+/// the key type and base64 key, as in authorized_keys
+fn authorized_key(key : &models::SshKey) -> String {
+    let name = base64::decode(&key.public_key).ok()
+        .and_then(|blob| PublicKey::parse(&blob).map(|key| key.name()))
+        .unwrap_or_default();
+
+    format!("{} {}", name, key.public_key)
+}
+
+fn get_ssh_keys(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(GET / "ssh-keys"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let (token, _) = server.authenticate(&req).await?;
+
+            let keys = server.database.list_ssh_keys(&token.sub).await?
+                .into_iter()
+                .map(|key| SshKeyInfo{
+                    id : key.id,
+                    public_key : authorized_key(&key),
+                    fingerprint : fingerprint(&base64::decode(&key.public_key).unwrap_or_default()),
+                    comment : key.comment,
+                    created : key.created,
+                    last_used : key.last_used,
+                })
+                .collect();
+
+            let s = serde_json::to_string(&GetSshKeysResponse{ keys })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn post_ssh_keys(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "ssh-keys"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let (token, user) = server.authenticate(&req).await?;
+
+            // a key is a lasting way in, which a stolen or impersonated
+            // token mustn't be able to add
+            let now = unix_now();
+            let auth_time = token.auth_time.unwrap_or(0);
+            if token.act.is_some() || (now as u64).saturating_sub(auth_time) > RECENT_LOGIN {
+                return Err(AuthError::Forbidden.into())
+            }
+
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostSshKeyRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            let (public_key, comment) = parse_authorized_key(&req.public_key)
+                .ok_or(TransportError::BadRequest)?;
+
+            let id = server.database.add_ssh_key(&user.name, &base64::encode(&public_key), &comment, now).await?;
+
+            server.audit(
+                Some(&user.name),
+                "add-ssh-key",
+                Some(&user.name),
+                Some(&fingerprint(&public_key)),
+            ).await?;
+
+            let s = serde_json::to_string(&PostSshKeyResponse{ id })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn delete_ssh_key(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(DELETE / "ssh-keys" / i64),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, id : i64, server : Arc<Server>| async move {
+            let (token, _) = server.authenticate(&req).await?;
+
+            server.database.delete_ssh_key(&token.sub, id).await?;
+
+            Ok(http::response::Builder::new()
+                .status(http::StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        })
+    )
+}
+
+fn post_login_ssh_challenge(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "login" / "ssh" / "challenge"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostSshChallengeRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            // keys are stored encoded with padding
+            let public_key = base64::decode(&req.public_key)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            // unknown users and keys look the same
+            let key = server.database.find_ssh_key(&req.name, &base64::encode(&public_key)).await?
+                .ok_or(AuthError::LoginFailed)?;
+
+            let challenge = crypto::new_device_token();
+            let expires = unix_now() + CHALLENGE_DURATION;
+            server.database.insert_ssh_challenge(
+                &crypto::hash_device_token(&challenge),
+                &models::SshChallenge{
+                    key_id : key.id,
+                    aud : req.aud,
+                    expires,
+                },
+            ).await?;
+
+            let s = serde_json::to_string(&PostSshChallengeResponse{ challenge, expires })?;
+            Ok(Response::new(s.into()))
+        })
+    )
+}
+
+fn post_login_ssh(server : Arc<Server>, m : Router) -> Router {
+    m.handle(
+        route!(POST / "login" / "ssh"),
+        mux::new_handler()
+        .map_bind(server.clone())
+        .aand_then(|req : Request, server : Arc<Server>| async move {
+            let user_agent = user_agent(&req);
+            let addr = client_addr(&req);
+            let reader = hyper::body::aggregate(req.into_body()).await?.reader();
+            let req : PostSshLoginRequest = serde_json::from_reader(reader)
+                .map_err(|_| TransportError::BadRequest)?;
+
+            let now = unix_now();
+            let challenge = server.database.take_ssh_challenge(&crypto::hash_device_token(&req.challenge), now).await?
+                .filter(|challenge| challenge.expires >= now)
+                .ok_or(AuthError::LoginFailed)?;
+            let key = server.database.get_ssh_key(challenge.key_id).await?
+                .ok_or(AuthError::LoginFailed)?;
+
+            let attempt = server.login_attempt(key.name.clone(), challenge.aud.clone(), user_agent, addr).await?;
+
+            let login = ssh_login(&server, key, challenge.aud, req, &attempt);
+            server.hook_login(&attempt, login).await
+        })
+    )
+}
+
+async fn ssh_login(
+    server : &Server,
+    key : models::SshKey,
+    aud : String,
+    req : PostSshLoginRequest,
+    attempt : &LoginAttempt,
+) -> Result<Response> {
+    let public_key = base64::decode(&key.public_key)
+        .map_err(|_| AuthError::LoginFailed)?;
+    let signature = base64::decode(&req.signature)
+        .map_err(|_| AuthError::LoginFailed)?;
+
+    if !verify(&public_key, &signed_data(&server.issuer, &req.challenge), &signature) {
+        return Err(AuthError::LoginFailed.into())
+    }
+
+    let mut user = server.database.get_user_by_name(&key.name).await?;
+    let aud_version = server.database.get_audience_version(&key.name, &aud).await?;
+
+    let now = unix_now();
+    // break glass accounts can't log in with a key
+    server.record_login(&mut user, attempt, now, false).await?;
+    server.database.touch_ssh_key(key.id, now).await?;
+
+    let token = server.issue_token(&user, crypto::Token{
+        iss : server.issuer.to_string(),
+        aud,
+        sub : key.name,
+        version : user.token_version,
+        aud_version,
+        acr : crypto::Assurance::SshKey,
+        act : None,
+        auth_time : None,
+        org : None,
+        extra : Default::default(),
+    }, req.duration).await?;
+
+    let s = serde_json::to_string(&PostLoginResponse::new(token))?;
+    Ok(Response::new(s.into()))
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub const LOGIN : &str = "login";
+pub const FAILED_LOGIN : &str = "failed_login";
+pub const TOKEN_ISSUED : &str = "token_issued";
+/// requests to the discovery routes, labeled by one of the
+/// `DISCOVERY_` labels
+pub const DISCOVERY : &str = "discovery";
+
+pub const DISCOVERY_ANONYMOUS : &str = "anonymous";
+pub const DISCOVERY_AUTHENTICATED : &str = "authenticated";
+/// anonymous requests refused by `ratelimit::DiscoveryLimiter`
+pub const DISCOVERY_LIMITED : &str = "limited";
+
+/// A counter increment, counters are bucketed by hour and carry an
+/// optional label, e.g. the audience of an issued token
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct StatKey {
+    pub hour : i64,
+    pub counter : &'static str,
+    pub label : String,
+}
+
+/// Counters kept in memory until they are flushed to the database, so
+/// counting never adds a write to the request path
+#[derive(Default)]
+pub struct Stats {
+    pending : Mutex<HashMap<StatKey, i64>>,
+}
+
+impl Stats {
+    pub fn incr(&self, now : i64, counter : &'static str, label : &str) {
+        let key = StatKey{
+            hour : now / 3600,
+            counter,
+            label : label.to_string(),
+        };
+
+        *self.pending.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    /// removes and returns the counts accumulated since the last call
+    pub fn take(&self) -> Vec<(StatKey, i64)> {
+        self.pending.lock().unwrap().drain().collect()
+    }
+
+    /// puts back counts which could not be persisted
+    pub fn restore(&self, counts : Vec<(StatKey, i64)>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (key, n) in counts {
+            *pending.entry(key).or_insert(0) += n;
+        }
+    }
+}
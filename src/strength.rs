@@ -0,0 +1,509 @@
+//! Estimating how hard a password is to guess, after zxcvbn: the password
+//! is split into the patterns an attacker would try first, e.g. common
+//! passwords, the user's name, sequences, repeats, rows of keys and years,
+//! anything else is guessed by brute force. The number of guesses of the
+//! cheapest split gives a score from 0 to 4, the pattern taking up most of
+//! the password gives the feedback.
+//!
+//! The server refuses passwords below `Config::min_score` with
+//! `auth.weak_password`, whose body holds the `Estimate` so frontends can
+//! show it, `authn-utils` prints it. `POST /password/estimate` gives the
+//! estimate beforehand, e.g. while the user types.
+
+use std::collections::HashMap;
+
+use serde::{Serialize, Deserialize};
+
+/// the default of `Config::min_score`, any password is accepted
+pub const DEFAULT_MIN_SCORE : u8 = 0;
+
+/// the highest score
+pub const MAX_SCORE : u8 = 4;
+
+/// only this many characters are matched against patterns, the rest count
+/// as brute force
+const MAX_MATCH_LEN : usize = 100;
+
+/// guesses per character of brute force
+const BRUTEFORCE_CARDINALITY : f64 = 10.0;
+
+/// the fewest guesses a pattern of one character, or of more, counts for
+const MIN_SINGLE_GUESSES : f64 = 10.0;
+const MIN_SUBMATCH_GUESSES : f64 = 50.0;
+
+/// years around this one are guessed first
+const REFERENCE_YEAR : i32 = 2026;
+const MIN_YEAR_SPACE : f64 = 20.0;
+
+/// the upper bounds, as log10 of the guesses, of scores 0 to 3
+const SCORE_THRESHOLDS : [f64; 4] = [3.0, 6.0, 8.0, 10.0];
+
+/// keys a row pattern may start on, roughly the keys of a keyboard
+const KEYBOARD_STARTS : f64 = 40.0;
+
+const KEYBOARD_ROWS : &[&str] = &[
+    "1234567890-=",
+    "qwertyuiop[]",
+    "asdfghjkl;'",
+    "zxcvbnm,./",
+    "qwertzuiop",
+    "yxcvbnm",
+    "azertyuiop",
+    "qsdfghjklm",
+    "wxcvbn",
+];
+
+/// the most common passwords, most common first
+const COMMON_PASSWORDS : &[&str] = &[
+    "123456", "password", "12345678", "qwerty", "123456789", "12345",
+    "1234", "111111", "1234567", "dragon", "123123", "baseball", "abc123",
+    "football", "monkey", "letmein", "696969", "shadow", "master", "666666",
+    "qwertyuiop", "123321", "mustang", "1234567890", "michael", "654321",
+    "superman", "1qaz2wsx", "7777777", "121212", "000000", "qazwsx",
+    "123qwe", "killer", "trustno1", "jordan", "jennifer", "zxcvbnm",
+    "asdfgh", "hunter", "buster", "soccer", "harley", "batman", "andrew",
+    "tigger", "sunshine", "iloveyou", "2000", "charlie", "robert", "thomas",
+    "hockey", "ranger", "daniel", "starwars", "klaster", "112233", "george",
+    "computer", "michelle", "jessica", "pepper", "1111", "zxcvbn", "555555",
+    "11111111", "131313", "freedom", "777777", "pass", "maggie", "159753",
+    "aaaaaa", "ginger", "princess", "joshua", "cheese", "amanda", "summer",
+    "love", "ashley", "nicole", "chelsea", "biteme", "matthew", "access",
+    "yankees", "987654321", "dallas", "austin", "thunder", "taylor",
+    "matrix", "admin", "welcome", "login", "secret", "passw", "hello",
+    "changeme", "default", "root", "test", "guest", "qwerty123", "p4ssword",
+    "solo", "flower", "whatever", "dragon1", "monkey1", "starwars1",
+    "lovely", "hottie", "loveme", "zaq1zaq1", "blink182", "winter",
+    "spring", "autumn", "fall", "dolphin", "internet", "samsung", "apple",
+    "google", "facebook", "linux", "windows", "azerty", "qwertz",
+];
+
+/// the letters digits and symbols stand in for
+const L33T : &[(char, char)] = &[
+    ('4', 'a'), ('@', 'a'), ('8', 'b'), ('(', 'c'), ('3', 'e'), ('6', 'g'),
+    ('9', 'g'), ('1', 'i'), ('!', 'i'), ('|', 'l'), ('0', 'o'), ('$', 's'),
+    ('5', 's'), ('7', 't'), ('+', 't'), ('2', 'z'),
+];
+
+fn default_min_score() -> u8 {
+    DEFAULT_MIN_SCORE
+}
+
+#[derive(Deserialize,Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// passwords set over the api must score at least this, from 0 to 4
+    #[serde(default = "default_min_score")]
+    pub min_score : u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self{
+            min_score : DEFAULT_MIN_SCORE,
+        }
+    }
+}
+
+impl Config {
+    /// the estimate of `pass`, an error if it scores below `min_score`.
+    /// `user_inputs` are guessed first, e.g. the user's name.
+    pub fn check(&self, pass : &str, user_inputs : &[&str]) -> Result<Estimate, Estimate> {
+        let estimate = estimate(pass, user_inputs);
+        if estimate.score < self.min_score {
+            return Err(estimate)
+        }
+
+        Ok(estimate)
+    }
+}
+
+/// How hard a password is to guess, and how to make it harder
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct Estimate {
+    /// 0, guessed in a few tries, to 4, very unlikely to be guessed
+    pub score : u8,
+    /// log10 of the guesses it takes
+    pub guesses_log10 : f64,
+    /// what makes the password weak, if it is
+    pub warning : Option<String>,
+    /// how to make the password stronger, empty for strong ones
+    pub suggestions : Vec<String>,
+}
+
+#[derive(Clone,Copy,PartialEq)]
+enum Pattern {
+    Dictionary{
+        rank : usize,
+        user_input : bool,
+        reversed : bool,
+        l33t : bool,
+    },
+    Repeat,
+    Sequence,
+    Keyboard,
+    Year,
+}
+
+#[derive(Clone,Copy)]
+struct Match {
+    start : usize,
+    end : usize,
+    guesses : f64,
+    pattern : Pattern,
+}
+
+/// estimates `pass`, `user_inputs` are guessed first, e.g. the user's name
+pub fn estimate(pass : &str, user_inputs : &[&str]) -> Estimate {
+    let chars = pass.chars().collect::<Vec<_>>();
+    let matched = &chars[..chars.len().min(MAX_MATCH_LEN)];
+
+    let mut matches = Vec::new();
+    dictionary_matches(matched, user_inputs, &mut matches);
+    repeat_matches(matched, &mut matches);
+    sequence_matches(matched, &mut matches);
+    keyboard_matches(matched, &mut matches);
+    year_matches(matched, &mut matches);
+
+    let (guesses_log10, sequence) = cheapest(chars.len(), &matches);
+    let score = SCORE_THRESHOLDS.iter()
+        .position(|threshold| guesses_log10 < *threshold)
+        .unwrap_or(MAX_SCORE as usize) as u8;
+
+    let (warning, suggestions) = feedback(&chars, score, &sequence);
+
+    Estimate{
+        score,
+        guesses_log10,
+        warning : warning.map(str::to_string),
+        suggestions : suggestions.into_iter().map(str::to_string).collect(),
+    }
+}
+
+fn lower(c : char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+fn unl33t(c : char) -> Option<char> {
+    L33T.iter().find(|(from, _)| *from == c).map(|(_, to)| *to)
+}
+
+/// common passwords and `user_inputs`, as they are, reversed, and with
+/// digits and symbols standing in for letters
+fn dictionary_matches(chars : &[char], user_inputs : &[&str], matches : &mut Vec<Match>) {
+    let mut ranks = HashMap::<String, (usize, bool)>::new();
+    for (i, word) in user_inputs.iter().enumerate() {
+        let word = word.chars().map(lower).collect::<String>();
+        if word.chars().count() >= 3 {
+            ranks.entry(word).or_insert((i + 1, true));
+        }
+    }
+    for (i, word) in COMMON_PASSWORDS.iter().enumerate() {
+        ranks.entry(word.to_string()).or_insert((i + 1, false));
+    }
+
+    let max_len = ranks.keys().map(|word| word.chars().count()).max().unwrap_or(0);
+
+    for start in 0..chars.len() {
+        for end in (start + 3)..=chars.len().min(start + max_len) {
+            let token = &chars[start..end];
+            let lowered = token.iter().copied().map(lower).collect::<Vec<_>>();
+            let subs = lowered.iter()
+                .filter(|c| unl33t(**c).is_some() && !c.is_alphabetic())
+                .count();
+            let unl33ted = lowered.iter()
+                .map(|c| unl33t(*c).unwrap_or(*c))
+                .collect::<Vec<_>>();
+
+            let mut candidates = vec![
+                (lowered.iter().collect::<String>(), false, false),
+                (lowered.iter().rev().collect::<String>(), true, false),
+            ];
+            if subs > 0 {
+                candidates.push((unl33ted.iter().collect::<String>(), false, true));
+            }
+
+            // the cheapest reading of the token
+            let best = candidates.into_iter()
+                .filter_map(|(word, reversed, l33t)| {
+                    let (rank, user_input) = *ranks.get(&word)?;
+                    let mut guesses = rank as f64 * uppercase_variations(token);
+                    if reversed {
+                        guesses *= 2.0;
+                    }
+                    if l33t {
+                        guesses *= 2f64.powi(subs as i32);
+                    }
+
+                    Some(Match{
+                        start,
+                        end,
+                        guesses,
+                        pattern : Pattern::Dictionary{ rank, user_input, reversed, l33t },
+                    })
+                })
+                .min_by(|a, b| a.guesses.total_cmp(&b.guesses));
+
+            matches.extend(best);
+        }
+    }
+}
+
+fn binomial(n : usize, k : usize) -> f64 {
+    (0..k).fold(1.0, |acc, i| acc * (n - i) as f64 / (i + 1) as f64)
+}
+
+/// how many ways of capitalizing `token` are tried before its own
+fn uppercase_variations(token : &[char]) -> f64 {
+    let upper = token.iter().filter(|c| c.is_uppercase()).count();
+    let lower = token.iter().filter(|c| c.is_lowercase()).count();
+
+    if upper == 0 {
+        return 1.0
+    }
+
+    let first_or_last = upper == 1
+        && (token[0].is_uppercase() || token[token.len() - 1].is_uppercase());
+    if lower == 0 || first_or_last {
+        return 2.0
+    }
+
+    (1..=upper.min(lower))
+        .map(|i| binomial(upper + lower, i))
+        .sum()
+}
+
+/// guesses per character of a pattern made of `c`
+fn cardinality(c : char) -> f64 {
+    if c.is_ascii_digit() {
+        10.0
+    } else if c.is_ascii_alphabetic() {
+        26.0
+    } else {
+        33.0
+    }
+}
+
+/// runs of one character, e.g. `aaaa`
+fn repeat_matches(chars : &[char], matches : &mut Vec<Match>) {
+    let mut start = 0;
+    while start < chars.len() {
+        let end = chars[start..].iter()
+            .position(|c| *c != chars[start])
+            .map(|len| start + len)
+            .unwrap_or(chars.len());
+
+        if end - start >= 3 {
+            matches.push(Match{
+                start,
+                end,
+                guesses : cardinality(chars[start]) * (end - start) as f64,
+                pattern : Pattern::Repeat,
+            });
+        }
+
+        start = end;
+    }
+}
+
+/// runs of characters counting up or down by one, e.g. `abcd` or `4321`
+fn sequence_matches(chars : &[char], matches : &mut Vec<Match>) {
+    let step = |i : usize| chars[i + 1] as i64 - chars[i] as i64;
+
+    let mut start = 0;
+    while start + 1 < chars.len() {
+        let delta = step(start);
+        let mut end = start + 2;
+        while end < chars.len() && step(end - 1) == delta {
+            end += 1;
+        }
+
+        let first = chars[start];
+        let alike = chars[start..end].iter()
+            .all(|c| cardinality(*c) == cardinality(first));
+
+        if delta.abs() == 1 && end - start >= 3 && alike {
+            let base = if "aAzZ019".contains(first) { 4.0 } else { cardinality(first) };
+            let direction = if delta < 0 { 2.0 } else { 1.0 };
+
+            matches.push(Match{
+                start,
+                end,
+                guesses : base * direction * (end - start) as f64,
+                pattern : Pattern::Sequence,
+            });
+        }
+
+        start = end - 1;
+    }
+}
+
+/// keys next to each other in a row, e.g. `asdf` or `poiu`
+fn keyboard_matches(chars : &[char], matches : &mut Vec<Match>) {
+    let lowered = chars.iter().copied().map(lower).collect::<String>();
+    let lowered = lowered.chars().collect::<Vec<_>>();
+
+    for row in KEYBOARD_ROWS {
+        let keys = row.chars().collect::<Vec<_>>();
+
+        for start in 0..lowered.len() {
+            for reversed in [false, true] {
+                let next = |c : char| {
+                    let i = keys.iter().position(|k| *k == c)?;
+                    if reversed {
+                        i.checked_sub(1).map(|i| keys[i])
+                    } else {
+                        keys.get(i + 1).copied()
+                    }
+                };
+
+                let mut end = start + 1;
+                while end < lowered.len() && next(lowered[end - 1]) == Some(lowered[end]) {
+                    end += 1;
+                }
+
+                if end - start >= 4 {
+                    let direction = if reversed { 2.0 } else { 1.0 };
+                    matches.push(Match{
+                        start,
+                        end,
+                        guesses : KEYBOARD_STARTS * direction * (end - start) as f64,
+                        pattern : Pattern::Keyboard,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// four digit years from 1900 to 2099
+fn year_matches(chars : &[char], matches : &mut Vec<Match>) {
+    for start in 0..chars.len().saturating_sub(3) {
+        let token = &chars[start..start + 4];
+        if !token.iter().all(char::is_ascii_digit) {
+            continue
+        }
+
+        let year = token.iter().collect::<String>().parse::<i32>().unwrap();
+        if (1900..2100).contains(&year) {
+            matches.push(Match{
+                start,
+                end : start + 4,
+                guesses : ((year - REFERENCE_YEAR).abs() as f64).max(MIN_YEAR_SPACE),
+                pattern : Pattern::Year,
+            });
+        }
+    }
+}
+
+/// the log10 of the guesses of the cheapest split of the password into
+/// matches and brute force, and the matches of it
+fn cheapest(len : usize, matches : &[Match]) -> (f64, Vec<Match>) {
+    // best[i] is the cheapest split of the first i characters, with the
+    // match ending it, if any
+    let mut best = vec![(0.0, None::<usize>); len + 1];
+
+    for end in 1..=len {
+        let mut cost = best[end - 1].0 + BRUTEFORCE_CARDINALITY.log10();
+        let mut last = None;
+
+        for (i, m) in matches.iter().enumerate().filter(|(_, m)| m.end == end) {
+            let min = if m.end - m.start == 1 { MIN_SINGLE_GUESSES } else { MIN_SUBMATCH_GUESSES };
+            let match_cost = best[m.start].0 + m.guesses.max(min).log10();
+            if match_cost < cost {
+                cost = match_cost;
+                last = Some(i);
+            }
+        }
+
+        best[end] = (cost, last);
+    }
+
+    let mut sequence = Vec::new();
+    let mut end = len;
+    while end > 0 {
+        match best[end].1 {
+            Some(i) => {
+                sequence.push(matches[i]);
+                end = matches[i].start;
+            },
+            None => end -= 1,
+        }
+    }
+    sequence.reverse();
+
+    (best[len].0, sequence)
+}
+
+/// the warning and suggestions for the longest match of the split
+fn feedback(
+    chars : &[char],
+    score : u8,
+    sequence : &[Match],
+) -> (Option<&'static str>, Vec<&'static str>) {
+    if chars.is_empty() {
+        return (None, vec![
+            "Use a few words, avoid common phrases",
+            "No need for symbols, digits, or uppercase letters",
+        ])
+    }
+
+    if score >= 3 {
+        return (None, vec![])
+    }
+
+    let mut suggestions = vec!["Add another word or two, uncommon words are better"];
+
+    let longest = match sequence.iter().max_by_key(|m| m.end - m.start) {
+        Some(m) => m,
+        None => return (None, suggestions),
+    };
+
+    let warning = match longest.pattern {
+        Pattern::Dictionary{ rank, user_input, reversed, l33t } => {
+            let token = &chars[longest.start..longest.end];
+            let whole = sequence.len() == 1 && token.len() == chars.len();
+
+            if token[0].is_uppercase() && token[1..].iter().all(|c| !c.is_uppercase()) {
+                suggestions.push("Capitalization doesn't help very much");
+            } else if token.iter().all(|c| !c.is_lowercase()) && token.iter().any(|c| c.is_uppercase()) {
+                suggestions.push("All-uppercase is almost as easy to guess as all-lowercase");
+            }
+            if reversed {
+                suggestions.push("Reversed words aren't much harder to guess");
+            }
+            if l33t {
+                suggestions.push("Predictable substitutions like '@' instead of 'a' don't help very much");
+            }
+
+            if user_input {
+                Some("Passwords with your name in them are easy to guess")
+            } else if whole && rank <= 10 {
+                Some("This is a top-10 common password")
+            } else if whole && rank <= 100 {
+                Some("This is a top-100 common password")
+            } else if whole {
+                Some("This is a very common password")
+            } else {
+                Some("This is similar to a commonly used password")
+            }
+        },
+        Pattern::Repeat => {
+            suggestions.push("Avoid repeated words and characters");
+            Some("Repeats like \"aaa\" are easy to guess")
+        },
+        Pattern::Sequence => {
+            suggestions.push("Avoid sequences");
+            Some("Sequences like abc or 6543 are easy to guess")
+        },
+        Pattern::Keyboard => {
+            suggestions.push("Use a longer keyboard pattern with more turns");
+            Some("Straight rows of keys are easy to guess")
+        },
+        Pattern::Year => {
+            suggestions.push("Avoid recent years, and years associated with you");
+            Some("Recent years are easy to guess")
+        },
+    };
+
+    (warning, suggestions)
+}
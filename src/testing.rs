@@ -0,0 +1,190 @@
+//! An in-process server for end-to-end tests.
+//!
+//! The server relies on `block_in_place`, so tests need the multi threaded
+//! runtime:
+//!
+//! ```ignore
+//! #[tokio::test(flavor = "multi_thread")]
+//! async fn login() {
+//!     let server = TestServer::new().await.unwrap();
+//!     server.add_user("alice", "hunter2").await.unwrap();
+//!
+//!     let client = server.client("example.com");
+//!     let token = client.login("alice", "hunter2", Duration::from_secs(60)).await.unwrap();
+//! }
+//! ```
+
+use std::convert::{Infallible, TryFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyperlocal::UnixServerExt;
+use plumb::{Pipe, PipeExt};
+
+use crate::client;
+use crate::crypto;
+use crate::database::{Database, MIGRATIONS};
+use crate::peer::PeerCredentials;
+use crate::server::{self, Error, Server};
+
+pub const SERVER_NAME : &str = "authn.test";
+
+const PRIV_KEY : &str = include_str!("test-priv-key.pem");
+const PUB_KEY : &str = include_str!("test-pub-key.pem");
+/// self signed, for `PRIV_KEY`
+const CERT : &str = include_str!("test-cert.pem");
+
+/// A server listening on a unix socket in a fresh temporary directory,
+/// which also holds its database, keys and certificate. The directory is removed and
+/// the server stopped on drop.
+pub struct TestServer {
+    dir : PathBuf,
+    database : Database,
+    config : serde_json::Value,
+    handle : tokio::task::JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn new() -> Result<Self, Error> {
+        Self::with(|server| server).await
+    }
+
+    /// like `new`, `setup` can add hooks to the server before it starts
+    pub async fn with<F>(setup : F) -> Result<Self, Error>
+    where
+        F : FnOnce(Server) -> Server,
+    {
+        Self::with_config(serde_json::json!({}), setup).await
+    }
+
+    /// like `with`, the fields of `extra` are set in the server's config,
+    /// e.g. to enable `saml`
+    pub async fn with_config<F>(extra : serde_json::Value, setup : F) -> Result<Self, Error>
+    where
+        F : FnOnce(Server) -> Server,
+    {
+
+        let dir = std::env::temp_dir()
+            .join(format!("authn-test-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir(&dir)?;
+
+        let database = dir.join("authn.sqlite3");
+        {
+            let conn = rusqlite::Connection::open(&database)?;
+            for (_, migration) in MIGRATIONS {
+                conn.execute_batch(migration)?;
+            }
+        }
+
+        std::fs::write(dir.join("priv-key.pem"), PRIV_KEY)?;
+        std::fs::write(dir.join("pub-key.pem"), PUB_KEY)?;
+        std::fs::write(dir.join("cert.pem"), CERT)?;
+
+        let mut config = serde_json::json!({
+            "server_name" : SERVER_NAME,
+            "server_path" : dir.join("authn.sock"),
+            "alg" : "ES256",
+            "priv_key_file" : dir.join("priv-key.pem"),
+            "pub_key_file" : dir.join("pub-key.pem"),
+            "cert_file" : dir.join("cert.pem"),
+            "database" : database,
+            "read_connections" : 2,
+        });
+        if let serde_json::Value::Object(extra) = extra {
+            for (key, value) in extra {
+                config[key] = value;
+            }
+        }
+
+        let (server, path) = server::new_server(serde_json::from_value(config.clone())?)?;
+        let server = setup(server);
+        let header_read = std::time::Duration::from_secs(server.timeouts().header_read);
+        let pipe = Arc::new(server::routes(server).tuple().seq(Ok::<_, Infallible>));
+
+        let make_service = make_service_fn(move |conn : &tokio::net::UnixStream| {
+            let pipe = Arc::clone(&pipe);
+            let peer = PeerCredentials::of_stream(conn);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut req : hyper::Request<hyper::Body>| {
+                    let pipe = Arc::clone(&pipe);
+                    if let Some(peer) = peer {
+                        req.extensions_mut().insert(peer);
+                    }
+                    async move { pipe.run((req,)).await }
+                }))
+            }
+        });
+
+        let serve = hyper::Server::bind_unix(path)?
+            .http1_header_read_timeout(header_read)
+            .serve(make_service);
+        let handle = tokio::spawn(async move {
+            if let Err(err) = serve.await {
+                eprintln!("test server failed: {:?}", err);
+            }
+        });
+
+        Ok(Self{
+            database : Database::new(database.to_str().unwrap())?,
+            dir,
+            config,
+            handle,
+        })
+    }
+
+    /// the unix socket the server listens on
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("authn.sock")
+    }
+
+    /// the directory holding the socket, database and keys
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// direct access to the server's database
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    pub async fn add_user(&self, name : &str, pass : &str) -> Result<(), Error> {
+        let hash = crypto::encode_password(pass.as_bytes())?;
+        self.database.insert_user(name, &hash).await
+    }
+
+    pub async fn set_roles(&self, name : &str, roles : &str) -> Result<(), Error> {
+        self.database.set_roles(name, roles).await
+    }
+
+    /// a client for the server using `aud` as its audience
+    pub fn client(&self, aud : &str) -> client::Client {
+        client::Client::try_from(client::Config{
+            server_path : self.path().to_str().unwrap().to_string(),
+            server_name : SERVER_NAME.to_string(),
+            external_url : None,
+            client_name : aud.to_string(),
+            alg : jsonwebtoken::Algorithm::ES256,
+            pub_key_file : self.dir.join("pub-key.pem").to_str().unwrap().to_string(),
+            pool : Default::default(),
+            timeout : 30,
+            shadow : Vec::new(),
+        }).expect("the test key is valid")
+    }
+
+    /// like `client`, but the client calls a second server, sharing the
+    /// database and keys, in-process rather than through the socket
+    pub fn local_client(&self, aud : &str) -> Result<client::Client, Error> {
+        let (server, _) = server::new_server(serde_json::from_value(self.config.clone())?)?;
+        let transport = client::LocalTransport::new(server::routes(server));
+
+        Ok(self.client(aud).with_transport(transport))
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
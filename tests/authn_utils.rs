@@ -0,0 +1,101 @@
+// the test server listens on a unix socket
+#![cfg(unix)]
+
+use std::process::Command;
+use std::time::Duration;
+
+use authn::client;
+use authn::testing::{TestServer, SERVER_NAME};
+
+/// runs `authn-utils` with a client config for `server`
+fn authn_utils(server : &TestServer, args : &[&str]) -> std::process::Output {
+    let config = server.dir().join("utils-config.json");
+    std::fs::write(&config, serde_json::json!({
+        "server_path" : server.path(),
+        "server_name" : SERVER_NAME,
+        "client_name" : SERVER_NAME,
+        "alg" : "ES256",
+        "pub_key_file" : server.dir().join("pub-key.pem"),
+    }).to_string()).unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_authn-utils"))
+        .args(args)
+        .env("AUTHN_CONFIG", &config)
+        .output()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn set_org_member() {
+    let server = TestServer::new().await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+    server.database().insert_org("acme", 0).await.unwrap();
+    server.database().set_org_member("acme", "alice", false, "").await.unwrap();
+
+    let client = server.client(SERVER_NAME);
+    let org = client.login_org("alice", "hunter2", "acme", Duration::from_secs(60)).await.unwrap();
+    let plain = client.login("alice", "hunter2", Duration::from_secs(60)).await.unwrap();
+
+    let db = server.dir().join("authn.sqlite3");
+    let out = authn_utils(&server, &["set-org-member", db.to_str().unwrap(), "acme", "alice", "admin"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    // only the tokens holding the old membership are invalidated
+    assert!(matches!(client.validate_token(&org).await, Err(client::Error::VersionMismatch)));
+    client.validate_token(&plain).await.unwrap();
+
+    let org = client.login_org("alice", "hunter2", "acme", Duration::from_secs(60)).await.unwrap();
+    assert!(client.validate_token_claims(&org).await.unwrap().org.unwrap().admin);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rotate_keys() {
+    let server = TestServer::new().await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+    let old = server.client("example.com").login("alice", "hunter2", Duration::from_secs(60)).await.unwrap();
+
+    let dir = server.dir();
+    let config_file = dir.join("server-config.json");
+    std::fs::write(&config_file, serde_json::json!({
+        "server_name" : SERVER_NAME,
+        "server_path" : server.path(),
+        "alg" : "ES256",
+        "priv_key_file" : dir.join("priv-key.pem"),
+        "pub_key_file" : dir.join("pub-key.pem"),
+        "database" : dir.join("authn.sqlite3"),
+    }).to_string()).unwrap();
+
+    let out = authn_utils(&server, &["rotate-keys", config_file.to_str().unwrap(), "ES384"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let config : serde_json::Value = serde_json::from_slice(&std::fs::read(&config_file).unwrap()).unwrap();
+    assert_eq!(config["retired_key"]["alg"], "ES256");
+
+    // a server with the new keys, the test certificate is for the old one
+    let rotated = |retired_key : serde_json::Value| TestServer::with_config(serde_json::json!({
+        "alg" : config["alg"],
+        "priv_key_file" : config["priv_key_file"],
+        "pub_key_file" : config["pub_key_file"],
+        "token_header" : config["token_header"],
+        "retired_key" : retired_key,
+        "cert_file" : null,
+    }), |server| server);
+
+    // tokens signed with the old key still validate, renewing them signs
+    // them with the new one
+    let server = rotated(config["retired_key"].clone()).await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+    let client = server.client("example.com");
+    let new = client.renew(&old, Duration::from_secs(60)).await.unwrap();
+    let header = jsonwebtoken::decode_header(&new).unwrap();
+    assert_eq!(header.alg, jsonwebtoken::Algorithm::ES384);
+    assert_eq!(Some(header.kid.unwrap()), config["token_header"]["kid"].as_str().map(String::from));
+    client.renew(&new, Duration::from_secs(60)).await.unwrap();
+
+    // until the old tokens could have expired
+    let mut retired_key = config["retired_key"].clone();
+    retired_key["until"] = serde_json::json!(1);
+    let server = rotated(retired_key).await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+    let res = server.client("example.com").renew(&old, Duration::from_secs(60)).await;
+    assert!(matches!(res, Err(client::Error::Api(e)) if e == "unauthorized"));
+}
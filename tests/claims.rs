@@ -0,0 +1,289 @@
+use std::time::Duration;
+
+use jsonwebtoken as jwt;
+use proptest::prelude::*;
+
+use authn::crypto::{self, Actor, Assurance, Org, Token};
+
+const PRIV_KEY : &[u8] = include_bytes!("../src/test-priv-key.pem");
+const PUB_KEY : &[u8] = include_bytes!("../src/test-pub-key.pem");
+
+const ISS : &str = "authn.test";
+
+fn assurance() -> impl Strategy<Value = Assurance> {
+    prop_oneof![
+        Just(Assurance::Password),
+        Just(Assurance::Kerberos),
+        Just(Assurance::SshKey),
+        Just(Assurance::PasswordTotp),
+        Just(Assurance::Webauthn),
+    ]
+}
+
+fn extra() -> impl Strategy<Value = serde_json::Map<String, serde_json::Value>> {
+    let value = prop_oneof![
+        any::<bool>().prop_map(serde_json::Value::from),
+        any::<i64>().prop_map(serde_json::Value::from),
+        ".*".prop_map(serde_json::Value::from),
+    ];
+
+    // nbf is checked by validation, so it's left out along with the
+    // claims set by Token itself
+    prop::collection::btree_map("[a-z_]{1,12}", value, 0..4)
+        .prop_map(|m| m.into_iter()
+            .filter(|(k, _)| !crypto::RESERVED_CLAIMS.contains(&k.as_str()) && k != "nbf")
+            .collect())
+}
+
+prop_compose! {
+    fn token()(
+        aud in "[a-z.]{1,20}",
+        sub in ".{1,20}",
+        version in any::<u32>(),
+        aud_version in any::<u32>(),
+        acr in assurance(),
+        act in prop::option::of(".{1,20}"),
+        auth_time in prop::option::of(0u64..4_000_000_000),
+        org in prop::option::of((".{1,20}", any::<bool>(), prop::collection::vec("[a-z]{1,8}", 0..3))),
+        extra in extra(),
+    ) -> Token {
+        Token{
+            iss : ISS.to_string(),
+            aud,
+            sub,
+            version,
+            aud_version,
+            acr,
+            act : act.map(|sub| Actor{ sub }),
+            auth_time,
+            org : org.map(|(name, admin, groups)| Org{ name, admin, groups, version : 0 }),
+            extra,
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn issue_validate_round_trip(token in token()) {
+        let enc_key = jwt::EncodingKey::from_ec_pem(PRIV_KEY).unwrap();
+        let dec_key = crypto::decoding_key(jwt::Algorithm::ES256, PUB_KEY).unwrap();
+        let validation = crypto::validation(jwt::Algorithm::ES256, &token.aud, ISS);
+
+        let s = token.issue(&enc_key, jwt::Algorithm::ES256, Duration::from_secs(60)).unwrap();
+        let got = Token::validate(&s, &validation, &dec_key).unwrap();
+
+        prop_assert_eq!(&got.iss, &token.iss);
+        prop_assert_eq!(&got.aud, &token.aud);
+        prop_assert_eq!(&got.sub, &token.sub);
+        prop_assert_eq!(got.version, token.version);
+        prop_assert_eq!(got.aud_version, token.aud_version);
+        prop_assert_eq!(got.acr, token.acr);
+        prop_assert_eq!(&got.act, &token.act);
+        prop_assert_eq!(&got.org, &token.org);
+        prop_assert_eq!(&got.extra, &token.extra);
+
+        // auth_time defaults to the issue time
+        if token.auth_time.is_some() {
+            prop_assert_eq!(got.auth_time, token.auth_time);
+        } else {
+            prop_assert!(got.auth_time.is_some());
+        }
+    }
+
+    #[test]
+    fn validate_never_panics(s in ".*") {
+        let dec_key = crypto::decoding_key(jwt::Algorithm::ES256, PUB_KEY).unwrap();
+        let validation = crypto::validation(jwt::Algorithm::ES256, "example.com", ISS);
+
+        let _ = Token::validate(&s, &validation, &dec_key);
+    }
+}
+
+/// callers require minimum levels by this order, kerberos and ssh keys sit
+/// between passwords and second factors
+#[test]
+fn assurance_order() {
+    assert!(Assurance::Password < Assurance::Kerberos);
+    assert!(Assurance::Kerberos < Assurance::SshKey);
+    assert!(Assurance::SshKey < Assurance::PasswordTotp);
+    assert!(Assurance::PasswordTotp < Assurance::Webauthn);
+    assert_eq!(Assurance::default(), Assurance::Password);
+}
+
+#[test]
+fn header_fields() {
+    let enc_key = jwt::EncodingKey::from_ec_pem(PRIV_KEY).unwrap();
+    let fields = crypto::HeaderFields{
+        typ : Some("at+jwt".to_string()),
+        kid : Some("2026-10".to_string()),
+        ..Default::default()
+    };
+
+    let token = Token{
+        iss : ISS.to_string(),
+        aud : "example.com".to_string(),
+        sub : "alice".to_string(),
+        version : 0,
+        aud_version : 0,
+        acr : Assurance::Password,
+        act : None,
+        auth_time : None,
+        org : None,
+        extra : Default::default(),
+    };
+
+    let s = token.issue_with(
+        &crypto::SystemClock,
+        &enc_key,
+        &fields.header(jwt::Algorithm::ES256),
+        Duration::from_secs(60),
+    ).unwrap();
+    let header = jwt::decode_header(&s).unwrap();
+
+    assert_eq!(header.alg, jwt::Algorithm::ES256);
+    assert_eq!(header.typ.as_deref(), Some("at+jwt"));
+    assert_eq!(header.kid.as_deref(), Some("2026-10"));
+    assert_eq!(header.x5t, None);
+}
+
+#[test]
+fn claims_mapping() {
+    let enc_key = jwt::EncodingKey::from_ec_pem(PRIV_KEY).unwrap();
+    let dec_key = crypto::decoding_key(jwt::Algorithm::ES256, PUB_KEY).unwrap();
+    let validation = crypto::validation(jwt::Algorithm::ES256, "legacy.example.com", ISS);
+
+    let mapping : crypto::ClaimsMapping = serde_json::from_value(serde_json::json!({
+        "omit" : ["tenant"],
+        "audiences" : {
+            "legacy.example.com" : {
+                "rename" : { "version" : "ver", "auth_time" : "authn_time", "aud_version" : "aver" },
+                "omit" : ["tenant"],
+            },
+        },
+    })).unwrap();
+    assert_eq!(mapping.invalid_claim(), None);
+
+    let token = Token{
+        iss : ISS.to_string(),
+        aud : "legacy.example.com".to_string(),
+        sub : "alice".to_string(),
+        version : 3,
+        aud_version : 2,
+        acr : Assurance::Webauthn,
+        act : None,
+        auth_time : Some(1_000),
+        org : None,
+        extra : serde_json::json!({ "tenant" : "acme" }).as_object().unwrap().clone(),
+    };
+
+    let s = token.issue_mapped(
+        &crypto::SystemClock,
+        &enc_key,
+        &jwt::Header::new(jwt::Algorithm::ES256),
+        Duration::from_secs(60),
+        mapping.for_audience(&token.aud),
+    ).unwrap();
+
+    let claims = jwt::dangerous_insecure_decode::<serde_json::Map<String, serde_json::Value>>(&s)
+        .unwrap()
+        .claims;
+    assert_eq!(claims["ver"], 3);
+    assert_eq!(claims["authn_time"], 1_000);
+    assert_eq!(claims["aver"], 2);
+    assert_eq!(claims["acr"], "webauthn");
+    for claim in ["version", "auth_time", "aud_version", "tenant"].iter() {
+        assert!(!claims.contains_key(*claim), "{}", claim);
+    }
+
+    let got = Token::validate_mapped(&crypto::SystemClock, &s, &validation, &dec_key, &mapping).unwrap();
+    assert_eq!(got.version, 3);
+    assert_eq!(got.aud_version, 2);
+    assert_eq!(got.auth_time, Some(1_000));
+    assert!(got.extra.is_empty());
+
+    // registered claims keep their names, claims the server relies on
+    // stay, and renames can't clash
+    let invalid = [
+        serde_json::json!({ "omit" : ["exp"] }),
+        serde_json::json!({ "omit" : ["act"] }),
+        serde_json::json!({ "audiences" : { "a" : { "omit" : ["version"] } } }),
+        serde_json::json!({ "audiences" : { "a" : { "rename" : { "sub" : "user" } } } }),
+        serde_json::json!({ "rename" : { "acr" : "version" } }),
+        serde_json::json!({ "rename" : { "acr" : "level", "org" : "level" } }),
+    ];
+    for config in invalid.iter() {
+        let mapping : crypto::ClaimsMapping = serde_json::from_value(config.clone()).unwrap();
+        assert!(mapping.invalid_claim().is_some(), "{}", config);
+    }
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_vectors() {
+    use crypto::testvectors::{self, FixedClock};
+
+    let expected = testvectors::claims();
+
+    for vector in testvectors::VECTORS {
+        let dec_key = crypto::decoding_key(vector.alg, vector.pub_key.as_bytes()).unwrap();
+        let validation = vector.validation();
+
+        let got = Token::validate_with(
+            &FixedClock(testvectors::ISSUED_AT),
+            vector.token,
+            &validation,
+            &dec_key,
+        ).unwrap();
+        assert_eq!(got.sub, expected.sub, "{:?}", vector.alg);
+        assert_eq!(got.version, expected.version, "{:?}", vector.alg);
+        assert_eq!(got.aud_version, expected.aud_version, "{:?}", vector.alg);
+        assert_eq!(got.acr, expected.acr, "{:?}", vector.alg);
+        assert_eq!(got.auth_time, expected.auth_time, "{:?}", vector.alg);
+        assert_eq!(got.org, expected.org, "{:?}", vector.alg);
+        assert_eq!(got.extra, expected.extra, "{:?}", vector.alg);
+
+        let expired = FixedClock(testvectors::ISSUED_AT + testvectors::DURATION + validation.leeway + 1);
+        assert!(Token::validate_with(&expired, vector.token, &validation, &dec_key).is_err(), "{:?}", vector.alg);
+
+        let enc_key = match vector.alg {
+            jwt::Algorithm::ES256 | jwt::Algorithm::ES384 => jwt::EncodingKey::from_ec_pem(vector.priv_key.as_bytes()),
+            _ => jwt::EncodingKey::from_rsa_pem(vector.priv_key.as_bytes()),
+        }.unwrap();
+        let s = expected.issue_with(
+            &FixedClock(testvectors::ISSUED_AT),
+            &enc_key,
+            &jwt::Header::new(vector.alg),
+            Duration::from_secs(testvectors::DURATION),
+        ).unwrap();
+
+        if vector.deterministic() {
+            assert_eq!(s, vector.token, "{:?}", vector.alg);
+        } else {
+            assert!(s.starts_with(&format!("{}.", vector.signing_input())), "{:?}", vector.alg);
+        }
+    }
+}
+
+#[test]
+fn issuer_url() {
+    use crypto::IssuerUrlError::*;
+
+    let cases = [
+        ("https://auth.example.com", Ok("https://auth.example.com")),
+        ("HTTPS://Auth.Example.com/", Ok("https://auth.example.com")),
+        ("https://example.com/authn/", Ok("https://example.com/authn")),
+        ("http://localhost:8080", Ok("http://localhost:8080")),
+        ("http://[::1]/", Ok("http://[::1]")),
+        ("http://auth.example.com", Err(NotHttps)),
+        ("http://localhost.example.com", Err(NotHttps)),
+        ("auth.example.com", Err(Invalid)),
+        ("https:///authn", Err(Invalid)),
+        ("https://user@auth.example.com", Err(Invalid)),
+        ("https://auth.example.com/?tenant=a", Err(Invalid)),
+        ("https://auth.example.com/#a", Err(Invalid)),
+    ];
+
+    for (url, expected) in cases.iter() {
+        assert_eq!(crypto::issuer_url(url).as_deref(), expected.as_deref(), "{}", url);
+    }
+}
@@ -0,0 +1,246 @@
+use authn::client;
+use authn::config::{self, LoadError, Sources};
+
+fn write_config(name : &str, value : &serde_json::Value) -> std::path::PathBuf {
+    let path = std::env::temp_dir()
+        .join(format!("authn-config-{}-{}.json", name, std::process::id()));
+    std::fs::write(&path, value.to_string()).unwrap();
+    path
+}
+
+fn client_config() -> serde_json::Value {
+    serde_json::json!({
+        "server_path" : "authn.sock",
+        "server_name" : "authn.example.com",
+        "client_name" : "app.example.com",
+        "alg" : "ES256",
+        "pub_key_file" : "pub-key.pem",
+    })
+}
+
+#[test]
+fn layers() {
+    let path = write_config("layers", &client_config());
+
+    let mut args = vec![
+        "--json".to_string(),
+        "--set".to_string(),
+        "timeout=5".to_string(),
+        "--set=pool.http2=true".to_string(),
+        "list-users".to_string(),
+    ];
+    let sources = Sources::file(&path)
+        .with_vars(vec![
+            ("AUTHN__TIMEOUT".to_string(), "10".to_string()),
+            ("AUTHN__SERVER_NAME".to_string(), "auth.example.com".to_string()),
+            ("AUTHN__POOL__MAX_IDLE".to_string(), "4".to_string()),
+            ("AUTHN_CONFIG".to_string(), "ignored.json".to_string()),
+        ])
+        .with_flags(&mut args)
+        .unwrap();
+    assert_eq!(args, ["--json", "list-users"]);
+
+    let config : client::Config = sources.load().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // flags win over variables, which win over the file
+    assert_eq!(config.timeout, 5);
+    assert_eq!(config.server_name, "auth.example.com");
+    assert_eq!(config.pool.max_idle, Some(4));
+    assert!(config.pool.http2);
+    assert_eq!(config.client_name, "app.example.com");
+}
+
+#[test]
+fn unknown_fields() {
+    let mut typo = client_config();
+    typo["client_nme"] = "app.example.com".into();
+    let mut nested = client_config();
+    nested["pool"] = serde_json::json!({ "http_2" : true });
+
+    for (name, value, expected) in [
+        ("typo", typo, "unknown field `client_nme`, did you mean `client_name`?"),
+        ("nested", nested, "unknown field `http_2`, did you mean `http2`?"),
+    ].iter() {
+        let path = write_config(name, value);
+        let res = Sources::file(&path).load::<client::Config>();
+        std::fs::remove_file(&path).unwrap();
+
+        match res {
+            Err(LoadError::Invalid(msg)) => assert_eq!(&msg, expected),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("{} was accepted", value),
+        }
+    }
+
+    let mut args = vec!["--set".to_string(), "timeout".to_string()];
+    assert!(matches!(Sources::default().with_flags(&mut args), Err(LoadError::InvalidOverride(_))));
+}
+
+#[test]
+fn fields_of() {
+    let fields = config::fields_of::<client::PoolConfig>();
+    assert_eq!(fields, ["max_idle", "idle_timeout", "http2", "http2_keep_alive"]);
+    assert!(config::fields_of::<String>().is_empty());
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn secrets_file() {
+    use authn::server;
+
+    let secrets = write_config("secrets", &serde_json::json!({
+        "priv_key_file" : "/etc/authn/priv-key.pem",
+        "login_notifications" : { "webhook" : "https://hooks.example.com/?token=s3cret" },
+    }));
+
+    let mut value = client_config();
+    value["priv_key_file"] = "priv-key.pem".into();
+    value["database"] = "authn.sqlite3".into();
+    value["secrets_file"] = secrets.to_str().unwrap().into();
+    let path = write_config("with-secrets", &value);
+
+    let sources = Sources::file(&path)
+        .with_vars(vec![("AUTHN__LOGIN_NOTIFICATIONS__INACTIVITY".to_string(), "60".to_string())]);
+    let config : server::Config = sources.load().unwrap();
+    std::fs::remove_file(&secrets).unwrap();
+
+    // the secrets win over the file, the variables over the secrets
+    assert_eq!(config.priv_key_file, "/etc/authn/priv-key.pem");
+    let notify = config.login_notifications.unwrap();
+    assert_eq!(notify.webhook, "https://hooks.example.com/?token=s3cret");
+    assert_eq!(notify.inactivity, 60);
+
+    // the client has no secrets, so it doesn't need to read them
+    let config : client::Config = sources.load().unwrap();
+    assert_eq!(config.client_name, "app.example.com");
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// the password settings authn-utils shares with the server come from the
+/// same layers, invalid values are errors rather than panics
+#[cfg(feature = "server")]
+#[test]
+fn password_settings() {
+    use authn::server;
+
+    let mut value = client_config();
+    value["priv_key_file"] = "priv-key.pem".into();
+    value["database"] = "authn.sqlite3".into();
+    value["password_history"] = 3.into();
+    let path = write_config("passwords", &value);
+
+    let sources = Sources::file(&path)
+        .with_vars(vec![
+            ("AUTHN__PASSWORD_HASH__MEM_COST".to_string(), "8192".to_string()),
+            ("AUTHN__PASSWORD_SALT_LEN".to_string(), "16".to_string()),
+        ]);
+    let config : server::Config = sources.load().unwrap();
+    assert_eq!(config.password_history, 3);
+    assert_eq!(config.password_hash.mem_cost, 8192);
+    assert_eq!(config.password_salt_len, 16);
+
+    let sources = Sources::file(&path)
+        .with_vars(vec![("AUTHN__PASSWORD_HISTORY".to_string(), "many".to_string())]);
+    let res = sources.load::<server::Config>();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(res, Err(LoadError::Invalid(_))));
+}
+
+#[test]
+fn expand_path() {
+    std::env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
+
+    assert_eq!(config::expand_path("%t/authn/authn.sock").unwrap(), std::path::Path::new("/run/user/1000/authn/authn.sock"));
+    assert_eq!(config::expand_path("authn-100%%.sock").unwrap(), std::path::Path::new("authn-100%.sock"));
+    assert_eq!(config::expand_path("authn.sock").unwrap(), std::path::Path::new("authn.sock"));
+    assert!(config::expand_path("%h/authn.sock").is_none());
+    assert!(config::expand_path("authn.sock%").is_none());
+}
+
+#[test]
+fn defaults() {
+    let path = write_config("defaults", &client_config());
+    let defaults = serde_json::from_value(serde_json::json!({
+        "server_path" : "/run/authn/authn.sock",
+        "timeout" : 5,
+    })).unwrap();
+
+    let config : client::Config = Sources::file(&path)
+        .with_defaults(defaults)
+        .load()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // the file wins over the defaults
+    assert_eq!(config.server_path, "authn.sock");
+    assert_eq!(config.timeout, 5);
+}
+
+#[cfg(feature = "server")]
+#[tokio::test(flavor = "multi_thread")]
+async fn container() {
+    use authn::{container, server};
+
+    let dir = std::env::temp_dir().join(format!("authn-container-{}", std::process::id()));
+    let data = dir.join("data");
+    let sources = Sources::default()
+        .with_defaults(container::defaults())
+        .with_vars(vec![
+            ("AUTHN__SERVER_NAME".to_string(), "auth.example.com".to_string()),
+            ("AUTHN__PRIV_KEY_FILE".to_string(), data.join("priv-key.pem").to_str().unwrap().to_string()),
+            ("AUTHN__PUB_KEY_FILE".to_string(), data.join("pub-key.pem").to_str().unwrap().to_string()),
+            ("AUTHN__DATABASE".to_string(), data.join("authn.sqlite3").to_str().unwrap().to_string()),
+        ]);
+    let config : server::Config = sources.load().unwrap();
+    assert_eq!(config.server_path, "/run/authn/authn.sock");
+
+    container::provision(&config).await.unwrap();
+    let pub_key = std::fs::read_to_string(&config.pub_key_file).unwrap();
+
+    // a second start keeps the keys and finds the database up to date
+    container::provision(&config).await.unwrap();
+    assert_eq!(std::fs::read_to_string(&config.pub_key_file).unwrap(), pub_key);
+    let db = authn::database::Database::new(&config.database).unwrap();
+    assert!(db.migrate().await.unwrap().is_empty());
+
+    let config : server::Config = sources.load().unwrap();
+    assert!(server::new_server(config).is_ok());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn deprecated_fields() {
+    let mut value = client_config();
+    value["log"] = serde_json::json!([{ "type" : "json" }]);
+
+    let warnings = config::migrate(&mut value);
+    assert_eq!(warnings, ["`log` is deprecated, use `log_sinks`"]);
+    assert!(value.get("log").is_none());
+    assert_eq!(value["log_sinks"], serde_json::json!([{ "type" : "json" }]));
+
+    // the new field wins
+    value["log"] = serde_json::json!([]);
+    let warnings = config::migrate(&mut value);
+    assert_eq!(warnings, ["`log` is deprecated and ignored, `log_sinks` is set"]);
+    assert_eq!(value["log_sinks"], serde_json::json!([{ "type" : "json" }]));
+
+    assert!(config::migrate(&mut value).is_empty());
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn load_deprecated() {
+    use authn::server;
+
+    let mut value = client_config();
+    value["priv_key_file"] = "priv-key.pem".into();
+    value["database"] = "authn.sqlite3".into();
+    value["log"] = serde_json::json!([{ "type" : "json" }]);
+    let path = write_config("deprecated", &value);
+
+    let config : server::Config = Sources::file(&path).load().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(config.log_sinks.len(), 1);
+}
@@ -0,0 +1,100 @@
+use authn::jwe::{self, Key};
+use ring::{aead, agreement, rand::SystemRandom};
+
+fn b64(data : &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn unb64(data : &str) -> Vec<u8> {
+    base64::decode_config(data, base64::URL_SAFE_NO_PAD).unwrap()
+}
+
+/// RFC 7518 appendix C, ECDH-ES for A128GCM between "Alice" and "Bob"
+#[test]
+fn concat_kdf_known_answer() {
+    let z = [
+        158, 86, 217, 29, 129, 113, 53, 211, 114, 131, 66, 131, 191, 132, 38, 156,
+        251, 49, 110, 163, 218, 128, 106, 72, 246, 218, 167, 121, 140, 254, 144, 196,
+    ];
+
+    let key = jwe::concat_kdf(&z, "A128GCM", b"Alice", b"Bob", 16);
+    assert_eq!(b64(&key), "VqqN6vgjbSBcIijNcacQGg");
+}
+
+/// keys longer than a SHA-256 output take more rounds, each a prefix of
+/// the next longer key
+#[test]
+fn concat_kdf_rounds() {
+    let z = [7; 32];
+
+    let long = jwe::concat_kdf(&z, "A256GCM", b"", b"", 64);
+    assert_eq!(long.len(), 64);
+    assert_ne!(long[..32], long[32..]);
+    assert_ne!(jwe::concat_kdf(&z, "A256GCM", b"", b"", 32), long[..32]);
+}
+
+#[test]
+fn decrypt_round_trip() {
+    let rng = SystemRandom::new();
+    // the audience's key, ring only agrees with ephemeral keys but this one
+    // only decrypts once
+    let private = agreement::EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).unwrap();
+    let public = private.compute_public_key().unwrap();
+    let (x, y) = public.as_ref()[1..].split_at(32);
+
+    let key : Key = serde_json::from_value(serde_json::json!({
+        "kty" : "EC",
+        "crv" : "P-256",
+        "x" : b64(x),
+        "y" : b64(y),
+        "kid" : "aud-1",
+    })).unwrap();
+
+    let token = "eyJhbGciOiJFUzI1NiJ9.eyJzdWIiOiJhbGljZSJ9.c2ln";
+    let jwe = key.encrypt(token).unwrap();
+
+    let parts = jwe.split('.').collect::<Vec<_>>();
+    assert_eq!(parts.len(), 5);
+    assert_eq!(parts[1], "", "direct key agreement has no encrypted key");
+
+    let header : serde_json::Value = serde_json::from_slice(&unb64(parts[0])).unwrap();
+    assert_eq!(header["alg"], "ECDH-ES");
+    assert_eq!(header["enc"], "A256GCM");
+    assert_eq!(header["cty"], "JWT");
+    assert_eq!(header["kid"], "aud-1");
+
+    let mut epk = vec![0x04];
+    epk.extend(unb64(header["epk"]["x"].as_str().unwrap()));
+    epk.extend(unb64(header["epk"]["y"].as_str().unwrap()));
+    let epk = agreement::UnparsedPublicKey::new(&agreement::ECDH_P256, epk);
+    let cek = agreement::agree_ephemeral(private, &epk, (), |z| Ok(jwe::concat_kdf(z, "A256GCM", b"", b"", 32))).unwrap();
+
+    let open = |aad : &[u8]| {
+        let key = aead::LessSafeKey::new(aead::UnboundKey::new(&aead::AES_256_GCM, &cek).unwrap());
+        let nonce = aead::Nonce::try_assume_unique_for_key(&unb64(parts[2])).unwrap();
+        let mut data = unb64(parts[3]);
+        data.extend(unb64(parts[4]));
+
+        key.open_in_place(nonce, aead::Aad::from(aad), &mut data)
+            .map(|plaintext| plaintext.to_vec())
+    };
+
+    assert_eq!(open(parts[0].as_bytes()).unwrap(), token.as_bytes());
+    // the header is authenticated
+    assert!(open(b"e30").is_err());
+}
+
+#[test]
+fn invalid_keys() {
+    let key = |crv : &str, x : &str| serde_json::from_value::<Key>(serde_json::json!({
+        "kty" : "EC",
+        "crv" : crv,
+        "x" : x,
+        "y" : b64(&[1; 32]),
+    }));
+
+    assert!(key("P-384", &b64(&[1; 32])).is_err());
+    assert!(key("P-256", &b64(&[1; 31])).is_err());
+    // not on the curve
+    assert!(key("P-256", &b64(&[1; 32])).is_err());
+}
@@ -0,0 +1,73 @@
+#![cfg(feature = "server")]
+
+use std::time::Duration;
+
+use authn::metrics::{LatencyConfig, RollingQuantiles};
+
+#[test]
+fn rolling_quantiles() {
+    let latency = RollingQuantiles::new(&LatencyConfig::default());
+    assert_eq!(latency.quantiles().count, 0);
+
+    for ms in 1..=100 {
+        assert!(latency.observe(Duration::from_millis(ms)).is_none());
+    }
+
+    let q = latency.quantiles();
+    assert_eq!(q.count, 100);
+    assert_eq!(q.p50, Duration::from_millis(50));
+    assert_eq!(q.p95, Duration::from_millis(95));
+    assert_eq!(q.p99, Duration::from_millis(99));
+
+    let mut out = String::new();
+    latency.render("authn_login_latency_seconds", "Login latency.", &mut out);
+    assert!(out.contains("authn_login_latency_seconds{quantile=\"0.95\"} 0.095\n"));
+    assert!(out.contains("authn_login_latency_seconds_count 100\n"));
+}
+
+#[test]
+fn rolling_quantiles_warn() {
+    let latency = RollingQuantiles::new(&LatencyConfig{
+        window : 60,
+        warn_p95 : Some(10),
+    });
+
+    for _ in 0..10 {
+        assert!(latency.observe(Duration::from_millis(1)).is_none());
+    }
+
+    // one slow sample in eleven is past the p95
+    let q = latency.observe(Duration::from_millis(50)).unwrap();
+    assert_eq!(q.p95, Duration::from_millis(50));
+
+    // reported once per window
+    assert!(latency.observe(Duration::from_millis(50)).is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_query() {
+    use authn::database::Database;
+    use authn::logging::{self, SinkConfig};
+
+    let dir = std::env::temp_dir().join(format!("authn-slow-query-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("authn.log");
+    logging::init(&[SinkConfig::File{
+        path : log.clone(),
+        max_size : None,
+        max_age : None,
+        keep : 0,
+    }]).unwrap();
+
+    let db = Database::new(dir.join("authn.sqlite3").to_str().unwrap()).unwrap()
+        .with_slow_query(Duration::ZERO);
+    db.migrate().await.unwrap();
+
+    let line = std::fs::read_to_string(&log).unwrap();
+    let line : serde_json::Value = serde_json::from_str(line.lines().next().unwrap()).unwrap();
+    assert_eq!(line["level"], "error");
+    assert_eq!(line["method"], "migrate");
+    assert!(line["msg"].as_str().unwrap().starts_with("slow database method migrate took "));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
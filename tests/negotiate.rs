@@ -0,0 +1,105 @@
+// the test server listens on a unix socket
+#![cfg(unix)]
+
+use authn::negotiate::{Config, Negotiator};
+use authn::testing::TestServer;
+use hyperlocal::UnixClientExt;
+
+fn config(library : &str, keytab : Option<&std::path::Path>) -> Config {
+    serde_json::from_value(serde_json::json!({
+        "realm" : "EXAMPLE.COM",
+        "library" : library,
+        "keytab" : keytab,
+    })).unwrap()
+}
+
+/// writes a keytab with a made up key for `HTTP/localhost@EXAMPLE.COM`,
+/// enough for the library to get as far as reading tokens without a KDC
+fn write_keytab(path : &std::path::Path) {
+    fn counted(buf : &mut Vec<u8>, data : &[u8]) {
+        buf.extend((data.len() as u16).to_be_bytes());
+        buf.extend(data);
+    }
+
+    let mut entry = Vec::new();
+    entry.extend(2u16.to_be_bytes());
+    counted(&mut entry, b"EXAMPLE.COM");
+    counted(&mut entry, b"HTTP");
+    counted(&mut entry, b"localhost");
+    // KRB5_NT_PRINCIPAL, timestamp, key version
+    entry.extend(1u32.to_be_bytes());
+    entry.extend(0u32.to_be_bytes());
+    entry.push(1);
+    // aes256-cts-hmac-sha1-96
+    entry.extend(18u16.to_be_bytes());
+    counted(&mut entry, &[7; 32]);
+
+    let mut keytab = vec![0x05, 0x02];
+    keytab.extend((entry.len() as i32).to_be_bytes());
+    keytab.extend(entry);
+    std::fs::write(path, keytab).unwrap();
+}
+
+/// the host's GSSAPI library with the keytab of `write_keytab`, `None` if
+/// the library isn't installed
+fn negotiator() -> Option<Negotiator> {
+    let keytab = std::env::temp_dir().join(format!("authn-test-{}.keytab", std::process::id()));
+    write_keytab(&keytab);
+
+    Negotiator::new(config("libgssapi_krb5.so.2", Some(&keytab))).ok()
+}
+
+#[test]
+fn missing_library() {
+    let err = Negotiator::new(config("libgssapi-missing.so", None)).err().unwrap();
+    assert_eq!(err.code(), "config.gssapi");
+}
+
+/// framed as a kerberos token, but holding no ticket
+const NOT_A_TICKET : [u8; 15] = [0x60, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x02, 0x01, 0x00];
+
+/// tokens which aren't kerberos tickets are refused by the library without
+/// a KDC or replay cache
+#[test]
+fn malformed_tokens() {
+    let negotiator = match negotiator() {
+        Some(negotiator) => negotiator,
+        None => return,
+    };
+
+    // the OID of the mechanism is missing
+    assert_eq!(negotiator.accept(&[0x60, 0x02, 0x06, 0x00]).err().unwrap().code(), "transport.bad_request");
+    assert_eq!(negotiator.accept(&NOT_A_TICKET).err().unwrap().code(), "auth.login_failed");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn login() {
+    if negotiator().is_none() {
+        return
+    }
+
+    let server = TestServer::with_config(serde_json::json!({
+        "negotiate" : { "realm" : "EXAMPLE.COM" },
+    }), |server| server).await.unwrap();
+
+    let login = |authorization : Option<&str>, body : &str| {
+        let mut req = hyper::Request::builder()
+            .method("POST")
+            .uri(hyperlocal::Uri::new(server.path(), "/login"));
+        if let Some(authorization) = authorization {
+            req = req.header("authorization", authorization);
+        }
+        hyper::Client::unix().request(req.body(body.to_string().into()).unwrap())
+    };
+    let body = r#"{"aud":"example.com","duration":60}"#;
+
+    // a body without credentials gets the challenge
+    let res = login(None, "").await.unwrap();
+    assert_eq!(res.status(), 401);
+    assert_eq!(res.headers()["www-authenticate"], "Negotiate");
+
+    assert_eq!(login(Some("Negotiate !!!"), body).await.unwrap().status(), 400);
+    assert_eq!(login(Some("Negotiate bm90IGEgdGlja2V0"), body).await.unwrap().status(), 400);
+    let authorization = format!("Negotiate {}", base64::encode(NOT_A_TICKET));
+    assert_eq!(login(Some(&authorization), body).await.unwrap().status(), 401);
+}
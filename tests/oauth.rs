@@ -0,0 +1,323 @@
+// the test server listens on a unix socket
+#![cfg(unix)]
+
+use std::time::Duration;
+
+use authn::testing::TestServer;
+use hyperlocal::UnixClientExt;
+
+const CLIENT : &str = "app";
+const REDIRECT_URI : &str = "https://app.example.com/callback";
+
+async fn oauth_server() -> TestServer {
+    let server = TestServer::with_config(serde_json::json!({
+        "oauth" : {
+            "clients" : [{ "client_id" : CLIENT, "name" : "The App", "redirect_uris" : [REDIRECT_URI] }],
+            "verification_uri" : "https://authn.example.com/device",
+            "device_poll_interval" : 0,
+            "token_exchange" : [{ "from" : CLIENT, "to" : ["api"] }],
+        },
+    }), |server| server).await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+
+    server
+}
+
+struct Page {
+    status : hyper::StatusCode,
+    /// the `name=value` of the cookie set, if any
+    cookie : Option<String>,
+    location : Option<String>,
+    body : String,
+}
+
+impl Page {
+    /// the value of the hidden field `name`
+    fn field(&self, name : &str) -> Option<&str> {
+        let start = format!(r#"name="{}" value=""#, name);
+        let start = self.body.find(&start)? + start.len();
+        let len = self.body[start..].find('"')?;
+
+        Some(&self.body[start..start + len])
+    }
+
+    /// the query parameter `name` of the redirect
+    fn redirect_param(&self, name : &str) -> Option<String> {
+        let (_, query) = self.location.as_ref()?.split_once('?')?;
+        serde_urlencoded::from_str::<Vec<(String, String)>>(query).ok()?
+            .into_iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+async fn request(server : &TestServer, method : &str, path : &str, form : &[(&str, &str)], cookie : Option<&str>) -> Page {
+    let form = serde_urlencoded::to_string(form).unwrap();
+    let (path, body) = match method {
+        "GET" => (format!("{}?{}", path, form), String::new()),
+        _ => (path.to_string(), form),
+    };
+
+    let mut req = hyper::Request::builder()
+        .method(method)
+        .uri(hyperlocal::Uri::new(server.path(), &path))
+        .header("content-type", "application/x-www-form-urlencoded");
+    if let Some(cookie) = cookie {
+        req = req.header("cookie", cookie);
+    }
+
+    let res = hyper::Client::unix().request(req.body(body.into()).unwrap()).await.unwrap();
+    let status = res.status();
+    let header = |name| res.headers().get(name).map(|value : &http::HeaderValue| value.to_str().unwrap().to_string());
+    let cookie = header("set-cookie").map(|cookie| cookie.split(';').next().unwrap().to_string());
+    let location = header("location");
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+    Page{ status, cookie, location, body : String::from_utf8(body.to_vec()).unwrap() }
+}
+
+/// the S256 challenge of `verifier`
+fn challenge(verifier : &str) -> String {
+    base64::encode_config(ring::digest::digest(&ring::digest::SHA256, verifier.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+const VERIFIER : &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+/// logs in as alice on the authorize page of `params`, returning the
+/// redirect
+async fn authorize(server : &TestServer, params : &[(&str, &str)]) -> Page {
+    let page = request(server, "GET", "/authorize", params, None).await;
+    assert_eq!(page.status, 200);
+    assert!(page.body.contains("The App"));
+
+    let mut form = params.to_vec();
+    form.extend([("csrf", page.field("csrf").unwrap()), ("name", "alice"), ("pass", "hunter2")]);
+
+    request(server, "POST", "/authorize", &form, page.cookie.as_deref()).await
+}
+
+async fn token(server : &TestServer, form : &[(&str, &str)]) -> Result<String, String> {
+    let page = request(server, "POST", "/token", form, None).await;
+    let body : serde_json::Value = serde_json::from_str(&page.body).unwrap();
+
+    match page.status.as_u16() {
+        200 => Ok(body["access_token"].as_str().unwrap().to_string()),
+        _ => Err(body["error"].as_str().unwrap().to_string()),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn code_grant() {
+    let server = oauth_server().await;
+    let challenge = challenge(VERIFIER);
+
+    let params = [
+        ("response_type", "code"),
+        ("client_id", CLIENT),
+        ("redirect_uri", REDIRECT_URI),
+        ("code_challenge", &challenge),
+        ("code_challenge_method", "S256"),
+        ("state", "xyz"),
+    ];
+    let page = authorize(&server, &params).await;
+    assert_eq!(page.status, 303);
+    assert!(page.location.as_ref().unwrap().starts_with(REDIRECT_URI));
+    assert_eq!(page.redirect_param("state").as_deref(), Some("xyz"));
+    let code = page.redirect_param("code").unwrap();
+
+    // the redirect uri of the authorization request has to be repeated
+    let res = token(&server, &[
+        ("grant_type", "authorization_code"),
+        ("client_id", CLIENT),
+        ("code", &code),
+        ("code_verifier", VERIFIER),
+    ]).await;
+    assert_eq!(res.unwrap_err(), "invalid_grant");
+
+    let page = authorize(&server, &params).await;
+    let code = page.redirect_param("code").unwrap();
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("client_id", CLIENT),
+        ("code", code.as_str()),
+        ("code_verifier", VERIFIER),
+        ("redirect_uri", REDIRECT_URI),
+    ];
+    let access_token = token(&server, &form).await.unwrap();
+    assert_eq!(server.client(CLIENT).validate_token(&access_token).await.unwrap(), "alice");
+
+    // codes are used once
+    assert_eq!(token(&server, &form).await.unwrap_err(), "invalid_grant");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pkce() {
+    let server = oauth_server().await;
+    let challenge = challenge(VERIFIER);
+
+    // plain challenges are refused
+    let page = request(&server, "GET", "/authorize", &[
+        ("response_type", "code"),
+        ("client_id", CLIENT),
+        ("code_challenge", VERIFIER),
+        ("code_challenge_method", "plain"),
+    ], None).await;
+    assert_eq!(page.status, 303);
+    assert_eq!(page.redirect_param("error").as_deref(), Some("invalid_request"));
+
+    // the redirect uri can be left out for a client with only one
+    let params = [
+        ("response_type", "code"),
+        ("client_id", CLIENT),
+        ("code_challenge", &challenge),
+        ("code_challenge_method", "S256"),
+    ];
+
+    let code = authorize(&server, &params).await.redirect_param("code").unwrap();
+    let other = "a".repeat(43);
+    let res = token(&server, &[
+        ("grant_type", "authorization_code"),
+        ("client_id", CLIENT),
+        ("code", &code),
+        ("code_verifier", &other),
+    ]).await;
+    assert_eq!(res.unwrap_err(), "invalid_grant");
+
+    let code = authorize(&server, &params).await.redirect_param("code").unwrap();
+    let res = token(&server, &[
+        ("grant_type", "authorization_code"),
+        ("client_id", CLIENT),
+        ("code", &code),
+        ("code_verifier", VERIFIER),
+    ]).await;
+    assert!(res.is_ok());
+
+    // the code of a user deleted since is worthless
+    let code = authorize(&server, &params).await.redirect_param("code").unwrap();
+    let conn = rusqlite::Connection::open(server.dir().join("authn.sqlite3")).unwrap();
+    conn.execute_batch("PRAGMA foreign_keys = ON; DELETE FROM users WHERE name = 'alice';").unwrap();
+    let res = token(&server, &[
+        ("grant_type", "authorization_code"),
+        ("client_id", CLIENT),
+        ("code", &code),
+        ("code_verifier", VERIFIER),
+    ]).await;
+    assert_eq!(res.unwrap_err(), "invalid_grant");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn authorize_csrf() {
+    let server = oauth_server().await;
+    let challenge = challenge(VERIFIER);
+
+    let params = [
+        ("response_type", "code"),
+        ("client_id", CLIENT),
+        ("code_challenge", challenge.as_str()),
+        ("code_challenge_method", "S256"),
+    ];
+    let page = request(&server, "GET", "/authorize", &params, None).await;
+    let csrf = page.field("csrf").unwrap().to_string();
+
+    let mut form = params.to_vec();
+    form.extend([("csrf", csrf.as_str()), ("name", "alice"), ("pass", "hunter2")]);
+
+    let page = request(&server, "POST", "/authorize", &form, None).await;
+    assert_eq!(page.status, 403);
+    assert!(page.location.is_none());
+    let page = request(&server, "POST", "/authorize", &form, Some("authn_csrf=other")).await;
+    assert_eq!(page.status, 403);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn device_flow() {
+    let server = oauth_server().await;
+
+    let page = request(&server, "POST", "/device_authorization", &[("client_id", CLIENT)], None).await;
+    assert_eq!(page.status, 200);
+    let auth : serde_json::Value = serde_json::from_str(&page.body).unwrap();
+    let device_code = auth["device_code"].as_str().unwrap();
+    let user_code = auth["user_code"].as_str().unwrap();
+    assert!(auth["verification_uri_complete"].as_str().unwrap().ends_with(user_code));
+
+    let poll = [
+        ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+        ("client_id", CLIENT),
+        ("device_code", device_code),
+    ];
+    assert_eq!(token(&server, &poll).await.unwrap_err(), "authorization_pending");
+
+    // the code is asked for first, the login form only comes with the
+    // client it lets in
+    let page = request(&server, "GET", "/device", &[], None).await;
+    assert_eq!(page.status, 200);
+    assert!(!page.body.contains(r#"name="pass""#));
+
+    let page = request(&server, "GET", "/device", &[("user_code", "BBBB-BBBB")], None).await;
+    assert_eq!(page.status, 400);
+    assert!(!page.body.contains(r#"name="pass""#));
+
+    let page = request(&server, "GET", "/device", &[("user_code", &user_code.to_lowercase())], None).await;
+    assert_eq!(page.status, 200);
+    assert!(page.body.contains("<b>The App</b>"));
+    assert!(page.body.contains(user_code));
+    assert!(page.body.contains(r#"name="pass""#));
+
+    let csrf = page.field("csrf").unwrap();
+    let form = [("user_code", user_code), ("csrf", csrf), ("name", "alice"), ("pass", "hunter2")];
+    assert_eq!(request(&server, "POST", "/device", &form, None).await.status, 403);
+    assert_eq!(token(&server, &poll).await.unwrap_err(), "authorization_pending");
+
+    let page = request(&server, "POST", "/device", &form, page.cookie.as_deref()).await;
+    assert_eq!(page.status, 200);
+    assert!(page.body.contains("The App"));
+
+    let access_token = token(&server, &poll).await.unwrap();
+    assert_eq!(server.client(CLIENT).validate_token(&access_token).await.unwrap(), "alice");
+    // device codes are exchanged once
+    assert_eq!(token(&server, &poll).await.unwrap_err(), "invalid_grant");
+}
+
+async fn exchange(server : &TestServer, subject_token : &str, subject_token_type : &str, audience : &str) -> Page {
+    request(server, "POST", "/token", &[
+        ("grant_type", "urn:ietf:params:oauth:grant-type:token-exchange"),
+        ("subject_token", subject_token),
+        ("subject_token_type", subject_token_type),
+        ("audience", audience),
+    ], None).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn token_exchange() {
+    let server = oauth_server().await;
+    let subject_token = server.client(CLIENT).login("alice", "hunter2", Duration::from_secs(60)).await.unwrap();
+
+    let jwt = "urn:ietf:params:oauth:token-type:jwt";
+
+    let page = exchange(&server, &subject_token, jwt, "api").await;
+    assert_eq!(page.status, 200);
+    let body : serde_json::Value = serde_json::from_str(&page.body).unwrap();
+    assert_eq!(body["issued_token_type"], jwt);
+    // no later than the exchanged token
+    assert!(body["expires_in"].as_u64().unwrap() <= 60);
+
+    let api = server.client("api");
+    let claims = api.validate_token_claims(body["access_token"].as_str().unwrap()).await.unwrap();
+    assert_eq!(claims.sub, "alice");
+    assert_eq!(claims.aud, "api");
+    assert!(claims.auth_time.is_some());
+
+    let error = |page : Page| {
+        assert_eq!(page.status, 400);
+        serde_json::from_str::<serde_json::Value>(&page.body).unwrap()["error"].as_str().unwrap().to_string()
+    };
+
+    // only audiences a rule allows
+    assert_eq!(error(exchange(&server, &subject_token, jwt, "admin").await), "invalid_target");
+    assert_eq!(error(exchange(&server, &subject_token, "urn:ietf:params:oauth:token-type:saml2", "api").await), "invalid_request");
+    assert_eq!(error(exchange(&server, "not.a.token", jwt, "api").await), "invalid_grant");
+
+    // nor tokens logged out of
+    server.client(CLIENT).logout(&subject_token).await.unwrap();
+    assert_eq!(error(exchange(&server, &subject_token, jwt, "api").await), "invalid_grant");
+}
@@ -0,0 +1,100 @@
+// the test server listens on a unix socket
+#![cfg(unix)]
+
+use std::time::Duration;
+
+use authn::client;
+use authn::crypto;
+use authn::pam::{self, Pam};
+use authn::testing::{TestServer, SERVER_NAME};
+use hyperlocal::UnixClientExt;
+
+/// a service without a file in `/etc/pam.d`, so the host's `other` stack,
+/// which refuses users the host doesn't have
+const SERVICE : &str = "authn-test-missing";
+
+fn config(library : &str) -> serde_json::Value {
+    serde_json::json!({
+        "service" : SERVICE,
+        "library" : library,
+        "create_users" : true,
+    })
+}
+
+/// whether the host's libpam loads
+fn has_libpam() -> bool {
+    Pam::new(serde_json::from_value(config("libpam.so.0")).unwrap()).is_ok()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missing_library() {
+    let err = TestServer::with_config(serde_json::json!({ "pam" : config("libpam-missing.so") }), |server| server)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.code(), "config.pam");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn login() {
+    if !has_libpam() {
+        return
+    }
+
+    let server = TestServer::with_config(serde_json::json!({ "pam" : config("libpam.so.0") }), |server| server)
+        .await
+        .unwrap();
+
+    // PAM checks the password, not the hash in the database
+    server.add_user("alice", "hunter2").await.unwrap();
+    let client = server.client("example.com");
+    let res = client.login("alice", "hunter2", Duration::from_secs(60)).await;
+    assert!(matches!(res, Err(client::Error::Api(e)) if e == "login failed"));
+
+    // users PAM refuses aren't created
+    let res = client.login("authn-test-nobody", "hunter2", Duration::from_secs(60)).await;
+    assert!(matches!(res, Err(client::Error::Api(e)) if e == "login failed"));
+    assert!(server.database().get_user_by_name("authn-test-nobody").await.is_err());
+}
+
+/// break glass accounts skip PAM, and the hash of users created by PAM
+/// logins neither gets rehashed nor counts in the password history
+#[tokio::test(flavor = "multi_thread")]
+async fn password_hashes() {
+    use authn::api::PostAdminPasswordRequest;
+
+    if !has_libpam() {
+        return
+    }
+
+    let server = TestServer::with_config(serde_json::json!({ "pam" : config("libpam.so.0") }), |server| server)
+        .await
+        .unwrap();
+
+    let hash = crypto::encode_password(b"hunter2").unwrap();
+    server.database().arm_break_glass("root", &hash, "admin", 0, false).await.unwrap();
+    server.database().insert_user("alice", pam::PASS_HASH).await.unwrap();
+
+    let client = server.client(SERVER_NAME);
+    let token = client.login("root", "hunter2", Duration::from_secs(60)).await.unwrap();
+
+    let reset = |pass : &str| {
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri(hyperlocal::Uri::new(server.path(), "/admin/users/alice/password"))
+            .header("authorization", format!("Bearer {}", token))
+            .body(serde_json::to_string(&PostAdminPasswordRequest::new(pass)).unwrap().into())
+            .unwrap();
+        async move { hyper::Client::unix().request(req).await.unwrap().status() }
+    };
+
+    assert_eq!(reset("correct horse battery").await, 204);
+    // only the password set over the api is in the history
+    assert_eq!(reset("correct horse battery").await, 400);
+
+    // but PAM still checks alice's password, and the new hash is left as is
+    let user = server.database().get_user_by_name("alice").await.unwrap();
+    let res = client.login("alice", "correct horse battery", Duration::from_secs(60)).await;
+    assert!(matches!(res, Err(client::Error::Api(e)) if e == "login failed"));
+    assert_eq!(server.database().get_user_by_name("alice").await.unwrap().pass_hash, user.pass_hash);
+}
@@ -0,0 +1,68 @@
+use authn::risk::{Config, Network, Risk, Verdict, MAX_SCORE};
+
+fn network(s : &str) -> Network {
+    s.parse().unwrap()
+}
+
+#[test]
+fn network_contains() {
+    let cases = [
+        ("192.0.2.0/24", "192.0.2.0", true),
+        ("192.0.2.0/24", "192.0.2.255", true),
+        ("192.0.2.0/24", "192.0.3.0", false),
+        ("192.0.2.7", "192.0.2.7", true),
+        ("192.0.2.7", "192.0.2.8", false),
+        ("0.0.0.0/0", "203.0.113.7", true),
+        ("10.0.0.0/9", "10.127.255.255", true),
+        ("10.0.0.0/9", "10.128.0.0", false),
+        ("2001:db8::/32", "2001:db8:ffff::1", true),
+        ("2001:db8::/32", "2001:db9::1", false),
+        ("::/0", "2001:db8::1", true),
+        ("2001:db8::1", "2001:db8::1", true),
+        ("2001:db8::1", "2001:db8::2", false),
+        // families never match
+        ("0.0.0.0/0", "::ffff:192.0.2.1", false),
+        ("::/0", "192.0.2.1", false),
+    ];
+
+    for (net, addr, contains) in cases.iter() {
+        assert_eq!(network(net).contains(addr.parse().unwrap()), *contains, "{} {}", net, addr);
+    }
+}
+
+#[test]
+fn network_parse() {
+    assert_eq!(network("192.0.2.7").to_string(), "192.0.2.7/32");
+    assert_eq!(network("2001:db8::/32").to_string(), "2001:db8::/32");
+
+    for s in ["192.0.2.0/33", "2001:db8::/129", "192.0.2.0/", "192.0.2/24", "example.com"].iter() {
+        assert!(s.parse::<Network>().is_err(), "{}", s);
+    }
+}
+
+#[test]
+fn verdict() {
+    let config = Config{
+        step_up_score : 50,
+        block_score : 80,
+        ..Config::default()
+    };
+    let verdict = |score| config.verdict(&Risk{ score, reasons : vec![] });
+
+    assert_eq!(verdict(0), Verdict::Allow);
+    assert_eq!(verdict(49), Verdict::Allow);
+    assert_eq!(verdict(50), Verdict::StepUp);
+    assert_eq!(verdict(79), Verdict::StepUp);
+    assert_eq!(verdict(80), Verdict::Block);
+    assert_eq!(verdict(MAX_SCORE), Verdict::Block);
+}
+
+#[test]
+fn risk_caps_score() {
+    let mut risk = Risk::default();
+    risk.add(60, "a".to_string());
+    risk.add(60, "b".to_string());
+
+    assert_eq!(risk.score, MAX_SCORE);
+    assert_eq!(risk.reasons, ["a", "b"]);
+}
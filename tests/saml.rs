@@ -0,0 +1,267 @@
+// the test server listens on a unix socket
+#![cfg(unix)]
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use authn::testing::TestServer;
+use hyperlocal::UnixClientExt;
+
+const SP : &str = "https://sp.example.com";
+const ACS_URL : &str = "https://sp.example.com/acs";
+
+async fn saml_server() -> TestServer {
+    let server = TestServer::with_config(serde_json::json!({
+        "saml" : {
+            "entity_id" : "https://idp.example.com",
+            "sso_url" : "https://idp.example.com/saml/sso",
+            "service_providers" : [{ "entity_id" : SP, "acs_url" : ACS_URL }],
+        },
+    }), |server| server).await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+
+    server
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+/// a unix timestamp as an xml `dateTime` in utc
+fn xml_time(unix : i64) -> String {
+    let (days, secs) = (unix.div_euclid(86400), unix.rem_euclid(86400));
+
+    // from Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + z.div_euclid(146097) * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// a base64 encoded `AuthnRequest` of `SP`
+fn authn_request(id : &str, issue_instant : i64) -> String {
+    base64::encode(format!(
+        concat!(
+            r#"<samlp:AuthnRequest xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion" "#,
+            r#"ID="{}" Version="2.0" IssueInstant="{}" AssertionConsumerServiceURL="{}">"#,
+            r#"<saml:Issuer>{}</saml:Issuer>"#,
+            r#"</samlp:AuthnRequest>"#,
+        ),
+        id, xml_time(issue_instant), ACS_URL, SP,
+    ))
+}
+
+struct Page {
+    status : hyper::StatusCode,
+    /// the `name=value` of the cookie set, if any
+    cookie : Option<String>,
+    body : String,
+}
+
+impl Page {
+    /// the value of the hidden field `name`
+    fn field(&self, name : &str) -> Option<&str> {
+        let start = format!(r#"name="{}" value=""#, name);
+        let start = self.body.find(&start)? + start.len();
+        let len = self.body[start..].find('"')?;
+
+        Some(&self.body[start..start + len])
+    }
+}
+
+async fn post(server : &TestServer, path : &str, form : &[(&str, &str)], cookie : Option<&str>) -> Page {
+    let mut req = hyper::Request::builder()
+        .method("POST")
+        .uri(hyperlocal::Uri::new(server.path(), path))
+        .header("content-type", "application/x-www-form-urlencoded");
+    if let Some(cookie) = cookie {
+        req = req.header("cookie", cookie);
+    }
+    let req = req.body(serde_urlencoded::to_string(form).unwrap().into()).unwrap();
+
+    let res = hyper::Client::unix().request(req).await.unwrap();
+    let status = res.status();
+    let cookie = res.headers().get("set-cookie")
+        .map(|value| value.to_str().unwrap().split(';').next().unwrap().to_string());
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+    Page{ status, cookie, body : String::from_utf8(body.to_vec()).unwrap() }
+}
+
+/// posts `request` to the sso endpoint and logs in as alice on the page
+/// it returns
+async fn login(server : &TestServer, request : &str) -> Page {
+    let page = post(server, "/saml/sso", &[("SAMLRequest", request), ("RelayState", "state")], None).await;
+    assert_eq!(page.status, 200);
+    let csrf = page.field("csrf").unwrap();
+
+    post(server, "/saml/login", &[
+        ("SAMLRequest", request),
+        ("RelayState", "state"),
+        ("csrf", csrf),
+        ("name", "alice"),
+        ("pass", "hunter2"),
+    ], page.cookie.as_deref()).await
+}
+
+/// Verifies signatures with xmlsec, as a service provider would, trusting
+/// only the test server's certificate. The library is loaded at runtime,
+/// like the server loads gssapi and pam.
+struct XmlSec {
+    xml : libloading::Library,
+    xmlsec : libloading::Library,
+    openssl : libloading::Library,
+}
+
+type Ptr = *mut libc::c_void;
+
+impl XmlSec {
+    /// `None` if the libraries aren't installed
+    fn load() -> Option<Self> {
+        unsafe {
+            let xmlsec = Self{
+                xml : libloading::Library::new("libxml2.so.2").ok()?,
+                xmlsec : libloading::Library::new("libxmlsec1.so.1").ok()?,
+                openssl : libloading::Library::new("libxmlsec1-openssl.so.1").ok()?,
+            };
+
+            let init = xmlsec.xml.get::<unsafe extern "C" fn()>(b"xmlInitParser").unwrap();
+            init();
+            let init = xmlsec.xmlsec.get::<unsafe extern "C" fn() -> libc::c_int>(b"xmlSecInit").unwrap();
+            assert_eq!(init(), 0);
+            let init = xmlsec.openssl.get::<unsafe extern "C" fn(*const libc::c_char) -> libc::c_int>(b"xmlSecOpenSSLAppInit").unwrap();
+            assert_eq!(init(std::ptr::null()), 0);
+            let init = xmlsec.openssl.get::<unsafe extern "C" fn() -> libc::c_int>(b"xmlSecOpenSSLInit").unwrap();
+            assert_eq!(init(), 0);
+
+            Some(xmlsec)
+        }
+    }
+
+    /// whether the enveloped signature of `response` verifies with the
+    /// certificate in `cert_file`, the status is read from the context's
+    /// debug dump so no struct layouts are assumed
+    fn verify(&self, response : &str, cert_file : &std::path::Path, dump_file : &std::path::Path) -> bool {
+        use std::ffi::CString;
+
+        unsafe {
+            let mngr = self.xmlsec.get::<unsafe extern "C" fn() -> Ptr>(b"xmlSecKeysMngrCreate").unwrap()();
+            assert!(!mngr.is_null());
+            let init = self.openssl.get::<unsafe extern "C" fn(Ptr) -> libc::c_int>(b"xmlSecOpenSSLAppDefaultKeysMngrInit").unwrap();
+            assert_eq!(init(mngr), 0);
+
+            // xmlSecKeyDataFormatPem, xmlSecKeyDataTypeTrusted
+            let cert_file = CString::new(cert_file.to_str().unwrap()).unwrap();
+            let load = self.openssl.get::<unsafe extern "C" fn(Ptr, *const libc::c_char, libc::c_int, libc::c_uint) -> libc::c_int>(b"xmlSecOpenSSLAppKeysMngrCertLoad").unwrap();
+            assert_eq!(load(mngr, cert_file.as_ptr(), 2, 0x0100), 0);
+
+            let read = self.xml.get::<unsafe extern "C" fn(*const libc::c_char, libc::c_int, *const libc::c_char, *const libc::c_char, libc::c_int) -> Ptr>(b"xmlReadMemory").unwrap();
+            let doc = read(response.as_ptr() as *const _, response.len() as libc::c_int, std::ptr::null(), std::ptr::null(), 0);
+            assert!(!doc.is_null());
+            let root = self.xml.get::<unsafe extern "C" fn(Ptr) -> Ptr>(b"xmlDocGetRootElement").unwrap()(doc);
+
+            // the reference is to the assertion's `ID`, which isn't an id
+            // without a schema
+            let ids = [b"ID\0".as_ptr(), std::ptr::null()];
+            self.xmlsec.get::<unsafe extern "C" fn(Ptr, Ptr, *const *const u8)>(b"xmlSecAddIDs").unwrap()(doc, root, ids.as_ptr());
+
+            let find = self.xmlsec.get::<unsafe extern "C" fn(Ptr, *const u8, *const u8) -> Ptr>(b"xmlSecFindNode").unwrap();
+            let signature = find(root, b"Signature\0".as_ptr(), b"http://www.w3.org/2000/09/xmldsig#\0".as_ptr());
+            assert!(!signature.is_null());
+
+            let ctx = self.xmlsec.get::<unsafe extern "C" fn(Ptr) -> Ptr>(b"xmlSecDSigCtxCreate").unwrap()(mngr);
+            let res = self.xmlsec.get::<unsafe extern "C" fn(Ptr, Ptr) -> libc::c_int>(b"xmlSecDSigCtxVerify").unwrap()(ctx, signature);
+
+            let dump_path = CString::new(dump_file.to_str().unwrap()).unwrap();
+            let dump = libc::fopen(dump_path.as_ptr(), b"w\0".as_ptr() as *const _);
+            self.xmlsec.get::<unsafe extern "C" fn(Ptr, *mut libc::FILE)>(b"xmlSecDSigCtxDebugDump").unwrap()(ctx, dump);
+            libc::fclose(dump);
+
+            self.xmlsec.get::<unsafe extern "C" fn(Ptr)>(b"xmlSecDSigCtxDestroy").unwrap()(ctx);
+            self.xml.get::<unsafe extern "C" fn(Ptr)>(b"xmlFreeDoc").unwrap()(doc);
+            self.xmlsec.get::<unsafe extern "C" fn(Ptr)>(b"xmlSecKeysMngrDestroy").unwrap()(mngr);
+
+            res == 0 && std::fs::read_to_string(dump_file).unwrap().contains("== Status: succeeded")
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn signed_response() {
+    let server = saml_server().await;
+
+    let page = login(&server, &authn_request("_request", now())).await;
+    assert_eq!(page.status, 200);
+    assert_eq!(page.field("RelayState"), Some("state"));
+    let response = String::from_utf8(base64::decode(page.field("SAMLResponse").unwrap()).unwrap()).unwrap();
+    assert!(response.contains(r#"InResponseTo="_request""#));
+    assert!(response.contains(">alice</saml:NameID>"));
+
+    let xmlsec = match XmlSec::load() {
+        Some(xmlsec) => xmlsec,
+        None => {
+            eprintln!("xmlsec isn't installed, the signature isn't verified");
+            return
+        },
+    };
+
+    let cert = server.dir().join("cert.pem");
+    let dump = server.dir().join("dsig.txt");
+    assert!(xmlsec.verify(&response, &cert, &dump));
+
+    let forged = response.replace(">alice</saml:NameID>", ">mallory</saml:NameID>");
+    assert!(!xmlsec.verify(&forged, &cert, &dump));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn request_replay() {
+    let server = saml_server().await;
+
+    let request = authn_request("_request", now());
+    assert_eq!(login(&server, &request).await.status, 200);
+    // the same request isn't answered twice
+    assert_eq!(login(&server, &request).await.status, 400);
+    assert_eq!(login(&server, &authn_request("_other", now())).await.status, 200);
+
+    // nor once it's stale, or from too far in the future
+    let stale = authn_request("_stale", now() - 60 * 60);
+    assert_eq!(post(&server, "/saml/sso", &[("SAMLRequest", &stale)], None).await.status, 400);
+    let future = authn_request("_future", now() + 60 * 60);
+    assert_eq!(post(&server, "/saml/sso", &[("SAMLRequest", &future)], None).await.status, 400);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn login_csrf() {
+    let server = saml_server().await;
+
+    let request = authn_request("_request", now());
+    let page = post(&server, "/saml/sso", &[("SAMLRequest", &request)], None).await;
+    let csrf = page.field("csrf").unwrap().to_string();
+    let cookie = page.cookie.unwrap();
+    assert!(cookie.starts_with("authn_csrf="));
+
+    let form = |csrf| vec![
+        ("SAMLRequest", request.as_str()),
+        ("csrf", csrf),
+        ("name", "alice"),
+        ("pass", "hunter2"),
+    ];
+
+    // a form posted from another site has no cookie, or not its own
+    let page = post(&server, "/saml/login", &form(&csrf), None).await;
+    assert_eq!(page.status, 403);
+    assert!(page.field("SAMLResponse").is_none());
+    assert!(page.field("csrf").is_some());
+    let other = format!("authn_csrf={}", page.field("csrf").unwrap());
+    assert_eq!(post(&server, "/saml/login", &form(&csrf), Some(&other)).await.status, 403);
+    assert_eq!(post(&server, "/saml/login", &form(""), Some("authn_csrf=")).await.status, 403);
+
+    let page = post(&server, "/saml/login", &form(&csrf), Some(&cookie)).await;
+    assert_eq!(page.status, 200);
+    assert!(page.field("SAMLResponse").is_some());
+}
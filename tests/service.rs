@@ -0,0 +1,53 @@
+// systemd's notify socket is a unix socket
+#![cfg(unix)]
+
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use authn::service;
+use authn::testing::TestServer;
+
+fn recv(socket : &UnixDatagram) -> String {
+    let mut buf = [0; 64];
+    let n = socket.recv(&mut buf).unwrap();
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+// the variables are shared by the whole process, so this is one test
+#[tokio::test(flavor = "multi_thread")]
+async fn notify() {
+    // not started by systemd
+    std::env::remove_var("NOTIFY_SOCKET");
+    assert!(!service::notify("READY=1").unwrap());
+
+    let path = std::env::temp_dir().join(format!("authn-notify-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    std::env::set_var("NOTIFY_SOCKET", &path);
+
+    service::ready();
+    assert_eq!(recv(&socket), "READY=1");
+
+    // the watchdog is pinged at half its interval while the database answers
+    std::env::set_var("WATCHDOG_USEC", "200000");
+    std::env::set_var("WATCHDOG_PID", std::process::id().to_string());
+    let _server = TestServer::new().await.unwrap();
+    assert_eq!(recv(&socket), "WATCHDOG=1");
+    assert_eq!(recv(&socket), "WATCHDOG=1");
+
+    std::fs::remove_file(&path).unwrap();
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("authn-notify-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+
+        assert!(service::notify("STOPPING=1").unwrap());
+        assert_eq!(recv(&socket), "STOPPING=1");
+    }
+}
@@ -0,0 +1,299 @@
+// the test server and ssh agent listen on unix sockets
+#![cfg(unix)]
+
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use jsonwebtoken as jwt;
+use ring::rand::SystemRandom;
+use ring::signature::{self, KeyPair};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+
+use authn::client;
+use authn::crypto;
+use authn::ssh;
+use authn::testing::{TestServer, SERVER_NAME};
+
+fn put_string(buf : &mut Vec<u8>, s : &[u8]) {
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+fn put_mpint(buf : &mut Vec<u8>, n : &[u8]) {
+    let start = n.iter().position(|b| *b != 0).unwrap_or(n.len());
+    let mut mpint = n[start..].to_vec();
+    if mpint.first().is_some_and(|b| b & 0x80 != 0) {
+        mpint.insert(0, 0);
+    }
+
+    put_string(buf, &mpint);
+}
+
+fn read_string<'a>(buf : &mut &'a [u8]) -> &'a [u8] {
+    let n = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    let s = &buf[4..4 + n];
+    *buf = &buf[4 + n..];
+    s
+}
+
+fn ed25519_key() -> signature::Ed25519KeyPair {
+    let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+    signature::Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+}
+
+fn ed25519_blob(key : &signature::Ed25519KeyPair) -> Vec<u8> {
+    let mut blob = Vec::new();
+    put_string(&mut blob, b"ssh-ed25519");
+    put_string(&mut blob, key.public_key().as_ref());
+    blob
+}
+
+fn ed25519_sign(key : &signature::Ed25519KeyPair, data : &[u8]) -> Vec<u8> {
+    let mut sig = Vec::new();
+    put_string(&mut sig, b"ssh-ed25519");
+    put_string(&mut sig, key.sign(data).as_ref());
+    sig
+}
+
+/// the line of authorized_keys for `blob`
+fn authorized_key(blob : &[u8], comment : &str) -> String {
+    format!("ssh-ed25519 {} {}", base64::encode(blob), comment)
+}
+
+/// serves the ssh agent protocol at `path`, holding `key`
+fn agent(path : &Path, key : signature::Ed25519KeyPair) {
+    let listener = UnixListener::bind(path).unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            loop {
+                let mut len = [0u8;4];
+                if stream.read_exact(&mut len).await.is_err() {
+                    break
+                }
+                let mut msg = vec![0u8;u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut msg).await.unwrap();
+
+                let mut res = Vec::new();
+                match msg[0] {
+                    // request identities
+                    11 => {
+                        res.push(12);
+                        res.extend_from_slice(&1u32.to_be_bytes());
+                        put_string(&mut res, &ed25519_blob(&key));
+                        put_string(&mut res, b"alice@laptop");
+                    },
+                    // sign request
+                    13 => {
+                        let mut body = &msg[1..];
+                        assert_eq!(read_string(&mut body), ed25519_blob(&key));
+                        let data = read_string(&mut body);
+                        res.push(14);
+                        put_string(&mut res, &ed25519_sign(&key, data));
+                    },
+                    _ => res.push(5),
+                }
+
+                stream.write_all(&(res.len() as u32).to_be_bytes()).await.unwrap();
+                stream.write_all(&res).await.unwrap();
+            }
+        }
+    });
+}
+
+fn login_failed<T : std::fmt::Debug>(res : Result<T, client::Error>) -> bool {
+    matches!(res, Err(client::Error::Api(e)) if e == "login failed")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn login() {
+    let server = TestServer::new().await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+    server.add_user("bob", "hunter2").await.unwrap();
+
+    let key = ed25519_key();
+    let blob = ed25519_blob(&key);
+    let sock = server.dir().join("agent.sock");
+    agent(&sock, key);
+
+    let own = server.client(SERVER_NAME);
+    let token = own.login("alice", "hunter2", Duration::from_secs(60)).await.unwrap();
+    let id = own.add_ssh_key(&token, &authorized_key(&blob, "alice@laptop")).await.unwrap();
+
+    let keys = own.ssh_keys(&token).await.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].id, id);
+    assert_eq!(keys[0].public_key, format!("ssh-ed25519 {}", base64::encode(&blob)));
+    assert_eq!(keys[0].comment, "alice@laptop");
+    assert_eq!(keys[0].fingerprint, ssh::fingerprint(&blob));
+    assert!(keys[0].last_used.is_none());
+
+    let client = server.client("example.com");
+    let mut agent = ssh::Agent::connect(&sock).await.unwrap();
+    let ssh_token = ssh::login(&client, &mut agent, "alice", Duration::from_secs(60)).await.unwrap();
+    let claims = client.validate_token_claims(&ssh_token).await.unwrap();
+    assert_eq!(claims.sub, "alice");
+    assert_eq!(claims.acr, crypto::Assurance::SshKey);
+    assert!(own.ssh_keys(&token).await.unwrap()[0].last_used.is_some());
+
+    // the key is only alice's
+    assert!(login_failed(ssh::login(&client, &mut agent, "bob", Duration::from_secs(60)).await));
+
+    own.remove_ssh_key(&token, id).await.unwrap();
+    assert!(login_failed(ssh::login(&client, &mut agent, "alice", Duration::from_secs(60)).await));
+
+    let audit = server.database().list_audit(None, 10).await.unwrap();
+    assert_eq!(audit[0].action, "add-ssh-key");
+    assert_eq!(audit[0].detail.as_deref(), Some(ssh::fingerprint(&blob).as_str()));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn challenges() {
+    let server = TestServer::new().await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+
+    let key = ed25519_key();
+    let blob = ed25519_blob(&key);
+    let own = server.client(SERVER_NAME);
+    let token = own.login("alice", "hunter2", Duration::from_secs(60)).await.unwrap();
+    own.add_ssh_key(&token, &authorized_key(&blob, "")).await.unwrap();
+
+    let client = server.client("example.com");
+    let public_key = base64::encode(&blob);
+
+    // answered once
+    let challenge = client.ssh_challenge("alice", &public_key).await.unwrap().challenge;
+    let signature = base64::encode(ed25519_sign(&key, &ssh::signed_data(client.issuer(), &challenge)));
+    assert!(client.login_ssh(&challenge, &signature, Duration::from_secs(60)).await.is_ok());
+    assert!(login_failed(client.login_ssh(&challenge, &signature, Duration::from_secs(60)).await));
+
+    // a challenge relayed from another server
+    let challenge = client.ssh_challenge("alice", &public_key).await.unwrap().challenge;
+    let signature = base64::encode(ed25519_sign(&key, &ssh::signed_data("evil.test", &challenge)));
+    assert!(login_failed(client.login_ssh(&challenge, &signature, Duration::from_secs(60)).await));
+
+    // another key's signature
+    let challenge = client.ssh_challenge("alice", &public_key).await.unwrap().challenge;
+    let signature = base64::encode(ed25519_sign(&ed25519_key(), &ssh::signed_data(client.issuer(), &challenge)));
+    assert!(login_failed(client.login_ssh(&challenge, &signature, Duration::from_secs(60)).await));
+
+    // unknown keys and users look the same
+    let other = base64::encode(ed25519_blob(&ed25519_key()));
+    assert!(login_failed(client.ssh_challenge("alice", &other).await));
+    assert!(login_failed(client.ssh_challenge("mallory", &public_key).await));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn adding_keys() {
+    let server = TestServer::new().await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+
+    let blob = ed25519_blob(&ed25519_key());
+    let client = server.client(SERVER_NAME);
+    let token = client.login("alice", "hunter2", Duration::from_secs(60)).await.unwrap();
+
+    // the key type must match the key
+    let res = client.add_ssh_key(&token, &format!("ssh-rsa {}", base64::encode(&blob))).await;
+    assert!(matches!(res, Err(client::Error::Api(e)) if e == "bad request"));
+    let res = client.add_ssh_key(&token, "ssh-ed25519 AAAA").await;
+    assert!(matches!(res, Err(client::Error::Api(e)) if e == "bad request"));
+
+    // adding it again only updates the comment
+    let id = client.add_ssh_key(&token, &authorized_key(&blob, "old")).await.unwrap();
+    assert_eq!(client.add_ssh_key(&token, &authorized_key(&blob, "new")).await.unwrap(), id);
+    assert_eq!(client.ssh_keys(&token).await.unwrap()[0].comment, "new");
+
+    // a token from a login long ago can't add keys
+    let jwt::TokenData{ header, mut claims } = jwt::dangerous_insecure_decode::<serde_json::Value>(&token).unwrap();
+    claims["auth_time"] = serde_json::json!(claims["iat"].as_u64().unwrap() - 10 * 60);
+    let key = jwt::EncodingKey::from_ec_pem(include_bytes!("../src/test-priv-key.pem")).unwrap();
+    let stale = jwt::encode(&header, &claims, &key).unwrap();
+    let res = client.add_ssh_key(&stale, &authorized_key(&ed25519_blob(&ed25519_key()), "")).await;
+    assert!(matches!(res, Err(client::Error::Api(e)) if e == "forbidden"));
+}
+
+/// the ECDSA and RSA signatures agents make, checked without a server
+#[test]
+fn verify() {
+    let data = ssh::signed_data(SERVER_NAME, "challenge");
+    let rng = SystemRandom::new();
+
+    let pem = include_str!("../src/test-priv-key.pem");
+    let der = base64::decode(pem.lines().filter(|line| !line.starts_with("-----")).collect::<String>()).unwrap();
+    let key = signature::EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &der).unwrap();
+    let mut blob = Vec::new();
+    put_string(&mut blob, b"ecdsa-sha2-nistp256");
+    put_string(&mut blob, b"nistp256");
+    put_string(&mut blob, key.public_key().as_ref());
+    let fixed = key.sign(&rng, &data).unwrap();
+    let mut rs = Vec::new();
+    put_mpint(&mut rs, &fixed.as_ref()[..32]);
+    put_mpint(&mut rs, &fixed.as_ref()[32..]);
+    let mut sig = Vec::new();
+    put_string(&mut sig, b"ecdsa-sha2-nistp256");
+    put_string(&mut sig, &rs);
+    assert!(ssh::verify(&blob, &data, &sig));
+    assert!(!ssh::verify(&blob, b"other data", &sig));
+
+    let pem = include_str!("../src/test-rsa-priv-key.pem");
+    let der = base64::decode(pem.lines().filter(|line| !line.starts_with("-----")).collect::<String>()).unwrap();
+    let key = signature::RsaKeyPair::from_pkcs8(&der).unwrap();
+    let mut blob = Vec::new();
+    put_string(&mut blob, b"ssh-rsa");
+    put_mpint(&mut blob, key.public_key().exponent().big_endian_without_leading_zero());
+    put_mpint(&mut blob, key.public_key().modulus().big_endian_without_leading_zero());
+    let mut rsa_sig = vec![0u8;key.public_modulus_len()];
+    key.sign(&signature::RSA_PKCS1_SHA256, &rng, &data, &mut rsa_sig).unwrap();
+    let mut sig = Vec::new();
+    put_string(&mut sig, b"rsa-sha2-256");
+    put_string(&mut sig, &rsa_sig);
+    assert!(ssh::verify(&blob, &data, &sig));
+
+    // ssh-rsa signatures are SHA-1
+    let mut sig = Vec::new();
+    put_string(&mut sig, b"ssh-rsa");
+    put_string(&mut sig, &rsa_sig);
+    assert!(!ssh::verify(&blob, &data, &sig));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn authn_utils() {
+    let server = TestServer::new().await.unwrap();
+    server.add_user("alice", "hunter2").await.unwrap();
+
+    let key = ed25519_key();
+    let key_file = server.dir().join("id_ed25519.pub");
+    std::fs::write(&key_file, authorized_key(&ed25519_blob(&key), "alice@laptop")).unwrap();
+    let sock = server.dir().join("agent.sock");
+    agent(&sock, key);
+
+    let config = server.dir().join("utils-config.json");
+    std::fs::write(&config, serde_json::json!({
+        "server_path" : server.path(),
+        "server_name" : SERVER_NAME,
+        "client_name" : "example.com",
+        "alg" : "ES256",
+        "pub_key_file" : server.dir().join("pub-key.pem"),
+    }).to_string()).unwrap();
+    let authn_utils = |args : &[&str]| Command::new(env!("CARGO_BIN_EXE_authn-utils"))
+        .args(args)
+        .env("AUTHN_CONFIG", &config)
+        .env("SSH_AUTH_SOCK", &sock)
+        .output()
+        .unwrap();
+
+    let db = server.dir().join("authn.sqlite3");
+    let out = authn_utils(&["add-ssh-key", db.to_str().unwrap(), "alice", key_file.to_str().unwrap()]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+
+    let out = authn_utils(&["login", "alice", "60", "--ssh"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let token = String::from_utf8(out.stdout).unwrap();
+
+    let client = server.client("example.com");
+    assert_eq!(client.validate_token(token.trim()).await.unwrap(), "alice");
+}
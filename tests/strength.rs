@@ -0,0 +1,59 @@
+use authn::strength::{self, Config, MAX_SCORE};
+
+#[test]
+fn common_passwords() {
+    for pass in ["password", "123456", "qwerty", "P@ssw0rd", "drowssap", "letmein1"] {
+        let estimate = strength::estimate(pass, &[]);
+        assert_eq!(estimate.score, 0, "{}", pass);
+        assert!(estimate.warning.is_some(), "{}", pass);
+    }
+
+    let estimate = strength::estimate("password", &[]);
+    assert_eq!(estimate.warning.as_deref(), Some("This is a top-10 common password"));
+}
+
+#[test]
+fn patterns() {
+    let warning = |pass : &str| strength::estimate(pass, &[]).warning;
+
+    assert_eq!(warning("aaaaaaaa").as_deref(), Some("Repeats like \"aaa\" are easy to guess"));
+    assert_eq!(warning("abcdefgh").as_deref(), Some("Sequences like abc or 6543 are easy to guess"));
+    assert_eq!(warning("zxcvbnm,.").as_deref(), Some("Straight rows of keys are easy to guess"));
+    assert_eq!(warning("1987").as_deref(), Some("Recent years are easy to guess"));
+}
+
+#[test]
+fn user_inputs() {
+    let estimate = strength::estimate("Carol1990", &["carol"]);
+    assert!(estimate.score < 2);
+    assert_eq!(estimate.warning.as_deref(), Some("Passwords with your name in them are easy to guess"));
+    assert!(estimate.suggestions.iter().any(|s| s == "Capitalization doesn't help very much"));
+
+    assert!(strength::estimate("Carol1990", &[]).guesses_log10 > estimate.guesses_log10);
+}
+
+#[test]
+fn strong_passwords() {
+    for pass in ["flat ochre tumbleweed sings", "vT7#qLp2!zR9wX", "correct horse battery staple"] {
+        let estimate = strength::estimate(pass, &["alice"]);
+        assert_eq!(estimate.score, MAX_SCORE, "{}", pass);
+        assert_eq!(estimate.warning, None);
+        assert!(estimate.suggestions.is_empty());
+    }
+}
+
+#[test]
+fn empty() {
+    let estimate = strength::estimate("", &[]);
+    assert_eq!(estimate.score, 0);
+    assert!(!estimate.suggestions.is_empty());
+}
+
+#[test]
+fn min_score() {
+    let config = Config{ min_score : 3 };
+    assert!(config.check("monkey123", &[]).is_err());
+    assert!(config.check("flat ochre tumbleweed sings", &[]).is_ok());
+
+    assert!(Config::default().check("", &[]).is_ok());
+}